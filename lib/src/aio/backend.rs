@@ -6,13 +6,16 @@ use std::sync::mpsc;
 /// A lock held on the backend
 ///
 /// It doesn't do much, except unlock on `drop`.
-pub(crate) trait Lock {}
+pub trait Lock {}
 
 /// Backend API
 ///
 /// Backend is thread-safe, and the actual work
 /// is implemented by per-thread instances of it.
-pub(crate) trait Backend: Send + Sync {
+///
+/// All paths passed to the backend are relative to the root
+/// of the repository and use `/` as a separator.
+pub trait Backend: Send + Sync {
     /// Lock the repository exclusively
     ///
     /// Use to protect operations that are potentially destructive,
@@ -24,19 +27,56 @@ pub(crate) trait Backend: Send + Sync {
     /// Use to protect operations that only add new data, like `write`.
     fn lock_shared(&self) -> io::Result<Box<dyn Lock>>;
 
+    /// Like `lock_exclusive`, but fail with `WouldBlock` instead of
+    /// waiting for other lock holders
+    ///
+    /// Default implementation just calls `lock_exclusive`.
+    fn try_lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.lock_exclusive()
+    }
+    /// Like `lock_shared`, but fail with `WouldBlock` instead of waiting
+    /// for other lock holders
+    ///
+    /// Default implementation just calls `lock_shared`.
+    fn try_lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.lock_shared()
+    }
+
+    /// Whether `BackendThread::read_range` reads just the requested range
+    ///
+    /// Repositories storing chunks in packs (`Layout::Packs`) read every
+    /// chunk with it, so they can't be created on backends reading whole
+    /// files instead. Default implementation returns `false`.
+    fn ranged_reads(&self) -> bool {
+        false
+    }
+
     /// Spawn a new thread object of the backend.
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>>;
 }
 
-pub(crate) trait BackendThread: Send {
+/// Per-thread backend instance
+///
+/// Every worker thread of the IO pool gets its own instance
+/// (eg. with its own connection), created with `Backend::new_thread`.
+pub trait BackendThread: Send {
+    /// Remove a directory and everything in it
+    ///
+    /// Returns `NotFound` error if there was nothing to remove.
     fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()>;
 
+    /// Move a file, creating parent directories if needed
     fn rename(
         &mut self,
         src_path: PathBuf,
         dst_path: PathBuf,
     ) -> io::Result<()>;
 
+    /// Write a file, creating parent directories if needed
+    ///
+    /// Readers must never observe partially written data. If
+    /// `idempotent` is set and the file already exists, the write can be
+    /// skipped, as the content is guaranteed to be the same.
     fn write(
         &mut self,
         path: PathBuf,
@@ -44,13 +84,46 @@ pub(crate) trait BackendThread: Send {
         idempotent: bool,
     ) -> io::Result<()>;
 
+    /// Read whole file
     fn read(&mut self, path: PathBuf) -> io::Result<SGData>;
 
+    /// Read `len` bytes starting at `offset`
+    ///
+    /// Default implementation reads the whole file; backends that
+    /// support it should override it (and `Backend::ranged_reads`).
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let data = self.read(path)?.to_linear_vec();
+        let start = offset as usize;
+        let end = start + len as usize;
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "range past the end of the file",
+            ));
+        }
+        Ok(SGData::from_single(data[start..end].to_vec()))
+    }
+
+    /// Remove a file
     fn remove(&mut self, path: PathBuf) -> io::Result<()>;
 
+    /// Get file metadata; `NotFound` error if the file does not exist
     fn read_metadata(&mut self, path: PathBuf) -> io::Result<super::Metadata>;
+
+    /// List direct children of a directory
+    ///
+    /// Only the file names of the returned paths are used. Non-existing
+    /// directory should be reported as empty.
     fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>>;
 
+    /// List all files under a directory, in batches sent over `tx`
+    ///
+    /// Only the file names of the returned paths are used.
     fn list_recursively(
         &mut self,
         path: PathBuf,
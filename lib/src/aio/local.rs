@@ -4,9 +4,10 @@ use rand::distributions::Alphanumeric;
 use rand::Rng;
 use INGRESS_BUFFER_SIZE;
 
+use fs2;
 use fs2::FileExt;
 use sgdata::SGData;
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::{fs, io, mem};
@@ -23,6 +24,32 @@ pub(crate) fn lock_file_path(path: &Path) -> PathBuf {
     path.join(config::LOCK_FILE)
 }
 
+/// Lock `path` with `try_lock`, failing with `WouldBlock` if it's held by
+/// another process
+fn try_lock_file<F>(path: &Path, try_lock: F) -> io::Result<fs::File>
+where
+    F: Fn(&fs::File) -> io::Result<()>,
+{
+    let file = fs::File::create(path)?;
+
+    match try_lock(&file) {
+        Ok(()) => Ok(file),
+        Err(ref e)
+            if e.raw_os_error()
+                == fs2::lock_contended_error().raw_os_error() =>
+        {
+            Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "repository is locked by another process ({})",
+                    path.display()
+                ),
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Local {
     path: PathBuf,
@@ -53,6 +80,26 @@ impl Backend for Local {
         Ok(Box::new(file))
     }
 
+    fn try_lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        let lock_path = lock_file_path(&self.path);
+
+        let file = try_lock_file(&lock_path, |file| file.try_lock_exclusive())?;
+
+        Ok(Box::new(file))
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        let lock_path = lock_file_path(&self.path);
+
+        let file = try_lock_file(&lock_path, |file| file.try_lock_shared())?;
+
+        Ok(Box::new(file))
+    }
+
+    fn ranged_reads(&self) -> bool {
+        true
+    }
+
     fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
         Ok(Box::new(LocalThread {
             path: self.path.clone(),
@@ -143,6 +190,22 @@ impl BackendThread for LocalThread {
         }
     }
 
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let path = self.path.join(path);
+
+        let mut file = fs::File::open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = vec![0u8; len as usize];
+        file.read_exact(&mut buf)?;
+        Ok(SGData::from_single(buf))
+    }
+
     fn remove(&mut self, path: PathBuf) -> io::Result<()> {
         let path = self.path.join(path);
         fs::remove_file(&path)
@@ -152,8 +215,8 @@ impl BackendThread for LocalThread {
         let path = self.path.join(path);
         let md = fs::metadata(&path)?;
         Ok(Metadata {
-            _len: md.len(),
-            _is_file: md.is_file(),
+            len: md.len(),
+            is_file: md.is_file(),
         })
     }
 
@@ -210,4 +273,35 @@ impl BackendThread for LocalThread {
     }
 }
 
+#[test]
+fn local_lock_contention() {
+    let dir = ::std::env::temp_dir().join("rdedup-tests").join(
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(20)
+            .collect::<String>(),
+    );
+    fs::create_dir_all(&dir).unwrap();
+    let local = Local::new(dir.clone());
+    let exclusive = || local.try_lock_exclusive();
+    let shared = || local.try_lock_shared();
+    let is_locked = |res: io::Result<Box<dyn Lock>>| {
+        res.err().map(|e| e.kind()) == Some(io::ErrorKind::WouldBlock)
+    };
+
+    let lock = exclusive().unwrap();
+    assert!(is_locked(shared()));
+    assert!(is_locked(exclusive()));
+    drop(lock);
+
+    let lock1 = shared().unwrap();
+    let lock2 = shared().unwrap();
+    assert!(is_locked(exclusive()));
+    drop((lock1, lock2));
+    assert!(exclusive().is_ok());
+    assert!(local.lock_exclusive().is_ok());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
 // vim: foldmethod=marker foldmarker={{{,}}}
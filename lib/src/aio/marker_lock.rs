@@ -0,0 +1,217 @@
+//! Locks emulated with marker files
+//!
+//! Backends without native locking (`Sftp`, `S3`) keep markers in
+//! `config::LOCK_DIR`:
+//!
+//! * exclusive lock holder creates `exclusive` (failing if it exists), and
+//!   then waits until all `shared-*` markers are gone,
+//! * shared lock holder creates its own `shared-<random>` marker, and backs
+//!   off if `exclusive` exists.
+//!
+//! Both sides create their own marker before checking for the other one,
+//! so at least one of them will always notice the conflict.
+// {{{ use and mod
+use chrono;
+use rand;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::{io, process, time};
+
+use super::lock_wait;
+use super::Lock;
+// }}}
+
+const EXCLUSIVE_MARKER: &'static str = "exclusive";
+const SHARED_MARKER_PREFIX: &'static str = "shared-";
+
+/// Give up waiting for a lock after that much time
+///
+/// Markers are left behind if `rdedup` gets killed, so waiting forever is
+/// not an option.
+pub(crate) const LOCK_TIMEOUT_SECS: u64 = 60 * 60;
+
+/// Place where a backend keeps lock markers
+pub(crate) trait MarkerStore {
+    /// Create marker `name`; `false` if it already exists
+    fn create_new(&self, name: &str, content: &[u8]) -> io::Result<bool>;
+
+    /// Check if marker `name` exists
+    fn exists(&self, name: &str) -> io::Result<bool>;
+
+    /// Names of the markers starting with `prefix`
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    fn remove(&self, name: &str) -> io::Result<()>;
+
+    /// Where the markers are, to tell the user
+    fn location(&self) -> String;
+}
+
+/// Lock held by keeping a marker; the marker is removed on `drop`
+pub(crate) struct MarkerLock<S: MarkerStore> {
+    store: S,
+    name: String,
+}
+
+impl<S: MarkerStore> Lock for MarkerLock<S> {}
+
+impl<S: MarkerStore> Drop for MarkerLock<S> {
+    fn drop(&mut self) {
+        let _ = self.store.remove(&self.name);
+    }
+}
+
+/// Content of a marker, to help finding out who left it behind
+fn description() -> String {
+    format!(
+        "pid: {}\ncreated: {}\n",
+        process::id(),
+        chrono::Utc::now().to_rfc3339()
+    )
+}
+
+fn wait<S: MarkerStore>(
+    start: time::Instant,
+    timeout_secs: u64,
+    store: &S,
+) -> io::Result<()> {
+    lock_wait(
+        start,
+        timeout_secs,
+        &format!(
+            "repository is locked; if no other `rdedup` instance is running \
+             remove stale lock file(s) in {}",
+            store.location()
+        ),
+    )
+}
+
+impl<S: MarkerStore> MarkerLock<S> {
+    /// Lock exclusively, waiting at most `timeout_secs`
+    pub(crate) fn exclusive(store: S, timeout_secs: u64) -> io::Result<Self> {
+        let start = time::Instant::now();
+        while !store.create_new(EXCLUSIVE_MARKER, description().as_bytes())? {
+            wait(start, timeout_secs, &store)?
+        }
+
+        // From now on, dropping `lock` will remove the marker
+        let lock = MarkerLock {
+            store,
+            name: EXCLUSIVE_MARKER.into(),
+        };
+        while !lock.store.list(SHARED_MARKER_PREFIX)?.is_empty() {
+            wait(start, timeout_secs, &lock.store)?
+        }
+        Ok(lock)
+    }
+
+    /// Lock in shared mode, waiting at most `timeout_secs`
+    pub(crate) fn shared(store: S, timeout_secs: u64) -> io::Result<Self> {
+        let name = format!(
+            "{}{}",
+            SHARED_MARKER_PREFIX,
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(20)
+                .collect::<String>()
+        );
+
+        let start = time::Instant::now();
+        loop {
+            store.create_new(&name, description().as_bytes())?;
+            match store.exists(EXCLUSIVE_MARKER) {
+                Ok(false) => return Ok(MarkerLock { store, name }),
+                res => {
+                    // back off to let the exclusive lock holder in
+                    let _ = store.remove(&name);
+                    res?;
+                    wait(start, timeout_secs, &store)?
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use std::collections::BTreeSet;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Markers kept in memory, shared between clones
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct TestStore(Arc<Mutex<BTreeSet<String>>>);
+
+#[cfg(test)]
+impl MarkerStore for TestStore {
+    fn create_new(&self, name: &str, _content: &[u8]) -> io::Result<bool> {
+        Ok(self.0.lock().unwrap().insert(name.into()))
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        Ok(self.0.lock().unwrap().contains(name))
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        self.0.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        "test-locks".into()
+    }
+}
+
+#[test]
+fn marker_lock_conflicts() {
+    let store = TestStore::default();
+    let markers = || store.0.lock().unwrap().len();
+    let is_locked = |res: io::Result<MarkerLock<TestStore>>| match res {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            assert!(e.to_string().contains("test-locks"));
+            true
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => false,
+    };
+
+    // shared locks don't exclude each other
+    let shared1 = MarkerLock::shared(store.clone(), 0).unwrap();
+    let shared2 = MarkerLock::shared(store.clone(), 0).unwrap();
+    assert_eq!(markers(), 2);
+
+    // exclusive waits for shared ones, and doesn't leave its marker behind
+    assert!(is_locked(MarkerLock::exclusive(store.clone(), 0)));
+    assert_eq!(markers(), 2);
+    drop(shared1);
+    assert!(is_locked(MarkerLock::exclusive(store.clone(), 0)));
+    drop(shared2);
+    assert_eq!(markers(), 0);
+
+    // shared backs off while exclusive is held
+    let exclusive = MarkerLock::exclusive(store.clone(), 0).unwrap();
+    assert!(is_locked(MarkerLock::shared(store.clone(), 0)));
+    assert!(is_locked(MarkerLock::exclusive(store.clone(), 0)));
+    assert_eq!(markers(), 1);
+    drop(exclusive);
+    assert_eq!(markers(), 0);
+
+    // a stale marker
+    store.create_new(EXCLUSIVE_MARKER, b"").unwrap();
+    assert!(is_locked(MarkerLock::shared(store.clone(), 0)));
+    store.remove(EXCLUSIVE_MARKER).unwrap();
+    let _shared = MarkerLock::shared(store.clone(), 0).unwrap();
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
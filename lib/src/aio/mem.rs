@@ -0,0 +1,424 @@
+//! In-memory backend
+//!
+//! Data is kept in a process-global registry, keyed by the name
+//! from the `mem:<name>` url, so opening the same url twice gives
+//! access to the same repository. The data lives until the process exits,
+//! or until it's removed with `Repo::remove_mem`.
+// {{{ use and mod
+use sgdata::SGData;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::{cmp, io};
+
+use super::{Backend, BackendThread};
+use super::{Lock, Metadata};
+// }}}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<HashMap<String, Arc<MemShared>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Default, Debug)]
+struct LockState {
+    shared: usize,
+    exclusive: bool,
+}
+
+#[derive(Default, Debug)]
+struct MemShared {
+    /// All files; directories are implicit
+    files: RwLock<BTreeMap<PathBuf, SGData>>,
+    lock: Mutex<LockState>,
+    lock_cond: Condvar,
+}
+
+#[derive(Debug)]
+pub(crate) struct Mem {
+    shared: Arc<MemShared>,
+}
+
+#[derive(Debug)]
+struct MemThread {
+    shared: Arc<MemShared>,
+}
+
+struct MemLock {
+    shared: Arc<MemShared>,
+    exclusive: bool,
+}
+
+impl Lock for MemLock {}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock.lock().unwrap();
+        if self.exclusive {
+            state.exclusive = false;
+        } else {
+            state.shared -= 1;
+        }
+        self.shared.lock_cond.notify_all();
+    }
+}
+
+/// Strip `.` (and root) components, so all keys have the same form
+///
+/// `..` is rejected, as there's no real directory tree to resolve it in.
+fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let mut res = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(c) => res.push(c),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("mem: {}: unsupported path", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// `len` bytes of `data` starting at `offset`; the data is not copied
+fn range(data: &SGData, mut offset: usize, mut len: usize) -> SGData {
+    let mut res = SGData::empty();
+    for part in data.as_parts() {
+        if len == 0 {
+            break;
+        }
+        if offset >= part.len() {
+            offset -= part.len();
+            continue;
+        }
+        let start = offset;
+        let end = cmp::min(part.len(), start + len);
+        res.push_arcref(part.clone().map(|part| &part[start..end]));
+        len -= end - start;
+        offset = 0;
+    }
+    res
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("mem: {}: not found", path.display()),
+    )
+}
+
+impl Mem {
+    /// Get (or create) in-memory repository with a given name
+    pub(crate) fn new(name: &str) -> Self {
+        let shared = REGISTRY
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(MemShared::default()))
+            .clone();
+
+        Mem { shared }
+    }
+
+    /// Remove in-memory repository with a given name from the registry
+    ///
+    /// The data is freed once no `Mem` instance uses it anymore.
+    /// Returns `false` if there was no such repository.
+    pub(crate) fn remove(name: &str) -> bool {
+        REGISTRY.lock().unwrap().remove(name).is_some()
+    }
+}
+
+impl Backend for Mem {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        let mut state = self.shared.lock.lock().unwrap();
+        while state.exclusive || state.shared > 0 {
+            state = self.shared.lock_cond.wait(state).unwrap();
+        }
+        state.exclusive = true;
+
+        Ok(Box::new(MemLock {
+            shared: self.shared.clone(),
+            exclusive: true,
+        }))
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        let mut state = self.shared.lock.lock().unwrap();
+        while state.exclusive {
+            state = self.shared.lock_cond.wait(state).unwrap();
+        }
+        state.shared += 1;
+
+        Ok(Box::new(MemLock {
+            shared: self.shared.clone(),
+            exclusive: false,
+        }))
+    }
+
+    fn ranged_reads(&self) -> bool {
+        true
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(MemThread {
+            shared: self.shared.clone(),
+        }))
+    }
+}
+
+impl MemThread {
+    /// All the files under `dir`
+    fn files_under(
+        files: &BTreeMap<PathBuf, SGData>,
+        dir: &Path,
+    ) -> Vec<PathBuf> {
+        // `PathBuf` is ordered by components, so everything under
+        // `dir` is in one continuous range
+        files
+            .range(dir.to_owned()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(dir))
+            .filter(|path| path.as_path() != dir)
+            .cloned()
+            .collect()
+    }
+}
+
+impl BackendThread for MemThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = normalize(&path)?;
+        let mut files = self.shared.files.write().unwrap();
+
+        let to_remove = MemThread::files_under(&files, &path);
+        if to_remove.is_empty() {
+            return Err(not_found(&path));
+        }
+        for path in to_remove {
+            files.remove(&path);
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let src_path = normalize(&src_path)?;
+        let dst_path = normalize(&dst_path)?;
+        let mut files = self.shared.files.write().unwrap();
+
+        if let Some(sg) = files.remove(&src_path) {
+            files.insert(dst_path, sg);
+            return Ok(());
+        }
+
+        // rename of a whole directory
+        let to_move = MemThread::files_under(&files, &src_path);
+        if to_move.is_empty() {
+            return Err(not_found(&src_path));
+        }
+        for path in to_move {
+            let sg = files.remove(&path).unwrap();
+            let rel = path.strip_prefix(&src_path).unwrap().to_owned();
+            files.insert(dst_path.join(rel), sg);
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        let path = normalize(&path)?;
+        let mut files = self.shared.files.write().unwrap();
+
+        if idempotent && files.contains_key(&path) {
+            return Ok(());
+        }
+        files.insert(path, sg);
+        Ok(())
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let path = normalize(&path)?;
+        self.shared
+            .files
+            .read()
+            .unwrap()
+            .get(&path)
+            .cloned()
+            .ok_or_else(|| not_found(&path))
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let path = normalize(&path)?;
+        let files = self.shared.files.read().unwrap();
+        let data = files.get(&path).ok_or_else(|| not_found(&path))?;
+        if offset + len > data.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("mem: {}: range past the end", path.display()),
+            ));
+        }
+        Ok(range(data, offset as usize, len as usize))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = normalize(&path)?;
+        self.shared
+            .files
+            .write()
+            .unwrap()
+            .remove(&path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(&path))
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let path = normalize(&path)?;
+        let files = self.shared.files.read().unwrap();
+
+        if let Some(sg) = files.get(&path) {
+            return Ok(Metadata {
+                len: sg.len() as u64,
+                is_file: true,
+            });
+        }
+
+        if MemThread::files_under(&files, &path).is_empty() {
+            Err(not_found(&path))
+        } else {
+            Ok(Metadata {
+                len: 0,
+                is_file: false,
+            })
+        }
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let path = normalize(&path)?;
+        let files = self.shared.files.read().unwrap();
+        let depth = path.components().count();
+
+        // Direct children: files and (implicit) directories
+        let children: BTreeSet<PathBuf> = MemThread::files_under(&files, &path)
+            .iter()
+            .map(|file| file.components().take(depth + 1).collect())
+            .collect();
+
+        Ok(children.into_iter().collect())
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        let path = match normalize(&path) {
+            Ok(path) => path,
+            Err(e) => {
+                tx.send(Err(e)).expect("send failed");
+                return;
+            }
+        };
+        let v = {
+            let files = self.shared.files.read().unwrap();
+            MemThread::files_under(&files, &path)
+        };
+
+        for chunk in v.chunks(100) {
+            tx.send(Ok(chunk.to_vec())).expect("send failed")
+        }
+    }
+}
+
+#[test]
+fn mem_list() {
+    let mut t = Mem::new("mem_list").new_thread().unwrap();
+
+    for p in &["a/b/c", "a/b/d", "a/e", "f", "a.x/g"] {
+        t.write(PathBuf::from(p), SGData::from_single(vec![1]), false)
+            .unwrap();
+    }
+
+    assert_eq!(
+        t.list(PathBuf::from(".")).unwrap(),
+        vec![PathBuf::from("a"), PathBuf::from("a.x"), PathBuf::from("f"),]
+    );
+    assert_eq!(
+        t.list(PathBuf::from("a")).unwrap(),
+        vec![PathBuf::from("a/b"), PathBuf::from("a/e")]
+    );
+    assert!(t.list(PathBuf::from("nope")).unwrap().is_empty());
+
+    assert!(!t.read_metadata(PathBuf::from("a/b")).unwrap().is_file);
+    t.rename(PathBuf::from("a/b"), PathBuf::from("h")).unwrap();
+    assert_eq!(t.read(PathBuf::from("h/c")).unwrap().len(), 1);
+
+    t.remove_dir_all(PathBuf::from("a")).unwrap();
+    assert_eq!(
+        t.read_metadata(PathBuf::from("a/e")).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert!(t.read(PathBuf::from("a.x/g")).is_ok());
+
+    assert_eq!(
+        t.read(PathBuf::from("a.x/../f")).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn mem_read_range() {
+    let mut t = Mem::new("mem_read_range").new_thread().unwrap();
+    let mut sg = SGData::from_single(vec![0, 1, 2]);
+    sg.push_vec(vec![3, 4]);
+    sg.push_vec(vec![5, 6, 7]);
+    t.write(PathBuf::from("a"), sg, false).unwrap();
+
+    for &(offset, len) in &[(0, 8), (0, 0), (1, 2), (2, 4), (3, 2), (4, 4)] {
+        assert_eq!(
+            t.read_range(PathBuf::from("a"), offset, len)
+                .unwrap()
+                .to_linear_vec(),
+            (offset as u8..(offset + len) as u8).collect::<Vec<_>>()
+        );
+    }
+    assert_eq!(
+        t.read_range(PathBuf::from("a"), 6, 3).unwrap_err().kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+}
+
+#[test]
+fn mem_remove() {
+    let mut t = Mem::new("mem_remove").new_thread().unwrap();
+    t.write(PathBuf::from("a"), SGData::from_single(vec![1]), false)
+        .unwrap();
+    assert!(Mem::new("mem_remove")
+        .new_thread()
+        .unwrap()
+        .read(PathBuf::from("a"))
+        .is_ok());
+
+    assert!(Mem::remove("mem_remove"));
+    assert!(!Mem::remove("mem_remove"));
+    // existing instances keep working, new ones start empty
+    assert!(t.read(PathBuf::from("a")).is_ok());
+    let mut t = Mem::new("mem_remove").new_thread().unwrap();
+    assert!(t.read(PathBuf::from("a")).is_err());
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -16,15 +16,22 @@ use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::{io, thread};
+use std::{io, thread, time};
 
 mod local;
 pub(crate) use self::local::Local;
 mod b2;
 pub(crate) use self::b2::B2;
+mod s3;
+pub(crate) use self::s3::S3;
+mod sftp;
+pub(crate) use self::sftp::Sftp;
+mod mem;
+pub(crate) use self::mem::Mem;
+mod marker_lock;
 
 mod backend;
-use self::backend::*;
+pub use self::backend::{Backend, BackendThread, Lock};
 
 // {{{ Misc
 struct WriteArgs {
@@ -34,9 +41,10 @@ struct WriteArgs {
     complete_tx: Option<mpsc::Sender<io::Result<()>>>,
 }
 
-pub(crate) struct Metadata {
-    _len: u64,
-    _is_file: bool,
+/// File metadata, as returned by `BackendThread::read_metadata`
+pub struct Metadata {
+    pub len: u64,
+    pub is_file: bool,
 }
 
 /// A result of async io operation
@@ -54,12 +62,46 @@ impl<T> AsyncIOResult<T> {
     pub fn wait(self) -> io::Result<T> {
         self.rx.recv().expect("No `AsyncIO` thread response")
     }
+
+    /// Get the result if it already arrived
+    pub(crate) fn try_wait(&self) -> Option<io::Result<T>> {
+        match self.rx.try_recv() {
+            Ok(res) => Some(res),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                panic!("No `AsyncIO` thread response")
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct WriteStats {
     pub new_chunks: usize,
     pub new_bytes: u64,
+    /// New data chunks stored uncompressed, as they don't compress well
+    /// (see `settings::Repo::set_adaptive_compression`)
+    pub uncompressed_chunks: usize,
+}
+// }}}
+
+// {{{ Lock
+const LOCK_POLL_INTERVAL_SECS: u64 = 1;
+
+/// Wait a bit before checking a lock held by someone else again
+///
+/// Fails with a `WouldBlock` error saying `reason` after waiting since
+/// `start` for more than `timeout_secs`.
+pub(crate) fn lock_wait(
+    start: time::Instant,
+    timeout_secs: u64,
+    reason: &str,
+) -> io::Result<()> {
+    if start.elapsed() > time::Duration::from_secs(timeout_secs) {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, reason));
+    }
+    thread::sleep(time::Duration::from_secs(LOCK_POLL_INTERVAL_SECS));
+    Ok(())
 }
 // }}}
 
@@ -70,6 +112,7 @@ pub struct WriteStats {
 enum Message {
     Write(WriteArgs),
     Read(PathBuf, mpsc::Sender<io::Result<SGData>>),
+    ReadRange(PathBuf, u64, u64, mpsc::Sender<io::Result<SGData>>),
     ReadMetadata(PathBuf, mpsc::Sender<io::Result<Metadata>>),
     List(PathBuf, mpsc::Sender<io::Result<Vec<PathBuf>>>),
     ListRecursively(PathBuf, mpsc::Sender<io::Result<Vec<PathBuf>>>),
@@ -94,7 +137,7 @@ pub struct AsyncIO {
 
 impl AsyncIO {
     pub(crate) fn new(
-        backend: Box<dyn Backend + Send + Sync>,
+        backend: Arc<dyn Backend>,
         log: Logger,
     ) -> io::Result<Self> {
         let thread_num = 4 * num_cpus::get();
@@ -145,6 +188,14 @@ impl AsyncIO {
         self.shared.backend.lock_shared()
     }
 
+    pub(crate) fn try_lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.shared.backend.try_lock_exclusive()
+    }
+
+    pub(crate) fn try_lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.shared.backend.try_lock_shared()
+    }
+
     pub fn stats(&self) -> AsyncIOThreadShared {
         self.shared.stats.clone()
     }
@@ -184,8 +235,6 @@ impl AsyncIO {
         AsyncIOResult { rx }
     }
 
-    // TODO: No need for it anymore
-    #[allow(dead_code)]
     pub fn write_idempotent(
         &self,
         path: PathBuf,
@@ -214,6 +263,8 @@ impl AsyncIO {
         })).expect("aio tx closed: write_checked");
     }
 
+    // TODO: No need for it anymore
+    #[allow(dead_code)]
     pub fn write_checked_idempotent(&self, path: PathBuf, sg: SGData) {
         self.tx.send(Message::Write(WriteArgs {
             path,
@@ -229,6 +280,17 @@ impl AsyncIO {
         AsyncIOResult { rx }
     }
 
+    pub fn read_range(
+        &self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> AsyncIOResult<SGData> {
+        let (tx, rx) = mpsc::channel();
+        self.tx.send(Message::ReadRange(path, offset, len, tx)).expect("aio tx closed: read_range");
+        AsyncIOResult { rx }
+    }
+
     pub(crate) fn read_metadata(
         &self,
         path: PathBuf,
@@ -275,7 +337,7 @@ pub struct AsyncIOShared {
     join: Vec<thread::JoinHandle<()>>,
     log: slog::Logger,
     stats: AsyncIOThreadShared,
-    backend: Box<dyn Backend + Send + Sync>,
+    backend: Arc<dyn Backend>,
 }
 
 impl Drop for AsyncIOShared {
@@ -312,6 +374,7 @@ impl AsyncIOThreadShared {
             write_stats: WriteStats {
                 new_bytes: 0,
                 new_chunks: 0,
+                uncompressed_chunks: 0,
             },
             in_progress: Default::default(),
         };
@@ -321,6 +384,13 @@ impl AsyncIOThreadShared {
         }
     }
 
+    /// Account a chunk that was written not as a separate file
+    pub(crate) fn record_chunk_written(&self, len: u64) {
+        let mut sh = self.inner.lock().unwrap();
+        sh.write_stats.new_bytes += len;
+        sh.write_stats.new_chunks += 1;
+    }
+
     pub fn get_stats(&self) -> WriteStats {
         let sh = self.inner.lock().unwrap();
         sh.write_stats.clone()
@@ -382,6 +452,9 @@ impl AsyncIOThread {
                         complete_tx,
                     }) => self.write(path, data, idempotent, complete_tx),
                     Message::Read(path, tx) => self.read(path, tx),
+                    Message::ReadRange(path, offset, len, tx) => {
+                        self.read_range(path, offset, len, tx)
+                    }
                     Message::ReadMetadata(path, tx) => {
                         self.read_metadata(path, tx)
                     }
@@ -496,6 +569,25 @@ impl AsyncIOThread {
         tx.send(res).expect("send failed")
     }
 
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        tx: mpsc::Sender<io::Result<SGData>>,
+    ) {
+        trace!(self.log, "read-range"; "path" => %path.display(),
+               "offset" => offset, "len" => len);
+
+        self.time_reporter.start("read-range");
+        let res = {
+            let _guard = self.pending_wait_and_insert(&path);
+            self.backend.borrow_mut().read_range(path.clone(), offset, len)
+        };
+        self.time_reporter.start("read send response");
+        tx.send(res).expect("send failed")
+    }
+
     fn read_metadata(
         &mut self,
         path: PathBuf,
@@ -590,12 +682,13 @@ impl AsyncIOThread {
 // ```norust
 // let s = "file:/foo/bar";
 // let s = "b2:myid#bucket";
+// let s = "s3://bucket/prefix";
+// let s = "sftp://user@host:22/path";
+// let s = "mem:name";
 // ```
-pub(crate) fn backend_from_url(
-    u: &Url,
-) -> io::Result<Box<dyn Backend + Send + Sync>> {
+pub(crate) fn backend_from_url(u: &Url) -> io::Result<Arc<dyn Backend>> {
     if u.scheme() == "file" {
-        return Ok(Box::new(Local::new(PathBuf::from(u.path()))));
+        return Ok(Arc::new(Local::new(PathBuf::from(u.path()))));
     } else if u.scheme() == "b2" {
         let id = u.path();
         let bucket = u.fragment().ok_or_else(|| {
@@ -621,7 +714,13 @@ pub(crate) fn backend_from_url(
                     ),
                 )
             })?;
-        return Ok(Box::new(B2::new(id, bucket, &key)));
+        return Ok(Arc::new(B2::new(id, bucket, &key)));
+    } else if u.scheme() == "s3" {
+        return Ok(Arc::new(S3::new_from_url(u)?));
+    } else if u.scheme() == "sftp" {
+        return Ok(Arc::new(Sftp::new_from_url(u)?));
+    } else if u.scheme() == "mem" {
+        return Ok(Arc::new(Mem::new(u.path())));
     }
 
     return Err(io::Error::new(
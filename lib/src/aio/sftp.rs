@@ -0,0 +1,1021 @@
+//! SFTP (SSH) backend
+//!
+//! Every worker thread gets its own SSH session. Writes go to a temporary
+//! file first and are `rename`d into place, just like in `Local`.
+// {{{ use and mod
+use rand;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use INGRESS_BUFFER_SIZE;
+
+use sgdata::SGData;
+use ssh2;
+use ssh2::{
+    CheckResult, FileStat, KnownHostFileKind, OpenFlags, OpenType, RenameFlags,
+    Session,
+};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Arc};
+#[cfg(test)]
+use std::{collections::BTreeMap, sync::Mutex};
+use std::{env, io, mem};
+use url::{percent_encoding, Url};
+
+use super::marker_lock::{MarkerLock, MarkerStore, LOCK_TIMEOUT_SECS};
+use super::{Backend, BackendThread};
+use super::{Lock, Metadata};
+use config;
+// }}}
+
+// libssh2 SFTP status codes
+const LIBSSH2_FX_NO_SUCH_FILE: i32 = 2;
+const LIBSSH2_FX_PERMISSION_DENIED: i32 = 3;
+const LIBSSH2_FX_NO_SUCH_PATH: i32 = 10;
+const LIBSSH2_FX_FILE_ALREADY_EXISTS: i32 = 11;
+
+fn sftp_err(e: ssh2::Error, path: &Path) -> io::Error {
+    let kind = match e.code() {
+        LIBSSH2_FX_NO_SUCH_FILE | LIBSSH2_FX_NO_SUCH_PATH => {
+            io::ErrorKind::NotFound
+        }
+        LIBSSH2_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        LIBSSH2_FX_FILE_ALREADY_EXISTS => io::ErrorKind::AlreadyExists,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("sftp: {}: {}", path.display(), e))
+}
+
+fn ssh_err(e: ssh2::Error, host: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("ssh: {}: {}", host, e),
+    )
+}
+
+fn rand_ext() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(20)
+        .collect::<String>()
+}
+
+#[derive(Debug)]
+struct SftpShared {
+    host: String,
+    port: u16,
+    user: String,
+    /// Path of the repository on the remote host
+    path: PathBuf,
+}
+
+/// A connected SFTP session
+struct Connection {
+    // `Sftp` keeps the session alive on its own, but let's be explicit
+    _session: Session,
+    sftp: ssh2::Sftp,
+}
+
+impl SftpShared {
+    fn connect(&self) -> io::Result<Connection> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))?;
+        let mut session = Session::new().map_err(|e| ssh_err(e, &self.host))?;
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(|e| ssh_err(e, &self.host))?;
+
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+
+        let sftp = session.sftp().map_err(|e| ssh_err(e, &self.host))?;
+
+        Ok(Connection {
+            _session: session,
+            sftp,
+        })
+    }
+
+    fn check_host_key(&self, session: &Session) -> io::Result<()> {
+        let known_hosts_path = match env::var_os("RDEDUP_SFTP_KNOWN_HOSTS") {
+            Some(path) => PathBuf::from(path),
+            None => env::var_os("HOME")
+                .map(PathBuf::from)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "can't find home directory to locate \
+                         `.ssh/known_hosts`",
+                    )
+                })?
+                .join(".ssh")
+                .join("known_hosts"),
+        };
+
+        let mut known_hosts =
+            session.known_hosts().map_err(|e| ssh_err(e, &self.host))?;
+        known_hosts
+            .read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| ssh_err(e, &self.host))?;
+
+        let (key, _key_type) = session.host_key().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("ssh: {}: no host key", self.host),
+            )
+        })?;
+
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "ssh: {}: host key not found in {}",
+                    self.host,
+                    known_hosts_path.display()
+                ),
+            )),
+            CheckResult::Mismatch => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "ssh: {}: host key does not match the one in {}",
+                    self.host,
+                    known_hosts_path.display()
+                ),
+            )),
+            CheckResult::Failure => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("ssh: {}: host key check failed", self.host),
+            )),
+        }
+    }
+
+    fn authenticate(&self, session: &Session) -> io::Result<()> {
+        if let Some(key) = env::var_os("RDEDUP_SFTP_KEY") {
+            let passphrase = env::var("RDEDUP_SFTP_KEY_PASSPHRASE").ok();
+            session
+                .userauth_pubkey_file(
+                    &self.user,
+                    None,
+                    Path::new(&key),
+                    passphrase.as_ref().map(|s| s.as_str()),
+                )
+                .map_err(|e| ssh_err(e, &self.host))?;
+        } else if let Ok(password) = env::var("RDEDUP_SFTP_PASSWORD") {
+            session
+                .userauth_password(&self.user, &password)
+                .map_err(|e| ssh_err(e, &self.host))?;
+        } else {
+            session
+                .userauth_agent(&self.user)
+                .map_err(|e| ssh_err(e, &self.host))?;
+        }
+
+        if !session.authenticated() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("ssh: {}: authentication failed", self.host),
+            ));
+        }
+        Ok(())
+    }
+
+    fn lock_dir(&self) -> PathBuf {
+        self.path.join(config::LOCK_DIR)
+    }
+}
+
+// {{{ SftpFs
+/// Remote file operations used by `SftpThread`
+///
+/// Implemented by `Connection`; tests use an in-memory `TestFs` instead,
+/// as no SSH server can be counted on.
+trait SftpFs {
+    type File: Read + Seek;
+
+    fn stat(&self, path: &Path) -> io::Result<FileStat>;
+    fn mkdir(&self, path: &Path) -> io::Result<()>;
+    /// Create (or truncate) `path`, write `sg` to it and `fsync` it
+    fn write_file(&self, path: &Path, sg: &SGData) -> io::Result<()>;
+    fn open(&self, path: &Path) -> io::Result<Self::File>;
+    /// Move `src` to `dst`
+    ///
+    /// With `overwrite` the server is asked to atomically replace `dst`,
+    /// which servers speaking SFTP v3 (most of them) don't support.
+    fn rename(&self, src: &Path, dst: &Path, overwrite: bool)
+        -> io::Result<()>;
+    fn unlink(&self, path: &Path) -> io::Result<()>;
+    fn rmdir(&self, path: &Path) -> io::Result<()>;
+    fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>>;
+}
+
+impl SftpFs for Connection {
+    type File = ssh2::File;
+
+    fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        self.sftp.stat(path).map_err(|e| sftp_err(e, path))
+    }
+
+    fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.sftp.mkdir(path, 0o755).map_err(|e| sftp_err(e, path))
+    }
+
+    fn write_file(&self, path: &Path, sg: &SGData) -> io::Result<()> {
+        let mut file = self.sftp.create(path).map_err(|e| sftp_err(e, path))?;
+        for data_part in sg.as_parts() {
+            file.write_all(data_part)?;
+        }
+        file.fsync().map_err(|e| sftp_err(e, path))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<ssh2::File> {
+        self.sftp.open(path).map_err(|e| sftp_err(e, path))
+    }
+
+    fn rename(
+        &self,
+        src: &Path,
+        dst: &Path,
+        overwrite: bool,
+    ) -> io::Result<()> {
+        let flags = if overwrite {
+            Some(RenameFlags::ATOMIC | RenameFlags::OVERWRITE)
+        } else {
+            None
+        };
+        self.sftp
+            .rename(src, dst, flags)
+            .map_err(|e| sftp_err(e, dst))
+    }
+
+    fn unlink(&self, path: &Path) -> io::Result<()> {
+        self.sftp.unlink(path).map_err(|e| sftp_err(e, path))
+    }
+
+    fn rmdir(&self, path: &Path) -> io::Result<()> {
+        self.sftp.rmdir(path).map_err(|e| sftp_err(e, path))
+    }
+
+    fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>> {
+        self.sftp.readdir(path).map_err(|e| sftp_err(e, path))
+    }
+}
+// }}}
+
+// {{{ Lock
+/// Lock markers (see `aio::marker_lock`) kept in `config::LOCK_DIR`
+///
+/// SFTP has no file locking; markers are created with `O_EXCL`.
+struct SftpMarkers {
+    conn: Connection,
+    dir: PathBuf,
+}
+
+impl SftpMarkers {
+    fn new(shared: &SftpShared) -> io::Result<Self> {
+        let conn = shared.connect()?;
+        let dir = shared.lock_dir();
+        // might already exist; any real problem will surface
+        // when creating a marker
+        let _ = conn.sftp.mkdir(&dir, 0o755);
+        Ok(SftpMarkers { conn, dir })
+    }
+}
+
+impl MarkerStore for SftpMarkers {
+    fn create_new(&self, name: &str, content: &[u8]) -> io::Result<bool> {
+        let path = self.dir.join(name);
+        let mut file = match self.conn.sftp.open_mode(
+            &path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE,
+            0o644,
+            OpenType::File,
+        ) {
+            Ok(file) => file,
+            // servers report "already exists" inconsistently
+            Err(_) if self.conn.sftp.stat(&path).is_ok() => return Ok(false),
+            Err(e) => return Err(sftp_err(e, &path)),
+        };
+        file.write_all(content)?;
+        Ok(true)
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        let path = self.dir.join(name);
+        match self.conn.sftp.stat(&path) {
+            Ok(_) => Ok(true),
+            Err(e) => match sftp_err(e, &path) {
+                ref e if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .conn
+            .sftp
+            .readdir(&self.dir)
+            .map_err(|e| sftp_err(e, &self.dir))?
+            .into_iter()
+            .filter_map(|(path, _stat)| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.to_string())
+            })
+            .filter(|name| name.starts_with(prefix))
+            .collect())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        let path = self.dir.join(name);
+        self.conn.sftp.unlink(&path).map_err(|e| sftp_err(e, &path))
+    }
+
+    fn location(&self) -> String {
+        self.dir.display().to_string()
+    }
+}
+// }}}
+
+#[derive(Debug)]
+pub(crate) struct Sftp {
+    shared: Arc<SftpShared>,
+}
+
+struct SftpThread<F = Connection> {
+    shared: Arc<SftpShared>,
+    fs: F,
+    rand_ext: String,
+}
+
+impl Sftp {
+    /// Create a new instance from `sftp://user@host:port/path` url
+    ///
+    /// Authentication is done using (in order of preference):
+    ///
+    /// * private key file in `RDEDUP_SFTP_KEY` (with optional
+    ///   `RDEDUP_SFTP_KEY_PASSPHRASE`),
+    /// * password in `RDEDUP_SFTP_PASSWORD`,
+    /// * ssh-agent.
+    ///
+    /// Host key is checked against `~/.ssh/known_hosts`
+    /// (or `RDEDUP_SFTP_KNOWN_HOSTS`).
+    pub(crate) fn new_from_url(u: &Url) -> io::Result<Self> {
+        let host = u.host_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "host in the url missing",
+            )
+        })?;
+
+        let user = if u.username().is_empty() {
+            env::var("USER").map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "user in the url missing",
+                )
+            })?
+        } else {
+            percent_decode(u.username())?
+        };
+
+        Ok(Sftp {
+            shared: Arc::new(SftpShared {
+                host: host.into(),
+                port: u.port().unwrap_or(22),
+                user,
+                path: PathBuf::from(percent_decode(u.path())?),
+            }),
+        })
+    }
+}
+
+fn percent_decode(s: &str) -> io::Result<String> {
+    percent_encoding::percent_decode(s.as_bytes())
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("url component not valid utf-8: {}", s),
+            )
+        })
+}
+
+impl Backend for Sftp {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(MarkerLock::exclusive(
+            SftpMarkers::new(&self.shared)?,
+            LOCK_TIMEOUT_SECS,
+        )?))
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(MarkerLock::shared(
+            SftpMarkers::new(&self.shared)?,
+            LOCK_TIMEOUT_SECS,
+        )?))
+    }
+
+    fn try_lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(MarkerLock::exclusive(
+            SftpMarkers::new(&self.shared)?,
+            0,
+        )?))
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        Ok(Box::new(MarkerLock::shared(
+            SftpMarkers::new(&self.shared)?,
+            0,
+        )?))
+    }
+
+    fn ranged_reads(&self) -> bool {
+        true
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(SftpThread {
+            shared: self.shared.clone(),
+            fs: self.shared.connect()?,
+            rand_ext: rand_ext(),
+        }))
+    }
+}
+
+impl<F: SftpFs> SftpThread<F> {
+    /// Path of `path` (relative to the repository) on the remote host
+    ///
+    /// Only plain relative paths are accepted, so nothing outside of the
+    /// repository can be touched.
+    fn remote_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut res = self.shared.path.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => res.push(c),
+                Component::CurDir => {}
+                Component::ParentDir
+                | Component::RootDir
+                | Component::Prefix(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("sftp: unsupported path: {}", path.display()),
+                    ))
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// `mkdir -p`
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut missing = vec![];
+        let mut cur = Some(path);
+        while let Some(dir) = cur {
+            if self.fs.stat(dir).is_ok() {
+                break;
+            }
+            missing.push(dir);
+            cur = dir.parent();
+        }
+
+        for dir in missing.iter().rev() {
+            match self.fs.mkdir(dir) {
+                Ok(()) => {}
+                // possibly created concurrently by another thread
+                Err(_) if self.fs.stat(dir).is_ok() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace `path` with `tmp_path` on servers that can't overwrite
+    /// on rename
+    ///
+    /// Most servers speak SFTP v3, which does not support overwriting on
+    /// rename. The old file is moved aside first and removed only once the
+    /// new one is in place, so `path` is never lost - at worst it's missing
+    /// for a moment. This only happens when overwriting metadata files (eg.
+    /// `config.yml`) - chunks are never overwritten.
+    fn replace(&self, tmp_path: &Path, path: &Path) -> io::Result<()> {
+        let old_path = path.with_extension(format!("{}.old", self.rand_ext));
+        self.fs.rename(path, &old_path, false)?;
+
+        if let Err(e) = self.fs.rename(tmp_path, path, false) {
+            let _ = self.fs.rename(&old_path, path, false);
+            let _ = self.fs.unlink(tmp_path);
+            return Err(e);
+        }
+
+        let _ = self.fs.unlink(&old_path);
+        Ok(())
+    }
+
+    fn remove_dir_all_abs(&self, path: &Path) -> io::Result<()> {
+        for (entry, stat) in self.fs.readdir(path)? {
+            if stat.is_dir() {
+                self.remove_dir_all_abs(&entry)?;
+            } else {
+                self.fs.unlink(&entry)?;
+            }
+        }
+        self.fs.rmdir(path)
+    }
+
+    fn list_recursively_abs(
+        &self,
+        path: &Path,
+        v: &mut Vec<PathBuf>,
+        tx: &mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        let entries = match self.fs.readdir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tx.send(Err(e)).expect("send failed");
+                return;
+            }
+        };
+
+        for (entry, stat) in entries {
+            if stat.is_dir() {
+                self.list_recursively_abs(&entry, v, tx);
+            } else if stat.is_file() {
+                v.push(entry);
+                if v.len() > 100 {
+                    tx.send(Ok(mem::replace(v, vec![]))).expect("send failed")
+                }
+            }
+        }
+    }
+}
+
+impl<F: SftpFs + Send> BackendThread for SftpThread<F> {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = self.remote_path(&path)?;
+        self.remove_dir_all_abs(&path)
+    }
+
+    fn rename(
+        &mut self,
+        src_path: PathBuf,
+        dst_path: PathBuf,
+    ) -> io::Result<()> {
+        let src_path = self.remote_path(&src_path)?;
+        let dst_path = self.remote_path(&dst_path)?;
+
+        match self.fs.rename(&src_path, &dst_path, false) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if self.fs.stat(&src_path).is_err() {
+                    return Err(e);
+                }
+                self.create_dir_all(dst_path.parent().unwrap())?;
+                self.fs.rename(&src_path, &dst_path, false)
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: SGData,
+        idempotent: bool,
+    ) -> io::Result<()> {
+        let path = self.remote_path(&path)?;
+        if idempotent && self.fs.stat(&path).is_ok() {
+            return Ok(());
+        }
+
+        let tmp_path = path.with_extension(format!("{}.tmp", self.rand_ext));
+        if self.fs.write_file(&tmp_path, &sg).is_err() {
+            self.create_dir_all(path.parent().unwrap())?;
+            self.fs.write_file(&tmp_path, &sg)?;
+        }
+
+        match self.fs.rename(&tmp_path, &path, true) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if self.fs.stat(&path).is_err() {
+                    let _ = self.fs.unlink(&tmp_path);
+                    return Err(e);
+                }
+                if idempotent {
+                    let _ = self.fs.unlink(&tmp_path);
+                    return Ok(());
+                }
+                self.replace(&tmp_path, &path)
+            }
+        }
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let path = self.remote_path(&path)?;
+
+        let mut file = self.fs.open(&path)?;
+
+        let mut bufs = Vec::with_capacity(16 * 1024 / INGRESS_BUFFER_SIZE);
+        loop {
+            let mut buf: Vec<u8> = vec![0u8; INGRESS_BUFFER_SIZE];
+            let len = file.read(&mut buf[..])?;
+
+            if len == 0 {
+                return Ok(SGData::from_many(bufs));
+            }
+            buf.truncate(len);
+            bufs.push(buf);
+        }
+    }
+
+    fn read_range(
+        &mut self,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<SGData> {
+        let path = self.remote_path(&path)?;
+
+        let mut file = self.fs.open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut buf = vec![0u8; len as usize];
+        file.read_exact(&mut buf)?;
+        Ok(SGData::from_single(buf))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        let path = self.remote_path(&path)?;
+        self.fs.unlink(&path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let path = self.remote_path(&path)?;
+        let stat = self.fs.stat(&path)?;
+        Ok(Metadata {
+            len: stat.size.unwrap_or(0),
+            is_file: stat.is_file(),
+        })
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let path = self.remote_path(&path)?;
+
+        match self.fs.readdir(&path) {
+            Ok(entries) => {
+                Ok(entries.into_iter().map(|(entry, _stat)| entry).collect())
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<io::Result<Vec<PathBuf>>>,
+    ) {
+        let path = match self.remote_path(&path) {
+            Ok(path) => path,
+            Err(e) => {
+                tx.send(Err(e)).expect("send failed");
+                return;
+            }
+        };
+
+        if self.fs.stat(&path).is_err() {
+            return;
+        }
+
+        let mut v = Vec::with_capacity(128);
+        self.list_recursively_abs(&path, &mut v, &tx);
+
+        if !v.is_empty() {
+            tx.send(Ok(v)).expect("send failed")
+        }
+    }
+}
+
+#[test]
+fn sftp_url_parsing() {
+    let url = Url::parse("sftp://bob@example.com:2222/srv/my%20repo").unwrap();
+    let sftp = Sftp::new_from_url(&url).unwrap();
+    assert_eq!(sftp.shared.host, "example.com");
+    assert_eq!(sftp.shared.port, 2222);
+    assert_eq!(sftp.shared.user, "bob");
+    assert_eq!(sftp.shared.path, Path::new("/srv/my repo"));
+    assert_eq!(
+        sftp.shared.lock_dir(),
+        Path::new("/srv/my repo").join(config::LOCK_DIR)
+    );
+
+    let url = Url::parse("sftp://bob@example.com/repo").unwrap();
+    assert_eq!(Sftp::new_from_url(&url).unwrap().shared.port, 22);
+
+    let url = Url::parse("sftp://bob@example.com/%FF").unwrap();
+    assert_eq!(
+        Sftp::new_from_url(&url).err().unwrap().kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+/// In-memory `SftpFs`
+///
+/// Like most (SFTP v3) servers, it never overwrites on rename.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct TestFs {
+    /// Files, and directories (`None`)
+    entries: Arc<Mutex<BTreeMap<PathBuf, Option<Vec<u8>>>>>,
+    /// Renames onto these paths fail (once each)
+    failing_renames: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+#[cfg(test)]
+impl TestFs {
+    fn new(root: &Path) -> Self {
+        let fs = TestFs::default();
+        fs.entries.lock().unwrap().insert(root.to_owned(), None);
+        fs
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.entries.lock().unwrap().get(path) == Some(&None)
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if self.is_dir(parent) => Ok(()),
+            _ => Err(test_not_found(path)),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("test: {}: not found", path.display()),
+    )
+}
+
+#[cfg(test)]
+fn test_stat(entry: &Option<Vec<u8>>) -> FileStat {
+    FileStat {
+        size: Some(entry.as_ref().map_or(0, |data| data.len() as u64)),
+        uid: None,
+        gid: None,
+        perm: Some(if entry.is_some() {
+            0o100_644
+        } else {
+            0o040_755
+        }),
+        atime: None,
+        mtime: None,
+    }
+}
+
+#[cfg(test)]
+impl SftpFs for TestFs {
+    type File = io::Cursor<Vec<u8>>;
+
+    fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(test_stat)
+            .ok_or_else(|| test_not_found(path))
+    }
+
+    fn mkdir(&self, path: &Path) -> io::Result<()> {
+        self.check_parent(path)?;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("test: {}: already exists", path.display()),
+            ));
+        }
+        entries.insert(path.to_owned(), None);
+        Ok(())
+    }
+
+    fn write_file(&self, path: &Path, sg: &SGData) -> io::Result<()> {
+        self.check_parent(path)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), Some(sg.clone().to_linear_vec()));
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<io::Cursor<Vec<u8>>> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(&Some(ref data)) => Ok(io::Cursor::new(data.clone())),
+            _ => Err(test_not_found(path)),
+        }
+    }
+
+    fn rename(
+        &self,
+        src: &Path,
+        dst: &Path,
+        _overwrite: bool,
+    ) -> io::Result<()> {
+        self.check_parent(dst)?;
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(src) {
+            return Err(test_not_found(src));
+        }
+        if entries.contains_key(dst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("test: {}: can't overwrite", dst.display()),
+            ));
+        }
+        {
+            let mut failing = self.failing_renames.lock().unwrap();
+            if let Some(i) = failing.iter().position(|path| path == dst) {
+                failing.remove(i);
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("test: {}: rename failed", dst.display()),
+                ));
+            }
+        }
+
+        let moved: Vec<PathBuf> = entries
+            .keys()
+            .filter(|path| path.starts_with(src))
+            .cloned()
+            .collect();
+        for path in moved {
+            let entry = entries.remove(&path).unwrap();
+            let rel = path.strip_prefix(src).unwrap();
+            let new_path = if rel == Path::new("") {
+                dst.to_owned()
+            } else {
+                dst.join(rel)
+            };
+            entries.insert(new_path, entry);
+        }
+        Ok(())
+    }
+
+    fn unlink(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some(&Some(_)) => {}
+            _ => return Err(test_not_found(path)),
+        }
+        entries.remove(path);
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &Path) -> io::Result<()> {
+        if !self.readdir(path)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("test: {}: not empty", path.display()),
+            ));
+        }
+        self.entries.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>> {
+        if !self.is_dir(path) {
+            return Err(test_not_found(path));
+        }
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(entry, _)| entry.parent() == Some(path))
+            .map(|(entry, data)| (entry.clone(), test_stat(data)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+fn test_thread(fs: &TestFs) -> SftpThread<TestFs> {
+    SftpThread {
+        shared: Arc::new(SftpShared {
+            host: "example.com".into(),
+            port: 22,
+            user: "bob".into(),
+            path: PathBuf::from("/repo"),
+        }),
+        fs: fs.clone(),
+        rand_ext: rand_ext(),
+    }
+}
+
+#[test]
+fn sftp_write_rename() {
+    let fs = TestFs::new(Path::new("/repo"));
+    let mut t = test_thread(&fs);
+    let sg = |data: &[u8]| SGData::from_single(data.to_vec());
+    let read = |t: &mut SftpThread<TestFs>, path: &str| {
+        t.read(PathBuf::from(path)).unwrap().to_linear_vec()
+    };
+    let names = |t: &mut SftpThread<TestFs>, path: &str| {
+        t.list(PathBuf::from(path))
+            .unwrap()
+            .iter()
+            .map(|path| {
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // missing directories get created
+    t.write(PathBuf::from("a/b/c"), sg(b"1"), false).unwrap();
+    assert_eq!(read(&mut t, "a/b/c"), b"1");
+
+    // the server can't overwrite on rename, so the old file is moved aside
+    t.write(PathBuf::from("a/b/c"), sg(b"2"), false).unwrap();
+    assert_eq!(read(&mut t, "a/b/c"), b"2");
+
+    t.write(PathBuf::from("a/b/c"), sg(b"3"), true).unwrap();
+    assert_eq!(read(&mut t, "a/b/c"), b"2");
+
+    // the old file is put back if the new one can't be moved in place
+    fs.failing_renames
+        .lock()
+        .unwrap()
+        .push(PathBuf::from("/repo/a/b/c"));
+    assert!(t.write(PathBuf::from("a/b/c"), sg(b"4"), false).is_err());
+    assert_eq!(read(&mut t, "a/b/c"), b"2");
+    // no temporary or old files are left behind
+    assert_eq!(names(&mut t, "a/b"), ["c"]);
+
+    assert_eq!(
+        t.read_range(PathBuf::from("a/b/c"), 0, 1)
+            .unwrap()
+            .to_linear_vec(),
+        b"2"
+    );
+    assert_eq!(
+        t.read_range(PathBuf::from("a/b/c"), 1, 1)
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::UnexpectedEof
+    );
+
+    // renaming creates missing directories too
+    t.rename(PathBuf::from("a/b"), PathBuf::from("d/e"))
+        .unwrap();
+    assert_eq!(read(&mut t, "d/e/c"), b"2");
+    assert_eq!(
+        t.rename(PathBuf::from("a/b"), PathBuf::from("f"))
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::NotFound
+    );
+
+    t.remove_dir_all(PathBuf::from("d")).unwrap();
+    assert_eq!(names(&mut t, "."), ["a"]);
+    assert!(names(&mut t, "d").is_empty());
+}
+
+#[test]
+fn sftp_paths() {
+    let fs = TestFs::new(Path::new("/repo"));
+    let mut t = test_thread(&fs);
+
+    assert_eq!(
+        t.remote_path(Path::new("./a/b")).unwrap(),
+        Path::new("/repo/a/b")
+    );
+    assert_eq!(t.remote_path(Path::new("")).unwrap(), Path::new("/repo"));
+
+    for path in &["..", "a/../../x", "/etc/passwd"] {
+        let path = PathBuf::from(path);
+        assert_eq!(
+            t.write(path.clone(), SGData::from_single(vec![1]), false)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            t.read(path.clone()).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            t.rename(PathBuf::from("a"), path.clone())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            t.remove_dir_all(path).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+    assert_eq!(fs.entries.lock().unwrap().len(), 1);
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
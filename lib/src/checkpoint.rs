@@ -0,0 +1,192 @@
+//! Checkpoints of an interrupted `Repo::write_with`
+//!
+//! While writing, digests of data chunks already stored are periodically
+//! persisted as segments: `<gen>/checkpoint/<name>.<n>.chunks` (raw digests,
+//! one after another, like the local chunk cache). Before a segment is
+//! written, the chunk store is flushed, so every digest in it is really
+//! stored in the generation.
+//!
+//! A resumed write is fed the same data from the start again. It still
+//! has to chunk and hash it, but chunks found in the checkpoint are not
+//! looked up in the backend, compressed, encrypted or uploaded again.
+//! Checkpoints of older generations are ignored: `gc` might have removed
+//! the chunks they refer to.
+// {{{ use and mod
+use aio;
+use chunk_store::ArcChunkStore;
+use slog::Logger;
+use std::collections::HashSet;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+use util::substitute_err_not_found;
+use {Generation, SGData, DIGEST_SIZE};
+// }}}
+
+pub(crate) const CHECKPOINT_SUBDIR: &'static str = "checkpoint";
+const SEGMENT_EXT: &'static str = "chunks";
+
+/// Directory of the segments of `name` and the prefix of their file names
+fn segment_dir(name: &str, gen: Generation) -> (PathBuf, String) {
+    let mut dir = PathBuf::from(gen.to_string()).join(CHECKPOINT_SUBDIR);
+    let stem = match name.rfind('/') {
+        Some(i) => {
+            dir.push(&name[..i]);
+            &name[i + 1..]
+        }
+        None => name,
+    };
+    (dir, stem.to_owned())
+}
+
+/// Paths of all the segments of `name` with their sequence numbers
+fn list_segments(
+    name: &str,
+    gen: Generation,
+    aio: &aio::AsyncIO,
+) -> io::Result<Vec<(PathBuf, u64)>> {
+    let (dir, stem) = segment_dir(name, gen);
+    let list = substitute_err_not_found(aio.list(dir.clone()).wait(), || {
+        vec![]
+    })?;
+
+    let mut segments = vec![];
+    for path in list {
+        let file_name = match path.file_name().and_then(|f| f.to_str()) {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        let mut parts = file_name.rsplitn(3, '.');
+        let (ext, seq, file_stem) = (parts.next(), parts.next(), parts.next());
+        if ext != Some(SEGMENT_EXT) || file_stem != Some(stem.as_str()) {
+            continue;
+        }
+        if let Some(seq) = seq.and_then(|seq| seq.parse().ok()) {
+            segments.push((dir.join(file_name), seq));
+        }
+    }
+    Ok(segments)
+}
+
+/// Digests in all the segments of `name` and the next sequence number
+pub(crate) fn load(
+    name: &str,
+    gen: Generation,
+    aio: &aio::AsyncIO,
+) -> io::Result<(HashSet<Vec<u8>>, u64)> {
+    let mut digests = HashSet::new();
+    let mut next_seq = 0;
+
+    for (path, seq) in list_segments(name, gen, aio)? {
+        let data = aio.read(path.clone()).wait()?.to_linear_vec();
+        if data.len() % DIGEST_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted checkpoint: {}", path.display()),
+            ));
+        }
+        digests.extend(data.chunks(DIGEST_SIZE).map(|d| d.to_vec()));
+        next_seq = next_seq.max(seq + 1);
+    }
+    Ok((digests, next_seq))
+}
+
+/// Remove all the segments of `name`
+pub(crate) fn remove(
+    name: &str,
+    gen: Generation,
+    aio: &aio::AsyncIO,
+) -> io::Result<()> {
+    for (path, _) in list_segments(name, gen, aio)? {
+        aio.remove(path).wait()?;
+    }
+    Ok(())
+}
+
+/// Checkpoints of a write in progress
+pub(crate) struct Checkpoint {
+    name: String,
+    gen: Generation,
+    aio: aio::AsyncIO,
+    store: ArcChunkStore,
+    /// Data length between checkpoints
+    interval: u64,
+    /// Digests of the checkpoint resumed from; not recorded again
+    resumed: Arc<HashSet<Vec<u8>>>,
+    /// Digests since the last checkpoint
+    digests: Vec<u8>,
+    len: u64,
+    next_seq: u64,
+    /// Set after a failure; the write goes on without checkpoints
+    disabled: bool,
+    log: Logger,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(
+        name: &str,
+        gen: Generation,
+        aio: aio::AsyncIO,
+        store: ArcChunkStore,
+        interval: u64,
+        resumed: Arc<HashSet<Vec<u8>>>,
+        next_seq: u64,
+        log: Logger,
+    ) -> Self {
+        Checkpoint {
+            name: name.to_owned(),
+            gen,
+            aio,
+            store,
+            interval,
+            resumed,
+            digests: vec![],
+            len: 0,
+            next_seq,
+            disabled: false,
+            log,
+        }
+    }
+
+    /// Record a stored data chunk, persisting a checkpoint if it's time
+    pub(crate) fn record(&mut self, digest: &[u8], data_len: u64) {
+        if self.disabled {
+            return;
+        }
+        if !self.resumed.contains(digest) {
+            self.digests.extend_from_slice(digest);
+        }
+        self.len += data_len;
+
+        if self.len >= self.interval {
+            if let Err(e) = self.persist() {
+                warn!(self.log, "Couldn't write checkpoint, disabling";
+                      "name" => self.name.as_str(), "err" => %e);
+                self.disabled = true;
+            }
+        }
+    }
+
+    fn persist(&mut self) -> io::Result<()> {
+        self.len = 0;
+        if self.digests.is_empty() {
+            return Ok(());
+        }
+
+        self.store.flush()?;
+
+        let (dir, stem) = segment_dir(&self.name, self.gen);
+        let path =
+            dir.join(format!("{}.{}.{}", stem, self.next_seq, SEGMENT_EXT));
+        let digests = mem::replace(&mut self.digests, vec![]);
+        debug!(self.log, "Writing checkpoint";
+               "path" => %path.display(),
+               "chunks" => digests.len() / DIGEST_SIZE);
+        self.aio.write(path, SGData::from_single(digests)).wait()?;
+        self.next_seq += 1;
+        Ok(())
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -1,53 +1,133 @@
-use super::aio;
 use super::{DataType, Repo};
-use compression::ArcCompression;
+use chunk_store::ArcChunkStore;
+use compression::{self, ArcCompression};
+use config::ChunkFormat;
 use crossbeam_channel;
 use encryption::ArcEncrypter;
+use envelope::{self, CompressionId};
 use hashing::ArcHasher;
+use hex;
+use known_chunks::KnownChunks;
+use progress::ProgressObserver;
 use sgdata::SGData;
-use slog::{Level, Logger};
+use slog::{FnValue, Level, Logger};
 use slog_perf::TimeReporter;
-use std::io;
-use std::sync::mpsc;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
 use {Digest, Generation};
 
 pub(crate) struct Message {
     pub data: (u64, SGData),
+    /// Length of the data addressed by the chunk (for the index)
+    pub data_len: u64,
     pub data_type: DataType,
-    pub response_tx: mpsc::Sender<(u64, Digest)>,
+    pub response_tx: mpsc::Sender<(u64, (Digest, u64))>,
 }
 
 pub(crate) struct ChunkProcessor {
-    repo: Repo,
     rx: crossbeam_channel::Receiver<Message>,
-    aio: aio::AsyncIO,
+    store: ArcChunkStore,
+    known_chunks: Option<Arc<KnownChunks>>,
+    /// Chunks stored by the interrupted write being resumed
+    resumed: Arc<HashSet<Vec<u8>>>,
     log: Logger,
     encrypter: ArcEncrypter,
     compressor: ArcCompression,
     hasher: ArcHasher,
     generations: Vec<Generation>,
+    progress: Arc<dyn ProgressObserver>,
+    /// Header of data chunks, if they have one (`ChunkFormat::V2`)
+    header: Option<envelope::Header>,
+    /// Minimum saving to keep a data chunk compressed, if adaptive
+    min_saving_percent: Option<u8>,
+    /// Data chunks stored uncompressed by adaptive compression
+    uncompressed: Arc<AtomicU64>,
 }
 
 impl ChunkProcessor {
     pub fn new(
-        repo: Repo,
+        repo: &Repo,
         rx: crossbeam_channel::Receiver<Message>,
-        aio: aio::AsyncIO,
+        store: ArcChunkStore,
+        known_chunks: Option<Arc<KnownChunks>>,
+        resumed: Arc<HashSet<Vec<u8>>>,
         encrypter: ArcEncrypter,
         compressor: ArcCompression,
+        dictionary: u8,
         hasher: ArcHasher,
         generations: Vec<Generation>,
+        uncompressed: Arc<AtomicU64>,
     ) -> Self {
         assert!(generations.len() >= 1);
+        let header = match repo.config.chunk_format {
+            ChunkFormat::V1 => None,
+            ChunkFormat::V2 => Some(envelope::Header {
+                compression: repo.config.compression.id(),
+                encryption: repo.config.encryption.id(),
+                dictionary,
+                data_len: 0,
+            }),
+        };
+        // without a header, it's not possible to tell which chunks are
+        // compressed
+        let min_saving_percent = match header {
+            Some(header) if header.compression != CompressionId::None => repo
+                .config
+                .adaptive_compression
+                .map(|adaptive| adaptive.min_saving_percent),
+            _ => None,
+        };
         ChunkProcessor {
             log: repo.log.clone(),
-            repo,
             rx,
-            aio,
+            store,
+            known_chunks,
+            resumed,
             encrypter,
             compressor,
             hasher,
             generations,
+            progress: Arc::clone(&repo.progress),
+            header,
+            min_saving_percent,
+            uncompressed,
+        }
+    }
+
+    /// Check the local cache of known chunks
+    ///
+    /// Returns `true` if the chunk is stored in the current generation.
+    /// On a miss (or any failure) the backend has to be checked.
+    fn lookup_known(&self, digest: &Digest, cur_gen_str: &str) -> bool {
+        let known = match self.known_chunks {
+            Some(ref known) => known,
+            None => return false,
+        };
+
+        match known.lookup(&digest.0) {
+            Some(gen_str) if gen_str == cur_gen_str => true,
+            Some(gen_str) => {
+                let res = self.store.move_chunk(
+                    digest.as_digest_ref(),
+                    gen_str,
+                    cur_gen_str,
+                );
+                match res {
+                    Ok(()) => {
+                        known.insert(&digest.0);
+                        true
+                    }
+                    Err(e) => {
+                        trace!(self.log, "moving known chunk failed";
+                               "digest" => FnValue(|_| hex::encode(&digest.0)),
+                               "gen" => gen_str,
+                               "err" => %e);
+                        false
+                    }
+                }
+            }
+            None => false,
         }
     }
 
@@ -70,6 +150,7 @@ impl ChunkProcessor {
 
                 let Message {
                     data,
+                    data_len,
                     response_tx,
                     data_type,
                 } = input;
@@ -77,101 +158,138 @@ impl ChunkProcessor {
 
                 let digest = Digest(self.hasher.calculate_digest(&sg));
 
-                let mut found = false;
-                // lookup all generations in order, starting from current one
-                // and at the end try the current gen. again, in case some other
-                // thread/ instance just moved it from older generation to the
-                // current one
-                for gen_str in gen_strings
-                    .iter()
-                    .rev()
-                    .chain([&last_gen_str].iter().cloned())
-                {
-                    let chunk_path = self.repo.chunk_rel_path_by_digest(
-                        digest.as_digest_ref(),
-                        gen_str,
-                    );
-                    match self.aio.read_metadata(chunk_path.clone()).wait() {
-                        Ok(_metadata) => {
-                            found = true;
-                            if gen_str == &last_gen_str {
-                                trace!(self.log, "already exists"; "path" => %chunk_path.display());
-                            } else {
-                                trace!(self.log, "already exists in previous generation";
-                                       "path" => %chunk_path.display());
-                                let dst_path =
-                                    self.repo.chunk_rel_path_by_digest(
-                                        digest.as_digest_ref(),
-                                        gen_strings.last().unwrap(),
-                                    );
-                                self.aio
-                                    .rename(
-                                        chunk_path.clone(),
-                                        dst_path.clone(),
-                                    )
-                                    .wait()
-                                    .unwrap_or_else(|_e| {
-                                        // chunk might have been upated
-                                        // concurrently; check
-                                        // if it's already in the destination
-                                        if self
-                                            .aio
-                                            .read_metadata(dst_path.clone())
-                                            .wait()
-                                            .is_err()
-                                        {
-                                            panic!(
-                                                "rename failed {} -> {}",
-                                                chunk_path.display(),
-                                                dst_path.display()
-                                            )
-                                        }
-                                    });
+                let mut found = self.resumed.contains(&digest.0)
+                    || self.lookup_known(&digest, &last_gen_str);
+                if !found {
+                    // lookup all generations in order, starting from current one
+                    // and at the end try the current gen. again, in case some other
+                    // thread/ instance just moved it from older generation to the
+                    // current one
+                    for gen_str in gen_strings
+                        .iter()
+                        .rev()
+                        .chain([&last_gen_str].iter().cloned())
+                    {
+                        let digest_ref = digest.as_digest_ref();
+                        match self.store.exists(digest_ref, gen_str) {
+                            Ok(true) => {
+                                found = true;
+                                if let Some(ref known) = self.known_chunks {
+                                    known.insert(&digest.0);
+                                }
+                                if gen_str == &last_gen_str {
+                                    trace!(self.log, "already exists";
+                                           "digest" => FnValue(|_| hex::encode(&digest.0)));
+                                } else {
+                                    trace!(self.log, "already exists in previous generation";
+                                           "digest" => FnValue(|_| hex::encode(&digest.0)),
+                                           "gen" => gen_str.as_str());
+                                    self.store
+                                        .move_chunk(
+                                            digest_ref,
+                                            gen_str,
+                                            &last_gen_str,
+                                        )
+                                        .unwrap_or_else(|_e| {
+                                            // chunk might have been upated
+                                            // concurrently; check
+                                            // if it's already in the destination
+                                            if !self
+                                                .store
+                                                .exists(digest_ref, &last_gen_str)
+                                                .unwrap_or(false)
+                                            {
+                                                panic!(
+                                                    "moving chunk {} failed {} -> {}",
+                                                    hex::encode(&digest.0),
+                                                    gen_str,
+                                                    last_gen_str
+                                                )
+                                            }
+                                        });
+                                }
+                                break;
                             }
-                            break;
+                            Ok(false) => {}
+                            Err(e) => panic!(
+                                "checking chunk {} in {} failed, err: {}",
+                                hex::encode(&digest.0),
+                                gen_str,
+                                e
+                            ),
                         }
-                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
-                        Err(e) => panic!(
-                            "read_metadata failed for {}, err: {}",
-                            chunk_path.display(),
-                            e
-                        ),
                     }
                 }
 
                 if !found {
-                    let chunk_path = self.repo.chunk_rel_path_by_digest(
-                        digest.as_digest_ref(),
-                        gen_strings.last().unwrap(),
-                    );
-                    let sg = if data_type.should_compress() {
-                        trace!(self.log, "compress"; "path" => %chunk_path.display());
+                    let data_len = sg.len() as u64;
+                    let mut compressed = data_type.should_compress();
+                    let sg = if !compressed {
+                        sg
+                    } else if let Some(min_saving_percent) =
+                        self.min_saving_percent
+                    {
+                        trace!(self.log, "compress (adaptive)"; "digest" => FnValue(|_| hex::encode(&digest.0)));
                         timer.start("compress");
-                        self.compressor.compress(sg).unwrap()
+                        let res = compression::compress_adaptive(
+                            &self.compressor,
+                            &sg,
+                            min_saving_percent,
+                        ).unwrap();
+                        res.unwrap_or_else(|| {
+                            trace!(self.log, "doesn't compress well, storing uncompressed";
+                                   "digest" => FnValue(|_| hex::encode(&digest.0)));
+                            compressed = false;
+                            self.uncompressed.fetch_add(1, Ordering::Relaxed);
+                            sg
+                        })
                     } else {
-                        sg
+                        trace!(self.log, "compress"; "digest" => FnValue(|_| hex::encode(&digest.0)));
+                        timer.start("compress");
+                        self.compressor.compress(sg).unwrap()
                     };
 
                     let sg = if data_type.should_encrypt() {
-                        trace!(self.log, "encrypt"; "path" => %chunk_path.display());
+                        trace!(self.log, "encrypt"; "digest" => FnValue(|_| hex::encode(&digest.0)));
                         timer.start("encrypt");
                         self.encrypter.encrypt(sg, &digest.0).unwrap()
                     } else {
                         sg
                     };
 
+                    let sg = match self.header {
+                        Some(header) if data_type == DataType::Data => {
+                            let (compression, dictionary) = if compressed {
+                                (header.compression, header.dictionary)
+                            } else {
+                                (CompressionId::None, 0)
+                            };
+                            envelope::Header {
+                                compression,
+                                dictionary,
+                                data_len,
+                                ..header
+                            }.wrap(sg)
+                        }
+                        _ => sg,
+                    };
+
                     timer.start("tx-writer");
-                    self.aio.write_checked_idempotent(
-                        self.repo.chunk_rel_path_by_digest(
-                            digest.as_digest_ref(),
-                            &last_gen_str,
-                        ),
-                        sg,
-                    );
+                    let len = sg.len() as u64;
+                    self.store
+                        .write(digest.as_digest_ref(), &last_gen_str, sg)
+                        .expect("chunk write failed");
+                    if let Some(ref known) = self.known_chunks {
+                        known.insert(&digest.0);
+                    }
+                    self.progress.new_bytes(len);
+                }
+                if data_type == DataType::Data {
+                    self.progress.chunk_processed();
                 }
                 timer.start("tx-digest");
                 response_tx
-                    .send((sg_id, digest))
+                    .send((sg_id, (digest, data_len)))
                     .expect("chunk_processor: digests_tx.send")
             } else {
                 return;
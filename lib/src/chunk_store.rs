@@ -0,0 +1,577 @@
+//! Chunk storage layouts
+//!
+//! Abstracts away how chunks of a generation are laid out in the backend:
+//! one file per chunk, or many chunks appended to bigger pack files.
+// {{{ use and mod
+use aio;
+use config;
+use hex;
+use iterators::StoredChunks;
+use rand::{self, RngCore};
+use sgdata::SGData;
+use slog::Logger;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use {DigestRef, DIGEST_SIZE};
+// }}}
+
+/// Storage of chunks of all generations
+///
+/// `gen_str` arguments are `Generation`s in their string form.
+pub(crate) trait ChunkStore {
+    /// Check if a chunk is stored in a generation
+    fn exists(&self, digest: DigestRef, gen_str: &str) -> io::Result<bool>;
+
+    /// Read chunk stored in a generation
+    fn read(&self, digest: DigestRef, gen_str: &str) -> io::Result<SGData>;
+
+    /// Move chunk from one generation to another
+    ///
+    /// Returns `NotFound` error if the chunk is not in `src_gen_str`.
+    fn move_chunk(
+        &self,
+        digest: DigestRef,
+        src_gen_str: &str,
+        dst_gen_str: &str,
+    ) -> io::Result<()>;
+
+    /// Store a new chunk
+    ///
+    /// The chunk might be only queued; use `flush` to make sure it is
+    /// really stored.
+    fn write(
+        &self,
+        digest: DigestRef,
+        gen_str: &str,
+        sg: SGData,
+    ) -> io::Result<()>;
+
+    /// Make sure all chunks written/moved so far are stored in the backend
+    fn flush(&self) -> io::Result<()>;
+
+    /// List digests of all chunks stored in a generation
+    fn list(&self, gen_str: &str) -> io::Result<DigestIter>;
+}
+
+pub(crate) type DigestIter = Box<dyn Iterator<Item = io::Result<Vec<u8>>>>;
+
+pub(crate) type ArcChunkStore = Arc<dyn ChunkStore + Send + Sync>;
+
+// {{{ FileChunkStore
+/// Every chunk in its own file: `<gen>/chunk/<nesting>/<digest>`
+pub(crate) struct FileChunkStore {
+    aio: aio::AsyncIO,
+    nesting: config::Nesting,
+    /// Writes not known to be complete yet, oldest first
+    pending: Mutex<VecDeque<aio::AsyncIOResult<()>>>,
+    log: Logger,
+}
+
+impl FileChunkStore {
+    pub(crate) fn new(
+        aio: aio::AsyncIO,
+        nesting: config::Nesting,
+        log: Logger,
+    ) -> Self {
+        FileChunkStore {
+            aio,
+            nesting,
+            pending: Mutex::new(VecDeque::new()),
+            log,
+        }
+    }
+
+    fn path(&self, digest: DigestRef, gen_str: &str) -> PathBuf {
+        self.nesting.get_path(
+            Path::new(config::DATA_SUBDIR),
+            digest.0,
+            gen_str,
+        )
+    }
+}
+
+impl ChunkStore for FileChunkStore {
+    fn exists(&self, digest: DigestRef, gen_str: &str) -> io::Result<bool> {
+        match self.aio.read_metadata(self.path(digest, gen_str)).wait() {
+            Ok(_metadata) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read(&self, digest: DigestRef, gen_str: &str) -> io::Result<SGData> {
+        self.aio.read(self.path(digest, gen_str)).wait()
+    }
+
+    fn move_chunk(
+        &self,
+        digest: DigestRef,
+        src_gen_str: &str,
+        dst_gen_str: &str,
+    ) -> io::Result<()> {
+        self.aio
+            .rename(
+                self.path(digest, src_gen_str),
+                self.path(digest, dst_gen_str),
+            )
+            .wait()
+    }
+
+    fn write(
+        &self,
+        digest: DigestRef,
+        gen_str: &str,
+        sg: SGData,
+    ) -> io::Result<()> {
+        let res = self.aio.write_idempotent(self.path(digest, gen_str), sg);
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.push_back(res);
+
+        // Writes complete roughly in order, so collecting the completed
+        // ones from the front keeps the queue short without rescanning it
+        let mut first_err = None;
+        while let Some(res) = pending.front().and_then(|res| res.try_wait()) {
+            pending.pop_front();
+            if let Err(e) = res {
+                first_err.get_or_insert(e);
+            }
+        }
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let pending =
+            mem::replace(&mut *self.pending.lock().unwrap(), VecDeque::new());
+        let mut res = Ok(());
+        for write in pending {
+            if let Err(e) = write.wait() {
+                res = res.and(Err(e));
+            }
+        }
+        res
+    }
+
+    fn list(&self, gen_str: &str) -> io::Result<DigestIter> {
+        Ok(Box::new(StoredChunks::new(
+            &self.aio,
+            PathBuf::from(gen_str).join(config::DATA_SUBDIR),
+            DIGEST_SIZE,
+            self.log.clone(),
+        )?))
+    }
+}
+// }}}
+
+// {{{ PackChunkStore
+/// Location of a chunk inside a pack
+#[derive(Clone, Debug)]
+struct PackEntry {
+    pack_path: PathBuf,
+    offset: u64,
+    len: u64,
+}
+
+/// Size of a record in the `.idx` file: digest, offset, length
+const PACK_IDX_RECORD_SIZE: usize = DIGEST_SIZE + 8 + 8;
+
+/// Chunks waiting to be written as a pack
+#[derive(Default)]
+struct PendingPack {
+    gen_str: String,
+    chunks: Vec<(Vec<u8>, SGData)>,
+    by_digest: HashMap<Vec<u8>, usize>,
+    size: u64,
+}
+
+impl PendingPack {
+    /// Chunk `digest` of generation `gen_str`, if it's in this pack
+    fn get(&self, digest: DigestRef, gen_str: &str) -> Option<&SGData> {
+        if self.gen_str != gen_str {
+            return None;
+        }
+        self.by_digest.get(digest.0).map(|&i| &self.chunks[i].1)
+    }
+}
+
+/// Chunks appended to packs: `<gen>/pack/<id>.pack`
+///
+/// Every pack is accompanied by `<gen>/pack/<id>.idx` listing the
+/// chunks in it. The index is written after the pack, so a pack
+/// without an index (eg. after a crash) is just ignored.
+///
+/// Chunks can't be moved out of a pack, so moving a chunk between
+/// generations copies it to a new pack in the destination generation.
+/// Old packs are removed together with their generation by the GC.
+pub(crate) struct PackChunkStore {
+    aio: aio::AsyncIO,
+    stats: aio::AsyncIOThreadShared,
+    pack_size: u64,
+    log: Logger,
+    /// Indices of already loaded generations
+    indices: Mutex<HashMap<String, HashMap<Vec<u8>, PackEntry>>>,
+    pending: Mutex<PendingPack>,
+    /// Packs taken out of `pending`, being written
+    writing: Mutex<Vec<Arc<PendingPack>>>,
+}
+
+impl PackChunkStore {
+    pub(crate) fn new(
+        aio: aio::AsyncIO,
+        stats: aio::AsyncIOThreadShared,
+        pack_size: u64,
+        log: Logger,
+    ) -> Self {
+        PackChunkStore {
+            aio,
+            stats,
+            pack_size,
+            log,
+            indices: Mutex::new(HashMap::new()),
+            pending: Mutex::new(Default::default()),
+            writing: Mutex::new(vec![]),
+        }
+    }
+
+    fn load_gen_index(
+        &self,
+        gen_str: &str,
+    ) -> io::Result<HashMap<Vec<u8>, PackEntry>> {
+        let pack_dir = PathBuf::from(gen_str).join(config::PACK_SUBDIR);
+        let mut index = HashMap::new();
+
+        for path in self.aio.list(pack_dir.clone()).wait()? {
+            let file_name = match path.file_name().and_then(|f| f.to_str()) {
+                Some(f) if f.ends_with(".idx") => f.to_owned(),
+                _ => continue,
+            };
+            let idx_path = pack_dir.join(&file_name);
+            let pack_path = idx_path.with_extension("pack");
+            let data = self.aio.read(idx_path.clone()).wait()?.to_linear_vec();
+
+            if data.len() % PACK_IDX_RECORD_SIZE != 0 {
+                warn!(self.log, "skipping corrupted pack index";
+                      "path" => %idx_path.display());
+                continue;
+            }
+
+            for record in data.chunks(PACK_IDX_RECORD_SIZE) {
+                let (digest, rest) = record.split_at(DIGEST_SIZE);
+                index.insert(
+                    digest.to_vec(),
+                    PackEntry {
+                        pack_path: pack_path.clone(),
+                        offset: read_u64_be(&rest[..8]),
+                        len: read_u64_be(&rest[8..]),
+                    },
+                );
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Find the chunk in the (stored) packs of a generation
+    fn find(
+        &self,
+        digest: DigestRef,
+        gen_str: &str,
+    ) -> io::Result<Option<PackEntry>> {
+        let mut indices = self.indices.lock().unwrap();
+        if !indices.contains_key(gen_str) {
+            let index = self.load_gen_index(gen_str)?;
+            indices.insert(gen_str.to_owned(), index);
+        }
+        Ok(indices[gen_str].get(digest.0).cloned())
+    }
+
+    /// Chunk that is not stored yet: pending, or in a pack being written
+    fn unstored(&self, digest: DigestRef, gen_str: &str) -> Option<SGData> {
+        if let Some(sg) = self.pending.lock().unwrap().get(digest, gen_str) {
+            return Some(sg.clone());
+        }
+        self.writing
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|pack| pack.get(digest, gen_str))
+            .next()
+            .cloned()
+    }
+
+    /// Take the pending pack out, to be written with `flush_pack`
+    ///
+    /// Its chunks stay visible through `writing` until they are stored.
+    fn take_pending(
+        &self,
+        pending: &mut PendingPack,
+    ) -> Option<Arc<PendingPack>> {
+        if pending.chunks.is_empty() {
+            return None;
+        }
+        let fresh = PendingPack {
+            gen_str: pending.gen_str.clone(),
+            ..Default::default()
+        };
+        let pack = Arc::new(mem::replace(pending, fresh));
+        self.writing.lock().unwrap().push(pack.clone());
+        Some(pack)
+    }
+
+    /// Write a pack taken with `take_pending`
+    fn flush_pack(&self, pack: Arc<PendingPack>) -> io::Result<()> {
+        let res = self.write_pack(&pack);
+        self.writing
+            .lock()
+            .unwrap()
+            .retain(|writing| !Arc::ptr_eq(writing, &pack));
+        res
+    }
+
+    /// Write `pending` out as a pack and its index
+    fn write_pack(&self, pending: &PendingPack) -> io::Result<()> {
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        let pack_dir =
+            PathBuf::from(&pending.gen_str).join(config::PACK_SUBDIR);
+        let pack_path = pack_dir.join(format!("{}.pack", hex::encode(id)));
+        let idx_path = pack_dir.join(format!("{}.idx", hex::encode(id)));
+
+        let mut parts = vec![];
+        let mut idx = Vec::with_capacity(
+            pending.chunks.len() * PACK_IDX_RECORD_SIZE,
+        );
+        let mut entries = vec![];
+        let mut offset = 0u64;
+        for &(ref digest, ref sg) in &pending.chunks {
+            let len = sg.len() as u64;
+            parts.extend_from_slice(sg.as_parts());
+            idx.extend_from_slice(digest);
+            idx.extend_from_slice(&offset.to_be_bytes());
+            idx.extend_from_slice(&len.to_be_bytes());
+            entries.push((
+                digest.clone(),
+                PackEntry {
+                    pack_path: pack_path.clone(),
+                    offset,
+                    len,
+                },
+            ));
+            offset += len;
+        }
+
+        trace!(self.log, "writing pack";
+               "path" => %pack_path.display(),
+               "chunks" => pending.chunks.len(),
+               "size" => offset);
+        self.aio
+            .write(pack_path, SGData::from_vec(parts))
+            .wait()?;
+        self.aio
+            .write(idx_path, SGData::from_single(idx))
+            .wait()?;
+
+        if let Some(index) =
+            self.indices.lock().unwrap().get_mut(&pending.gen_str)
+        {
+            index.extend(entries);
+        }
+        Ok(())
+    }
+}
+
+fn read_u64_be(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
+}
+
+impl ChunkStore for PackChunkStore {
+    fn exists(&self, digest: DigestRef, gen_str: &str) -> io::Result<bool> {
+        if self.unstored(digest, gen_str).is_some() {
+            return Ok(true);
+        }
+        Ok(self.find(digest, gen_str)?.is_some())
+    }
+
+    fn read(&self, digest: DigestRef, gen_str: &str) -> io::Result<SGData> {
+        if let Some(sg) = self.unstored(digest, gen_str) {
+            return Ok(sg);
+        }
+
+        match self.find(digest, gen_str)? {
+            Some(entry) => self
+                .aio
+                .read_range(entry.pack_path, entry.offset, entry.len)
+                .wait(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "chunk {} not found in packs of {}",
+                    hex::encode(digest.0),
+                    gen_str
+                ),
+            )),
+        }
+    }
+
+    fn move_chunk(
+        &self,
+        digest: DigestRef,
+        src_gen_str: &str,
+        dst_gen_str: &str,
+    ) -> io::Result<()> {
+        let sg = self.read(digest, src_gen_str)?;
+        self.write(digest, dst_gen_str, sg)
+    }
+
+    fn write(
+        &self,
+        digest: DigestRef,
+        gen_str: &str,
+        sg: SGData,
+    ) -> io::Result<()> {
+        if self.find(digest, gen_str)?.is_some() {
+            return Ok(());
+        }
+
+        // packs are written after releasing the lock, so other threads
+        // can keep adding chunks in the meantime
+        let mut packs = vec![];
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.gen_str != gen_str {
+                packs.extend(self.take_pending(&mut pending));
+                pending.gen_str = gen_str.to_owned();
+            }
+            let being_written = self
+                .writing
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|pack| pack.get(digest, gen_str).is_some());
+            if !pending.by_digest.contains_key(digest.0) && !being_written {
+                let len = sg.len() as u64;
+                let i = pending.chunks.len();
+                pending.chunks.push((digest.0.to_vec(), sg));
+                pending.by_digest.insert(digest.0.to_vec(), i);
+                pending.size += len;
+                self.stats.record_chunk_written(len);
+
+                if pending.size >= self.pack_size {
+                    packs.extend(self.take_pending(&mut pending));
+                }
+            }
+        }
+
+        for pack in packs {
+            self.flush_pack(pack)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let pack = self.take_pending(&mut self.pending.lock().unwrap());
+        match pack {
+            Some(pack) => self.flush_pack(pack),
+            None => Ok(()),
+        }
+    }
+
+    fn list(&self, gen_str: &str) -> io::Result<DigestIter> {
+        let index = self.load_gen_index(gen_str)?;
+        Ok(Box::new(index.into_iter().map(|(digest, _)| Ok(digest))))
+    }
+}
+
+impl Drop for PackChunkStore {
+    fn drop(&mut self) {
+        // chunks moved while reading are not flushed explicitly
+        if let Err(e) = self.flush() {
+            warn!(self.log, "failed to write pack"; "err" => %e);
+        }
+    }
+}
+// }}}
+
+// {{{ DryRunChunkStore
+/// Chunk store of `Repo::write_dry_run`: new chunks are only counted
+///
+/// Nothing is written or moved. Chunks "written" are remembered, so
+/// duplicates in the data are not counted again.
+pub(crate) struct DryRunChunkStore {
+    inner: ArcChunkStore,
+    written: Mutex<HashSet<Vec<u8>>>,
+    written_bytes: AtomicU64,
+}
+
+impl DryRunChunkStore {
+    pub(crate) fn new(inner: ArcChunkStore) -> Self {
+        DryRunChunkStore {
+            inner,
+            written: Mutex::new(HashSet::new()),
+            written_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Number and total size of the chunks that would be written
+    pub(crate) fn new_chunks(&self) -> (u64, u64) {
+        (
+            self.written.lock().unwrap().len() as u64,
+            self.written_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl ChunkStore for DryRunChunkStore {
+    fn exists(&self, digest: DigestRef, gen_str: &str) -> io::Result<bool> {
+        if self.written.lock().unwrap().contains(digest.0) {
+            return Ok(true);
+        }
+        self.inner.exists(digest, gen_str)
+    }
+
+    fn read(&self, digest: DigestRef, gen_str: &str) -> io::Result<SGData> {
+        self.inner.read(digest, gen_str)
+    }
+
+    fn move_chunk(
+        &self,
+        _digest: DigestRef,
+        _src_gen_str: &str,
+        _dst_gen_str: &str,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        digest: DigestRef,
+        _gen_str: &str,
+        sg: SGData,
+    ) -> io::Result<()> {
+        if self.written.lock().unwrap().insert(digest.0.to_vec()) {
+            self.written_bytes
+                .fetch_add(sg.len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, gen_str: &str) -> io::Result<DigestIter> {
+        self.inner.list(gen_str)
+    }
+}
+// }}}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
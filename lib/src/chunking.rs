@@ -3,7 +3,7 @@ use rollsum;
 use rollsum::CDC;
 use std::mem;
 use std::sync::Arc;
-use SGData;
+use {SGData, DIGEST_SIZE};
 
 /// Abstraction over the specific chunking algorithms being used
 pub(crate) trait Chunking {
@@ -151,3 +151,92 @@ impl<I: Iterator<Item = Vec<u8>>> Iterator for Chunker<I> {
         }
     }
 }
+
+/// Chunker of `IndexFormat::V2` index data
+///
+/// Takes whole index records and never splits them between chunks, so
+/// every index chunk can be interpreted on its own. Chunk boundaries are
+/// content-defined: a chunk ends after a record with a digest that has
+/// the lowest `record_bits` bits of its first 4 bytes zeroed.
+pub(crate) struct IndexChunker<I> {
+    iter: I,
+    record_bits: u32,
+    chunks_returned: usize,
+}
+
+impl<I> IndexChunker<I> {
+    /// `chunk_bits` - the same as for data chunks
+    pub fn new(iter: I, chunk_bits: u32) -> Self {
+        IndexChunker {
+            iter,
+            // records are around 32 bytes (`2^5`)
+            record_bits: chunk_bits.saturating_sub(5).max(1),
+            chunks_returned: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Iterator for IndexChunker<I> {
+    type Item = SGData;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mask = (1u32 << self.record_bits) - 1;
+        let max_records = 4usize << self.record_bits;
+        let mut records = vec![];
+
+        while let Some(record) = self.iter.next() {
+            debug_assert!(record.len() > DIGEST_SIZE);
+            let mut first = [0u8; 4];
+            first.copy_from_slice(&record[..4]);
+            records.push(record);
+
+            // at least two records, to be over the 64-byte minimum
+            // (see `Chunker`)
+            if records.len() >= 2
+                && (u32::from_be_bytes(first) & mask == 0
+                    || records.len() >= max_records)
+            {
+                break;
+            }
+        }
+
+        if records.is_empty() && self.chunks_returned > 0 {
+            return None;
+        }
+
+        self.chunks_returned += 1;
+        Some(SGData::from_many(records))
+    }
+}
+
+#[test]
+fn index_chunker_keeps_records_whole() {
+    use rand::{self, Rng};
+
+    let records: Vec<Vec<u8>> = (0..10_000)
+        .map(|_| {
+            (0..DIGEST_SIZE + 8)
+                .map(|_| rand::thread_rng().gen())
+                .collect()
+        }).collect();
+
+    let chunks: Vec<_> =
+        IndexChunker::new(records.clone().into_iter(), 10).collect();
+
+    assert!(chunks.len() > 1);
+    let mut all = vec![];
+    for chunk in &chunks {
+        let chunk = chunk.to_linear_vec();
+        assert_eq!(chunk.len() % (DIGEST_SIZE + 8), 0);
+        all.extend_from_slice(&chunk);
+    }
+    assert_eq!(all, records.concat());
+
+    // boundaries depend only on the content
+    let chunks2: Vec<_> =
+        IndexChunker::new(records[1..].to_vec().into_iter(), 10).collect();
+    assert_eq!(
+        chunks.last().unwrap().to_linear_vec(),
+        chunks2.last().unwrap().to_linear_vec()
+    );
+}
@@ -3,25 +3,42 @@ use sgdata::SGData;
 use std;
 use std::io;
 
-#[cfg(feature = "with-xz2")]
-use std::cmp;
-#[cfg(feature = "with-zstd")]
+#[cfg(
+    any(feature = "with-zstd", feature = "with-lz4", feature = "with-brotli")
+)]
 use std::io::Read;
 #[cfg(
     any(
         feature = "with-bzip2",
         feature = "with-deflate",
         feature = "with-xz2",
-        feature = "with-zstd"
+        feature = "with-zstd",
+        feature = "with-lz4",
+        feature = "with-brotli"
     )
 )]
 use std::io::Write;
+#[cfg(
+    any(
+        feature = "with-bzip2",
+        feature = "with-deflate",
+        feature = "with-xz2",
+        feature = "with-zstd",
+        feature = "with-lz4",
+        feature = "with-brotli"
+    )
+)]
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 
+#[cfg(feature = "with-brotli")]
+use brotli;
 #[cfg(feature = "with-bzip2")]
 use bzip2;
 #[cfg(feature = "with-deflate")]
 use flate2;
+#[cfg(feature = "with-lz4")]
+use lz4;
 #[cfg(feature = "with-xz2")]
 use lzma;
 #[cfg(feature = "with-zstd")]
@@ -29,11 +46,58 @@ use zstd;
 
 pub type ArcCompression = Arc<dyn Compression + Send + Sync>;
 
+// Every engine takes the level as defined by the algorithm (`LEVELS`);
+// out of range levels are rejected by `config::Compression::set_level`.
+
 pub trait Compression {
     fn compress(&self, buf: SGData) -> io::Result<SGData>;
     fn decompress(&self, bug: SGData) -> io::Result<SGData>;
 }
 
+/// Data is compressed without sampling if it's shorter than this times 4
+const ADAPTIVE_SAMPLE_SIZE: usize = 16 * 1024;
+
+/// Compress `buf`, unless it doesn't compress well
+///
+/// If compressing doesn't make the data smaller by at least
+/// `min_saving_percent`, returns `None`: the data should be stored
+/// uncompressed. A sample from the start of longer data is compressed first,
+/// so data that doesn't compress (eg. JPEGs, encrypted archives) costs little
+/// CPU.
+pub(crate) fn compress_adaptive(
+    compression: &ArcCompression,
+    buf: &SGData,
+    min_saving_percent: u8,
+) -> io::Result<Option<SGData>> {
+    let worth = |len: usize, compressed_len: usize| {
+        compressed_len as u64 * 100
+            <= len as u64 * (100 - u64::from(min_saving_percent))
+    };
+
+    if buf.len() >= 4 * ADAPTIVE_SAMPLE_SIZE {
+        let mut sample = Vec::with_capacity(ADAPTIVE_SAMPLE_SIZE);
+        for part in buf.as_parts() {
+            let len =
+                std::cmp::min(part.len(), ADAPTIVE_SAMPLE_SIZE - sample.len());
+            sample.extend_from_slice(&part[..len]);
+            if sample.len() == ADAPTIVE_SAMPLE_SIZE {
+                break;
+            }
+        }
+        let compressed = compression.compress(SGData::from_single(sample))?;
+        if !worth(ADAPTIVE_SAMPLE_SIZE, compressed.len()) {
+            return Ok(None);
+        }
+    }
+
+    let compressed = compression.compress(buf.clone())?;
+    Ok(if worth(buf.len(), compressed.len()) {
+        Some(compressed)
+    } else {
+        None
+    })
+}
+
 pub struct NoCompression;
 
 impl Compression for NoCompression {
@@ -51,16 +115,13 @@ pub struct Deflate {
 }
 #[cfg(feature = "with-deflate")]
 impl Deflate {
+    pub const LEVELS: RangeInclusive<i32> = 0..=9;
+    pub const DEFAULT_LEVEL: i32 = 6;
+
     pub fn new(level: i32) -> Self {
-        let level = if level < 0 {
-            flate2::Compression::fast()
-        } else if level > 0 {
-            flate2::Compression::best()
-        } else {
-            flate2::Compression::default()
-        };
-
-        Deflate { level }
+        Deflate {
+            level: flate2::Compression::new(level as u32),
+        }
     }
 }
 #[cfg(feature = "with-deflate")]
@@ -95,16 +156,13 @@ pub struct Bzip2 {
 }
 #[cfg(feature = "with-bzip2")]
 impl Bzip2 {
+    pub const LEVELS: RangeInclusive<i32> = 1..=9;
+    pub const DEFAULT_LEVEL: i32 = 6;
+
     pub fn new(level: i32) -> Self {
-        let level = if level < 0 {
-            bzip2::Compression::Fastest
-        } else if level > 0 {
-            bzip2::Compression::Best
-        } else {
-            bzip2::Compression::Default
-        };
-
-        Bzip2 { level }
+        Bzip2 {
+            level: bzip2::Compression::new(level as u32),
+        }
     }
 }
 #[cfg(feature = "with-bzip2")]
@@ -139,10 +197,13 @@ pub struct Xz2 {
 }
 #[cfg(feature = "with-xz2")]
 impl Xz2 {
-    pub fn new(level: i32) -> Self {
-        let level = cmp::min(cmp::max(level + 6, 0), 10) as u32;
+    pub const LEVELS: RangeInclusive<i32> = 0..=9;
+    pub const DEFAULT_LEVEL: i32 = 6;
 
-        Xz2 { level }
+    pub fn new(level: i32) -> Self {
+        Xz2 {
+            level: level as u32,
+        }
     }
 }
 #[cfg(feature = "with-xz2")]
@@ -194,11 +255,28 @@ impl Compression for Xz2 {
 #[cfg(feature = "with-zstd")]
 pub struct Zstd {
     level: i32,
+    dictionary: Option<Arc<Vec<u8>>>,
 }
 #[cfg(feature = "with-zstd")]
 impl Zstd {
+    /// Negative levels are the fast ones (`--fast=N` of the `zstd` tool);
+    /// 0 is the default level
+    pub const LEVELS: RangeInclusive<i32> = -(1 << 17)..=22;
+    pub const DEFAULT_LEVEL: i32 = 3;
+
     pub fn new(level: i32) -> Self {
-        Zstd { level }
+        Zstd {
+            level,
+            dictionary: None,
+        }
+    }
+
+    /// Compress and decompress using a trained `dictionary`
+    pub fn with_dictionary(level: i32, dictionary: Arc<Vec<u8>>) -> Self {
+        Zstd {
+            level,
+            dictionary: Some(dictionary),
+        }
     }
 }
 
@@ -245,8 +323,14 @@ impl Compression for Zstd {
     fn compress(&self, buf: SGData) -> io::Result<SGData> {
         let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
         {
-            let mut compressor =
-                zstd::Encoder::new(&mut backing, self.level).unwrap();
+            let mut compressor = match self.dictionary {
+                Some(ref dictionary) => zstd::Encoder::with_dictionary(
+                    &mut backing,
+                    self.level,
+                    dictionary,
+                ),
+                None => zstd::Encoder::new(&mut backing, self.level),
+            }.unwrap();
             for sg_part in buf.as_parts() {
                 compressor.write_all(sg_part).unwrap()
             }
@@ -260,9 +344,140 @@ impl Compression for Zstd {
         {
             // Ehh... https://github.com/gyscos/zstd-rs/issues/34
             let mut reader = SGReader::new(&buf);
-            let mut decompressor = zstd::Decoder::new(&mut reader).unwrap();
+            let mut decompressor = match self.dictionary {
+                Some(ref dictionary) => {
+                    zstd::Decoder::with_dictionary(&mut reader, dictionary)
+                }
+                None => zstd::Decoder::new(&mut reader),
+            }.unwrap();
+            let _ = decompressor.read_to_end(&mut backing)?;
+        }
+        Ok(SGData::from_single(backing))
+    }
+}
+
+#[cfg(feature = "with-lz4")]
+pub struct Lz4 {
+    level: u32,
+}
+#[cfg(feature = "with-lz4")]
+impl Lz4 {
+    /// Levels below 3 all mean the fast mode; 3 and up the high
+    /// compression mode
+    pub const LEVELS: RangeInclusive<i32> = 0..=12;
+    pub const DEFAULT_LEVEL: i32 = 0;
+
+    pub fn new(level: i32) -> Self {
+        Lz4 {
+            level: level as u32,
+        }
+    }
+}
+#[cfg(feature = "with-lz4")]
+impl Compression for Lz4 {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut compressor = lz4::EncoderBuilder::new()
+            .level(self.level)
+            .build(Vec::with_capacity(buf.len()))?;
+        for sg_part in buf.as_parts() {
+            compressor.write_all(sg_part)?;
+        }
+        let (backing, res) = compressor.finish();
+        res?;
+        Ok(SGData::from_single(backing))
+    }
+
+    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
+        {
+            let mut decompressor = lz4::Decoder::new(SGReader::new(&buf))?;
+            let _ = decompressor.read_to_end(&mut backing)?;
+        }
+        Ok(SGData::from_single(backing))
+    }
+}
+
+#[cfg(feature = "with-brotli")]
+const BROTLI_BUFFER_SIZE: usize = 4096;
+/// Base 2 logarithm of the brotli window size
+#[cfg(feature = "with-brotli")]
+const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+
+#[cfg(feature = "with-brotli")]
+pub struct Brotli {
+    quality: u32,
+}
+#[cfg(feature = "with-brotli")]
+impl Brotli {
+    pub const LEVELS: RangeInclusive<i32> = 0..=11;
+    pub const DEFAULT_LEVEL: i32 = 9;
+
+    pub fn new(level: i32) -> Self {
+        Brotli {
+            quality: level as u32,
+        }
+    }
+}
+#[cfg(feature = "with-brotli")]
+impl Compression for Brotli {
+    fn compress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut compressor = brotli::CompressorWriter::new(
+            Vec::with_capacity(buf.len()),
+            BROTLI_BUFFER_SIZE,
+            self.quality,
+            BROTLI_LG_WINDOW_SIZE,
+        );
+        for sg_part in buf.as_parts() {
+            compressor.write_all(sg_part)?;
+        }
+        // finishes the stream
+        Ok(SGData::from_single(compressor.into_inner()))
+    }
+
+    fn decompress(&self, buf: SGData) -> io::Result<SGData> {
+        let mut backing: Vec<u8> = Vec::with_capacity(buf.len());
+        {
+            let mut decompressor = brotli::Decompressor::new(
+                SGReader::new(&buf),
+                BROTLI_BUFFER_SIZE,
+            );
             let _ = decompressor.read_to_end(&mut backing)?;
         }
         Ok(SGData::from_single(backing))
     }
 }
+
+#[cfg(
+    all(test, any(feature = "with-lz4", feature = "with-brotli"))
+)]
+fn roundtrip(compression: &dyn Compression) {
+    let data: Vec<u8> = b"roundtrip through the compression engine "
+        .iter()
+        .cycle()
+        .take(100 * 1024)
+        .cloned()
+        .collect();
+    let sg =
+        SGData::from_many(vec![data[..1000].to_vec(), data[1000..].to_vec()]);
+
+    let compressed = compression.compress(sg).unwrap();
+    assert!(compressed.len() < data.len() / 10);
+    let decompressed = compression.decompress(compressed).unwrap();
+    assert!(decompressed.to_linear_vec() == data);
+}
+
+#[cfg(feature = "with-lz4")]
+#[test]
+fn lz4_roundtrip() {
+    for &level in &[0, 3, 12] {
+        roundtrip(&Lz4::new(level));
+    }
+}
+
+#[cfg(feature = "with-brotli")]
+#[test]
+fn brotli_roundtrip() {
+    for &level in &[0, 9, 11] {
+        roundtrip(&Brotli::new(level));
+    }
+}
@@ -39,6 +39,14 @@ impl Chunking {
         }
     }
 
+    pub(crate) fn chunk_bits(&self) -> u32 {
+        match *self {
+            Chunking::Bup { chunk_bits }
+            | Chunking::Gear { chunk_bits }
+            | Chunking::FastCDC { chunk_bits } => chunk_bits,
+        }
+    }
+
     pub(crate) fn to_engine(&self) -> Box<dyn chunking::Chunking> {
         match *self {
             Chunking::Bup { chunk_bits } => {
@@ -1,5 +1,17 @@
+//! Compression config
+//!
+//! Levels are stored as `native_level`: as defined by the algorithm (eg.
+//! 1-22 for zstd, or negative for its fast levels), `None` meaning its
+//! default. Configs written by older
+//! versions have only `level`, relative to the default level (negative
+//! meaning "faster", positive "smaller"), which is mapped to a few native
+//! levels, like it always was. LZ4 and Brotli came later, and never had
+//! it.
 use compression;
+use envelope::CompressionId;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
+use {Error, Result};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type")]
@@ -16,6 +28,12 @@ pub enum Compression {
     #[cfg(feature = "with-zstd")]
     #[serde(rename = "zstd")]
     Zstd(Zstd),
+    #[cfg(feature = "with-lz4")]
+    #[serde(rename = "lz4")]
+    Lz4(Lz4),
+    #[cfg(feature = "with-brotli")]
+    #[serde(rename = "brotli")]
+    Brotli(Brotli),
     #[serde(rename = "none")]
     None,
 }
@@ -23,7 +41,7 @@ pub enum Compression {
 impl Default for Compression {
     fn default() -> Compression {
         #[cfg(feature = "with-deflate")]
-        return Compression::Deflate(Deflate { level: 0 });
+        return Compression::Deflate(Deflate::new(None));
         #[cfg(not(feature = "with-deflate"))]
         return Compression::None;
     }
@@ -35,27 +53,200 @@ impl Compression {
             Compression::None => Arc::new(compression::NoCompression),
             #[cfg(feature = "with-deflate")]
             Compression::Deflate(d) => {
-                Arc::new(compression::Deflate::new(d.level))
+                Arc::new(compression::Deflate::new(d.level()))
             }
             #[cfg(feature = "with-xz2")]
-            Compression::Xz2(d) => Arc::new(compression::Xz2::new(d.level)),
+            Compression::Xz2(d) => Arc::new(compression::Xz2::new(d.level())),
             #[cfg(feature = "with-bzip2")]
-            Compression::Bzip2(d) => Arc::new(compression::Bzip2::new(d.level)),
+            Compression::Bzip2(d) => {
+                Arc::new(compression::Bzip2::new(d.level()))
+            }
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(d) => Arc::new(compression::Zstd::new(d.level())),
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(d) => Arc::new(compression::Lz4::new(d.level())),
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(d) => {
+                Arc::new(compression::Brotli::new(d.level()))
+            }
+        }
+    }
+
+    /// Levels supported by the algorithm, if it has any
+    fn levels(&self) -> Option<RangeInclusive<i32>> {
+        Some(match *self {
+            Compression::None => return None,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(_) => compression::Deflate::LEVELS,
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(_) => compression::Xz2::LEVELS,
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(_) => compression::Bzip2::LEVELS,
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(_) => compression::Zstd::LEVELS,
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(_) => compression::Lz4::LEVELS,
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(_) => compression::Brotli::LEVELS,
+        })
+    }
+
+    /// Level new data is compressed with, as defined by the algorithm
+    pub(crate) fn level(&self) -> Option<i32> {
+        match *self {
+            Compression::None => None,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(d) => Some(d.level()),
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(d) => Some(d.level()),
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(d) => Some(d.level()),
             #[cfg(feature = "with-zstd")]
-            Compression::Zstd(d) => Arc::new(compression::Zstd::new(d.level)),
+            Compression::Zstd(d) => Some(d.level()),
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(d) => Some(d.level()),
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(d) => Some(d.level()),
         }
     }
+
+    /// Set the level, as defined by the algorithm; `None` for its default
+    ///
+    /// Fails if the algorithm doesn't support `level`.
+    pub(crate) fn set_level(&mut self, level: Option<i32>) -> Result<()> {
+        if let Some(level) = level {
+            let name = self.id().name();
+            let levels = self.levels().ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "`{}` compression has no levels",
+                    name
+                ))
+            })?;
+            if !levels.contains(&level) {
+                return Err(Error::InvalidInput(format!(
+                    "`{}` compression level must be between {} and {}",
+                    name,
+                    levels.start(),
+                    levels.end()
+                )));
+            }
+        }
+
+        // the level relative to the default (if any) is no longer used
+        match *self {
+            Compression::None => {}
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(ref mut d) => d.set_level(level),
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(ref mut d) => d.set_level(level),
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(ref mut d) => d.set_level(level),
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(ref mut d) => d.set_level(level),
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(ref mut d) => d.set_level(level),
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(ref mut d) => d.set_level(level),
+        }
+        Ok(())
+    }
+
+    /// Engine compressing with the trained `dictionary`
+    ///
+    /// Only zstd uses dictionaries; other compressions ignore it.
+    pub(crate) fn to_engine_with_dictionary(
+        &self,
+        _dictionary: Arc<Vec<u8>>,
+    ) -> compression::ArcCompression {
+        match *self {
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(d) => Arc::new(
+                compression::Zstd::with_dictionary(d.level(), _dictionary),
+            ),
+            #[allow(unreachable_patterns)]
+            _ => self.to_engine(),
+        }
+    }
+
+    /// Id of the dictionary new data is compressed with, if any
+    pub(crate) fn dictionary(&self) -> Option<u8> {
+        match *self {
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(d) => d.dictionary,
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Id recorded in the header of chunks compressed this way
+    pub(crate) fn id(&self) -> CompressionId {
+        match *self {
+            Compression::None => CompressionId::None,
+            #[cfg(feature = "with-deflate")]
+            Compression::Deflate(_) => CompressionId::Deflate,
+            #[cfg(feature = "with-xz2")]
+            Compression::Xz2(_) => CompressionId::Xz2,
+            #[cfg(feature = "with-bzip2")]
+            Compression::Bzip2(_) => CompressionId::Bzip2,
+            #[cfg(feature = "with-zstd")]
+            Compression::Zstd(_) => CompressionId::Zstd,
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4(_) => CompressionId::Lz4,
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli(_) => CompressionId::Brotli,
+        }
+    }
+}
+/// Storing data chunks that don't compress well uncompressed
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AdaptiveCompression {
+    /// Minimum saving, in percent of the data length, to keep a chunk
+    /// compressed
+    pub min_saving_percent: u8,
 }
+
+/// Native level for a `level` written by older versions, mapped to the
+/// `fast`, `default` or `best` level of the algorithm
+#[cfg_attr(
+    not(any(feature = "with-deflate", feature = "with-bzip2")),
+    allow(dead_code)
+)]
+fn coarse_level(level: i32, fast: i32, default: i32, best: i32) -> i32 {
+    if level < 0 {
+        fast
+    } else if level > 0 {
+        best
+    } else {
+        default
+    }
+}
+
 #[cfg(feature = "with-deflate")]
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Deflate {
     #[serde(rename = "level")]
     level: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    native_level: Option<i32>,
 }
 #[cfg(feature = "with-deflate")]
 impl Deflate {
-    pub fn new(level: i32) -> Self {
-        Deflate { level }
+    pub fn new(level: Option<i32>) -> Self {
+        Deflate {
+            level: 0,
+            native_level: level,
+        }
+    }
+
+    fn level(&self) -> i32 {
+        self.native_level.unwrap_or_else(|| {
+            coarse_level(self.level, 1, compression::Deflate::DEFAULT_LEVEL, 9)
+        })
+    }
+
+    fn set_level(&mut self, level: Option<i32>) {
+        self.level = 0;
+        self.native_level = level;
     }
 }
 #[cfg(feature = "with-bzip2")]
@@ -63,11 +254,27 @@ impl Deflate {
 pub struct Bzip2 {
     #[serde(rename = "level")]
     level: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    native_level: Option<i32>,
 }
 #[cfg(feature = "with-bzip2")]
 impl Bzip2 {
-    pub fn new(level: i32) -> Self {
-        Bzip2 { level }
+    pub fn new(level: Option<i32>) -> Self {
+        Bzip2 {
+            level: 0,
+            native_level: level,
+        }
+    }
+
+    fn level(&self) -> i32 {
+        self.native_level.unwrap_or_else(|| {
+            coarse_level(self.level, 1, compression::Bzip2::DEFAULT_LEVEL, 9)
+        })
+    }
+
+    fn set_level(&mut self, level: Option<i32>) {
+        self.level = 0;
+        self.native_level = level;
     }
 }
 
@@ -76,11 +283,37 @@ impl Bzip2 {
 pub struct Zstd {
     #[serde(rename = "level")]
     level: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    native_level: Option<i32>,
+    /// Id of the trained dictionary to compress with (see `dictionary`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dictionary: Option<u8>,
 }
 #[cfg(feature = "with-zstd")]
 impl Zstd {
-    pub fn new(level: i32) -> Self {
-        Zstd { level }
+    pub fn new(level: Option<i32>) -> Self {
+        Zstd {
+            level: 0,
+            native_level: level,
+            dictionary: None,
+        }
+    }
+
+    pub fn with_dictionary(self, dictionary: Option<u8>) -> Self {
+        Zstd { dictionary, ..self }
+    }
+
+    fn level(&self) -> i32 {
+        // zstd always took the level as it is, 0 meaning the default
+        self.native_level.unwrap_or(match self.level {
+            0 => compression::Zstd::DEFAULT_LEVEL,
+            level => level,
+        })
+    }
+
+    fn set_level(&mut self, level: Option<i32>) {
+        self.level = 0;
+        self.native_level = level;
     }
 }
 
@@ -89,10 +322,75 @@ impl Zstd {
 pub struct Xz2 {
     #[serde(rename = "level")]
     level: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    native_level: Option<i32>,
 }
 #[cfg(feature = "with-xz2")]
 impl Xz2 {
-    pub fn new(level: i32) -> Self {
-        Xz2 { level }
+    pub fn new(level: Option<i32>) -> Self {
+        Xz2 {
+            level: 0,
+            native_level: level,
+        }
+    }
+
+    fn level(&self) -> i32 {
+        self.native_level.unwrap_or_else(|| {
+            let levels = compression::Xz2::LEVELS;
+            let level = compression::Xz2::DEFAULT_LEVEL + self.level;
+            level.max(*levels.start()).min(*levels.end())
+        })
+    }
+
+    fn set_level(&mut self, level: Option<i32>) {
+        self.level = 0;
+        self.native_level = level;
+    }
+}
+
+#[cfg(feature = "with-lz4")]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Lz4 {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    native_level: Option<i32>,
+}
+#[cfg(feature = "with-lz4")]
+impl Lz4 {
+    pub fn new(level: Option<i32>) -> Self {
+        Lz4 {
+            native_level: level,
+        }
+    }
+
+    fn level(&self) -> i32 {
+        self.native_level.unwrap_or(compression::Lz4::DEFAULT_LEVEL)
+    }
+
+    fn set_level(&mut self, level: Option<i32>) {
+        self.native_level = level;
+    }
+}
+
+#[cfg(feature = "with-brotli")]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Brotli {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    native_level: Option<i32>,
+}
+#[cfg(feature = "with-brotli")]
+impl Brotli {
+    pub fn new(level: Option<i32>) -> Self {
+        Brotli {
+            native_level: level,
+        }
+    }
+
+    fn level(&self) -> i32 {
+        self.native_level
+            .unwrap_or(compression::Brotli::DEFAULT_LEVEL)
+    }
+
+    fn set_level(&mut self, level: Option<i32>) {
+        self.native_level = level;
     }
 }
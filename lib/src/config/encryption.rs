@@ -1,3 +1,5 @@
+use envelope::EncryptionId;
+use keyfile::Keyfile;
 use std::io;
 use std::sync::Arc;
 use PassphraseFn;
@@ -15,17 +17,28 @@ pub(crate) enum Encryption {
     Curve25519(encryption::Curve25519),
 }
 
+impl Encryption {
+    /// Id recorded in the header of chunks encrypted this way
+    pub(crate) fn id(&self) -> EncryptionId {
+        match *self {
+            Encryption::None => EncryptionId::None,
+            Encryption::Curve25519(_) => EncryptionId::Curve25519,
+        }
+    }
+}
+
 impl encryption::EncryptionEngine for Encryption {
     fn change_passphrase(
         &mut self,
         old_p: PassphraseFn,
         new_p: PassphraseFn,
-        pwhash: &config::PWHash,
+        pwhash: &mut config::PWHash,
+        new_pwhash: Option<config::PWHash>,
     ) -> io::Result<()> {
         match *self {
             Encryption::None => Ok(()),
             Encryption::Curve25519(ref mut c) => {
-                c.change_passphrase(old_p, new_p, pwhash)
+                c.change_passphrase(old_p, new_p, pwhash, new_pwhash)
             }
         }
     }
@@ -50,4 +63,75 @@ impl encryption::EncryptionEngine for Encryption {
             Encryption::Curve25519(ref c) => c.decrypter(pass, pwhash),
         }
     }
+
+    fn add_key_slot(
+        &mut self,
+        name: &str,
+        old_p: PassphraseFn,
+        new_p: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        match *self {
+            Encryption::None => Err(not_encrypted()),
+            Encryption::Curve25519(ref mut c) => {
+                c.add_key_slot(name, old_p, new_p, pwhash)
+            }
+        }
+    }
+
+    fn add_keyfile_slot(
+        &mut self,
+        name: &str,
+        old_p: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Keyfile> {
+        match *self {
+            Encryption::None => Err(not_encrypted()),
+            Encryption::Curve25519(ref mut c) => {
+                c.add_keyfile_slot(name, old_p, pwhash)
+            }
+        }
+    }
+
+    fn key_slots(&self) -> Vec<String> {
+        match *self {
+            Encryption::None => vec![],
+            Encryption::Curve25519(ref c) => c.key_slots(),
+        }
+    }
+
+    fn revoke_key_slot(&mut self, name: &str) -> io::Result<()> {
+        match *self {
+            Encryption::None => Err(not_encrypted()),
+            Encryption::Curve25519(ref mut c) => c.revoke_key_slot(name),
+        }
+    }
+
+    fn export_keyfile(
+        &self,
+        pass: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Keyfile> {
+        match *self {
+            Encryption::None => Err(not_encrypted()),
+            Encryption::Curve25519(ref c) => c.export_keyfile(pass, pwhash),
+        }
+    }
+
+    fn decrypter_from_keyfile(
+        &self,
+        keyfile: &Keyfile,
+    ) -> io::Result<encryption::ArcDecrypter> {
+        match *self {
+            Encryption::None => Ok(Arc::new(encryption::NopDecrypter)),
+            Encryption::Curve25519(ref c) => c.decrypter_from_keyfile(keyfile),
+        }
+    }
+}
+
+fn not_encrypted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "repository is not encrypted",
+    )
 }
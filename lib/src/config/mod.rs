@@ -12,9 +12,11 @@ use hashing;
 use hex;
 use settings;
 
+use std::cmp;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use {Error, DIGEST_SIZE};
 
 mod chunking;
 mod compression;
@@ -26,11 +28,23 @@ pub(crate) use self::encryption::*;
 // }}}
 
 pub const REPO_VERSION_LOWEST: u32 = 3;
-pub const REPO_VERSION_CURRENT: u32 = 3;
+pub const REPO_VERSION_CURRENT: u32 = 6;
+/// Lowest version supporting `Layout::Packs`
+pub const REPO_VERSION_PACKS: u32 = 4;
+/// Lowest version supporting `IndexFormat::V2`
+pub const REPO_VERSION_INDEX_V2: u32 = 5;
+/// Lowest version supporting `ChunkFormat::V2`
+pub const REPO_VERSION_CHUNK_HEADER: u32 = 6;
 
 pub const DATA_SUBDIR: &'static str = "chunk";
+pub const PACK_SUBDIR: &'static str = "pack";
+pub const DEFAULT_PACK_SIZE: u64 = 32 * 1024 * 1024;
 pub const LOCK_FILE: &'static str = ".lock";
+/// Directory with lock files, for backends without file locking
+pub const LOCK_DIR: &'static str = ".locks";
 pub const CONFIG_YML_FILE: &'static str = "config.yml";
+/// Trained compression dictionaries (see `dictionary`)
+pub const DICT_SUBDIR: &'static str = "dict";
 
 // {{{ PWHash
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -40,6 +54,8 @@ pub const CONFIG_YML_FILE: &'static str = "config.yml";
 pub(crate) enum PWHash {
     #[serde(rename = "scryptsalsa208sha256")]
     SodiumOxide(pwhash::SodiumOxide),
+    #[serde(rename = "argon2id13")]
+    Argon2id(pwhash::Argon2id),
 }
 
 impl Default for PWHash {
@@ -49,7 +65,7 @@ impl Default for PWHash {
 }
 
 impl PWHash {
-    fn from_settings(pwhash: settings::PWHash) -> Self {
+    pub(crate) fn from_settings(pwhash: settings::PWHash) -> Self {
         match pwhash {
             settings::PWHash::Weak => {
                 PWHash::SodiumOxide(pwhash::SodiumOxide::new_weak())
@@ -60,6 +76,20 @@ impl PWHash {
             settings::PWHash::Strong => {
                 PWHash::SodiumOxide(pwhash::SodiumOxide::new_sensitive())
             }
+            settings::PWHash::Argon2id {
+                ops_limit,
+                mem_limit,
+            } => PWHash::Argon2id(pwhash::Argon2id::new(ops_limit, mem_limit)),
+        }
+    }
+
+    /// Same algorithm and parameters, but a fresh salt
+    pub(crate) fn with_new_salt(&self) -> Self {
+        match *self {
+            PWHash::SodiumOxide(ref so) => {
+                PWHash::SodiumOxide(so.with_new_salt())
+            }
+            PWHash::Argon2id(ref a) => PWHash::Argon2id(a.with_new_salt()),
         }
     }
 }
@@ -68,6 +98,7 @@ impl pwhash::PWHash for PWHash {
     fn derive_key(&self, passphrase: &str) -> io::Result<Vec<u8>> {
         match *self {
             PWHash::SodiumOxide(ref so) => so.derive_key(passphrase),
+            PWHash::Argon2id(ref a) => a.derive_key(passphrase),
         }
     }
 }
@@ -131,6 +162,107 @@ impl Nesting {
 }
 // }}}
 
+// {{{ Layout
+/// How chunks are stored in a generation
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum Layout {
+    /// Every chunk in its own file (see `Nesting`)
+    #[serde(rename = "files")]
+    Files,
+    /// Chunks appended to pack files of around `pack_size` bytes
+    #[serde(rename = "packs")]
+    Packs { pack_size: u64 },
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Files
+    }
+}
+
+impl Layout {
+    /// Lowest repo version that can be used with a given layout
+    fn repo_version(&self) -> u32 {
+        match *self {
+            Layout::Files => REPO_VERSION_LOWEST,
+            Layout::Packs { .. } => REPO_VERSION_PACKS,
+        }
+    }
+}
+// }}}
+
+// {{{ IndexFormat
+/// Format of index chunks
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum IndexFormat {
+    /// Digests only
+    #[serde(rename = "v1")]
+    V1,
+    /// Every digest followed by the length of the data it addresses (u64,
+    /// big endian); index chunks contain whole records only
+    #[serde(rename = "v2")]
+    V2,
+}
+
+impl Default for IndexFormat {
+    fn default() -> Self {
+        IndexFormat::V1
+    }
+}
+
+impl IndexFormat {
+    pub(crate) fn record_size(self) -> usize {
+        match self {
+            IndexFormat::V1 => DIGEST_SIZE,
+            IndexFormat::V2 => DIGEST_SIZE + 8,
+        }
+    }
+
+    pub(crate) fn is_v1(&self) -> bool {
+        *self == IndexFormat::V1
+    }
+
+    /// Lowest repo version that can be used with a given format
+    fn repo_version(self) -> u32 {
+        match self {
+            IndexFormat::V1 => REPO_VERSION_LOWEST,
+            IndexFormat::V2 => REPO_VERSION_INDEX_V2,
+        }
+    }
+}
+// }}}
+
+// {{{ ChunkFormat
+/// Format of data chunks
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ChunkFormat {
+    /// Compressed and encrypted data only, as the config says
+    #[serde(rename = "v1")]
+    V1,
+    /// Prefixed with a header recording compression, encryption and data
+    /// length (see `envelope`)
+    #[serde(rename = "v2")]
+    V2,
+}
+
+impl Default for ChunkFormat {
+    fn default() -> Self {
+        ChunkFormat::V1
+    }
+}
+
+impl ChunkFormat {
+    /// Lowest repo version that can be used with a given format
+    fn repo_version(self) -> u32 {
+        match self {
+            ChunkFormat::V1 => REPO_VERSION_LOWEST,
+            ChunkFormat::V2 => REPO_VERSION_CHUNK_HEADER,
+        }
+    }
+}
+// }}}
+
 // {{{ Repo
 /// Rdedup repository configuration
 ///
@@ -147,9 +279,18 @@ pub(crate) struct Repo {
     pub hashing: Hashing,
     #[serde(default)]
     pub compression: Compression,
+    /// Requires `ChunkFormat::V2`, to record which chunks are compressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive_compression: Option<AdaptiveCompression>,
     pub encryption: Encryption,
     #[serde(default)]
     pub nesting: Nesting,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub index_format: IndexFormat,
+    #[serde(default)]
+    pub chunk_format: ChunkFormat,
 }
 
 impl Repo {
@@ -165,16 +306,37 @@ impl Repo {
             settings::Encryption::None => Encryption::None,
         };
 
+        let layout = settings.layout.0;
+        let index_format = settings.index_format.0;
+        let chunk_format = settings.chunk_format.0;
+        if settings.adaptive_compression.is_some()
+            && chunk_format == ChunkFormat::V1
+        {
+            return Err(Error::InvalidInput(
+                "adaptive compression requires chunk headers".into(),
+            ).into());
+        }
         Ok(Repo {
-            version: REPO_VERSION_CURRENT,
+            version: cmp::max(
+                cmp::max(layout.repo_version(), index_format.repo_version()),
+                chunk_format.repo_version(),
+            ),
             pwhash,
             chunking: settings.chunking.0,
             encryption,
             compression: settings
                 .compression
-                .to_config(settings.compression_level),
+                .to_config(settings.compression_level)?,
+            adaptive_compression: settings.adaptive_compression.map(
+                |min_saving_percent| AdaptiveCompression {
+                    min_saving_percent,
+                },
+            ),
             nesting: settings.nesting.to_config(),
             hashing: settings.hashing.to_config(),
+            layout,
+            index_format,
+            chunk_format,
         })
     }
 
@@ -209,28 +371,15 @@ impl Repo {
 }
 
 fn check_version(version_int: u32) -> io::Result<()> {
-    if version_int > REPO_VERSION_CURRENT {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!(
-                "repo version {} higher than \
-                 supported {}; update?",
-                version_int, REPO_VERSION_CURRENT
-            ),
-        ));
-    }
-    // This if statement triggers the absurd_extreme_comparisons because the
+    // This comparison triggers the absurd_extreme_comparisons because the
     // minimum repo version is also the smallest value of a u32
-    if version_int < REPO_VERSION_LOWEST {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!(
-                "repo version {} lower than \
-                 lowest supported {}; \
-                 restore using older version?",
-                version_int, REPO_VERSION_LOWEST
-            ),
-        ));
+    if version_int > REPO_VERSION_CURRENT || version_int < REPO_VERSION_LOWEST
+    {
+        return Err(Error::UnsupportedVersion {
+            version: version_int,
+            lowest: REPO_VERSION_LOWEST,
+            current: REPO_VERSION_CURRENT,
+        }.into());
     }
 
     Ok(())
@@ -0,0 +1,181 @@
+//! Trained zstd dictionaries
+//!
+//! Small, similar chunks (eg. JSON documents, log lines) compress poorly on
+//! their own. `Repo::train_dictionary` samples stored data chunks and trains
+//! a zstd dictionary on them, stored as `dict/<id>`, where `id` is 1-255.
+//! `config::Compression::Zstd` refers to the dictionary new data chunks
+//! are compressed with; the id is recorded in the chunk header (see
+//! `envelope`), so chunks compressed with older dictionaries can still be
+//! decompressed after a new one is trained.
+//!
+//! A dictionary object is its digest followed by the dictionary in a chunk
+//! envelope. Dictionaries contain fragments of the data they were trained
+//! on, so they are encrypted like data chunks, and writing with a dictionary
+//! needs the secret key to read it. Storing them unencrypted, so that
+//! writing needs just the public key, has to be asked for explicitly (see
+//! `Repo::train_dictionary`).
+// {{{ use and mod
+use aio;
+use config;
+use encryption::ArcDecrypter;
+use envelope;
+use hashing::ArcHasher;
+use sgdata::SGData;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use DIGEST_SIZE;
+
+// only training needs to list and write dictionaries
+#[cfg(feature = "with-zstd")]
+use encryption::ArcEncrypter;
+#[cfg(feature = "with-zstd")]
+use envelope::{CompressionId, EncryptionId};
+#[cfg(feature = "with-zstd")]
+use util::substitute_err_not_found;
+#[cfg(feature = "with-zstd")]
+use zstd;
+// }}}
+
+/// Path of the dictionary `id`
+fn path(id: u8) -> PathBuf {
+    PathBuf::from(config::DICT_SUBDIR).join(id.to_string())
+}
+
+/// Ids of all the stored dictionaries
+#[cfg(feature = "with-zstd")]
+pub(crate) fn list(aio: &aio::AsyncIO) -> io::Result<Vec<u8>> {
+    let list = substitute_err_not_found(
+        aio.list(PathBuf::from(config::DICT_SUBDIR)).wait(),
+        || vec![],
+    )?;
+    let mut ids: Vec<u8> = list
+        .iter()
+        .filter_map(|path| path.file_name().and_then(|f| f.to_str()))
+        .filter_map(|file_name| file_name.parse().ok())
+        .filter(|&id| id != 0)
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// Store `dict` as the dictionary `id`
+#[cfg(feature = "with-zstd")]
+pub(crate) fn write(
+    aio: &aio::AsyncIO,
+    id: u8,
+    dict: Vec<u8>,
+    hasher: &ArcHasher,
+    encrypter: &ArcEncrypter,
+    encryption: EncryptionId,
+) -> io::Result<()> {
+    let data_len = dict.len() as u64;
+    let dict = SGData::from_single(dict);
+    let digest = hasher.calculate_digest(&dict);
+    let dict = encrypter.encrypt(dict, &digest)?;
+    let dict = envelope::Header {
+        compression: CompressionId::None,
+        encryption,
+        dictionary: 0,
+        data_len,
+    }.wrap(dict);
+
+    let mut object = SGData::from_single(digest);
+    for part in dict.as_parts() {
+        object.push_arcref(part.clone());
+    }
+    aio.write(path(id), object).wait()
+}
+
+/// Read the dictionary `id`
+///
+/// `decrypter` is needed only for encrypted dictionaries; without it they
+/// fail with `PermissionDenied`.
+pub(crate) fn read(
+    aio: &aio::AsyncIO,
+    id: u8,
+    hasher: &ArcHasher,
+    decrypter: Option<&ArcDecrypter>,
+) -> io::Result<Vec<u8>> {
+    let corrupted = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("dictionary {} corrupted: {}", id, reason),
+        )
+    };
+
+    let mut data = aio.read(path(id)).wait()?.to_linear_vec();
+    if data.len() < DIGEST_SIZE {
+        return Err(corrupted("too short".into()));
+    }
+    let dict = SGData::from_single(data.split_off(DIGEST_SIZE));
+    let digest = data;
+
+    let header =
+        envelope::Header::of(&dict).map_err(|e| corrupted(e.to_string()))?;
+    if header.encryption != envelope::EncryptionId::None && decrypter.is_none()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("dictionary {} is encrypted", id),
+        ));
+    }
+    let dict = envelope::decode_with_header(
+        dict,
+        &digest,
+        decrypter,
+        &|_| Err(corrupted("compressed with a dictionary".into())),
+    ).map_err(|e| corrupted(e.to_string()))?;
+
+    if hasher.calculate_digest(&dict) != digest {
+        return Err(corrupted("content doesn't match its digest".into()));
+    }
+    Ok(dict.to_linear_vec())
+}
+
+/// Train a dictionary of at most `max_size` bytes on `samples`
+#[cfg(feature = "with-zstd")]
+pub(crate) fn train(
+    samples: &[Vec<u8>],
+    max_size: usize,
+) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+/// Dictionaries read so far
+///
+/// Dictionaries never change once stored, so they are read only once per
+/// `Repo`.
+pub(crate) struct Dictionaries {
+    aio: aio::AsyncIO,
+    hasher: ArcHasher,
+    cache: Mutex<HashMap<u8, Arc<Vec<u8>>>>,
+}
+
+impl Dictionaries {
+    pub(crate) fn new(aio: aio::AsyncIO, hasher: ArcHasher) -> Self {
+        Dictionaries {
+            aio,
+            hasher,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Dictionary `id`, read if not cached yet
+    pub(crate) fn get(
+        &self,
+        id: u8,
+        decrypter: Option<&ArcDecrypter>,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(dict) = self.cache.lock().unwrap().get(&id) {
+            return Ok(Arc::clone(dict));
+        }
+
+        let dict = Arc::new(read(&self.aio, id, &self.hasher, decrypter)?);
+        self.cache.lock().unwrap().insert(id, Arc::clone(&dict));
+        Ok(dict)
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -1,6 +1,7 @@
 use hex;
+use keyfile::Keyfile;
 use pwhash::PWHash;
-use PassphraseFn;
+use {Error, PassphraseFn};
 use {as_base64, box_, from_base64, pwhash, secretbox};
 
 use sgdata::SGData;
@@ -12,12 +13,23 @@ use std::sync::Arc;
 pub type ArcEncrypter = Arc<dyn Encrypter + Send + Sync>;
 pub type ArcDecrypter = Arc<dyn Decrypter + Send + Sync>;
 
+/// Name of the key slot stored directly in `Curve25519`
+///
+/// It can be revoked just like the other ones; `sealed_sec_key` is empty
+/// then.
+pub const DEFAULT_KEY_SLOT: &'static str = "default";
+
 pub(crate) trait EncryptionEngine {
+    /// Seal the key (in the slot `old_p` unlocks) with a new passphrase
+    ///
+    /// `pwhash` is the key derivation of the default slot. If `new_pwhash`
+    /// is given, the slot is switched to it.
     fn change_passphrase(
         &mut self,
         old_p: PassphraseFn,
         new_p: PassphraseFn,
-        pwhash: &config::PWHash,
+        pwhash: &mut config::PWHash,
+        new_pwhash: Option<config::PWHash>,
     ) -> io::Result<()>;
 
     fn encrypter(
@@ -31,10 +43,46 @@ pub(crate) trait EncryptionEngine {
 
         pwhash: &config::PWHash,
     ) -> io::Result<ArcDecrypter>;
+
+    /// Seal the secret key with a new passphrase, in a new named slot
+    fn add_key_slot(
+        &mut self,
+        name: &str,
+        old_p: PassphraseFn,
+        new_p: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()>;
+
+    /// Seal the secret key with a new random key, in a new named slot
+    ///
+    /// The random key is returned in a keyfile.
+    fn add_keyfile_slot(
+        &mut self,
+        name: &str,
+        old_p: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Keyfile>;
+
+    /// Names of all the key slots
+    fn key_slots(&self) -> Vec<String>;
+
+    fn revoke_key_slot(&mut self, name: &str) -> io::Result<()>;
+
+    /// Unseal the secret key and export it to a keyfile
+    fn export_keyfile(
+        &self,
+        passphrase_f: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Keyfile>;
+
+    fn decrypter_from_keyfile(
+        &self,
+        keyfile: &Keyfile,
+    ) -> io::Result<ArcDecrypter>;
 }
 
 pub trait Encrypter {
-    fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData>;
+    fn encrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData>;
 }
 
 pub trait Decrypter {
@@ -75,13 +123,75 @@ pub struct Curve25519 {
         deserialize_with = "from_base64"
     )]
     pub nonce: secretbox::Nonce,
+    /// Additional copies of the secret key, sealed with other passphrases
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) key_slots: Vec<KeySlot>,
+}
+
+/// Secret key sealed with a passphrase of one of the key holders, or
+/// a random key kept in a keyfile
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct KeySlot {
+    pub(crate) name: String,
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub(crate) sealed_sec_key: Vec<u8>,
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    pub(crate) nonce: secretbox::Nonce,
+    /// Every slot has its own salt; `None` for keyfile slots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pwhash: Option<config::PWHash>,
+}
+
+fn seal_sec_key(
+    sec_key: &box_::SecretKey,
+    passphrase: &str,
+    nonce: &secretbox::Nonce,
+    pwhash: &dyn pwhash::PWHash,
+) -> io::Result<Vec<u8>> {
+    let derived_key =
+        secretbox::Key::from_slice(&pwhash.derive_key(passphrase)?[..32])
+            .unwrap();
+
+    Ok(secretbox::seal(&sec_key.0, nonce, &derived_key))
+}
+
+/// Try to unseal the secret key; `None` if the passphrase is wrong
+fn open_sec_key(
+    sealed_sec_key: &[u8],
+    passphrase: &str,
+    nonce: &secretbox::Nonce,
+    pwhash: &dyn pwhash::PWHash,
+) -> io::Result<Option<box_::SecretKey>> {
+    let derived_key =
+        secretbox::Key::from_slice(&pwhash.derive_key(passphrase)?[..32])
+            .unwrap();
+    let plain_seckey =
+        match secretbox::open(sealed_sec_key, nonce, &derived_key) {
+            Ok(plain_seckey) => plain_seckey,
+            Err(_) => return Ok(None),
+        };
+
+    Ok(Some(box_::SecretKey::from_slice(&plain_seckey).ok_or_else(
+        || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "plain secret key in a wrong format",
+            )
+        },
+    )?))
 }
 
 impl Curve25519 {
     pub(crate) fn new(
         passphrase_f: PassphraseFn,
         pwhash: &dyn pwhash::PWHash,
-    ) -> super::Result<Self> {
+    ) -> io::Result<Self> {
         let (pk, sk) = box_::gen_keypair();
         let passphrase = passphrase_f()?;
 
@@ -99,37 +209,118 @@ impl Curve25519 {
             sealed_sec_key: sealed_sk,
             pub_key: pk,
             nonce,
+            key_slots: vec![],
         })
     }
 
-    fn unseal_decrypt(
+    /// Unseal the secret key with a passphrase of any key slot
+    ///
+    /// Returns the key and index of the matching slot in `key_slots`
+    /// (`None` for the default slot). Every slot has its own salt, so
+    /// a key is derived for each slot tried.
+    fn unseal_any(
         &self,
         passphrase_f: &dyn Fn() -> io::Result<String>,
         pwhash: &config::PWHash,
-    ) -> io::Result<box_::SecretKey> {
+    ) -> io::Result<(box_::SecretKey, Option<usize>)> {
         let passphrase = passphrase_f()?;
 
-        let derived_key = secretbox::Key::from_slice(
-            &pwhash.derive_key(&passphrase)?[..32],
-        ).unwrap();
+        if self.has_default_slot() {
+            if let Some(sec_key) = open_sec_key(
+                &self.sealed_sec_key,
+                &passphrase,
+                &self.nonce,
+                pwhash,
+            )? {
+                return Ok((sec_key, None));
+            }
+        }
+
+        for (i, slot) in self.key_slots.iter().enumerate() {
+            let slot_pwhash = match slot.pwhash {
+                Some(ref slot_pwhash) => slot_pwhash,
+                None => continue,
+            };
+            if let Some(sec_key) = open_sec_key(
+                &slot.sealed_sec_key,
+                &passphrase,
+                &slot.nonce,
+                slot_pwhash,
+            )? {
+                return Ok((sec_key, Some(i)));
+            }
+        }
+
+        Err(Error::WrongPassphrase.into())
+    }
+
+    fn has_default_slot(&self) -> bool {
+        !self.sealed_sec_key.is_empty()
+    }
+
+    /// Number of slots that can be unlocked with a passphrase
+    fn passphrase_slots(&self) -> usize {
+        self.key_slots
+            .iter()
+            .filter(|slot| slot.pwhash.is_some())
+            .count()
+            + if self.has_default_slot() { 1 } else { 0 }
+    }
+
+    fn check_new_slot_name(&self, name: &str) -> io::Result<()> {
+        if name.is_empty() || name == DEFAULT_KEY_SLOT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid key slot name: `{}`", name),
+            ));
+        }
+        if self.key_slots.iter().any(|slot| slot.name == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("key slot `{}` already exists", name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Unseal the secret key with the key of a keyfile slot
+    fn unseal_keyfile_slot(
+        &self,
+        name: &str,
+        key: &secretbox::Key,
+    ) -> io::Result<box_::SecretKey> {
+        let slot = self
+            .key_slots
+            .iter()
+            .find(|slot| slot.name == name && slot.pwhash.is_none())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("keyfile slot `{}` not found; revoked?", name),
+                )
+            })?;
         let plain_seckey =
-            secretbox::open(&self.sealed_sec_key, &self.nonce, &derived_key)
-                .map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "can't decrypt key using given passphrase",
-                    )
-                })?;
-
-        Ok(box_::SecretKey::from_slice(&plain_seckey).ok_or_else(|| {
+            secretbox::open(&slot.sealed_sec_key, &slot.nonce, key)
+                .map_err(|_| Error::WrongPassphrase)?;
+
+        box_::SecretKey::from_slice(&plain_seckey).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
                 "plain secret key in a wrong format",
             )
-        })?)
+        })
     }
 
-    fn unseal_encrypt(&self) -> super::Result<box_::PublicKey> {
+    fn unseal_decrypt(
+        &self,
+        passphrase_f: &dyn Fn() -> io::Result<String>,
+        pwhash: &config::PWHash,
+    ) -> io::Result<box_::SecretKey> {
+        self.unseal_any(passphrase_f, pwhash)
+            .map(|(sec_key, _)| sec_key)
+    }
+
+    fn unseal_encrypt(&self) -> io::Result<box_::PublicKey> {
         Ok(self.pub_key)
     }
 }
@@ -139,20 +330,42 @@ impl EncryptionEngine for Curve25519 {
         &mut self,
         old_p: PassphraseFn,
         new_p: PassphraseFn,
-        pwhash: &config::PWHash,
+        pwhash: &mut config::PWHash,
+        new_pwhash: Option<config::PWHash>,
     ) -> io::Result<()> {
-        let sec_key = self.unseal_decrypt(old_p, pwhash)?;
+        let (sec_key, slot_i) = self.unseal_any(old_p, pwhash)?;
 
         let new_passphrase = new_p()?;
 
-        let sealed_sk = {
-            let derived_key = secretbox::Key::from_slice(
-                &pwhash.derive_key(&new_passphrase)?[..32],
-            ).unwrap();
-            secretbox::seal(&sec_key.0, &self.nonce, &derived_key)
-        };
-
-        self.sealed_sec_key = sealed_sk;
+        // change the passphrase of the slot that was used to unlock
+        match slot_i {
+            None => {
+                self.sealed_sec_key = seal_sec_key(
+                    &sec_key,
+                    &new_passphrase,
+                    &self.nonce,
+                    new_pwhash.as_ref().unwrap_or(&*pwhash),
+                )?;
+                if let Some(new_pwhash) = new_pwhash {
+                    *pwhash = new_pwhash;
+                }
+            }
+            Some(i) => {
+                let slot = &mut self.key_slots[i];
+                slot.sealed_sec_key = seal_sec_key(
+                    &sec_key,
+                    &new_passphrase,
+                    &slot.nonce,
+                    new_pwhash
+                        .as_ref()
+                        .or(slot.pwhash.as_ref())
+                        .expect("passphrase slot"),
+                )?;
+                if new_pwhash.is_some() {
+                    slot.pwhash = new_pwhash;
+                }
+            }
+        }
 
         Ok(())
     }
@@ -173,6 +386,130 @@ impl EncryptionEngine for Curve25519 {
         let key = self.unseal_decrypt(pass, pwhash)?;
         Ok(Arc::new(Curve25519Decrypter { sec_key: key }))
     }
+
+    fn add_key_slot(
+        &mut self,
+        name: &str,
+        old_p: PassphraseFn,
+        new_p: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<()> {
+        self.check_new_slot_name(name)?;
+
+        let (sec_key, _) = self.unseal_any(old_p, pwhash)?;
+        let new_passphrase = new_p()?;
+
+        let nonce = secretbox::gen_nonce();
+        let slot_pwhash = pwhash.with_new_salt();
+        let sealed_sec_key =
+            seal_sec_key(&sec_key, &new_passphrase, &nonce, &slot_pwhash)?;
+
+        self.key_slots.push(KeySlot {
+            name: name.to_owned(),
+            sealed_sec_key,
+            nonce,
+            pwhash: Some(slot_pwhash),
+        });
+
+        Ok(())
+    }
+
+    fn add_keyfile_slot(
+        &mut self,
+        name: &str,
+        old_p: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Keyfile> {
+        self.check_new_slot_name(name)?;
+
+        let (sec_key, _) = self.unseal_any(old_p, pwhash)?;
+
+        let key = secretbox::gen_key();
+        let nonce = secretbox::gen_nonce();
+        self.key_slots.push(KeySlot {
+            name: name.to_owned(),
+            sealed_sec_key: secretbox::seal(&sec_key.0, &nonce, &key),
+            nonce,
+            pwhash: None,
+        });
+
+        Ok(Keyfile::new_key_slot(name, &key))
+    }
+
+    fn key_slots(&self) -> Vec<String> {
+        let mut names = vec![];
+        if self.has_default_slot() {
+            names.push(DEFAULT_KEY_SLOT.to_owned());
+        }
+        names.extend(self.key_slots.iter().map(|slot| slot.name.clone()));
+        names
+    }
+
+    fn revoke_key_slot(&mut self, name: &str) -> io::Result<()> {
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("key slot `{}` not found", name),
+            )
+        };
+        let last_passphrase_slot = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the last passphrase key slot can't be revoked",
+            )
+        };
+
+        if name == DEFAULT_KEY_SLOT {
+            if !self.has_default_slot() {
+                return Err(not_found());
+            }
+            if self.passphrase_slots() == 1 {
+                return Err(last_passphrase_slot());
+            }
+            self.sealed_sec_key = vec![];
+            return Ok(());
+        }
+
+        let i = self
+            .key_slots
+            .iter()
+            .position(|slot| slot.name == name)
+            .ok_or_else(not_found)?;
+        if self.key_slots[i].pwhash.is_some() && self.passphrase_slots() == 1 {
+            return Err(last_passphrase_slot());
+        }
+        self.key_slots.remove(i);
+
+        Ok(())
+    }
+
+    fn export_keyfile(
+        &self,
+        passphrase_f: PassphraseFn,
+        pwhash: &config::PWHash,
+    ) -> io::Result<Keyfile> {
+        let sec_key = self.unseal_decrypt(passphrase_f, pwhash)?;
+        Ok(Keyfile::new_curve25519(self.pub_key, &sec_key))
+    }
+
+    fn decrypter_from_keyfile(
+        &self,
+        keyfile: &Keyfile,
+    ) -> io::Result<ArcDecrypter> {
+        if let Some((name, key)) = keyfile.key_slot()? {
+            let sec_key = self.unseal_keyfile_slot(name, &key)?;
+            return Ok(Arc::new(Curve25519Decrypter { sec_key }));
+        }
+
+        let (pub_key, sec_key) = keyfile.curve25519_key()?;
+        if pub_key != self.pub_key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keyfile of a different repository",
+            ));
+        }
+        Ok(Arc::new(Curve25519Decrypter { sec_key }))
+    }
 }
 
 struct Curve25519Encrypter {
@@ -180,7 +517,7 @@ struct Curve25519Encrypter {
 }
 
 impl Encrypter for Curve25519Encrypter {
-    fn encrypt(&self, buf: SGData, digest: &[u8]) -> super::Result<SGData> {
+    fn encrypt(&self, buf: SGData, digest: &[u8]) -> io::Result<SGData> {
         let nonce = box_::Nonce::from_slice(&digest[0..box_::NONCEBYTES])
             .expect("Nonce::from_slice failed");
 
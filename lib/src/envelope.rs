@@ -0,0 +1,435 @@
+//! Self-describing envelope of data chunks
+//!
+//! In repositories with `config::ChunkFormat::V2` every data chunk starts
+//! with a header recording how it was encoded:
+//!
+//! * magic: `rdch` (4 bytes)
+//! * envelope version: 1 (1 byte)
+//! * compression (`CompressionId`, 1 byte)
+//! * encryption (`EncryptionId`, 1 byte)
+//! * zstd dictionary id, 0 if none (1 byte, see `dictionary`)
+//! * length of the data before compression (u64, big endian)
+//!
+//! followed by the data, compressed and then encrypted. Chunks are decoded
+//! according to their own header, so chunks encoded differently (eg. after
+//! compression of the repository was changed) can coexist, and can be
+//! decoded even without `config.yml` (given the key, if encrypted).
+//!
+//! Index chunks are never compressed or encrypted, and have no header.
+//! Chunks of `ChunkFormat::V1` repositories have no header either; they are
+//! decoded according to the repository config. Whether a chunk has a header
+//! is told by the magic of every chunk, not by the repository config, so
+//! repositories upgraded to `V2` (`Repo::upgrade_chunk_format`) keep reading
+//! their older chunks. A `V1` chunk starting with the magic by chance is
+//! still decoded according to the config, once decoding it as `V2` fails.
+// {{{ use and mod
+use compression::{self, ArcCompression};
+use encryption::ArcDecrypter;
+use sgdata::SGData;
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+// }}}
+
+const MAGIC: &'static [u8; 4] = b"rdch";
+const VERSION: u8 = 1;
+pub(crate) const HEADER_SIZE: usize = 16;
+
+/// Compression of a chunk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum CompressionId {
+    None = 0,
+    Deflate = 1,
+    Bzip2 = 2,
+    Xz2 = 3,
+    Zstd = 4,
+    Lz4 = 5,
+    Brotli = 6,
+}
+
+impl CompressionId {
+    fn from_u8(id: u8) -> Option<Self> {
+        Some(match id {
+            0 => CompressionId::None,
+            1 => CompressionId::Deflate,
+            2 => CompressionId::Bzip2,
+            3 => CompressionId::Xz2,
+            4 => CompressionId::Zstd,
+            5 => CompressionId::Lz4,
+            6 => CompressionId::Brotli,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            CompressionId::None => "none",
+            CompressionId::Deflate => "deflate",
+            CompressionId::Bzip2 => "bzip2",
+            CompressionId::Xz2 => "xz2",
+            CompressionId::Zstd => "zstd",
+            CompressionId::Lz4 => "lz4",
+            CompressionId::Brotli => "brotli",
+        }
+    }
+
+    /// Engine able to decompress chunks compressed this way (and with
+    /// `dictionary`, if any)
+    ///
+    /// Fails if support for the compression is not compiled in.
+    fn decompressor(
+        self,
+        dictionary: Option<Arc<Vec<u8>>>,
+    ) -> io::Result<ArcCompression> {
+        if dictionary.is_some() && self != CompressionId::Zstd {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` compression doesn't use dictionaries",
+                    self.name()
+                ),
+            ));
+        }
+        // the level doesn't matter for decompression
+        Ok(match self {
+            CompressionId::None => Arc::new(compression::NoCompression),
+            #[cfg(feature = "with-deflate")]
+            CompressionId::Deflate => {
+                let level = compression::Deflate::DEFAULT_LEVEL;
+                Arc::new(compression::Deflate::new(level))
+            }
+            #[cfg(feature = "with-bzip2")]
+            CompressionId::Bzip2 => {
+                let level = compression::Bzip2::DEFAULT_LEVEL;
+                Arc::new(compression::Bzip2::new(level))
+            }
+            #[cfg(feature = "with-xz2")]
+            CompressionId::Xz2 => {
+                let level = compression::Xz2::DEFAULT_LEVEL;
+                Arc::new(compression::Xz2::new(level))
+            }
+            #[cfg(feature = "with-zstd")]
+            CompressionId::Zstd => match dictionary {
+                Some(dictionary) => {
+                    Arc::new(compression::Zstd::with_dictionary(0, dictionary))
+                }
+                None => Arc::new(compression::Zstd::new(0)),
+            },
+            #[cfg(feature = "with-lz4")]
+            CompressionId::Lz4 => {
+                let level = compression::Lz4::DEFAULT_LEVEL;
+                Arc::new(compression::Lz4::new(level))
+            }
+            #[cfg(feature = "with-brotli")]
+            CompressionId::Brotli => {
+                let level = compression::Brotli::DEFAULT_LEVEL;
+                Arc::new(compression::Brotli::new(level))
+            }
+            #[allow(unreachable_patterns)]
+            id => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "`{}` compression not supported by this build",
+                        id.name()
+                    ),
+                ))
+            }
+        })
+    }
+}
+
+/// Encryption of a chunk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum EncryptionId {
+    None = 0,
+    /// `Curve25519Blake2BSalsa20Poly1305`
+    Curve25519 = 1,
+}
+
+impl EncryptionId {
+    fn from_u8(id: u8) -> Option<Self> {
+        Some(match id {
+            0 => EncryptionId::None,
+            1 => EncryptionId::Curve25519,
+            _ => return None,
+        })
+    }
+}
+
+/// Header of a data chunk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Header {
+    pub compression: CompressionId,
+    pub encryption: EncryptionId,
+    /// Id of the zstd dictionary used to compress the data; 0 if none
+    pub dictionary: u8,
+    /// Length of the data before compression
+    pub data_len: u64,
+}
+
+impl Header {
+    pub(crate) fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = VERSION;
+        bytes[5] = self.compression as u8;
+        bytes[6] = self.encryption as u8;
+        bytes[7] = self.dictionary;
+        for i in 0..8 {
+            bytes[8 + i] = (self.data_len >> (56 - 8 * i)) as u8;
+        }
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid =
+            |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != &MAGIC[..] {
+            return Err(invalid("chunk header missing".into()));
+        }
+        if bytes[4] != VERSION {
+            return Err(invalid(format!(
+                "unsupported chunk header version: {}",
+                bytes[4]
+            )));
+        }
+        let compression =
+            CompressionId::from_u8(bytes[5]).ok_or_else(|| {
+                invalid(format!("unknown chunk compression: {}", bytes[5]))
+            })?;
+        let encryption = EncryptionId::from_u8(bytes[6]).ok_or_else(|| {
+            invalid(format!("unknown chunk encryption: {}", bytes[6]))
+        })?;
+        let data_len = bytes[8..HEADER_SIZE]
+            .iter()
+            .fold(0u64, |len, &b| (len << 8) | u64::from(b));
+
+        Ok(Header {
+            compression,
+            encryption,
+            dictionary: bytes[7],
+            data_len,
+        })
+    }
+
+    /// Header of the chunk `data`
+    pub(crate) fn of(data: &SGData) -> io::Result<Self> {
+        Header::from_bytes(&prefix(data, HEADER_SIZE))
+    }
+
+    /// Prepend the header to the (compressed and encrypted) chunk `data`
+    ///
+    /// The header is added as a separate part; `data` is not copied.
+    pub(crate) fn wrap(&self, data: SGData) -> SGData {
+        let mut wrapped = SGData::from_single(self.to_bytes().to_vec());
+        for part in data.as_parts() {
+            wrapped.push_arcref(part.clone());
+        }
+        wrapped
+    }
+}
+
+/// Up to `len` first bytes of `data`
+fn prefix(data: &SGData, len: usize) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(len);
+    for part in data.as_parts() {
+        let missing = len - prefix.len();
+        if missing == 0 {
+            break;
+        }
+        prefix.extend_from_slice(&part[..cmp::min(missing, part.len())]);
+    }
+    prefix
+}
+
+/// `data` without its first `len` bytes; the rest is not copied
+fn skip(data: &SGData, mut len: usize) -> SGData {
+    let mut rest = SGData::empty();
+    for part in data.as_parts() {
+        if len >= part.len() {
+            len -= part.len();
+            continue;
+        }
+        let start = len;
+        rest.push_arcref(part.clone().map(|part| &part[start..]));
+        len = 0;
+    }
+    rest
+}
+
+/// Whether `data` starts with the magic of a chunk header
+pub(crate) fn has_header(data: &SGData) -> bool {
+    prefix(data, MAGIC.len()) == &MAGIC[..]
+}
+
+fn need_decrypter(
+    decrypter: Option<&ArcDecrypter>,
+) -> io::Result<&ArcDecrypter> {
+    decrypter.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "chunk is encrypted")
+    })
+}
+
+/// Decode a data chunk, as read from the store
+///
+/// Chunks with a header are decoded as it says (see `decode_with_header`);
+/// chunks without one are decrypted with `decrypter` and decompressed with
+/// the repository `compression`.
+pub(crate) fn decode(
+    data: SGData,
+    digest: &[u8],
+    decrypter: Option<&ArcDecrypter>,
+    compression: &ArcCompression,
+    dictionary: &dyn Fn(u8) -> io::Result<Arc<Vec<u8>>>,
+) -> io::Result<SGData> {
+    let decode_legacy = |data: SGData| {
+        let data = need_decrypter(decrypter)?.decrypt(data, digest)?;
+        compression.decompress(data)
+    };
+
+    if !has_header(&data) {
+        return decode_legacy(data);
+    }
+    match decode_with_header(data.clone(), digest, decrypter, dictionary) {
+        Ok(data) => Ok(data),
+        Err(e) => decode_legacy(data).map_err(|_| e),
+    }
+}
+
+/// Decode a chunk that has a header, getting the dictionary it was
+/// compressed with (if any) from `dictionary`
+pub(crate) fn decode_with_header(
+    data: SGData,
+    digest: &[u8],
+    decrypter: Option<&ArcDecrypter>,
+    dictionary: &dyn Fn(u8) -> io::Result<Arc<Vec<u8>>>,
+) -> io::Result<SGData> {
+    let header = Header::of(&data)?;
+    let data = skip(&data, HEADER_SIZE);
+
+    let data = match header.encryption {
+        EncryptionId::None => data,
+        EncryptionId::Curve25519 => {
+            need_decrypter(decrypter)?.decrypt(data, digest)?
+        }
+    };
+    let dictionary = match header.dictionary {
+        0 => None,
+        id => Some(dictionary(id)?),
+    };
+    let data = header
+        .compression
+        .decompressor(dictionary)?
+        .decompress(data)?;
+
+    if data.len() as u64 != header.data_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "decoded length {} doesn't match the header: {}",
+                data.len(),
+                header.data_len
+            ),
+        ));
+    }
+    Ok(data)
+}
+
+#[test]
+fn header_roundtrip() {
+    let header = Header {
+        compression: CompressionId::Zstd,
+        encryption: EncryptionId::Curve25519,
+        dictionary: 3,
+        data_len: 0x0102_0304_0506,
+    };
+    let bytes = header.to_bytes();
+    assert_eq!(&bytes[..8], b"rdch\x01\x04\x01\x03");
+    assert_eq!(&bytes[8..], [0, 0, 1, 2, 3, 4, 5, 6]);
+    assert_eq!(Header::from_bytes(&bytes).unwrap(), header);
+
+    let mut bad = bytes;
+    bad[5] = 200;
+    assert!(Header::from_bytes(&bad).is_err());
+    assert!(Header::from_bytes(&bytes[..10]).is_err());
+    assert!(Header::from_bytes(b"\x00\x01\x02\x03rest of the chunk").is_err());
+}
+
+#[test]
+fn decode_v2() {
+    let compression: ArcCompression = Arc::new(compression::NoCompression);
+    let no_dictionary = |id: u8| -> io::Result<Arc<Vec<u8>>> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no dictionary {}", id),
+        ))
+    };
+    let try_decode =
+        |chunk| decode(chunk, &[], None, &compression, &no_dictionary);
+    let data = b"some data".to_vec();
+    let header = Header {
+        compression: CompressionId::None,
+        encryption: EncryptionId::None,
+        dictionary: 0,
+        data_len: data.len() as u64,
+    };
+    let chunk = header.wrap(SGData::from_single(data.clone()));
+    assert_eq!(chunk.len(), HEADER_SIZE + data.len());
+    assert_eq!(chunk.as_parts().len(), 2);
+    assert!(has_header(&chunk));
+
+    let decoded = try_decode(chunk);
+    assert_eq!(decoded.unwrap().to_linear_vec(), data);
+
+    // encrypted, but no key
+    let header = Header {
+        encryption: EncryptionId::Curve25519,
+        ..header
+    };
+    let chunk = header.wrap(SGData::from_single(data.clone()));
+    assert!(try_decode(chunk).is_err());
+
+    // dictionary not found
+    let header = Header {
+        encryption: EncryptionId::None,
+        dictionary: 1,
+        ..header
+    };
+    let chunk = header.wrap(SGData::from_single(data.clone()));
+    assert!(try_decode(chunk).is_err());
+
+    let header = Header {
+        dictionary: 0,
+        data_len: 1,
+        ..header
+    };
+    let chunk = header.wrap(SGData::from_single(data));
+    assert!(try_decode(chunk).is_err());
+}
+
+#[test]
+fn decode_legacy() {
+    use encryption::NopDecrypter;
+
+    let compression: ArcCompression = Arc::new(compression::NoCompression);
+    let decrypter: ArcDecrypter = Arc::new(NopDecrypter);
+    let no_dictionary = |_: u8| -> io::Result<Arc<Vec<u8>>> { unreachable!() };
+    let try_decode = |chunk| {
+        decode(chunk, &[], Some(&decrypter), &compression, &no_dictionary)
+    };
+
+    let data = b"some data".to_vec();
+    let chunk = SGData::from_single(data.clone());
+    assert!(!has_header(&chunk));
+    assert_eq!(try_decode(chunk).unwrap().to_linear_vec(), data);
+
+    // starts with the magic, but isn't a valid header
+    let data = b"rdch and some more data".to_vec();
+    let chunk = SGData::from_many(vec![b"rd".to_vec(), data[2..].to_vec()]);
+    assert!(has_header(&chunk));
+    assert_eq!(try_decode(chunk).unwrap().to_linear_vec(), data);
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
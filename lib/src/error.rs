@@ -1,21 +1,147 @@
-use std::io;
-use std::result;
+//! Error type of the library
+//!
+//! Internally most of the code works with `io::Result`; an `Error` created
+//! deep inside is carried in an `io::Error` (see `From<Error> for io::Error`)
+//! and recovered when converted back, so callers can match on it.
+// {{{ use and mod
+use hex;
+use std::error::Error as StdError;
+use std::{fmt, io, result};
+// }}}
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    /// Passphrase (or keyfile) doesn't unlock the encryption key
+    WrongPassphrase,
+    NameNotFound(String),
+    NameExists(String),
+    /// Chunk not stored in any generation
+    ChunkMissing(Vec<u8>),
+    /// Chunk that couldn't be decrypted, decompressed or doesn't match
+    /// its digest
+    ChunkCorrupted { digest: Vec<u8>, reason: String },
+    /// Repository version not supported by this version of the library
+    UnsupportedVersion { version: u32, lowest: u32, current: u32 },
+    /// Repository locked by someone else and waiting for the lock
+    /// timed out; the message comes from the backend
+    Locked(String),
+    InvalidInput(String),
+    /// Backend (or other) I/O error
     Io(io::Error),
-    NotFound,
-    Exists,
-    InvalidPubKey,
-    DecryptionFailed
+}
+
+impl Error {
+    /// Closest `io::ErrorKind`; used when converting to `io::Error`
+    pub fn kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::WrongPassphrase => io::ErrorKind::InvalidData,
+            Error::NameNotFound(_) => io::ErrorKind::NotFound,
+            Error::NameExists(_) => io::ErrorKind::AlreadyExists,
+            Error::ChunkMissing(_) => io::ErrorKind::NotFound,
+            Error::ChunkCorrupted { .. } => io::ErrorKind::InvalidData,
+            Error::UnsupportedVersion { .. } => io::ErrorKind::InvalidData,
+            Error::Locked(_) => io::ErrorKind::WouldBlock,
+            Error::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            Error::Io(ref e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::WrongPassphrase => {
+                write!(f, "can't decrypt key using given passphrase")
+            }
+            Error::NameNotFound(ref name) => {
+                write!(f, "name not found: {}", name)
+            }
+            Error::NameExists(ref name) => {
+                write!(f, "name already exists: {}", name)
+            }
+            Error::ChunkMissing(ref digest) => {
+                write!(f, "chunk missing: {}", hex::encode(digest))
+            }
+            Error::ChunkCorrupted {
+                ref digest,
+                ref reason,
+            } => write!(
+                f,
+                "chunk {} corrupted: {}",
+                hex::encode(digest),
+                reason
+            ),
+            Error::UnsupportedVersion {
+                version,
+                lowest,
+                current,
+            } => {
+                if version > current {
+                    write!(
+                        f,
+                        "repo version {} higher than supported {}; update?",
+                        version, current
+                    )
+                } else {
+                    write!(
+                        f,
+                        "repo version {} lower than lowest supported {}; \
+                         restore using older version?",
+                        version, lowest
+                    )
+                }
+            }
+            Error::Locked(ref msg) => write!(f, "{}", msg),
+            Error::InvalidInput(ref msg) => write!(f, "{}", msg),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
+        if err.get_ref().map_or(false, |e| e.is::<Error>()) {
+            let inner = err.into_inner().expect("checked above");
+            return *inner.downcast::<Error>().expect("checked above");
+        }
         Error::Io(err)
     }
 }
 
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[test]
+fn io_error_round_trip() {
+    let err: io::Error = Error::NameNotFound("a".into()).into();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    match Error::from(err) {
+        Error::NameNotFound(ref name) if name == "a" => {}
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    let err = io::Error::new(io::ErrorKind::Other, "backend");
+    match Error::from(err) {
+        Error::Io(ref e) if e.kind() == io::ErrorKind::Other => {}
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
 
+// vim: foldmethod=marker foldmarker={{{,}}}
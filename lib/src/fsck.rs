@@ -0,0 +1,387 @@
+//! Repository-wide consistency check
+//!
+//! Unlike `Repo::verify`, that reads the data of given names, `fsck` lists
+//! all the chunks stored in every generation and compares them with
+//! the chunks reachable from all the names. Nothing is ever modified;
+//! in particular, chunks are not moved between generations on access.
+// {{{ use and mod
+use config::IndexFormat;
+use envelope;
+use hex;
+use serde_json;
+use sgdata::SGData;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use {DataAddress, DataType, DecryptHandle, Generation, Name, Repo};
+use {DigestRef, DIGEST_SIZE};
+// }}}
+
+/// Kind of a problem found by `Repo::fsck`
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsckProblemKind {
+    /// Stored chunk not reachable from any name
+    Unreferenced,
+    /// Chunk reachable from a name, but not stored
+    Missing,
+    /// Chunk stored only in generations older than the generation
+    /// of a name it's reachable from; it will be lost on the next `gc`
+    WrongGeneration,
+    /// Chunk that couldn't be decrypted or decompressed
+    Undecodable,
+    /// Chunk with content not matching its digest (its file name)
+    DigestMismatch,
+    /// Generation without `config.yml`; it's ignored by everything else
+    MissingGenerationConfig,
+    /// Name that couldn't be loaded
+    BrokenName,
+}
+
+/// A problem found by `Repo::fsck`
+#[derive(Serialize, Clone, Debug)]
+pub struct FsckProblem {
+    pub kind: FsckProblemKind,
+    /// Hex-encoded digest of the chunk (if about a chunk)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub message: String,
+}
+
+/// Results of `Repo::fsck`
+#[derive(Serialize, Default, Debug)]
+pub struct FsckResults {
+    pub generations: usize,
+    pub names: usize,
+    /// Stored chunks (a chunk stored in many generations counts once)
+    pub stored_chunks: usize,
+    pub reachable_chunks: usize,
+    pub problems: Vec<FsckProblem>,
+}
+
+impl FsckResults {
+    /// No problems found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Write the results as JSON
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *writer, self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        writeln!(writer)
+    }
+}
+
+/// A reachable chunk
+struct Reachable {
+    data_type: DataType,
+    /// The newest generation of a name it's reachable from
+    gen: Generation,
+}
+
+pub(crate) struct Fsck<'a> {
+    repo: &'a Repo,
+    dec: &'a DecryptHandle,
+    /// Generations where each chunk is stored
+    stored: HashMap<Vec<u8>, Vec<Generation>>,
+    reachable: HashMap<Vec<u8>, Reachable>,
+    results: FsckResults,
+}
+
+impl<'a> Fsck<'a> {
+    pub(crate) fn new(repo: &'a Repo, dec: &'a DecryptHandle) -> Self {
+        Fsck {
+            repo,
+            dec,
+            stored: HashMap::new(),
+            reachable: HashMap::new(),
+            results: FsckResults::default(),
+        }
+    }
+
+    fn problem(
+        &mut self,
+        kind: FsckProblemKind,
+        chunk: Option<&[u8]>,
+        gen: Option<Generation>,
+        name: Option<&str>,
+        message: String,
+    ) {
+        self.results.problems.push(FsckProblem {
+            kind,
+            chunk: chunk.map(hex::encode),
+            generation: gen.map(|gen| gen.to_string()),
+            name: name.map(|name| name.to_owned()),
+            message,
+        });
+    }
+
+    pub(crate) fn run(mut self) -> io::Result<FsckResults> {
+        let generations = self.list_generations()?;
+        self.results.generations = generations.len();
+
+        let store = self.repo.chunk_store(&self.repo.aio);
+        for &gen in &generations {
+            info!(self.repo.log, "listing chunks";
+                  "gen" => gen.to_string());
+            for digest in store.list(&gen.to_string())? {
+                self.stored.entry(digest?).or_insert_with(Vec::new).push(gen);
+            }
+        }
+        self.results.stored_chunks = self.stored.len();
+
+        for &gen in &generations {
+            for name_str in Name::list(gen, &self.repo.aio)? {
+                self.results.names += 1;
+                match Name::load_from(&name_str, gen, &self.repo.aio) {
+                    Ok(name) => {
+                        info!(self.repo.log, "walking";
+                              "name" => name_str.as_str());
+                        let address: DataAddress = name.into();
+                        self.walk(
+                            &address.digest.0,
+                            address.index_level,
+                            address.index_format,
+                            gen,
+                            &name_str,
+                        );
+                    }
+                    Err(e) => self.problem(
+                        FsckProblemKind::BrokenName,
+                        None,
+                        Some(gen),
+                        Some(&name_str),
+                        e.to_string(),
+                    ),
+                }
+            }
+        }
+        self.results.reachable_chunks = self.reachable.len();
+
+        self.check_reachable();
+        self.check_stored();
+
+        Ok(self.results)
+    }
+
+    /// Live generations; ones with `config.yml` missing are reported
+    fn list_generations(&mut self) -> io::Result<Vec<Generation>> {
+        let mut generations = vec![];
+        for path in self.repo.aio.list(PathBuf::new()).wait()? {
+            let gen = match path
+                .file_name()
+                .and_then(|file| file.to_str())
+                .and_then(|s| Generation::try_from(s).ok())
+            {
+                Some(gen) => gen,
+                None => continue,
+            };
+
+            if self.repo.aio.read_metadata(gen.config_path()).wait().is_ok() {
+                generations.push(gen);
+            } else {
+                self.problem(
+                    FsckProblemKind::MissingGenerationConfig,
+                    None,
+                    Some(gen),
+                    None,
+                    "generation config missing".into(),
+                );
+            }
+        }
+        generations.sort();
+        Ok(generations)
+    }
+
+    /// Mark everything reachable from a chunk of a name stored in `gen`
+    fn walk(
+        &mut self,
+        digest: &[u8],
+        index_level: u32,
+        index_format: IndexFormat,
+        gen: Generation,
+        name_str: &str,
+    ) {
+        let data_type = if index_level == 0 {
+            DataType::Data
+        } else {
+            DataType::Index
+        };
+
+        if let Some(reachable) = self.reachable.get_mut(digest) {
+            if reachable.gen >= gen {
+                return;
+            }
+            // already walked, but for an older generation
+            reachable.gen = gen;
+        } else {
+            self.reachable
+                .insert(digest.to_vec(), Reachable { data_type, gen });
+        }
+
+        if data_type == DataType::Data {
+            return;
+        }
+
+        let data = match self.read_index_chunk(digest) {
+            Ok(data) => data,
+            Err(e) => {
+                // `check_reachable`/`check_stored` will tell why
+                debug!(self.repo.log, "couldn't read index chunk";
+                       "name" => name_str, "err" => %e);
+                return;
+            }
+        };
+
+        let record_size = index_format.record_size();
+        for record in data.chunks(record_size) {
+            if record.len() != record_size {
+                self.problem(
+                    FsckProblemKind::DigestMismatch,
+                    Some(digest),
+                    None,
+                    Some(name_str),
+                    "index chunk of invalid size".into(),
+                );
+                break;
+            }
+            self.walk(
+                &record[..DIGEST_SIZE],
+                index_level - 1,
+                index_format,
+                gen,
+                name_str,
+            );
+        }
+    }
+
+    fn read_index_chunk(&self, digest: &[u8]) -> io::Result<Vec<u8>> {
+        let gen = self
+            .stored
+            .get(digest)
+            .and_then(|gens| gens.last())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "chunk missing")
+            })?;
+        let data = self
+            .repo
+            .chunk_store(&self.repo.aio)
+            .read(DigestRef(digest), &gen.to_string())?;
+        if self.repo.hasher.calculate_digest(&data) != digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "digest mismatch",
+            ));
+        }
+        Ok(data.to_linear_vec())
+    }
+
+    fn check_reachable(&mut self) {
+        let mut problems = vec![];
+        for (digest, reachable) in &self.reachable {
+            match self.stored.get(digest) {
+                None => problems.push((
+                    FsckProblemKind::Missing,
+                    digest.clone(),
+                    reachable.gen,
+                    "chunk missing".to_owned(),
+                )),
+                Some(gens) if gens.iter().all(|&gen| gen < reachable.gen) => {
+                    problems.push((
+                        FsckProblemKind::WrongGeneration,
+                        digest.clone(),
+                        reachable.gen,
+                        "chunk stored only in older generations".to_owned(),
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (kind, digest, gen, message) in problems {
+            self.problem(kind, Some(&digest), Some(gen), None, message);
+        }
+    }
+
+    /// Check content of every stored copy of every chunk
+    fn check_stored(&mut self) {
+        let store = self.repo.chunk_store(&self.repo.aio);
+        let mut problems = vec![];
+
+        for (digest, gens) in &self.stored {
+            let data_type =
+                self.reachable.get(digest).map(|reachable| reachable.data_type);
+            if data_type.is_none() {
+                problems.push((
+                    FsckProblemKind::Unreferenced,
+                    digest.clone(),
+                    gens[0],
+                    "chunk not reachable from any name".to_owned(),
+                ));
+            }
+
+            for &gen in gens {
+                let res = match store.read(DigestRef(digest), &gen.to_string())
+                {
+                    Ok(data) => self.check_content(digest, data_type, data),
+                    Err(e) => {
+                        Err((FsckProblemKind::Undecodable, e.to_string()))
+                    }
+                };
+                if let Err((kind, message)) = res {
+                    problems.push((kind, digest.clone(), gen, message));
+                }
+            }
+        }
+
+        for (kind, digest, gen, message) in problems {
+            self.problem(kind, Some(&digest), Some(gen), None, message);
+        }
+    }
+
+    /// Check that the chunk content matches its digest
+    ///
+    /// Unreferenced chunks (`data_type` unknown) can be of any type.
+    fn check_content(
+        &self,
+        digest: &[u8],
+        data_type: Option<DataType>,
+        data: SGData,
+    ) -> Result<(), (FsckProblemKind, String)> {
+        if data_type != Some(DataType::Data)
+            && self.repo.hasher.calculate_digest(&data) == digest
+        {
+            return Ok(());
+        }
+        if data_type == Some(DataType::Index) {
+            return Err((
+                FsckProblemKind::DigestMismatch,
+                "index chunk content doesn't match its digest".into(),
+            ));
+        }
+
+        let data = envelope::decode(
+            data,
+            digest,
+            Some(&self.dec.decrypter),
+            &self.repo.compression,
+            &|id| self.repo.dictionaries.get(id, Some(&self.dec.decrypter)),
+        ).map_err(|e| (FsckProblemKind::Undecodable, e.to_string()))?;
+
+        if self.repo.hasher.calculate_digest(&data) == digest {
+            Ok(())
+        } else {
+            Err((
+                FsckProblemKind::DigestMismatch,
+                "chunk content doesn't match its digest".into(),
+            ))
+        }
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
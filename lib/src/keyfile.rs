@@ -0,0 +1,172 @@
+//! Keyfile: unsealed secret key, for unlocking without a passphrase
+//!
+//! Anyone that has the keyfile can decrypt all the data in the repository,
+//! so it has to be protected just like the passphrase.
+//!
+//! A keyfile can also hold the key of a keyfile slot (see
+//! `Repo::add_keyfile_slot`) instead of the secret key itself. Such keyfile
+//! stops working once its slot is revoked.
+// {{{ use and mod
+use serde_yaml;
+use sodiumoxide::utils::memzero;
+use std::io::{self, Read, Write};
+use {as_base64, box_, from_base64, secretbox};
+// }}}
+
+/// Current version of the keyfile format
+pub const KEYFILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum Key {
+    #[serde(rename = "curve25519")]
+    Curve25519 {
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        pub_key: box_::PublicKey,
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        sec_key: Vec<u8>,
+    },
+    /// Key the secret key is sealed with in a keyfile slot
+    #[serde(rename = "key_slot")]
+    KeySlot {
+        name: String,
+        #[serde(
+            serialize_with = "as_base64",
+            deserialize_with = "from_base64"
+        )]
+        key: Vec<u8>,
+    },
+}
+
+/// Exported encryption key of a repository
+///
+/// See `Repo::export_keyfile` and `Repo::unlock_decrypt_with_keyfile`.
+#[derive(Serialize, Deserialize)]
+pub struct Keyfile {
+    version: u32,
+    key: Key,
+}
+
+impl Drop for Keyfile {
+    fn drop(&mut self) {
+        match self.key {
+            Key::Curve25519 {
+                ref mut sec_key, ..
+            } => memzero(sec_key),
+            Key::KeySlot { ref mut key, .. } => memzero(key),
+        }
+    }
+}
+
+impl Keyfile {
+    pub(crate) fn new_curve25519(
+        pub_key: box_::PublicKey,
+        sec_key: &box_::SecretKey,
+    ) -> Self {
+        Keyfile {
+            version: KEYFILE_VERSION,
+            key: Key::Curve25519 {
+                pub_key,
+                sec_key: sec_key.0.to_vec(),
+            },
+        }
+    }
+
+    pub(crate) fn new_key_slot(name: &str, key: &secretbox::Key) -> Self {
+        Keyfile {
+            version: KEYFILE_VERSION,
+            key: Key::KeySlot {
+                name: name.to_owned(),
+                key: key.0.to_vec(),
+            },
+        }
+    }
+
+    /// Name and key of the slot, if this is a keyfile of a keyfile slot
+    pub(crate) fn key_slot(
+        &self,
+    ) -> io::Result<Option<(&str, secretbox::Key)>> {
+        match self.key {
+            Key::Curve25519 { .. } => Ok(None),
+            Key::KeySlot { ref name, ref key } => {
+                let key = secretbox::Key::from_slice(key).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "key slot key in a wrong format",
+                    )
+                })?;
+                Ok(Some((name, key)))
+            }
+        }
+    }
+
+    pub(crate) fn curve25519_key(
+        &self,
+    ) -> io::Result<(box_::PublicKey, box_::SecretKey)> {
+        match self.key {
+            Key::Curve25519 {
+                ref pub_key,
+                ref sec_key,
+            } => {
+                let sec_key =
+                    box_::SecretKey::from_slice(sec_key).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "secret key in a wrong format",
+                        )
+                    })?;
+                // a corrupted (or hand-edited) keyfile would otherwise
+                // pass as a keyfile of the repository, but fail to
+                // decrypt anything
+                if sec_key.public_key() != *pub_key {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "keyfile secret key doesn't match its public key",
+                    ));
+                }
+                Ok((*pub_key, sec_key))
+            }
+            Key::KeySlot { ref name, .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("keyfile of key slot `{}`, not a secret key", name),
+            )),
+        }
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let keyfile: Keyfile =
+            serde_yaml::from_reader(reader).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("couldn't parse keyfile: {}", e.to_string()),
+                )
+            })?;
+
+        if keyfile.version > KEYFILE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "keyfile version {} higher than supported {}; update?",
+                    keyfile.version, KEYFILE_VERSION
+                ),
+            ));
+        }
+
+        Ok(keyfile)
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let keyfile_str =
+            serde_yaml::to_string(self).expect("yaml serialization failed");
+        writer.write_all(keyfile_str.as_bytes())?;
+        writer.flush()
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -0,0 +1,235 @@
+//! Local cache of chunks known to be stored in the repository
+//!
+//! Checking if a chunk exists requires a round trip to the backend
+//! for every chunk, which is very slow for remote backends. This cache
+//! keeps digests of chunks that are known to be stored, in a file per
+//! generation: `<cache-dir>/<gen>.chunks` (raw digests, one after another).
+//!
+//! The cache is only a hint: a digest found in an older generation still
+//! has to be moved to the current one, and if that fails, the backend is
+//! asked as usual. A digest is added only after its chunk was stored.
+// {{{ use and mod
+use slog::Logger;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use {Generation, DIGEST_SIZE};
+// }}}
+
+const CACHE_FILE_EXT: &'static str = "chunks";
+
+fn cache_file_path(dir: &Path, gen_str: &str) -> PathBuf {
+    dir.join(format!("{}.{}", gen_str, CACHE_FILE_EXT))
+}
+
+fn read_cache_file(path: &Path) -> io::Result<HashSet<Vec<u8>>> {
+    let mut data = vec![];
+    match fs::File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut data)?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    if data.len() % DIGEST_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("corrupted chunk cache file: {}", path.display()),
+        ));
+    }
+
+    Ok(data.chunks(DIGEST_SIZE).map(|d| d.to_vec()).collect())
+}
+
+/// Chunks known to be stored, per generation
+pub(crate) struct KnownChunks {
+    dir: PathBuf,
+    /// Known digests, starting from the newest generation
+    known: Vec<(String, HashSet<Vec<u8>>)>,
+    /// Digests stored in the current generation, not persisted yet
+    new: Mutex<Vec<Vec<u8>>>,
+    log: Logger,
+}
+
+impl KnownChunks {
+    /// Load the cache for `generations`
+    ///
+    /// Cache files of generations that don't exist anymore are removed.
+    pub(crate) fn load(
+        dir: &Path,
+        generations: &[Generation],
+        log: Logger,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let gen_strings: Vec<_> =
+            generations.iter().map(|gen| gen.to_string()).collect();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str())
+                != Some(CACHE_FILE_EXT)
+            {
+                continue;
+            }
+            let gen_str = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(s) if Generation::try_from(s).is_ok() => s.to_owned(),
+                _ => continue,
+            };
+            if !gen_strings.contains(&gen_str) {
+                debug!(log, "removing chunk cache of a gone generation";
+                       "gen" => gen_str.as_str());
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let mut known = vec![];
+        for gen_str in gen_strings.iter().rev() {
+            let path = cache_file_path(dir, gen_str);
+            let digests = match read_cache_file(&path) {
+                Ok(digests) => digests,
+                Err(e) => {
+                    warn!(log, "discarding chunk cache";
+                          "path" => %path.display(), "err" => %e);
+                    fs::remove_file(&path)?;
+                    HashSet::new()
+                }
+            };
+            known.push((gen_str.clone(), digests));
+        }
+
+        Ok(KnownChunks {
+            dir: dir.to_owned(),
+            known,
+            new: Mutex::new(vec![]),
+            log,
+        })
+    }
+
+    /// Find the newest generation the chunk is known to be stored in
+    pub(crate) fn lookup(&self, digest: &[u8]) -> Option<&str> {
+        self.known
+            .iter()
+            .find(|&&(_, ref digests)| digests.contains(digest))
+            .map(|&(ref gen_str, _)| gen_str.as_str())
+    }
+
+    /// Record a chunk stored in the current generation
+    ///
+    /// It's only persisted with `persist`, that must not be called
+    /// before the chunk is really stored.
+    pub(crate) fn insert(&self, digest: &[u8]) {
+        self.new.lock().unwrap().push(digest.to_vec());
+    }
+
+    /// Append recorded chunks to the cache of the current generation
+    pub(crate) fn persist(&self) -> io::Result<()> {
+        let gen_str = match self.known.first() {
+            Some(&(ref gen_str, _)) => gen_str,
+            None => return Ok(()),
+        };
+
+        let mut new = self.new.lock().unwrap();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let mut data = Vec::with_capacity(new.len() * DIGEST_SIZE);
+        for digest in new.iter() {
+            data.extend_from_slice(digest);
+        }
+
+        let path = cache_file_path(&self.dir, gen_str);
+        trace!(self.log, "persisting chunk cache";
+               "path" => %path.display(), "count" => new.len());
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        // append everything at once, to minimize interleaving with
+        // other `rdedup` instances
+        file.write_all(&data)?;
+        new.clear();
+        Ok(())
+    }
+
+    /// Replace the cache of a generation
+    pub(crate) fn rebuild<I>(
+        dir: &Path,
+        gen_str: &str,
+        digests: I,
+    ) -> io::Result<()>
+    where
+        I: Iterator<Item = io::Result<Vec<u8>>>,
+    {
+        fs::create_dir_all(dir)?;
+        let mut data = vec![];
+        for digest in digests {
+            let digest = digest?;
+            if digest.len() == DIGEST_SIZE {
+                data.extend_from_slice(&digest);
+            }
+        }
+
+        let path = cache_file_path(dir, gen_str);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &data)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Remove cache of a generation that was deleted
+    pub(crate) fn forget(dir: &Path, gen_str: &str) -> io::Result<()> {
+        match fs::remove_file(cache_file_path(dir, gen_str)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+#[test]
+fn known_chunks_persist_and_lookup() {
+    use rand::distributions::Alphanumeric;
+    use rand::{self, Rng};
+    use slog;
+
+    let dir = ::std::env::temp_dir().join("rdedup-tests").join(
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(20)
+            .collect::<String>(),
+    );
+    let log = Logger::root(slog::Discard, o!());
+    let gen1 = Generation::gen_first();
+    let gen2 = gen1.gen_next();
+    let a = vec![1u8; DIGEST_SIZE];
+    let b = vec![2u8; DIGEST_SIZE];
+
+    let known = KnownChunks::load(&dir, &[gen1], log.clone()).unwrap();
+    assert_eq!(known.lookup(&a), None);
+    known.insert(&a);
+    // not persisted yet
+    assert_eq!(known.lookup(&a), None);
+    known.persist().unwrap();
+
+    let known = KnownChunks::load(&dir, &[gen1, gen2], log.clone()).unwrap();
+    assert_eq!(known.lookup(&a), Some(gen1.to_string().as_str()));
+    known.insert(&b);
+    known.persist().unwrap();
+
+    let known = KnownChunks::load(&dir, &[gen1, gen2], log.clone()).unwrap();
+    assert_eq!(known.lookup(&b), Some(gen2.to_string().as_str()));
+
+    // gen1 gone
+    let known = KnownChunks::load(&dir, &[gen2], log.clone()).unwrap();
+    assert_eq!(known.lookup(&a), None);
+    assert!(!cache_file_path(&dir, &gen1.to_string()).exists());
+
+    KnownChunks::forget(&dir, &gen2.to_string()).unwrap();
+    let known = KnownChunks::load(&dir, &[gen2], log).unwrap();
+    assert_eq!(known.lookup(&b), None);
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
@@ -12,6 +12,10 @@ extern crate fs2;
 extern crate hex;
 extern crate hyper;
 extern crate hyper_native_tls;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(unix)]
+extern crate libc;
 extern crate num_cpus;
 extern crate owning_ref;
 extern crate rand;
@@ -27,6 +31,7 @@ extern crate sha2;
 extern crate slog;
 extern crate slog_perf;
 extern crate sodiumoxide;
+extern crate ssh2;
 extern crate url;
 extern crate walkdir;
 
@@ -38,27 +43,39 @@ extern crate flate2;
 extern crate lzma;
 #[cfg(feature = "with-zstd")]
 extern crate zstd;
+#[cfg(feature = "with-lz4")]
+extern crate lz4;
+#[cfg(feature = "with-brotli")]
+extern crate brotli;
 // }}}
 
 // {{{ use and mod
-use sgdata::SGData;
 use slog::{FnValue, Level, Logger};
 use slog_perf::TimeReporter;
 use sodiumoxide::crypto::{self, box_, secretbox};
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
 use std::io;
-use std::io::{Error, Read, Result, Write};
+use std::io::{Read, Write};
 use std::iter::Iterator;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc};
+use std::time;
 use url::Url;
 
+pub mod error;
+pub use error::{Error, Result};
+
 mod iterators;
 
 mod config;
 
 mod aio;
 use aio::*;
+pub use aio::{Backend, BackendThread, Lock, Metadata};
+pub use keyfile::Keyfile;
+pub use sgdata::SGData;
 
 mod chunking;
 mod hashing;
@@ -66,6 +83,22 @@ mod hashing;
 mod chunk_processor;
 use chunk_processor::*;
 
+mod checkpoint;
+use checkpoint::Checkpoint;
+
+mod chunk_store;
+use chunk_store::{
+    ArcChunkStore, DryRunChunkStore, FileChunkStore, PackChunkStore,
+};
+
+mod fsck;
+pub use fsck::{FsckProblem, FsckProblemKind, FsckResults};
+
+mod known_chunks;
+use known_chunks::KnownChunks;
+
+mod keyfile;
+
 mod sorting_recv;
 use sorting_recv::SortingIterator;
 
@@ -75,6 +108,17 @@ use encryption::EncryptionEngine;
 mod compression;
 use compression::ArcCompression;
 
+mod envelope;
+
+mod dictionary;
+use dictionary::Dictionaries;
+
+mod prune;
+pub use prune::{PruneDecision, PruneOptions};
+
+mod progress;
+pub use progress::{NoProgress, ProgressObserver};
+
 mod pwhash;
 
 pub mod settings;
@@ -83,12 +127,14 @@ mod util;
 use self::util::*;
 
 mod reading;
+pub use self::reading::{ChunkCache, NameReader};
 use self::reading::*;
 
 mod generation;
 use self::generation::*;
 
 mod name;
+pub use self::name::NameMetadata;
 use self::name::*;
 
 mod misc;
@@ -133,6 +179,91 @@ pub struct DuResults {
     pub bytes: u64,
 }
 
+/// Results of `Repo::write_dry_run`
+#[derive(Clone, Debug)]
+pub struct DryRunResults {
+    /// Data chunks, including duplicates
+    pub chunks: u64,
+    /// Length of the data
+    pub bytes: u64,
+    /// Chunks (data and index) not stored in the repository yet
+    pub new_chunks: u64,
+    /// Size of the new chunks, after compression
+    pub new_bytes: u64,
+    /// New data chunks that would be stored uncompressed, as they don't
+    /// compress well (see `settings::Repo::set_adaptive_compression`)
+    pub uncompressed_chunks: u64,
+}
+
+impl DryRunResults {
+    /// How many times the data is bigger than what would be stored
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.new_bytes == 0 {
+            return std::f64::INFINITY;
+        }
+        self.bytes as f64 / self.new_bytes as f64
+    }
+}
+
+/// Options of `Repo::write_with`
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    hostname: Option<String>,
+    tags: BTreeMap<String, String>,
+    checkpoint_interval: Option<u64>,
+    resume: bool,
+}
+
+impl WriteOptions {
+    /// Record the hostname of this machine and no tags, don't write
+    /// checkpoints
+    pub fn new() -> Self {
+        WriteOptions {
+            hostname: util::hostname(),
+            tags: BTreeMap::new(),
+            checkpoint_interval: None,
+            resume: false,
+        }
+    }
+
+    /// Write a checkpoint every `interval` bytes of data, so an interrupted
+    /// write can be resumed (`None` to not write any)
+    pub fn set_checkpoint_interval(&mut self, interval: Option<u64>) {
+        self.checkpoint_interval = interval;
+    }
+
+    /// Resume an interrupted write of the same name from its checkpoints
+    ///
+    /// The same data has to be written again, from the start. Chunks
+    /// stored before the last checkpoint are not uploaded again.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// Set the hostname to record (`None` to not record any)
+    pub fn set_hostname(&mut self, hostname: Option<String>) {
+        self.hostname = hostname;
+    }
+
+    /// Record a tag, replacing any previous value of `key`
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(key.to_owned(), value.to_owned());
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions::new()
+    }
+}
+
+/// Data chunks produced by the chunker during a write
+#[derive(Default)]
+struct ChunkerStats {
+    chunks: AtomicU64,
+    bytes: AtomicU64,
+}
+
 /// A decryption handle
 ///
 /// Used as an argument to operations that decrypt data.
@@ -145,13 +276,15 @@ pub struct DecryptHandle {
 /// Used as an argument to operations that encrypt data.
 pub struct EncryptHandle {
     encrypter: ArcEncrypter,
+    /// Compression of data chunks, and the id of its dictionary (0 if none)
+    compression: (u8, ArcCompression),
 }
 
 // {{{ Repo
 /// Rdedup repository handle
 #[derive(Clone)]
 pub struct Repo {
-    url: Url,
+    backend: Arc<dyn Backend>,
     config: config::Repo,
 
     compression: compression::ArcCompression,
@@ -161,13 +294,22 @@ pub struct Repo {
     log: slog::Logger,
 
     aio: aio::AsyncIO,
+
+    /// Local cache of chunks known to be stored (see `set_chunk_cache_dir`)
+    chunk_cache_dir: Option<PathBuf>,
+
+    /// Give up waiting for the repository lock (see `set_lock_timeout`)
+    lock_timeout: Option<u64>,
+
+    /// Observer of long running operations (see `set_progress_observer`)
+    progress: Arc<dyn ProgressObserver>,
+
+    /// Trained compression dictionaries read so far
+    dictionaries: Arc<Dictionaries>,
 }
 
 impl Repo {
-    pub fn unlock_decrypt(
-        &self,
-        pass: PassphraseFn,
-    ) -> io::Result<DecryptHandle> {
+    pub fn unlock_decrypt(&self, pass: PassphraseFn) -> Result<DecryptHandle> {
         info!(self.log, "Opening read handle");
         let decrypter =
             self.config.encryption.decrypter(pass, &self.config.pwhash)?;
@@ -177,27 +319,84 @@ impl Repo {
         })
     }
 
-    pub fn unlock_encrypt(
-        &self,
-        pass: PassphraseFn,
-    ) -> io::Result<EncryptHandle> {
+    /// Unlock writing
+    ///
+    /// Encrypting needs just the public key, but if data is compressed
+    /// with an encrypted dictionary (see `train_dictionary`), `pass` is
+    /// needed to decrypt it.
+    pub fn unlock_encrypt(&self, pass: PassphraseFn) -> Result<EncryptHandle> {
         info!(self.log, "Opening write handle");
         let encrypter =
             self.config.encryption.encrypter(pass, &self.config.pwhash)?;
+        let compression = self.data_compression(Some(pass))?;
 
         Ok(EncryptHandle {
             encrypter,
+            compression,
         })
     }
 
+    /// Compression of data chunks with the current dictionary, and its id
+    /// (0 if there's no dictionary)
+    ///
+    /// `pass` is needed only if the dictionary is encrypted; without it
+    /// that fails with `PermissionDenied`.
+    fn data_compression(
+        &self,
+        pass: Option<PassphraseFn>,
+    ) -> Result<(u8, ArcCompression)> {
+        let id = match self.config.compression.dictionary() {
+            Some(id) => id,
+            None => return Ok((0, Arc::clone(&self.compression))),
+        };
+        let dictionary = match (self.dictionaries.get(id, None), pass) {
+            (Err(ref e), Some(pass))
+                if e.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                let decrypter = self
+                    .config
+                    .encryption
+                    .decrypter(pass, &self.config.pwhash)?;
+                self.dictionaries.get(id, Some(&decrypter))?
+            }
+            (res, _) => res?,
+        };
+        let compression = self
+            .config
+            .compression
+            .to_engine_with_dictionary(dictionary);
+        Ok((id, compression))
+    }
+
+    /// Like `unlock_decrypt`, but using a keyfile instead of a passphrase
+    ///
+    /// See `export_keyfile`.
+    pub fn unlock_decrypt_with_keyfile(
+        &self,
+        keyfile: &Keyfile,
+    ) -> Result<DecryptHandle> {
+        info!(self.log, "Opening read handle using keyfile");
+        let decrypter =
+            self.config.encryption.decrypter_from_keyfile(keyfile)?;
+
+        Ok(DecryptHandle {
+            decrypter,
+        })
+    }
+
+    /// Unlock the encryption key and export it to a keyfile
+    pub fn export_keyfile(&self, pass: PassphraseFn) -> Result<Keyfile> {
+        Ok(self.config.encryption.export_keyfile(pass, &self.config.pwhash)?)
+    }
+
     fn ensure_repo_empty_or_new(aio: &AsyncIO) -> Result<()> {
         let list = aio.list(PathBuf::from(".")).wait();
 
         if !list.is_err() && !list.unwrap().is_empty() {
-            return Err(Error::new(
+            return Err(io::Error::new(
                 io::ErrorKind::AlreadyExists,
                 "repo dir must not exist or be empty to be used",
-            ));
+            ).into());
         }
         Ok(())
     }
@@ -209,6 +408,20 @@ impl Repo {
         settings: settings::Repo,
         log: L,
     ) -> Result<Repo>
+    where
+        L: Into<Option<Logger>>,
+    {
+        let backend = aio::backend_from_url(url)?;
+        Repo::init_with_backend(backend, passphrase, settings, log)
+    }
+
+    /// Create new rdedup repository using a custom backend
+    pub fn init_with_backend<L>(
+        backend: Arc<dyn Backend>,
+        passphrase: PassphraseFn,
+        settings: settings::Repo,
+        log: L,
+    ) -> Result<Repo>
     where
         L: Into<Option<Logger>>,
     {
@@ -216,8 +429,17 @@ impl Repo {
             .into()
             .unwrap_or_else(|| Logger::root(slog::Discard, o!()));
 
-        let backend = aio::backend_from_url(url)?;
-        let aio = aio::AsyncIO::new(backend, log.clone())?;
+        let aio = aio::AsyncIO::new(backend.clone(), log.clone())?;
+
+        if let config::Layout::Packs { .. } = settings.layout.0 {
+            if !backend.ranged_reads() {
+                return Err(Error::InvalidInput(
+                    "backend can't read parts of files, which pack layout \
+                     needs"
+                        .into(),
+                ));
+            }
+        }
 
         Repo::ensure_repo_empty_or_new(&aio)?;
         let config = config::Repo::new_from_settings(passphrase, settings)?;
@@ -225,18 +447,51 @@ impl Repo {
 
         let compression = config.compression.to_engine();
         let hasher = config.hashing.to_hasher();
+        let dictionaries =
+            Arc::new(Dictionaries::new(aio.clone(), Arc::clone(&hasher)));
 
         Ok(Repo {
-            url: url.clone(),
+            backend,
             config,
             compression,
             hasher,
             log,
             aio,
+            chunk_cache_dir: None,
+            lock_timeout: None,
+            progress: Arc::new(NoProgress),
+            dictionaries,
         })
     }
 
     pub fn open<L>(url: &Url, log: L) -> Result<Repo>
+    where
+        L: Into<Option<Logger>>,
+    {
+        let backend = aio::backend_from_url(url)?;
+        Repo::open_with_backend(backend, log)
+    }
+
+    /// Remove an in-memory (`mem:<name>`) repository
+    ///
+    /// In-memory repositories otherwise live until the process exits. The
+    /// data is freed once all `Repo`s using it are dropped. Returns `false`
+    /// if there was no such repository.
+    pub fn remove_mem(url: &Url) -> Result<bool> {
+        if url.scheme() != "mem" {
+            return Err(Error::InvalidInput(format!(
+                "not an in-memory repository url: {}",
+                url
+            )));
+        }
+        Ok(aio::Mem::remove(url.path()))
+    }
+
+    /// Open existing rdedup repository using a custom backend
+    pub fn open_with_backend<L>(
+        backend: Arc<dyn Backend>,
+        log: L,
+    ) -> Result<Repo>
     where
         L: Into<Option<Logger>>,
     {
@@ -244,47 +499,376 @@ impl Repo {
             .into()
             .unwrap_or_else(|| Logger::root(slog::Discard, o!()));
 
-        let backend = aio::backend_from_url(url)?;
-        let aio = aio::AsyncIO::new(backend, log.clone())?;
+        let aio = aio::AsyncIO::new(backend.clone(), log.clone())?;
 
         let config = config::Repo::read(&aio)?;
 
         let compression = config.compression.to_engine();
         let hasher = config.hashing.to_hasher();
+        let dictionaries =
+            Arc::new(Dictionaries::new(aio.clone(), Arc::clone(&hasher)));
         Ok(Repo {
-            url: url.clone(),
+            backend,
             config,
             compression,
             hasher,
             log,
             aio,
+            chunk_cache_dir: None,
+            lock_timeout: None,
+            progress: Arc::new(NoProgress),
+            dictionaries,
         })
     }
 
+    /// Use a local cache of chunks known to be stored in the repository
+    ///
+    /// This avoids asking the backend about every chunk when writing, which
+    /// makes a big difference for remote backends. `dir` must be specific
+    /// to this repository (eg. derived from its url), as it's not possible
+    /// to tell apart copies of the same repository.
+    pub fn set_chunk_cache_dir(&mut self, dir: Option<PathBuf>) {
+        self.chunk_cache_dir = dir;
+    }
+
+    /// Give up waiting for a repository locked by someone else after
+    /// `timeout_secs`
+    ///
+    /// By default, operations wait for the lock as long as it takes. With a
+    /// timeout set, they fail with `Error::Locked` instead.
+    pub fn set_lock_timeout(&mut self, timeout_secs: Option<u64>) {
+        self.lock_timeout = timeout_secs;
+    }
+
+    /// Report progress of `write`, `read`, `verify` and `gc` to `progress`
+    pub fn set_progress_observer(
+        &mut self,
+        progress: Arc<dyn ProgressObserver>,
+    ) {
+        self.progress = progress;
+    }
+
+    /// Rebuild the local chunk cache from the chunks in the backend
+    pub fn rebuild_chunk_cache(&self) -> Result<()> {
+        let dir = self.chunk_cache_dir.as_ref().ok_or_else(|| {
+            Error::InvalidInput("chunk cache not enabled".into())
+        })?;
+        let _lock = self.lock_shared()?;
+
+        let generations = self.read_generations()?;
+        // remove cache of generations that are gone
+        KnownChunks::load(dir, &generations, self.log.clone())?;
+
+        let store = self.chunk_store(&self.aio);
+        for gen in &generations {
+            let gen_str = gen.to_string();
+            info!(self.log, "Rebuilding chunk cache"; "gen" => gen_str.as_str());
+            KnownChunks::rebuild(dir, &gen_str, store.list(&gen_str)?)?;
+        }
+        Ok(())
+    }
+
     /// Change the passphrase
     pub fn change_passphrase(
         &mut self,
         old_p: PassphraseFn,
         new_p: PassphraseFn,
     ) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
+        self.change_passphrase_inner(old_p, new_p, None)
+    }
+
+    /// Change the passphrase, switching to a different key derivation
+    ///
+    /// Only the key slot unlocked with `old_p` is migrated to `pwhash`
+    /// (eg. `settings::PWHash::Argon2id`).
+    pub fn change_passphrase_with_pwhash(
+        &mut self,
+        old_p: PassphraseFn,
+        new_p: PassphraseFn,
+        pwhash: settings::PWHash,
+    ) -> Result<()> {
+        self.change_passphrase_inner(
+            old_p,
+            new_p,
+            Some(config::PWHash::from_settings(pwhash)),
+        )
+    }
+
+    fn change_passphrase_inner(
+        &mut self,
+        old_p: PassphraseFn,
+        new_p: PassphraseFn,
+        new_pwhash: Option<config::PWHash>,
+    ) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
 
         if self.config.version == 0 {
-            Err(Error::new(
-                io::ErrorKind::NotFound,
-                "rdedup v0 config format not supported",
-            ))
+            Err(Error::UnsupportedVersion {
+                version: self.config.version,
+                lowest: config::REPO_VERSION_LOWEST,
+                current: config::REPO_VERSION_CURRENT,
+            })
         } else {
             self.config.encryption.change_passphrase(
                 old_p,
                 new_p,
-                &self.config.pwhash,
+                &mut self.config.pwhash,
+                new_pwhash,
             )?;
             self.config.write(&self.aio)?;
             Ok(())
         }
     }
 
+    /// Add a named key slot
+    ///
+    /// The encryption key is unlocked with `pass` (of any existing slot)
+    /// and sealed again with `new_pass`. Data stored in the repository
+    /// is not touched.
+    pub fn add_key_slot(
+        &mut self,
+        name: &str,
+        pass: PassphraseFn,
+        new_pass: PassphraseFn,
+    ) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+
+        self.config.encryption.add_key_slot(
+            name,
+            pass,
+            new_pass,
+            &self.config.pwhash,
+        )?;
+        self.config.write(&self.aio)
+    }
+
+    /// Add a named keyfile slot
+    ///
+    /// Like `add_key_slot`, but the encryption key is sealed with a new
+    /// random key, returned in a keyfile to use with
+    /// `unlock_decrypt_with_keyfile`. Unlike a keyfile from
+    /// `export_keyfile`, it stops working once the slot is revoked.
+    pub fn add_keyfile_slot(
+        &mut self,
+        name: &str,
+        pass: PassphraseFn,
+    ) -> Result<Keyfile> {
+        let _lock = self.lock_exclusive()?;
+
+        let keyfile = self.config.encryption.add_keyfile_slot(
+            name,
+            pass,
+            &self.config.pwhash,
+        )?;
+        self.config.write(&self.aio)?;
+        Ok(keyfile)
+    }
+
+    /// Names of key slots, starting with the default one (if not revoked)
+    pub fn list_key_slots(&self) -> Vec<String> {
+        self.config.encryption.key_slots()
+    }
+
+    /// Remove a named key slot
+    ///
+    /// Any slot can be removed, including the default one, as long as at
+    /// least one passphrase slot remains.
+    ///
+    /// Note: the encryption key itself stays the same, so anyone that
+    /// already unlocked it (and kept a copy) is still able to decrypt data.
+    pub fn revoke_key_slot(&mut self, name: &str) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+
+        self.config.encryption.revoke_key_slot(name)?;
+        self.config.write(&self.aio)
+    }
+
+    /// Change the level data is compressed with from now on
+    ///
+    /// `level` is as defined by the compression algorithm (eg. 1-22 for
+    /// zstd, or negative for its fast levels); `None` means its default
+    /// level. Data already stored is not
+    /// recompressed. `EncryptHandle`s unlocked before keep using the
+    /// previous level, just like they keep using the previous dictionary.
+    pub fn set_compression_level(&mut self, level: Option<i32>) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+
+        self.config.compression.set_level(level)?;
+        self.compression = self.config.compression.to_engine();
+        self.config.write(&self.aio)
+    }
+
+    /// Compression level new data is compressed with, as defined by the
+    /// compression algorithm; `None` if the data isn't compressed
+    pub fn compression_level(&self) -> Option<i32> {
+        self.config.compression.level()
+    }
+
+    /// Prefix data chunks written from now on with a header describing
+    /// their encoding
+    ///
+    /// For repositories created with
+    /// `settings::Repo::use_legacy_chunk_format`. Chunks already stored
+    /// are kept as they are, and still readable. Older versions of `rdedup`
+    /// can't use the repository afterwards. Does nothing if the repository
+    /// already uses chunk headers.
+    pub fn upgrade_chunk_format(&mut self) -> Result<()> {
+        let _lock = self.lock_exclusive()?;
+
+        if self.config.chunk_format == config::ChunkFormat::V2 {
+            return Ok(());
+        }
+        self.config.chunk_format = config::ChunkFormat::V2;
+        self.config.version =
+            cmp::max(self.config.version, config::REPO_VERSION_CHUNK_HEADER);
+        self.config.write(&self.aio)
+    }
+
+    /// Train a zstd dictionary on stored data chunks
+    ///
+    /// Data chunks are sampled (about `sample_size` bytes, newest
+    /// generation first) and a dictionary of at most `max_size` bytes is
+    /// trained on them. It's stored under a new id, and data written from
+    /// now on is compressed with it; chunks compressed with earlier
+    /// dictionaries can still be read. Returns the id of the dictionary.
+    ///
+    /// The dictionary contains fragments of the sampled data, so it's
+    /// encrypted like the data, and writing then needs the passphrase (see
+    /// `unlock_encrypt`). With `unencrypted` it's stored as it is, so
+    /// writing needs just the public key and `write_dry_run` can use it,
+    /// but anyone with read access to the repository can read these
+    /// fragments.
+    ///
+    /// Requires zstd compression and chunk headers. `EncryptHandle`s
+    /// unlocked before keep using the previous dictionary.
+    #[cfg(feature = "with-zstd")]
+    pub fn train_dictionary(
+        &mut self,
+        dec: &DecryptHandle,
+        max_size: usize,
+        sample_size: u64,
+        unencrypted: bool,
+    ) -> Result<u8> {
+        let zstd = match self.config.compression {
+            config::Compression::Zstd(zstd) => zstd,
+            #[allow(unreachable_patterns)]
+            _ => {
+                return Err(Error::InvalidInput(
+                    "dictionaries require zstd compression".into(),
+                ))
+            }
+        };
+        if self.config.chunk_format == config::ChunkFormat::V1 {
+            return Err(Error::InvalidInput(
+                "dictionaries require chunk headers".into(),
+            ));
+        }
+
+        let samples = {
+            let _lock = self.lock_shared()?;
+            self.dictionary_samples(dec, sample_size)?
+        };
+        if samples.is_empty() {
+            return Err(Error::InvalidInput(
+                "no data chunks to train the dictionary on".into(),
+            ));
+        }
+        info!(self.log, "Training dictionary"; "samples" => samples.len());
+        let dict = dictionary::train(&samples, max_size)?;
+
+        let _lock = self.lock_exclusive()?;
+        let id = match dictionary::list(&self.aio)?.last() {
+            Some(&255) => {
+                return Err(Error::InvalidInput(
+                    "no dictionary ids left".into(),
+                ))
+            }
+            Some(&id) => id + 1,
+            None => 1,
+        };
+        info!(self.log, "Storing dictionary";
+              "id" => id, "size" => dict.len(), "unencrypted" => unencrypted);
+        let encryption = self.config.encryption.id();
+        let (encrypter, encryption): (ArcEncrypter, _) = if unencrypted {
+            if encryption != envelope::EncryptionId::None {
+                warn!(self.log, "Storing dictionary unencrypted; fragments \
+                                 of the stored data it contains are readable \
+                                 to anyone with access to the repository";
+                      "id" => id);
+            }
+            (
+                Arc::new(encryption::NopEncrypter),
+                envelope::EncryptionId::None,
+            )
+        } else {
+            // encrypting needs just the public key, no passphrase
+            let encrypter = self.config.encryption.encrypter(
+                &|| Err(io::Error::new(io::ErrorKind::Other, "not needed")),
+                &self.config.pwhash,
+            )?;
+            (encrypter, encryption)
+        };
+        dictionary::write(
+            &self.aio,
+            id,
+            dict,
+            &self.hasher,
+            &encrypter,
+            encryption,
+        )?;
+        self.config.compression =
+            config::Compression::Zstd(zstd.with_dictionary(Some(id)));
+        self.config.write(&self.aio)?;
+        Ok(id)
+    }
+
+    /// Decoded data chunks to train a dictionary on, about `sample_size`
+    /// bytes
+    #[cfg(feature = "with-zstd")]
+    fn dictionary_samples(
+        &self,
+        dec: &DecryptHandle,
+        sample_size: u64,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let store = self.chunk_store(&self.aio);
+        let mut samples = vec![];
+        let mut len = 0;
+
+        for gen in self.read_generations()?.iter().rev() {
+            let gen_str = gen.to_string();
+            for digest in store.list(&gen_str)? {
+                if len >= sample_size {
+                    return Ok(samples);
+                }
+                let digest = digest?;
+                let data = store.read(DigestRef(&digest), &gen_str)?;
+                // index chunks have no header, so they are skipped here,
+                // together with data chunks stored before the upgrade to
+                // chunk headers
+                if !envelope::has_header(&data) {
+                    continue;
+                }
+                let data = match envelope::decode_with_header(
+                    data,
+                    &digest,
+                    Some(&dec.decrypter),
+                    &|id| self.dictionaries.get(id, Some(&dec.decrypter)),
+                ) {
+                    Ok(data) => data.to_linear_vec(),
+                    Err(e) => {
+                        warn!(self.log, "Skipping undecodable chunk";
+                              "digest" => FnValue(|_| hex::encode(&digest)),
+                              "gen" => gen_str.as_str(), "err" => %e);
+                        continue;
+                    }
+                };
+                len += data.len() as u64;
+                samples.push(data);
+            }
+        }
+        Ok(samples)
+    }
+
     /// Write a chunk of data to the repo.
     fn chunk_and_write_data_thread<'a>(
         &'a self,
@@ -292,6 +876,8 @@ impl Repo {
         process_tx: crossbeam_channel::Sender<chunk_processor::Message>,
         aio: aio::AsyncIO,
         data_type: DataType,
+        chunker_stats: &'a ChunkerStats,
+        checkpoint: Option<&'a mut Checkpoint>,
     ) -> io::Result<DataAddress> {
         // Note: This channel is intentionally unbounded
         // The processing loop runs in sort of a loop (actually more of a
@@ -304,6 +890,7 @@ impl Repo {
         // the whole point of keeping index) so this channel does not have
         // to be bounded.
         let (digests_tx, digests_rx) = mpsc::channel();
+        let index_format = self.config.index_format;
 
         crossbeam::scope(move |scope| {
             let mut timer = slog_perf::TimeReporter::new_with_level(
@@ -322,10 +909,20 @@ impl Repo {
                         Level::Debug,
                     );
 
-                    let chunker = chunking::Chunker::new(
-                        input_data_iter.into_iter(),
-                        self.config.chunking.to_engine(),
-                    );
+                    let index_v2 = data_type == DataType::Index
+                        && index_format == config::IndexFormat::V2;
+                    let chunker: Box<dyn Iterator<Item = SGData> + 'a> =
+                        if index_v2 {
+                            Box::new(chunking::IndexChunker::new(
+                                input_data_iter.into_iter(),
+                                self.config.chunking.chunk_bits(),
+                            ))
+                        } else {
+                            Box::new(chunking::Chunker::new(
+                                input_data_iter.into_iter(),
+                                self.config.chunking.to_engine(),
+                            ))
+                        };
 
                     let mut data = util::EnumerateU64::new(chunker);
 
@@ -334,8 +931,23 @@ impl Repo {
                     {
                         timer.start("tx");
                         let (i, sg) = i_sg;
+                        let data_len = if index_v2 {
+                            misc::index_v2_data_len(&sg.to_linear_vec())
+                                .expect("invalid index chunk")
+                        } else {
+                            sg.len() as u64
+                        };
+                        if data_type == DataType::Data {
+                            chunker_stats
+                                .chunks
+                                .fetch_add(1, Ordering::Relaxed);
+                            chunker_stats
+                                .bytes
+                                .fetch_add(data_len, Ordering::Relaxed);
+                        }
                         process_tx.send(chunk_processor::Message {
                             data: (i as u64, sg),
+                            data_len,
                             response_tx: digests_tx.clone(),
                             data_type,
                         }).expect("chunk process tx channel closed")
@@ -348,23 +960,29 @@ impl Repo {
             let mut digests_rx = SortingIterator::new(digests_rx.into_iter());
 
             timer.start("digest-rx");
-            let first_digest =
+            let (first_digest, first_len) =
                 digests_rx.next().expect("At least one index digest");
 
             if let Some(second_digest) =
                 timer.start_with("digest-rx", || digests_rx.next())
             {
-                let mut two_first = vec![first_digest, second_digest];
+                let mut two_first =
+                    vec![(first_digest, first_len), second_digest];
+                let mut checkpoint = checkpoint;
                 let mut address = self.chunk_and_write_data_thread(
-                    Box::new(
-                        two_first
-                            .drain(..)
-                            .chain(digests_rx)
-                            .map(|digest| digest.0),
-                    ),
+                    Box::new(two_first.drain(..).chain(digests_rx).map(
+                        move |(digest, data_len)| {
+                            if let Some(ref mut checkpoint) = checkpoint {
+                                checkpoint.record(&digest.0, data_len);
+                            }
+                            misc::index_record(digest, data_len, index_format)
+                        },
+                    )),
                     process_tx,
                     aio.clone(),
                     DataType::Index,
+                    chunker_stats,
+                    None,
                 )?;
 
                 address.index_level += 1;
@@ -373,11 +991,110 @@ impl Repo {
                 Ok(DataAddress {
                     index_level: 0,
                     digest: first_digest,
+                    index_format,
                 })
             }
         }).expect("chunker thread failed")
     }
 
+    fn load_known_chunks(
+        &self,
+        generations: &[Generation],
+    ) -> io::Result<Option<Arc<KnownChunks>>> {
+        Ok(match self.chunk_cache_dir {
+            Some(ref dir) => Some(Arc::new(KnownChunks::load(
+                dir,
+                generations,
+                self.log.clone(),
+            )?)),
+            None => None,
+        })
+    }
+
+    /// Chunk data from `reader` and pass the chunks to `store`
+    ///
+    /// The pipeline shared by `write_with` and `write_dry_run`.
+    fn write_data<R>(
+        &self,
+        reader: R,
+        aio: aio::AsyncIO,
+        store: &ArcChunkStore,
+        known_chunks: &Option<Arc<KnownChunks>>,
+        encrypter: &ArcEncrypter,
+        compression: &(u8, ArcCompression),
+        generations: &[Generation],
+        resumed: &Arc<HashSet<Vec<u8>>>,
+        chunker_stats: &ChunkerStats,
+        uncompressed: &Arc<AtomicU64>,
+        checkpoint: Option<&mut Checkpoint>,
+    ) -> io::Result<DataAddress>
+    where
+        R: Read + Send,
+    {
+        let (dictionary, ref compression) = *compression;
+
+        let num_threads = num_cpus::get();
+        let (chunker_tx, chunker_rx) =
+            mpsc::sync_channel(self.write_cpu_thread_num());
+
+        // mpmc queue used  as spmc fan-out
+        let (process_tx, process_rx) = crossbeam_channel::bounded(num_threads);
+
+        let data_address = crossbeam::scope(|scope| {
+            scope.spawn(move |_| self.input_reader_thread(reader, chunker_tx));
+
+            for _ in 0..num_threads {
+                let process_rx = process_rx.clone();
+                let encrypter = Arc::clone(encrypter);
+                let compression = Arc::clone(compression);
+                let hasher = Arc::clone(&self.hasher);
+                let generations = generations.to_vec();
+                let store = store.clone();
+                let known_chunks = known_chunks.clone();
+                let resumed = Arc::clone(resumed);
+                let uncompressed = Arc::clone(uncompressed);
+                scope.spawn(move |_| {
+                    let processor = ChunkProcessor::new(
+                        self,
+                        process_rx,
+                        store,
+                        known_chunks,
+                        resumed,
+                        encrypter,
+                        compression,
+                        dictionary,
+                        hasher,
+                        generations,
+                        uncompressed,
+                    );
+                    processor.run();
+                });
+            }
+            drop(process_rx);
+
+            let chunk_and_write = scope.spawn(move |_| {
+                self.chunk_and_write_data_thread(
+                    Box::new(chunker_rx.into_iter()),
+                    process_tx,
+                    aio,
+                    DataType::Data,
+                    chunker_stats,
+                    checkpoint,
+                )
+            });
+
+            chunk_and_write.join()
+        }).expect("non-joined thread panicked (chunk processor?)");
+
+        data_address.map_err(|e| {
+            if let Some(io_e) = e.downcast_ref::<io::Error>() {
+                io::Error::new(io_e.kind(), format!("{}", io_e))
+            } else {
+                io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+            }
+        })?
+    }
+
     /// Number of threads to use to parallelize CPU-intense part of
     /// the workload.
     fn write_cpu_thread_num(&self) -> usize {
@@ -401,6 +1118,7 @@ impl Repo {
         let mut while_ok = WhileOk::new(r2vi);
 
         while let Some(buf) = time.start_with("input", || while_ok.next()) {
+            self.progress.bytes_consumed(buf.len() as u64);
             time.start("tx");
             chunker_tx.send(buf).expect("chunker tx channel closed")
         }
@@ -493,10 +1211,36 @@ impl Repo {
             || (),
         )?;
 
+        substitute_err_not_found(
+            self.aio
+                .remove_dir_all(
+                    PathBuf::from(gen.to_string()).join(config::PACK_SUBDIR),
+                )
+                .wait(),
+            || (),
+        )?;
+
+        substitute_err_not_found(
+            self.aio
+                .remove_dir_all(
+                    PathBuf::from(gen.to_string())
+                        .join(checkpoint::CHECKPOINT_SUBDIR),
+                )
+                .wait(),
+            || (),
+        )?;
+
         self.aio
             .remove_dir_all(PathBuf::from(gen.to_string()))
             .wait()?;
 
+        if let Some(ref dir) = self.chunk_cache_dir {
+            if let Err(e) = KnownChunks::forget(dir, &gen.to_string()) {
+                warn!(self.log, "Couldn't remove chunk cache";
+                      "gen" => FnValue(|_| gen.to_string()), "err" => %e);
+            }
+        }
+
         Ok(())
     }
 
@@ -520,7 +1264,8 @@ impl Repo {
             generations.to_vec(),
         );
         {
-            let traverser = ReadContext::new(&accessor);
+            let traverser =
+                ReadContext::with_progress(&accessor, &*self.progress);
             traverser.read_recursively(ReadRequest::new(
                 DataType::Data,
                 data_address.as_ref(),
@@ -528,8 +1273,11 @@ impl Repo {
                 self.log.clone(),
             ))?;
         }
+        // all the chunks must be stored in `cur_gen` before the name is
+        accessor.flush()?;
 
         Name::update_generation_to(name_str, cur_gen, generations, &self.aio)?;
+        self.progress.name_handled(name_str);
 
         Ok(())
     }
@@ -539,7 +1287,7 @@ impl Repo {
         da: DataAddressRef,
         reachable_digests: &mut HashSet<Vec<u8>>,
         generations: Vec<Generation>,
-    ) -> Result<()> {
+    ) -> io::Result<()> {
         reachable_digests.insert(da.digest.0.into());
 
         let accessor = self.get_recording_chunk_accessor(
@@ -584,31 +1332,145 @@ impl Repo {
         Ok(reachable_digests)
     }
 
-    fn chunk_rel_path_by_digest(
-        &self,
-        digest: DigestRef,
-        gen_str: &str,
-    ) -> PathBuf {
-        self.config.nesting.get_path(
-            Path::new(config::DATA_SUBDIR),
-            digest.0,
-            gen_str,
-        )
+    /// Chunk storage (according to the configured layout), doing IO
+    /// using `aio`
+    fn chunk_store(&self, aio: &AsyncIO) -> ArcChunkStore {
+        match self.config.layout {
+            config::Layout::Files => Arc::new(FileChunkStore::new(
+                aio.clone(),
+                self.config.nesting.clone(),
+                self.log.clone(),
+            )),
+            config::Layout::Packs { pack_size } => {
+                Arc::new(PackChunkStore::new(
+                    aio.clone(),
+                    aio.stats(),
+                    pack_size,
+                    self.log.clone(),
+                ))
+            }
+        }
+    }
+
+    pub fn list_names(&self) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+        Ok(Name::list_all(&self.read_generations()?, &self.aio)?)
+    }
+
+    /// List names starting with `prefix` (eg. `team-a/`)
+    pub fn list_names_with_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let _lock = self.lock_shared()?;
+        let generations = self.read_generations()?;
+        Ok(Name::list_all_prefixed(&generations, prefix, &self.aio)?)
+    }
+
+    /// List names matching a glob `pattern`
+    ///
+    /// `?` and `*` don't match `/`, `**` does (eg. `team-a/**`).
+    pub fn list_names_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        // no need to look outside the directory the pattern starts with
+        let prefix = match pattern.find(|c| c == '*' || c == '?') {
+            Some(i) => &pattern[..i],
+            None => pattern,
+        };
+        Ok(self
+            .list_names_with_prefix(prefix)?
+            .into_iter()
+            .filter(|name| name::matches_glob(pattern, name))
+            .collect())
+    }
+
+    /// Metadata recorded when the name was stored
+    pub fn name_metadata(&self, name_str: &str) -> Result<NameMetadata> {
+        let _lock = self.lock_shared()?;
+        let generations = self.read_generations()?;
+        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        Ok(name.metadata)
     }
 
-    pub fn list_names(&self) -> io::Result<Vec<String>> {
-        let _lock = self.aio.lock_shared();
-        Name::list_all(&self.read_generations()?, &self.aio)
+    /// Like `name_metadata`, for many names at once
+    ///
+    /// The repository is locked and generations are read only once, so
+    /// this is a lot cheaper than calling `name_metadata` for every name.
+    pub fn names_metadata<S: AsRef<str>>(
+        &self,
+        names: &[S],
+    ) -> Result<Vec<NameMetadata>> {
+        let _lock = self.lock_shared()?;
+        let generations = self.read_generations()?;
+        names
+            .iter()
+            .map(|name_str| {
+                Name::load_from_any(name_str.as_ref(), &generations, &self.aio)
+                    .map(|name| name.metadata)
+                    .map_err(|e| e.into())
+            })
+            .collect()
     }
 
     /// Remove a stored name from repo
     pub fn rm(&self, name: &str) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
-        Name::remove_any(name, &self.read_generations()?, &self.aio)
+        let _lock = self.lock_exclusive()?;
+        Ok(Name::remove_any(name, &self.read_generations()?, &self.aio)?)
+    }
+
+    /// Remove all names starting with `prefix`, returning them
+    pub fn rm_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        if prefix.is_empty() {
+            return Err(Error::InvalidInput(
+                "empty prefix; refusing to remove all the names".into(),
+            ));
+        }
+        let _lock = self.lock_exclusive()?;
+        let generations = self.read_generations()?;
+        let names = Name::list_all_prefixed(&generations, prefix, &self.aio)?;
+        for name in &names {
+            Name::remove_any(name, &generations, &self.aio)?;
+        }
+        Ok(names)
+    }
+
+    /// Remove names according to a retention policy
+    ///
+    /// Returns decisions about all the names selected by `options`, newest
+    /// first. Like with `rm`, space is reclaimed only by `gc`.
+    pub fn prune(&self, options: &PruneOptions) -> Result<Vec<PruneDecision>> {
+        if !options.has_rules() {
+            return Err(Error::InvalidInput(
+                "no keep rule given; refusing to prune all the names".into(),
+            ));
+        }
+        let _lock = if options.dry_run {
+            self.lock_shared()?
+        } else {
+            self.lock_exclusive()?
+        };
+
+        let generations = self.read_generations()?;
+        let prefix = options.prefix.as_ref().map_or("", |p| p.as_str());
+        let names_str =
+            Name::list_all_prefixed(&generations, prefix, &self.aio)?;
+        let mut names = vec![];
+        for name_str in names_str {
+            let name =
+                Name::load_from_any(&name_str, &generations, &self.aio)?;
+            if options.selects(&name_str, &name.metadata) {
+                names.push((name_str, name.metadata));
+            }
+        }
+
+        let decisions = prune::decide(options, names);
+        if !options.dry_run {
+            for decision in decisions.iter().filter(|d| !d.keep()) {
+                info!(self.log, "Removing name"; "name" => decision.name.as_str());
+                Name::remove_any(&decision.name, &generations, &self.aio)?;
+            }
+        }
+        Ok(decisions)
     }
 
     pub fn gc(&self, min_age_secs: u64) -> Result<()> {
-        let _lock = self.aio.lock_exclusive();
+        let _lock = self.lock_exclusive()?;
 
         let generations = self.read_generations()?;
 
@@ -626,6 +1488,11 @@ impl Repo {
                   "gen" => FnValue(|_| generations.last().unwrap().to_string()));
         }
 
+        let total_names =
+            Name::list_all(&generations[..generations.len() - 1], &self.aio)?
+                .len();
+        self.progress.total_names(total_names as u64);
+
         loop {
             let generations = self.read_generations()?;
             assert!(!generations.is_empty());
@@ -657,11 +1524,14 @@ impl Repo {
         writer: &mut W,
         dec: &DecryptHandle,
     ) -> Result<()> {
-        let _lock = self.aio.lock_shared();
+        let _lock = self.lock_shared()?;
 
         let generations = self.read_generations()?;
 
         let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        if let Some(size) = name.metadata.size {
+            self.progress.total_bytes(size);
+        }
         let data_address: DataAddress = name.into();
 
         let accessor = self.get_chunk_accessor(
@@ -669,17 +1539,41 @@ impl Repo {
             Arc::clone(&self.compression),
             generations,
         );
-        let traverser = ReadContext::new(&accessor);
+        let traverser = ReadContext::with_progress(&accessor, &*self.progress);
         traverser.read_recursively(ReadRequest::new(
             DataType::Data,
             data_address.as_ref(),
             Some(writer),
             self.log.clone(),
-        ))
+        ))?;
+        self.progress.name_handled(name_str);
+        Ok(())
+    }
+
+    /// Open data stored under a name for random access
+    ///
+    /// The repository is locked for reading until the returned reader is
+    /// dropped.
+    pub fn name_reader(
+        &self,
+        name_str: &str,
+        dec: &DecryptHandle,
+    ) -> Result<NameReader> {
+        let lock = self.lock_shared()?;
+
+        let generations = self.read_generations()?;
+        let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+
+        let accessor = self.get_chunk_accessor(
+            Some(Arc::clone(&dec.decrypter)),
+            Arc::clone(&self.compression),
+            generations,
+        );
+        Ok(NameReader::new(accessor, name.into(), lock))
     }
 
     pub fn du(&self, name_str: &str, dec: &DecryptHandle) -> Result<DuResults> {
-        let _lock = self.aio.lock_shared();
+        let _lock = self.lock_shared()?;
 
         let generations = self.read_generations()?;
         let name = Name::load_from_any(name_str, &generations, &self.aio)?;
@@ -712,11 +1606,14 @@ impl Repo {
         name_str: &str,
         dec: &DecryptHandle,
     ) -> Result<VerifyResults> {
-        let _lock = self.aio.lock_shared();
+        let _lock = self.lock_shared()?;
 
         let generations = self.read_generations()?;
 
         let name = Name::load_from_any(name_str, &generations, &self.aio)?;
+        if let Some(size) = name.metadata.size {
+            self.progress.total_bytes(size);
+        }
         let data_address: DataAddress = name.into();
 
         let mut counter = CounterWriter::new();
@@ -727,7 +1624,8 @@ impl Repo {
             generations,
         );
         {
-            let traverser = ReadContext::new(&accessor);
+            let traverser =
+                ReadContext::with_progress(&accessor, &*self.progress);
             traverser.read_recursively(ReadRequest::new(
                 DataType::Data,
                 data_address.as_ref(),
@@ -735,9 +1633,62 @@ impl Repo {
                 self.log.clone(),
             ))?;
         }
+        self.progress.name_handled(name_str);
         Ok(accessor.get_results())
     }
 
+    /// Check consistency of the whole repository
+    ///
+    /// Reads every stored chunk, so it takes as long as loading all the data
+    /// once. Problems are reported in the results, not as errors.
+    pub fn fsck(&self, dec: &DecryptHandle) -> Result<FsckResults> {
+        let _lock = self.lock_shared()?;
+        Ok(fsck::Fsck::new(self, dec).run()?)
+    }
+
+    /// Lock for operations that can run concurrently (reading, writing
+    /// new data)
+    fn lock_shared(&self) -> Result<Box<dyn Lock>> {
+        self.lock(|aio| aio.lock_shared(), |aio| aio.try_lock_shared())
+    }
+
+    /// Lock for operations that modify or remove existing data
+    fn lock_exclusive(&self) -> Result<Box<dyn Lock>> {
+        self.lock(|aio| aio.lock_exclusive(), |aio| aio.try_lock_exclusive())
+    }
+
+    /// Lock with `lock`, or poll with `try_lock` until `lock_timeout`
+    fn lock<L, T>(&self, lock: L, try_lock: T) -> Result<Box<dyn Lock>>
+    where
+        L: Fn(&aio::AsyncIO) -> io::Result<Box<dyn Lock>>,
+        T: Fn(&aio::AsyncIO) -> io::Result<Box<dyn Lock>>,
+    {
+        let timeout_secs = match self.lock_timeout {
+            Some(timeout_secs) => timeout_secs,
+            None => return lock(&self.aio).map_err(Repo::lock_error),
+        };
+
+        let start = time::Instant::now();
+        loop {
+            match try_lock(&self.aio) {
+                Ok(lock) => return Ok(lock),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    aio::lock_wait(start, timeout_secs, &e.to_string())
+                        .map_err(Repo::lock_error)?
+                }
+                Err(e) => return Err(Repo::lock_error(e)),
+            }
+        }
+    }
+
+    fn lock_error(e: io::Error) -> Error {
+        if e.kind() == io::ErrorKind::WouldBlock {
+            Error::Locked(e.to_string())
+        } else {
+            e.into()
+        }
+    }
+
     fn read_generations(&self) -> io::Result<Vec<Generation>> {
         let mut list: Vec<_> = self
             .aio
@@ -748,6 +1699,8 @@ impl Repo {
             .filter(|&item| {
                 item != config::CONFIG_YML_FILE
                     && item != config::LOCK_FILE
+                    && item != config::LOCK_DIR
+                    && item != config::DICT_SUBDIR
                     && !item.ends_with(".yml")
             })
             .filter_map(|item| match Generation::try_from(item) {
@@ -786,11 +1739,27 @@ impl Repo {
         reader: R,
         enc: &EncryptHandle,
     ) -> Result<WriteStats>
+    where
+        R: Read + Send,
+    {
+        self.write_with(name_str, reader, enc, &WriteOptions::new())
+    }
+
+    /// Like `write`, recording metadata according to `options`
+    pub fn write_with<R>(
+        &self,
+        name_str: &str,
+        reader: R,
+        enc: &EncryptHandle,
+        options: &WriteOptions,
+    ) -> Result<WriteStats>
     where
         R: Read + Send,
     {
         info!(self.log, "Writing data"; "name" => name_str);
-        let _lock = self.aio.lock_shared();
+        Name::validate(name_str)?;
+        let created = chrono::Utc::now();
+        let _lock = self.lock_shared()?;
 
         let mut generations = self.read_generations()?;
 
@@ -806,66 +1775,159 @@ impl Repo {
             Level::Info,
         );
         timer.start("write");
-        let num_threads = num_cpus::get();
-        let (chunker_tx, chunker_rx) =
-            mpsc::sync_channel(self.write_cpu_thread_num());
 
-        let backend = backend_from_url(&self.url)?;
-        let aio = aio::AsyncIO::new(backend, self.log.clone())?;
+        let aio = aio::AsyncIO::new(self.backend.clone(), self.log.clone())?;
 
         let stats = aio.stats();
+        let store = self.chunk_store(&aio);
+        let known_chunks = self.load_known_chunks(&generations)?;
+
+        let chunker_stats = ChunkerStats::default();
+        let uncompressed = Arc::new(AtomicU64::new(0));
+
+        let gen = *generations.last().unwrap();
+        let (resumed, next_seq) = if options.resume {
+            let (resumed, next_seq) =
+                checkpoint::load(name_str, gen, &self.aio)?;
+            if resumed.is_empty() {
+                warn!(self.log, "No checkpoint to resume from";
+                      "name" => name_str);
+            } else {
+                info!(self.log, "Resuming from checkpoint";
+                      "name" => name_str, "chunks" => resumed.len());
+            }
+            (Arc::new(resumed), next_seq)
+        } else {
+            if options.checkpoint_interval.is_some() {
+                // left by an earlier write that is not resumed
+                checkpoint::remove(name_str, gen, &self.aio)?;
+            }
+            (Arc::new(HashSet::new()), 0)
+        };
+        let mut checkpoint = options.checkpoint_interval.map(|interval| {
+            Checkpoint::new(
+                name_str,
+                gen,
+                self.aio.clone(),
+                store.clone(),
+                interval,
+                Arc::clone(&resumed),
+                next_seq,
+                self.log.clone(),
+            )
+        });
 
-        // mpmc queue used  as spmc fan-out
-        let (process_tx, process_rx) = crossbeam_channel::bounded(num_threads);
+        let data_address = self.write_data(
+            reader,
+            aio,
+            &store,
+            &known_chunks,
+            &enc.encrypter,
+            &enc.compression,
+            &generations,
+            &resumed,
+            &chunker_stats,
+            &uncompressed,
+            checkpoint.as_mut(),
+        )?;
 
-        let data_address = crossbeam::scope(|scope| {
-            scope.spawn(move |_| self.input_reader_thread(reader, chunker_tx));
+        // Make sure all the chunks are stored before writing the name.
+        // Dropping the store drops the last `AsyncIO` handle, which waits
+        // for all pending writes to finish.
+        drop(checkpoint);
+        store.flush()?;
+        drop(store);
 
-            for _ in 0..num_threads {
-                let process_rx = process_rx.clone();
-                let aio = aio.clone();
-                let encrypter = Arc::clone(&enc.encrypter);
-                let compression = Arc::clone(&self.compression);
-                let hasher = Arc::clone(&self.hasher);
-                let generations = generations.clone();
-                scope.spawn(move |_| {
-                    let processor = ChunkProcessor::new(
-                        self.clone(),
-                        process_rx,
-                        aio,
-                        encrypter,
-                        compression,
-                        hasher,
-                        generations,
-                    );
-                    processor.run();
-                });
+        if let Some(known_chunks) = known_chunks {
+            if let Err(e) = known_chunks.persist() {
+                warn!(self.log, "Couldn't update chunk cache"; "err" => %e);
             }
-            drop(process_rx);
+        }
 
-            let chunk_and_write = scope.spawn(move |_| {
-                self.chunk_and_write_data_thread(
-                    Box::new(chunker_rx.into_iter()),
-                    process_tx,
-                    aio,
-                    DataType::Data,
-                )
-            });
+        let mut name: Name = data_address.into();
+        name.metadata = NameMetadata {
+            created: Some(created),
+            size: Some(chunker_stats.bytes.load(Ordering::Relaxed)),
+            chunks: Some(chunker_stats.chunks.load(Ordering::Relaxed)),
+            hostname: options.hostname.clone(),
+            tags: options.tags.clone(),
+        };
+        name.write_as(name_str, gen, &self.aio)?;
 
-            chunk_and_write.join()
-        }).expect("non-joined thread panicked (chunk processor?)");
+        if options.resume || options.checkpoint_interval.is_some() {
+            if let Err(e) = checkpoint::remove(name_str, gen, &self.aio) {
+                warn!(self.log, "Couldn't remove checkpoint";
+                      "name" => name_str, "err" => %e);
+            }
+        }
+        self.progress.name_handled(name_str);
+        let mut stats = stats.get_stats();
+        stats.uncompressed_chunks =
+            uncompressed.load(Ordering::Relaxed) as usize;
+        Ok(stats)
+    }
 
-        let data_address = data_address.map_err(|e| {
-            if let Some(io_e) = e.downcast_ref::<io::Error>() {
-                io::Error::new(io_e.kind(), format!("{}", io_e))
-            } else {
-                io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+    /// Find out how much of the data from `reader` would be new
+    ///
+    /// Data is chunked, hashed and compressed like by `write`, but
+    /// nothing is written (or moved between generations). No encryption
+    /// key is needed, and the repository is not locked, so it works
+    /// without write access; results might be off if it's modified
+    /// at the same time. Data is compressed with the current dictionary,
+    /// unless it's encrypted (see `train_dictionary`).
+    pub fn write_dry_run<R>(&self, reader: R) -> Result<DryRunResults>
+    where
+        R: Read + Send,
+    {
+        info!(self.log, "Writing data (dry run)");
+        let mut generations = self.read_generations()?;
+        if generations.is_empty() {
+            generations.push(Generation::gen_first());
+        }
+
+        let aio = aio::AsyncIO::new(self.backend.clone(), self.log.clone())?;
+        let dry_run_store = Arc::new(DryRunChunkStore::new(
+            self.chunk_store(&aio),
+        ));
+        let store: ArcChunkStore = dry_run_store.clone();
+        let known_chunks = self.load_known_chunks(&generations)?;
+        let encrypter: ArcEncrypter = Arc::new(encryption::NopEncrypter);
+        let chunker_stats = ChunkerStats::default();
+        let uncompressed = Arc::new(AtomicU64::new(0));
+        let compression = match self.data_compression(None) {
+            Ok(compression) => compression,
+            // compression without it is a bit worse, but close enough
+            Err(Error::Io(ref e))
+                if e.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                warn!(self.log, "Dictionary is encrypted, not using it");
+                (0, Arc::clone(&self.compression))
             }
-        })?;
+            Err(e) => return Err(e),
+        };
+
+        self.write_data(
+            reader,
+            aio,
+            &store,
+            &known_chunks,
+            &encrypter,
+            &compression,
+            &generations,
+            &Arc::new(HashSet::new()),
+            &chunker_stats,
+            &uncompressed,
+            None,
+        )?;
 
-        let name: Name = data_address?.into();
-        name.write_as(name_str, *generations.last().unwrap(), &self.aio)?;
-        Ok(stats.get_stats())
+        let (new_chunks, new_bytes) = dry_run_store.new_chunks();
+        Ok(DryRunResults {
+            chunks: chunker_stats.chunks.load(Ordering::Relaxed),
+            bytes: chunker_stats.bytes.load(Ordering::Relaxed),
+            new_chunks,
+            new_bytes,
+            uncompressed_chunks: uncompressed.load(Ordering::Relaxed),
+        })
     }
 }
 // }}}
@@ -1,5 +1,8 @@
 // {{{ use
+use config::IndexFormat;
+use std::io;
 use Name;
+use DIGEST_SIZE;
 // }}}
 
 // {{{ DataAddress & DataAddressRef
@@ -13,6 +16,8 @@ pub(crate) struct DataAddressRef<'a> {
     pub(crate) index_level: u32,
     // final digest
     pub(crate) digest: DigestRef<'a>,
+    // format of the index chunks
+    pub(crate) index_format: IndexFormat,
 }
 
 #[derive(Clone)]
@@ -26,6 +31,8 @@ pub(crate) struct DataAddress {
     pub(crate) index_level: u32,
     // final digest
     pub(crate) digest: Digest,
+    // format of the index chunks
+    pub(crate) index_format: IndexFormat,
 }
 
 impl DataAddress {
@@ -33,6 +40,7 @@ impl DataAddress {
         DataAddressRef {
             index_level: self.index_level,
             digest: self.digest.as_digest_ref(),
+            index_format: self.index_format,
         }
     }
 }
@@ -42,6 +50,7 @@ impl From<Name> for DataAddress {
         DataAddress {
             index_level: name.index_level,
             digest: Digest(name.digest),
+            index_format: name.index_format,
         }
     }
 }
@@ -61,4 +70,43 @@ impl Digest {
 pub(crate) struct DigestRef<'a>(pub(crate) &'a [u8]);
 // }}}
 
+// {{{ Index records
+/// Index record of a chunk, holding `data_len` bytes of data
+pub(crate) fn index_record(
+    digest: Digest,
+    data_len: u64,
+    index_format: IndexFormat,
+) -> Vec<u8> {
+    let mut record = digest.0;
+    if index_format == IndexFormat::V2 {
+        record.extend_from_slice(&data_len.to_be_bytes());
+    }
+    record
+}
+
+/// Parse an `IndexFormat::V2` index chunk into digests and data lengths
+pub(crate) fn parse_index_v2(data: &[u8]) -> io::Result<Vec<(&[u8], u64)>> {
+    let record_size = IndexFormat::V2.record_size();
+    if data.len() % record_size != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "index chunk of invalid size",
+        ));
+    }
+
+    Ok(data
+        .chunks(record_size)
+        .map(|record| {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&record[DIGEST_SIZE..]);
+            (&record[..DIGEST_SIZE], u64::from_be_bytes(len))
+        }).collect())
+}
+
+/// Total length of data addressed by an `IndexFormat::V2` index chunk
+pub(crate) fn index_v2_data_len(data: &[u8]) -> io::Result<u64> {
+    Ok(parse_index_v2(data)?.iter().map(|&(_, len)| len).sum())
+}
+// }}}
+
 // vim: foldmethod=marker foldmarker={{{,}}}
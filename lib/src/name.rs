@@ -1,11 +1,14 @@
 use aio;
+use chrono::prelude::*;
+use config::IndexFormat;
 use serde_yaml;
+use std::collections::BTreeMap;
 use std::io;
 use std::path::PathBuf;
 use util::*;
 use SGData;
 use DIGEST_SIZE;
-use {DataAddress, DataAddressRef, Generation};
+use {DataAddress, DataAddressRef, Error, Generation};
 
 pub(crate) const NAME_SUBDIR: &'static str = "name";
 
@@ -14,6 +17,37 @@ pub(crate) struct Name {
     #[serde(serialize_with = "as_hex", deserialize_with = "from_hex")]
     pub(crate) digest: Vec<u8>,
     pub(crate) index_level: u32,
+    #[serde(default, skip_serializing_if = "IndexFormat::is_v1")]
+    pub(crate) index_format: IndexFormat,
+    #[serde(default, skip_serializing_if = "NameMetadata::is_empty")]
+    pub(crate) metadata: NameMetadata,
+}
+
+/// Metadata recorded with a name when it's stored
+///
+/// Names stored by older versions of `rdedup` have none of it.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct NameMetadata {
+    /// When storing the data started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+    /// Length of the stored data in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Number of data chunks (including ones already stored before)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<u64>,
+    /// Host the data was stored from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl NameMetadata {
+    fn is_empty(&self) -> bool {
+        *self == NameMetadata::default()
+    }
 }
 
 // TODO: I am very displeased with myself how this
@@ -44,10 +78,7 @@ impl Name {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(Error::NameNotFound(name.to_owned()).into())
     }
 
     pub(crate) fn update_generation_to(
@@ -72,10 +103,7 @@ impl Name {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(Error::NameNotFound(name.to_owned()).into())
     }
 
     pub(crate) fn path(name: &str, gen: Generation) -> PathBuf {
@@ -85,36 +113,107 @@ impl Name {
         path
     }
 
+    /// Check that `name` can be stored
+    ///
+    /// Names are paths, with components separated by `/`. Directories
+    /// can't look like name files (`.yml`) or temporary files (`.tmp`).
+    pub(crate) fn validate(name: &str) -> Result<(), Error> {
+        let invalid = |why: &str| {
+            Err(Error::InvalidInput(format!(
+                "invalid name `{}`: {}",
+                name, why
+            )))
+        };
+
+        let components: Vec<_> = name.split('/').collect();
+        for (i, component) in components.iter().enumerate() {
+            if component.is_empty() {
+                return invalid("empty path component");
+            }
+            if *component == "." || *component == ".." {
+                return invalid("`.` and `..` are not allowed");
+            }
+            if i + 1 < components.len()
+                && (component.ends_with(".yml") || component.ends_with(".tmp"))
+            {
+                return invalid("directory ending with `.yml` or `.tmp`");
+            }
+        }
+        Ok(())
+    }
+
     /// List all names
     pub(crate) fn list(
         gen: Generation,
         aio: &aio::AsyncIO,
     ) -> io::Result<Vec<String>> {
-        let list = substitute_err_not_found(
-            aio.list(PathBuf::from(gen.to_string()).join(NAME_SUBDIR))
-                .wait(),
-            || vec![],
-        )?;
-
-        Ok(list
-            .iter()
-            .map(|e| {
-                e.file_stem()
-                    .expect(&format!("malformed name: {:?}", e))
-                    .to_string_lossy()
-                    .to_string()
-            })
-            .collect())
+        Name::list_prefixed(gen, "", aio)
+    }
+
+    /// List names starting with `prefix`
+    ///
+    /// Only the directory `prefix` points into is traversed.
+    pub(crate) fn list_prefixed(
+        gen: Generation,
+        prefix: &str,
+        aio: &aio::AsyncIO,
+    ) -> io::Result<Vec<String>> {
+        let dir = match prefix.rfind('/') {
+            Some(i) => &prefix[..=i],
+            None => "",
+        };
+        let mut names = vec![];
+        Name::list_dir(gen, dir, aio, &mut names)?;
+        names.retain(|name| name.starts_with(prefix));
+        Ok(names)
+    }
+
+    /// Add names in `dir` (empty or ending with `/`) and its subdirectories
+    fn list_dir(
+        gen: Generation,
+        dir: &str,
+        aio: &aio::AsyncIO,
+        names: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let path = PathBuf::from(gen.to_string()).join(NAME_SUBDIR).join(dir);
+        let list = substitute_err_not_found(aio.list(path).wait(), || vec![])?;
+
+        for entry in list {
+            let file_name = match entry.file_name().and_then(|f| f.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            if file_name.ends_with(".yml") {
+                let name = &file_name[..file_name.len() - ".yml".len()];
+                names.push(format!("{}{}", dir, name));
+            } else if !file_name.ends_with(".tmp") {
+                Name::list_dir(
+                    gen,
+                    &format!("{}{}/", dir, file_name),
+                    aio,
+                    names,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     pub fn list_all(
         gens: &[Generation],
         aio: &aio::AsyncIO,
+    ) -> io::Result<Vec<String>> {
+        Name::list_all_prefixed(gens, "", aio)
+    }
+
+    pub(crate) fn list_all_prefixed(
+        gens: &[Generation],
+        prefix: &str,
+        aio: &aio::AsyncIO,
     ) -> io::Result<Vec<String>> {
         let mut res = vec![];
 
         for gen in gens.iter().rev() {
-            res.append(&mut Name::list(*gen, aio)?);
+            res.append(&mut Name::list_prefixed(*gen, prefix, aio)?);
         }
 
         Ok(res)
@@ -132,10 +231,7 @@ impl Name {
         let path = Name::path(name, gen);
 
         if aio.read(path.clone()).wait().is_ok() {
-            return Err(io::Error::new(
-                io::ErrorKind::AlreadyExists,
-                "name already exists",
-            ));
+            return Err(Error::NameExists(name.to_owned()).into());
         }
 
         aio.write(path, SGData::from_single(serialized_str.into_bytes()))
@@ -183,10 +279,70 @@ impl Name {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("name not found: {}", name),
-        ))
+        Err(Error::NameNotFound(name.to_owned()).into())
+    }
+}
+
+/// Match a name against a glob `pattern`
+///
+/// `?` matches any character and `*` any number of characters, except
+/// `/`; `**` matches across `/` too.
+pub(crate) fn matches_glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_glob_chars(&pattern, &name)
+}
+
+fn matches_glob_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => {
+            let across_dirs = pattern.get(1) == Some(&'*');
+            let rest = if across_dirs { &pattern[2..] } else { &pattern[1..] };
+            for i in 0..=name.len() {
+                if matches_glob_chars(rest, &name[i..]) {
+                    return true;
+                }
+                if i < name.len() && name[i] == '/' && !across_dirs {
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => match name.first() {
+            Some(&c) if c != '/' => {
+                matches_glob_chars(&pattern[1..], &name[1..])
+            }
+            _ => false,
+        },
+        Some(&c) => {
+            name.first() == Some(&c)
+                && matches_glob_chars(&pattern[1..], &name[1..])
+        }
+    }
+}
+
+#[test]
+fn name_glob() {
+    assert!(matches_glob("db", "db"));
+    assert!(!matches_glob("db", "db2"));
+    assert!(matches_glob("db-*", "db-2026-10-17"));
+    assert!(matches_glob("team-a/*/2026-*", "team-a/db/2026-10-17"));
+    assert!(!matches_glob("team-a/*", "team-a/db/2026-10-17"));
+    assert!(matches_glob("team-a/**", "team-a/db/2026-10-17"));
+    assert!(matches_glob("**/2026-10-1?", "team-a/db/2026-10-17"));
+    assert!(!matches_glob("db-?", "db-"));
+    assert!(!matches_glob("a?b", "a/b"));
+    assert!(matches_glob("*", ""));
+}
+
+#[test]
+fn name_validate() {
+    assert!(Name::validate("db").is_ok());
+    assert!(Name::validate("team-a/db/2026-10-17").is_ok());
+    assert!(Name::validate("team-a/backup.tmp").is_ok());
+    for name in &["", "/db", "db/", "a//b", "a/../b", "./a", "a.yml/b"] {
+        assert!(Name::validate(name).is_err(), "{}", name);
     }
 }
 
@@ -195,6 +351,8 @@ impl<'a> From<DataAddressRef<'a>> for Name {
         Name {
             digest: da.digest.0.into(),
             index_level: da.index_level,
+            index_format: da.index_format,
+            metadata: NameMetadata::default(),
         }
     }
 }
@@ -204,6 +362,8 @@ impl From<DataAddress> for Name {
         Name {
             digest: da.digest.0,
             index_level: da.index_level,
+            index_format: da.index_format,
+            metadata: NameMetadata::default(),
         }
     }
 }
@@ -0,0 +1,69 @@
+//! Progress reporting of long running `Repo` operations
+//!
+//! An observer set with `Repo::set_progress_observer` is called by
+//! `write`, `read`, `verify` and `gc` as they go. Calls come from multiple
+//! threads (eg. one per chunk processor when writing), so implementations
+//! should be cheap and must not block for long.
+// {{{ use and mod
+use std::io::{self, Write};
+// }}}
+
+/// Observer of the progress of `Repo` operations
+///
+/// All the methods do nothing by default.
+pub trait ProgressObserver: Send + Sync {
+    /// Data to be processed, when known in advance (`read`, `verify`)
+    fn total_bytes(&self, _bytes: u64) {}
+
+    /// Names to be handled, when known in advance (`gc`)
+    fn total_names(&self, _names: u64) {}
+
+    /// Data read from the input (`write`) or restored (`read`, `verify`)
+    fn bytes_consumed(&self, _bytes: u64) {}
+
+    /// Data chunk stored (`write`), read (`read`, `verify`) or moved to
+    /// the current generation (`gc`)
+    fn chunk_processed(&self) {}
+
+    /// New chunk stored (`write`), with its size after compression and
+    /// encryption
+    fn new_bytes(&self, _bytes: u64) {}
+
+    /// Name written, read, verified or moved to the current
+    /// generation (`gc`)
+    fn name_handled(&self, _name: &str) {}
+}
+
+/// Observer that ignores everything; the default
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {}
+
+/// Writer reporting the data written through it as consumed
+pub(crate) struct ProgressWriter<'a> {
+    writer: &'a mut dyn Write,
+    progress: &'a dyn ProgressObserver,
+}
+
+impl<'a> ProgressWriter<'a> {
+    pub(crate) fn new(
+        writer: &'a mut dyn Write,
+        progress: &'a dyn ProgressObserver,
+    ) -> Self {
+        ProgressWriter { writer, progress }
+    }
+}
+
+impl<'a> Write for ProgressWriter<'a> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(bytes)?;
+        self.progress.bytes_consumed(len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
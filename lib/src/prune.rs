@@ -0,0 +1,302 @@
+//! Retention policies: deciding which names `Repo::prune` removes
+//!
+//! Every rule is applied independently to the selected names, newest
+//! first; a name is kept if any rule keeps it. Periods (hours, days, ...)
+//! are in UTC, and in each period only the newest name is kept.
+// {{{ use and mod
+use chrono::prelude::*;
+use NameMetadata;
+// }}}
+
+/// Which names `Repo::prune` looks at and how many of them to keep
+#[derive(Clone, Default, Debug)]
+pub struct PruneOptions {
+    /// Only names starting with this prefix
+    pub prefix: Option<String>,
+    /// Only names with this tag (key and value)
+    pub tag: Option<(String, String)>,
+    /// Keep this many newest names
+    pub keep_last: usize,
+    /// Keep the newest name of each of this many last hours (with any name)
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    /// Weeks are ISO 8601 weeks, starting on Monday
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+    /// Only decide, don't remove anything
+    pub dry_run: bool,
+}
+
+impl PruneOptions {
+    /// Anything to keep at all
+    ///
+    /// Pruning with no rule would remove all the selected names, which
+    /// is more likely a mistake than intended.
+    pub(crate) fn has_rules(&self) -> bool {
+        self.keep_last > 0
+            || self.keep_hourly > 0
+            || self.keep_daily > 0
+            || self.keep_weekly > 0
+            || self.keep_monthly > 0
+            || self.keep_yearly > 0
+    }
+
+    pub(crate) fn selects(&self, name: &str, metadata: &NameMetadata) -> bool {
+        if let Some(ref prefix) = self.prefix {
+            if !name.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some((ref key, ref value)) = self.tag {
+            if metadata.tags.get(key) != Some(value) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// What `Repo::prune` did (or would do) with a name
+#[derive(Clone, Debug)]
+pub struct PruneDecision {
+    pub name: String,
+    pub created: Option<DateTime<Utc>>,
+    /// Rules keeping the name (eg. `keep-daily 2026-10-17`); empty if it's
+    /// removed
+    pub kept_by: Vec<String>,
+    /// Why the other rules don't keep the name (eg. `keep-daily: newer name
+    /// in 2026-10-17`)
+    pub skipped_by: Vec<String>,
+}
+
+impl PruneDecision {
+    pub fn keep(&self) -> bool {
+        !self.kept_by.is_empty()
+    }
+}
+
+/// Decide about every name, returning decisions sorted newest first
+///
+/// Names without creation time (stored by older versions) are always kept.
+pub(crate) fn decide(
+    options: &PruneOptions,
+    names: Vec<(String, NameMetadata)>,
+) -> Vec<PruneDecision> {
+    let mut decisions: Vec<_> = names
+        .into_iter()
+        .map(|(name, metadata)| PruneDecision {
+            name,
+            created: metadata.created,
+            kept_by: vec![],
+            skipped_by: vec![],
+        })
+        .collect();
+    // `None` sorts first, so ends up last
+    decisions.sort_by(|a, b| b.created.cmp(&a.created));
+
+    if options.keep_last > 0 {
+        for (i, decision) in decisions
+            .iter_mut()
+            .filter(|decision| decision.created.is_some())
+            .enumerate()
+        {
+            if i < options.keep_last {
+                decision.kept_by.push(format!("keep-last {}", i + 1));
+            } else {
+                decision.skipped_by.push(format!(
+                    "keep-last: not among {} newest",
+                    options.keep_last
+                ));
+            }
+        }
+    }
+
+    keep_per_period(&mut decisions, "keep-hourly", options.keep_hourly, |t| {
+        t.format("%Y-%m-%d %H:00").to_string()
+    });
+    keep_per_period(&mut decisions, "keep-daily", options.keep_daily, |t| {
+        t.format("%Y-%m-%d").to_string()
+    });
+    keep_per_period(&mut decisions, "keep-weekly", options.keep_weekly, |t| {
+        let week = t.iso_week();
+        format!("{}-W{:02}", week.year(), week.week())
+    });
+    keep_per_period(
+        &mut decisions,
+        "keep-monthly",
+        options.keep_monthly,
+        |t| t.format("%Y-%m").to_string(),
+    );
+    keep_per_period(&mut decisions, "keep-yearly", options.keep_yearly, |t| {
+        t.format("%Y").to_string()
+    });
+
+    for decision in &mut decisions {
+        if decision.created.is_none() {
+            decision.kept_by.push("no creation time".into());
+        }
+    }
+
+    decisions
+}
+
+/// Keep the newest name of each of the `keep` newest periods
+///
+/// `decisions` must be sorted newest first. Nothing is recorded if `keep`
+/// is zero, as the rule is not in use then.
+fn keep_per_period<F>(
+    decisions: &mut [PruneDecision],
+    rule: &str,
+    keep: usize,
+    period: F,
+) where
+    F: Fn(&DateTime<Utc>) -> String,
+{
+    if keep == 0 {
+        return;
+    }
+    let mut last_period = None;
+    let mut kept = 0;
+
+    for decision in decisions.iter_mut() {
+        let period = match decision.created {
+            Some(ref created) => period(created),
+            None => continue,
+        };
+        if last_period.as_ref() == Some(&period) {
+            decision
+                .skipped_by
+                .push(format!("{}: newer name in {}", rule, period));
+        } else if kept == keep {
+            decision
+                .skipped_by
+                .push(format!("{}: not among {} newest periods", rule, keep));
+        } else {
+            decision.kept_by.push(format!("{} {}", rule, period));
+            last_period = Some(period);
+            kept += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+fn names(created: &[(&str, &str)]) -> Vec<(String, NameMetadata)> {
+    created
+        .iter()
+        .map(|&(name, created)| {
+            let mut metadata = NameMetadata::default();
+            if !created.is_empty() {
+                metadata.created = Some(created.parse().unwrap());
+            }
+            (name.to_owned(), metadata)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn kept(decisions: &[PruneDecision]) -> Vec<&str> {
+    decisions
+        .iter()
+        .filter(|decision| decision.keep())
+        .map(|decision| decision.name.as_str())
+        .collect()
+}
+
+#[test]
+fn prune_keep_last() {
+    let options = PruneOptions {
+        keep_last: 2,
+        ..PruneOptions::default()
+    };
+    let decisions = decide(
+        &options,
+        names(&[
+            ("a", "2026-10-01T00:00:00Z"),
+            ("c", "2026-10-03T00:00:00Z"),
+            ("old", ""),
+            ("b", "2026-10-02T00:00:00Z"),
+        ]),
+    );
+
+    let order: Vec<_> = decisions.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(order, ["c", "b", "a", "old"]);
+    assert_eq!(kept(&decisions), ["c", "b", "old"]);
+    assert_eq!(decisions[1].kept_by, ["keep-last 2"]);
+    assert_eq!(decisions[2].skipped_by, ["keep-last: not among 2 newest"]);
+    assert_eq!(decisions[3].kept_by, ["no creation time"]);
+}
+
+#[test]
+fn prune_keep_per_period() {
+    let options = PruneOptions {
+        keep_daily: 3,
+        keep_weekly: 2,
+        keep_monthly: 2,
+        ..PruneOptions::default()
+    };
+    let decisions = decide(
+        &options,
+        names(&[
+            ("10-17-night", "2026-10-17T23:00:00Z"),
+            ("10-17-morning", "2026-10-17T06:00:00Z"),
+            ("10-16", "2026-10-16T23:00:00Z"),
+            ("10-15", "2026-10-15T23:00:00Z"),
+            ("10-14", "2026-10-14T23:00:00Z"),
+            // sunday of the previous ISO week
+            ("10-11", "2026-10-11T23:00:00Z"),
+            ("10-10", "2026-10-10T23:00:00Z"),
+            ("09-30", "2026-09-30T23:00:00Z"),
+            ("08-31", "2026-08-31T23:00:00Z"),
+        ]),
+    );
+
+    assert_eq!(
+        kept(&decisions),
+        ["10-17-night", "10-16", "10-15", "10-11", "09-30"]
+    );
+    assert_eq!(
+        decisions[0].kept_by,
+        [
+            "keep-daily 2026-10-17",
+            "keep-weekly 2026-W42",
+            "keep-monthly 2026-10"
+        ]
+    );
+    assert_eq!(decisions[5].kept_by, ["keep-weekly 2026-W41"]);
+    assert_eq!(decisions[7].kept_by, ["keep-monthly 2026-09"]);
+    assert_eq!(
+        decisions[1].skipped_by,
+        [
+            "keep-daily: newer name in 2026-10-17",
+            "keep-weekly: newer name in 2026-W42",
+            "keep-monthly: newer name in 2026-10"
+        ]
+    );
+    assert_eq!(
+        decisions[8].skipped_by,
+        [
+            "keep-daily: not among 3 newest periods",
+            "keep-weekly: not among 2 newest periods",
+            "keep-monthly: not among 2 newest periods"
+        ]
+    );
+}
+
+#[test]
+fn prune_selects() {
+    let mut metadata = NameMetadata::default();
+    metadata.tags.insert("env".into(), "prod".into());
+
+    let mut options = PruneOptions::default();
+    assert!(!options.has_rules());
+    assert!(options.selects("db-1", &metadata));
+
+    options.prefix = Some("db-".into());
+    options.tag = Some(("env".into(), "prod".into()));
+    assert!(options.selects("db-1", &metadata));
+    assert!(!options.selects("web-1", &metadata));
+    assert!(!options.selects("db-1", &NameMetadata::default()));
+}
+
+// vim: foldmethod=marker foldmarker={{{,}}}
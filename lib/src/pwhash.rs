@@ -1,4 +1,5 @@
 use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::pwhash::argon2id13;
 use std::io;
 use util::{as_base64, from_base64};
 
@@ -45,6 +46,16 @@ impl SodiumOxide {
     }
 }
 
+impl SodiumOxide {
+    /// Same parameters, but a fresh salt
+    pub(crate) fn with_new_salt(&self) -> Self {
+        Self {
+            salt: pwhash::gen_salt(),
+            ..self.clone()
+        }
+    }
+}
+
 impl Default for SodiumOxide {
     fn default() -> Self {
         SodiumOxide {
@@ -76,3 +87,61 @@ impl PWHash for SodiumOxide {
         Ok(key)
     }
 }
+
+/// Lowest limits accepted by libsodium's Argon2id
+pub(crate) const ARGON2ID_OPS_LIMIT_MIN: u64 = 1;
+pub(crate) const ARGON2ID_MEM_LIMIT_MIN: u64 = 8192;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Argon2id {
+    #[serde(
+        serialize_with = "as_base64",
+        deserialize_with = "from_base64"
+    )]
+    salt: argon2id13::Salt,
+    mem_limit: u64,
+    ops_limit: u64,
+}
+
+impl Argon2id {
+    /// Limits default to libsodium's "moderate" preset
+    pub(crate) fn new(ops_limit: Option<u64>, mem_limit: Option<u64>) -> Self {
+        Self {
+            ops_limit: ops_limit
+                .unwrap_or(argon2id13::OPSLIMIT_MODERATE.0 as u64),
+            mem_limit: mem_limit
+                .unwrap_or(argon2id13::MEMLIMIT_MODERATE.0 as u64),
+            salt: argon2id13::gen_salt(),
+        }
+    }
+
+    /// Same parameters, but a fresh salt
+    pub(crate) fn with_new_salt(&self) -> Self {
+        Self {
+            salt: argon2id13::gen_salt(),
+            ..self.clone()
+        }
+    }
+}
+
+impl PWHash for Argon2id {
+    /// Derive secret key from passphrase and salt
+    fn derive_key(&self, passphrase: &str) -> io::Result<Vec<u8>> {
+        let mut key = vec![0; 32];
+
+        argon2id13::derive_key(
+            &mut key,
+            passphrase.as_bytes(),
+            &self.salt,
+            argon2id13::OpsLimit(self.ops_limit as usize),
+            argon2id13::MemLimit(self.mem_limit as usize),
+        ).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive encryption key from passphrase",
+            )
+        })?;
+
+        Ok(key)
+    }
+}
@@ -1,29 +1,39 @@
 //! Primitives used for reading the chunked data stored in the `Repo`
 // {{{ use and mod
+use chunk_store::ArcChunkStore;
+use config::IndexFormat;
+use envelope;
 use hex;
+use misc::{index_v2_data_len, parse_index_v2};
+use progress::{ProgressObserver, ProgressWriter};
 use slog::{FnValue, Logger};
 use std;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
 use Generation;
 use VerifyResults;
-use {ArcCompression, ArcDecrypter};
-use {DataAddressRef, DataType, Digest, DigestRef, Error, Repo, DIGEST_SIZE};
+use {ArcCompression, ArcDecrypter, Lock};
+use {DataAddress, DataAddressRef, DataType, DigestRef, Error, Repo};
+use DIGEST_SIZE;
 // }}}
 
 /// Translates index stream into data stream
 ///
 /// This type implements `io::Write` and interprets what's written to it as a
-/// stream of digests.
+/// stream of index records (digests, possibly followed by data lengths,
+/// depending on `index_format`).
 ///
 /// For every digest written to it, it will access the corresponding chunk and
 /// write it into `writer` that it wraps.
 struct IndexTranslator<'a, 'b> {
     writer: Option<&'b mut dyn Write>,
-    digest_buf: Digest,
+    record_buf: Vec<u8>,
     data_type: DataType,
+    index_format: IndexFormat,
     read_context: &'a ReadContext<'a>,
     log: Logger,
 }
@@ -32,12 +42,14 @@ impl<'a, 'b> IndexTranslator<'a, 'b> {
     pub(crate) fn new(
         writer: Option<&'b mut dyn Write>,
         data_type: DataType,
+        index_format: IndexFormat,
         read_context: &'a ReadContext<'a>,
         log: Logger,
     ) -> Self {
         IndexTranslator {
             data_type,
-            digest_buf: Digest(Vec::with_capacity(DIGEST_SIZE)),
+            record_buf: Vec::with_capacity(index_format.record_size()),
+            index_format,
             read_context,
             writer,
             log,
@@ -50,29 +62,30 @@ impl<'a, 'b> Write for IndexTranslator<'a, 'b> {
         assert!(!bytes.is_empty());
 
         let total_len = bytes.len();
+        let record_size = self.index_format.record_size();
         loop {
-            let has_already = self.digest_buf.0.len();
-            if (has_already + bytes.len()) < DIGEST_SIZE {
-                self.digest_buf.0.extend_from_slice(bytes);
+            let has_already = self.record_buf.len();
+            if (has_already + bytes.len()) < record_size {
+                self.record_buf.extend_from_slice(bytes);
 
                 trace!(self.log, "left with a buffer";
-                       "digest" => FnValue(|_| hex::encode(&self.digest_buf.0)),
+                       "record" => FnValue(|_| hex::encode(&self.record_buf)),
                        );
                 return Ok(total_len);
             }
 
             let &mut IndexTranslator {
-                ref mut digest_buf,
+                ref mut record_buf,
                 data_type,
+                index_format,
                 ref mut writer,
                 read_context,
                 ..
             } = self;
-            let needs = DIGEST_SIZE - has_already;
+            let needs = record_size - has_already;
 
-            if digest_buf.0.is_empty() {
-                let digest = &bytes[..needs];
-                debug_assert_eq!(digest.len(), DIGEST_SIZE);
+            if record_buf.is_empty() {
+                let digest = &bytes[..DIGEST_SIZE];
                 bytes = &bytes[needs..];
 
                 read_context.read_recursively(ReadRequest::new(
@@ -80,25 +93,27 @@ impl<'a, 'b> Write for IndexTranslator<'a, 'b> {
                     DataAddressRef {
                         digest: DigestRef(digest),
                         index_level: 0,
+                        index_format,
                     },
                     writer.as_mut().map(|w| w as &mut dyn io::Write),
                     self.log.clone(),
                 ))?;
             } else {
-                digest_buf.0.extend_from_slice(&bytes[..needs]);
-                debug_assert_eq!(digest_buf.0.len(), DIGEST_SIZE);
+                record_buf.extend_from_slice(&bytes[..needs]);
+                debug_assert_eq!(record_buf.len(), record_size);
                 bytes = &bytes[needs..];
 
                 let res = read_context.read_recursively(ReadRequest::new(
                     data_type,
                     DataAddressRef {
-                        digest: digest_buf.as_digest_ref(),
+                        digest: DigestRef(&record_buf[..DIGEST_SIZE]),
                         index_level: 0,
+                        index_format,
                     },
                     writer.as_mut().map(|w| w as &mut dyn io::Write),
                     self.log.clone(),
                 ));
-                digest_buf.0.clear();
+                record_buf.clear();
                 res?;
             }
         }
@@ -112,7 +127,7 @@ impl<'a, 'b> Write for IndexTranslator<'a, 'b> {
 impl<'a, 'b> Drop for IndexTranslator<'a, 'b> {
     fn drop(&mut self) {
         if !std::thread::panicking() {
-            debug_assert_eq!(self.digest_buf.0.len(), 0);
+            debug_assert_eq!(self.record_buf.len(), 0);
         }
     }
 }
@@ -148,11 +163,27 @@ impl<'a> ReadRequest<'a> {
 pub(crate) struct ReadContext<'a> {
     /// Writer to write the data to; `None` will discard the data
     accessor: &'a dyn ChunkAccessor,
+    /// Observer to report data chunks to, if any
+    progress: Option<&'a dyn ProgressObserver>,
 }
 
 impl<'a> ReadContext<'a> {
     pub(crate) fn new(accessor: &'a dyn ChunkAccessor) -> Self {
-        ReadContext { accessor }
+        ReadContext {
+            accessor,
+            progress: None,
+        }
+    }
+
+    /// Like `new`, reporting data chunks (and data written) to `progress`
+    pub(crate) fn with_progress(
+        accessor: &'a dyn ChunkAccessor,
+        progress: &'a dyn ProgressObserver,
+    ) -> Self {
+        ReadContext {
+            accessor,
+            progress: Some(progress),
+        }
     }
 
     fn on_index(&self, mut req: ReadRequest) -> io::Result<()> {
@@ -163,6 +194,7 @@ impl<'a> ReadContext<'a> {
         let mut translator = IndexTranslator::new(
             req.writer.take(),
             req.data_type,
+            req.data_address.index_format,
             self,
             req.log.clone(),
         );
@@ -170,6 +202,7 @@ impl<'a> ReadContext<'a> {
         let da = DataAddressRef {
             digest: req.data_address.digest,
             index_level: req.data_address.index_level - 1,
+            index_format: req.data_address.index_format,
         };
         let req = ReadRequest::new(
             DataType::Index,
@@ -184,15 +217,29 @@ impl<'a> ReadContext<'a> {
         trace!(req.log, "Traversing data";
                "digest" => FnValue(|_| hex::encode(req.data_address.digest.0)),
                );
+        let data_type = req.data_type;
+        let progress = self.progress.filter(|_| data_type == DataType::Data);
+        let digest = req.data_address.digest;
+
         if let Some(writer) = req.writer.take() {
-            self.accessor.read_chunk_into(
-                req.data_address.digest,
-                req.data_type,
-                writer,
-            )
+            match progress {
+                Some(progress) => self.accessor.read_chunk_into(
+                    digest,
+                    data_type,
+                    &mut ProgressWriter::new(writer, progress),
+                )?,
+                None => {
+                    self.accessor.read_chunk_into(digest, data_type, writer)?
+                }
+            }
         } else {
-            self.accessor.touch(req.data_address.digest)
+            self.accessor.touch(digest)?;
         }
+
+        if let Some(progress) = progress {
+            progress.chunk_processed();
+        }
+        Ok(())
     }
 
     pub(crate) fn read_recursively(&self, req: ReadRequest) -> io::Result<()> {
@@ -227,6 +274,7 @@ pub(crate) trait ChunkAccessor {
 /// anything
 pub(crate) struct DefaultChunkAccessor<'a> {
     repo: &'a Repo,
+    store: ArcChunkStore,
     decrypter: Option<ArcDecrypter>,
     compression: ArcCompression,
     gen_strings: Vec<String>,
@@ -241,6 +289,7 @@ impl<'a> DefaultChunkAccessor<'a> {
     ) -> Self {
         DefaultChunkAccessor {
             repo,
+            store: repo.chunk_store(&repo.aio),
             decrypter,
             compression,
             gen_strings: generations.iter().map(|g| g.to_string()).collect(),
@@ -264,8 +313,7 @@ impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
         let mut data_gen_str = None;
 
         for gen_str in self.gen_strings.iter().rev() {
-            let path = self.repo.chunk_rel_path_by_digest(digest, gen_str);
-            match self.repo.aio.read(path).wait() {
+            match self.store.read(digest, gen_str) {
                 Ok(d) => {
                     data = Some(d);
                     data_gen_str = Some(gen_str);
@@ -276,52 +324,45 @@ impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
         }
 
         if data.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
-            ));
+            return Err(Error::ChunkMissing(digest.0.to_vec()).into());
         }
 
         let data_gen_str = data_gen_str.unwrap();
 
         if cur_gen_str != data_gen_str {
-            let data_gen_path =
-                self.repo.chunk_rel_path_by_digest(digest, data_gen_str);
-            let cur_gen_path =
-                self.repo.chunk_rel_path_by_digest(digest, cur_gen_str);
-
-            // `rename` is best effort
+            // `move_chunk` is best effort
             //
             // Should we fail if we're GCing, and we want to make sure
             // everything reachable has been moved? Well, if it wa
-            let res = self
-                .repo
-                .aio
-                .rename(data_gen_path.clone(), cur_gen_path.clone())
-                .wait();
+            let res = self.store.move_chunk(digest, data_gen_str, cur_gen_str);
             if let Err(e) = res {
                 if e.kind() != io::ErrorKind::NotFound {
                     warn!(self.repo.log, "Couldn't move chunk to the current generation";
-                          "src-path" => data_gen_path.display(),
-                          "dst-path" => cur_gen_path.display(),
+                          "digest" => FnValue(|_| hex::encode(digest.0)),
+                          "src-gen" => data_gen_str.as_str(),
+                          "dst-gen" => cur_gen_str.as_str(),
                           "err" => %e);
                     return Err(e);
                 }
             }
         }
 
-        let data = data.unwrap();
-        let data = if data_type.should_encrypt() {
-            self.decrypter
-                .as_ref()
-                .expect("Decrypter expected")
-                .decrypt(data, digest.0)?
-        } else {
-            data
+        let corrupted = |reason: String| -> io::Error {
+            Error::ChunkCorrupted {
+                digest: digest.0.to_vec(),
+                reason,
+            }.into()
         };
 
-        let data = if data_type.should_compress() {
-            self.compression.decompress(data)?
+        let data = data.unwrap();
+        let data = if data_type == DataType::Data {
+            envelope::decode(
+                data,
+                digest.0,
+                self.decrypter.as_ref(),
+                &self.compression,
+                &|id| self.repo.dictionaries.get(id, self.decrypter.as_ref()),
+            ).map_err(|e| corrupted(e.to_string()))?
         } else {
             data
         };
@@ -329,14 +370,7 @@ impl<'a> ChunkAccessor for DefaultChunkAccessor<'a> {
         let vec_result = self.repo.hasher.calculate_digest(&data);
 
         if vec_result != digest.0 {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "{} corrupted, data read: {}",
-                    hex::encode(digest.0),
-                    hex::encode(vec_result)
-                ),
-            ))
+            Err(corrupted(format!("data read: {}", hex::encode(vec_result))))
         } else {
             for part in data.as_parts() {
                 writer.write_all(&*part)?;
@@ -457,10 +491,8 @@ impl<'a> ChunkAccessor for VerifyingChunkAccessor<'a> {
         }
         let res = self.raw.read_chunk_into(digest, data_type, writer);
 
-        if res.is_err() {
-            self.errors
-                .borrow_mut()
-                .push((digest.0.into(), res.err().unwrap()));
+        if let Err(e) = res {
+            self.errors.borrow_mut().push((digest.0.into(), e.into()));
         }
         Ok(())
     }
@@ -493,6 +525,14 @@ impl<'a> GenerationUpdateChunkAccessor<'a> {
     }
 }
 
+impl<'a> GenerationUpdateChunkAccessor<'a> {
+    /// Make sure all the accessed chunks are stored in the current
+    /// generation
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.raw.store.flush()
+    }
+}
+
 impl<'a> ChunkAccessor for GenerationUpdateChunkAccessor<'a> {
     fn repo(&self) -> &Repo {
         self.raw.repo()
@@ -512,43 +552,31 @@ impl<'a> ChunkAccessor for GenerationUpdateChunkAccessor<'a> {
         let mut data_gen_str = None;
 
         for gen_str in self.raw.gen_strings.iter().rev() {
-            let path = self.raw.repo.chunk_rel_path_by_digest(digest, gen_str);
-            match self.raw.repo.aio.read_metadata(path).wait() {
-                Ok(_metadata) => {
+            match self.raw.store.exists(digest, gen_str) {
+                Ok(true) => {
                     data_gen_str = Some(gen_str);
                     break;
                 }
-                Err(_e) => {}
+                Ok(false) | Err(_) => {}
             }
         }
 
         if data_gen_str.is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Couldn't not find chunk: {}", hex::encode(digest.0),),
-            ));
+            return Err(Error::ChunkMissing(digest.0.to_vec()).into());
         }
 
         let data_gen_str = data_gen_str.unwrap();
 
         if cur_gen_str != data_gen_str {
-            let data_gen_path =
-                self.raw.repo.chunk_rel_path_by_digest(digest, data_gen_str);
-            let cur_gen_path =
-                self.raw.repo.chunk_rel_path_by_digest(digest, cur_gen_str);
-
-            // `rename` is best effort
-            let res = self
-                .raw
-                .repo
-                .aio
-                .rename(data_gen_path.clone(), cur_gen_path.clone())
-                .wait();
+            // `move_chunk` is best effort
+            let res =
+                self.raw.store.move_chunk(digest, data_gen_str, cur_gen_str);
             if let Err(e) = res {
                 if e.kind() != io::ErrorKind::NotFound {
                     warn!(self.raw.repo.log, "Couldn't move chunk to the current generation";
-                          "src-path" => data_gen_path.display(),
-                          "dst-path" => cur_gen_path.display(),
+                          "digest" => FnValue(|_| hex::encode(digest.0)),
+                          "src-gen" => data_gen_str.as_str(),
+                          "dst-gen" => cur_gen_str.as_str(),
                           "err" => %e);
                     return Err(e);
                 }
@@ -557,4 +585,348 @@ impl<'a> ChunkAccessor for GenerationUpdateChunkAccessor<'a> {
         Ok(())
     }
 }
+
+struct ChunkCacheInner {
+    capacity: usize,
+    size: usize,
+    /// Incremented on every access
+    tick: u64,
+    chunks: HashMap<Vec<u8>, (u64, Arc<Vec<u8>>)>,
+    /// Digests by the tick of the last access
+    lru: BTreeMap<u64, Vec<u8>>,
+}
+
+/// LRU cache of decrypted and decompressed chunks
+///
+/// Can be shared between `NameReader`s (see `NameReader::set_chunk_cache`),
+/// so the chunks they have in common are read only once.
+pub struct ChunkCache {
+    inner: Mutex<ChunkCacheInner>,
+}
+
+impl ChunkCache {
+    /// Create a cache holding up to `capacity` bytes of chunk data
+    pub fn new(capacity: usize) -> Self {
+        ChunkCache {
+            inner: Mutex::new(ChunkCacheInner {
+                capacity,
+                size: 0,
+                tick: 0,
+                chunks: HashMap::new(),
+                lru: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn get(&self, digest: &[u8]) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.chunks.get_mut(digest)?;
+        let digest = inner.lru.remove(&entry.0).expect("lru entry");
+        inner.lru.insert(tick, digest);
+        entry.0 = tick;
+        Some(Arc::clone(&entry.1))
+    }
+
+    pub(crate) fn insert(&self, digest: &[u8], data: Arc<Vec<u8>>) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if data.len() > inner.capacity || inner.chunks.contains_key(digest) {
+            return;
+        }
+
+        inner.tick += 1;
+        inner.size += data.len();
+        inner.lru.insert(inner.tick, digest.to_vec());
+        inner.chunks.insert(digest.to_vec(), (inner.tick, data));
+
+        while inner.size > inner.capacity {
+            let oldest = *inner.lru.keys().next().expect("cache not empty");
+            let digest = inner.lru.remove(&oldest).unwrap();
+            let (_, data) = inner.chunks.remove(&digest).unwrap();
+            inner.size -= data.len();
+        }
+    }
+}
+
+/// Data chunks of an `IndexFormat::V1` name
+///
+/// Lengths of chunks are not known until they are read.
+struct LegacyIndex {
+    digests: Vec<Vec<u8>>,
+    /// Offsets of chunks read so far, and the end of the last one
+    offsets: Vec<u64>,
+}
+
+/// Random access reader of data stored under a name
+///
+/// Returned by `Repo::name_reader`. For names stored with data lengths
+/// in the index (default for new repositories), seeking reads only
+/// the index chunks on the way to the data. For other names, all the data
+/// up to the position has to be read.
+///
+/// The repository is locked (shared) for as long as the reader exists.
+pub struct NameReader<'a> {
+    accessor: DefaultChunkAccessor<'a>,
+    address: DataAddress,
+    pos: u64,
+    /// Recently read data chunk: its offset and data
+    chunk: Option<(u64, Arc<Vec<u8>>)>,
+    /// Recently read index chunk of every level: its digest and data
+    index_cache: Vec<Option<(Vec<u8>, Arc<Vec<u8>>)>>,
+    legacy_index: Option<LegacyIndex>,
+    chunk_cache: Option<Arc<ChunkCache>>,
+    _lock: Box<dyn Lock>,
+}
+
+impl<'a> NameReader<'a> {
+    pub(crate) fn new(
+        accessor: DefaultChunkAccessor<'a>,
+        address: DataAddress,
+        lock: Box<dyn Lock>,
+    ) -> Self {
+        NameReader {
+            accessor,
+            index_cache: (0..address.index_level).map(|_| None).collect(),
+            address,
+            pos: 0,
+            chunk: None,
+            legacy_index: None,
+            chunk_cache: None,
+            _lock: lock,
+        }
+    }
+
+    /// Keep the chunks read in `cache`, and look for them there first
+    pub fn set_chunk_cache(&mut self, cache: Arc<ChunkCache>) {
+        self.chunk_cache = Some(cache);
+    }
+
+    /// Length of the data
+    pub fn data_len(&mut self) -> io::Result<u64> {
+        if self.address.index_format == IndexFormat::V1 {
+            // no way around reading everything
+            self.locate_legacy(u64::max_value())?;
+            return Ok(*self
+                .legacy_index
+                .as_ref()
+                .expect("index loaded")
+                .offsets
+                .last()
+                .unwrap());
+        }
+
+        let digest = self.address.digest.0.clone();
+        if self.address.index_level == 0 {
+            return Ok(self.read_chunk(&digest, DataType::Data)?.len() as u64);
+        }
+
+        index_v2_data_len(&self.read_chunk(&digest, DataType::Index)?)
+    }
+
+    fn read_chunk(
+        &self,
+        digest: &[u8],
+        data_type: DataType,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(ref cache) = self.chunk_cache {
+            if let Some(data) = cache.get(digest) {
+                return Ok(data);
+            }
+        }
+
+        let mut data = vec![];
+        self.accessor
+            .read_chunk_into(DigestRef(digest), data_type, &mut data)?;
+        let data = Arc::new(data);
+
+        if let Some(ref cache) = self.chunk_cache {
+            cache.insert(digest, Arc::clone(&data));
+        }
+        Ok(data)
+    }
+
+    /// Find the data chunk containing `pos`: its offset and digest
+    fn locate_v2(&mut self, pos: u64) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let mut digest = self.address.digest.0.clone();
+        let mut start = 0;
+
+        for level in (0..self.address.index_level as usize).rev() {
+            let cached = match self.index_cache[level] {
+                Some((ref cached_digest, _)) => *cached_digest == digest,
+                None => false,
+            };
+            if !cached {
+                let data = self.read_chunk(&digest, DataType::Index)?;
+                self.index_cache[level] = Some((digest.clone(), data));
+            }
+
+            let data = &self.index_cache[level].as_ref().unwrap().1;
+            let mut child = None;
+            for (child_digest, len) in parse_index_v2(data)? {
+                if pos < start + len {
+                    child = Some(child_digest.to_vec());
+                    break;
+                }
+                start += len;
+            }
+
+            digest = match child {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+
+        Ok(Some((start, digest)))
+    }
+
+    fn load_legacy_index(&self) -> io::Result<LegacyIndex> {
+        // every index level is a stream of digests of the level below
+        let mut digests = self.address.digest.0.clone();
+        for _ in 0..self.address.index_level {
+            let mut next = vec![];
+            for digest in digests.chunks(DIGEST_SIZE) {
+                self.accessor.read_chunk_into(
+                    DigestRef(digest),
+                    DataType::Index,
+                    &mut next,
+                )?;
+            }
+            digests = next;
+        }
+
+        if digests.len() % DIGEST_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "index of invalid size",
+            ));
+        }
+
+        Ok(LegacyIndex {
+            digests: digests.chunks(DIGEST_SIZE).map(|d| d.to_vec()).collect(),
+            offsets: vec![0],
+        })
+    }
+
+    /// Read the data chunk containing `pos`: its offset and data
+    fn locate_legacy(
+        &mut self,
+        pos: u64,
+    ) -> io::Result<Option<(u64, Arc<Vec<u8>>)>> {
+        if self.legacy_index.is_none() {
+            self.legacy_index = Some(self.load_legacy_index()?);
+        }
+
+        loop {
+            let (digest, start) = {
+                let index = self.legacy_index.as_ref().unwrap();
+                let known = index.offsets.len() - 1;
+                if pos < index.offsets[known] {
+                    // the last chunk starting before `pos`
+                    // (skipping empty ones)
+                    let i = index.offsets[..known]
+                        .iter()
+                        .rposition(|&offset| offset <= pos)
+                        .unwrap();
+                    let data =
+                        self.read_chunk(&index.digests[i], DataType::Data)?;
+                    return Ok(Some((index.offsets[i], data)));
+                }
+                if known == index.digests.len() {
+                    return Ok(None);
+                }
+                (index.digests[known].clone(), index.offsets[known])
+            };
+
+            // learn the length of the next chunk
+            let data = self.read_chunk(&digest, DataType::Data)?;
+            let end = start + data.len() as u64;
+            self.legacy_index.as_mut().unwrap().offsets.push(end);
+            if pos < end {
+                return Ok(Some((start, data)));
+            }
+        }
+    }
+
+    fn load_chunk(
+        &mut self,
+        pos: u64,
+    ) -> io::Result<Option<(u64, Arc<Vec<u8>>)>> {
+        match self.address.index_format {
+            IndexFormat::V1 => self.locate_legacy(pos),
+            IndexFormat::V2 => match self.locate_v2(pos)? {
+                Some((start, digest)) => Ok(Some((
+                    start,
+                    self.read_chunk(&digest, DataType::Data)?,
+                ))),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+impl<'a> Read for NameReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let loaded = match self.chunk {
+            Some((start, ref data)) => {
+                start <= self.pos && self.pos < start + data.len() as u64
+            }
+            None => false,
+        };
+        if !loaded {
+            let pos = self.pos;
+            self.chunk = self.load_chunk(pos)?;
+        }
+
+        let &(start, ref data) = match self.chunk {
+            Some(ref chunk) => chunk,
+            None => return Ok(0),
+        };
+        let offset = (self.pos - start) as usize;
+        if offset >= data.len() {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len(), data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a> Seek for NameReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => add_offset(self.pos, offset),
+            SeekFrom::End(offset) => add_offset(self.data_len()?, offset),
+        };
+
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
 // vim: foldmethod=marker foldmarker={{{,}}}
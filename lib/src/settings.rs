@@ -1,6 +1,7 @@
 //! Settings: options that user can pick
 
 use config;
+use pwhash;
 use std::io;
 
 #[derive(Clone)]
@@ -13,6 +14,10 @@ pub enum Compression {
     Bzip2,
     #[cfg(feature = "with-zstd")]
     Zstd,
+    #[cfg(feature = "with-lz4")]
+    Lz4,
+    #[cfg(feature = "with-brotli")]
+    Brotli,
     None,
 }
 
@@ -26,26 +31,43 @@ impl Default for Compression {
 }
 
 impl Compression {
-    pub fn to_config(&self, _level: i32) -> config::Compression {
-        match *self {
+    /// Config of the compression, at `level` as defined by the algorithm
+    /// (`None` for its default)
+    ///
+    /// Fails if the algorithm doesn't support `level`.
+    pub fn to_config(
+        &self,
+        level: Option<i32>,
+    ) -> super::Result<config::Compression> {
+        let mut compression = match *self {
             #[cfg(feature = "with-deflate")]
             Compression::Deflate => {
-                config::Compression::Deflate(config::Deflate::new(_level))
+                config::Compression::Deflate(config::Deflate::new(None))
             }
             #[cfg(feature = "with-xz2")]
             Compression::Xz2 => {
-                config::Compression::Xz2(config::Xz2::new(_level))
+                config::Compression::Xz2(config::Xz2::new(None))
             }
             #[cfg(feature = "with-bzip2")]
             Compression::Bzip2 => {
-                config::Compression::Bzip2(config::Bzip2::new(_level))
+                config::Compression::Bzip2(config::Bzip2::new(None))
             }
             #[cfg(feature = "with-zstd")]
             Compression::Zstd => {
-                config::Compression::Zstd(config::Zstd::new(_level))
+                config::Compression::Zstd(config::Zstd::new(None))
+            }
+            #[cfg(feature = "with-lz4")]
+            Compression::Lz4 => {
+                config::Compression::Lz4(config::Lz4::new(None))
+            }
+            #[cfg(feature = "with-brotli")]
+            Compression::Brotli => {
+                config::Compression::Brotli(config::Brotli::new(None))
             }
             Compression::None => config::Compression::None,
-        }
+        };
+        compression.set_level(level)?;
+        Ok(compression)
     }
 }
 
@@ -70,6 +92,42 @@ pub enum PWHash {
     Weak,
     Interactive,
     Strong,
+    /// Argon2id; limits default to libsodium's "moderate" preset
+    Argon2id {
+        ops_limit: Option<u64>,
+        mem_limit: Option<u64>,
+    },
+}
+
+impl PWHash {
+    /// Argon2id with custom limits
+    ///
+    /// `mem_limit` is in bytes.
+    pub fn argon2id(
+        ops_limit: Option<u64>,
+        mem_limit: Option<u64>,
+    ) -> super::Result<Self> {
+        if ops_limit.map_or(false, |l| l < pwhash::ARGON2ID_OPS_LIMIT_MIN) {
+            return Err(super::Error::InvalidInput(
+                format!(
+                    "argon2id ops limit must be at least {}",
+                    pwhash::ARGON2ID_OPS_LIMIT_MIN
+                ),
+            ));
+        }
+        if mem_limit.map_or(false, |l| l < pwhash::ARGON2ID_MEM_LIMIT_MIN) {
+            return Err(super::Error::InvalidInput(
+                format!(
+                    "argon2id memory limit must be at least {} bytes",
+                    pwhash::ARGON2ID_MEM_LIMIT_MIN
+                ),
+            ));
+        }
+        Ok(PWHash::Argon2id {
+            ops_limit,
+            mem_limit,
+        })
+    }
 }
 
 impl Default for PWHash {
@@ -84,6 +142,10 @@ impl<'a> From<&'a str> for PWHash {
             "weak" => PWHash::Weak,
             "interactive" => PWHash::Interactive,
             "strong" => PWHash::Strong,
+            "argon2id" => PWHash::Argon2id {
+                ops_limit: None,
+                mem_limit: None,
+            },
             _ => panic!("Wrong pwhash strenght string"),
         }
     }
@@ -103,6 +165,28 @@ impl Nesting {
     }
 }
 
+// Unlike encryption, settings == config here
+#[derive(Clone, Default)]
+pub struct Layout(pub(crate) config::Layout);
+
+#[derive(Clone)]
+pub struct IndexFormat(pub(crate) config::IndexFormat);
+
+impl Default for IndexFormat {
+    fn default() -> Self {
+        IndexFormat(config::IndexFormat::V2)
+    }
+}
+
+#[derive(Clone)]
+pub struct ChunkFormat(pub(crate) config::ChunkFormat);
+
+impl Default for ChunkFormat {
+    fn default() -> Self {
+        ChunkFormat(config::ChunkFormat::V2)
+    }
+}
+
 #[derive(Clone)]
 pub enum Hashing {
     Sha256,
@@ -129,10 +213,14 @@ pub struct Repo {
     pub(crate) pwhash: PWHash,
     pub(crate) encryption: Encryption,
     pub(crate) compression: Compression,
-    pub(crate) compression_level: i32,
+    pub(crate) compression_level: Option<i32>,
+    pub(crate) adaptive_compression: Option<u8>,
     pub(crate) chunking: Chunking,
     pub(crate) nesting: Nesting,
     pub(crate) hashing: Hashing,
+    pub(crate) layout: Layout,
+    pub(crate) index_format: IndexFormat,
+    pub(crate) chunk_format: ChunkFormat,
 }
 
 impl Repo {
@@ -157,8 +245,34 @@ impl Repo {
         self.pwhash = pwhash;
     }
 
+    /// Set the compression level, as defined by the algorithm (eg. 1-22
+    /// for zstd, or negative for its fast levels); the algorithm's default
+    /// level if not set
+    ///
+    /// The level is checked by `Repo::init`, as the valid range depends on
+    /// the compression.
     pub fn set_compression_level(&mut self, level: i32) {
-        self.compression_level = level;
+        self.compression_level = Some(level);
+    }
+
+    /// Store data chunks uncompressed if compressing them doesn't save at
+    /// least `min_saving_percent` of their length (`None` to always
+    /// compress)
+    ///
+    /// Saves CPU time on data that is already compressed (media files,
+    /// compressed or encrypted archives). Not supported with
+    /// `use_legacy_chunk_format`.
+    pub fn set_adaptive_compression(
+        &mut self,
+        min_saving_percent: Option<u8>,
+    ) -> super::Result<()> {
+        if min_saving_percent.map_or(false, |percent| percent > 100) {
+            return Err(super::Error::InvalidInput(
+                "minimum compression saving can't be over 100%".into(),
+            ));
+        }
+        self.adaptive_compression = min_saving_percent;
+        Ok(())
     }
 
     pub fn set_hashing(&mut self, hashing: Hashing) -> io::Result<()> {
@@ -171,9 +285,8 @@ impl Repo {
         let chunking = config::Chunking::Bup { chunk_bits: bits };
 
         if !chunking.valid() {
-            return Err(super::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid chunking algorithm defined",
+            return Err(super::Error::InvalidInput(
+                "invalid chunking algorithm defined".into(),
             ));
         }
         self.chunking = Chunking(chunking);
@@ -188,9 +301,8 @@ impl Repo {
         let chunking = config::Chunking::FastCDC { chunk_bits: bits };
 
         if !chunking.valid() {
-            return Err(super::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid chunking algorithm defined",
+            return Err(super::Error::InvalidInput(
+                "invalid chunking algorithm defined".into(),
             ));
         }
         self.chunking = Chunking(chunking);
@@ -205,9 +317,8 @@ impl Repo {
         let chunking = config::Chunking::Gear { chunk_bits: bits };
 
         if !chunking.valid() {
-            return Err(super::Error::new(
-                io::ErrorKind::InvalidInput,
-                "invalid chunking algorithm defined",
+            return Err(super::Error::InvalidInput(
+                "invalid chunking algorithm defined".into(),
             ));
         }
         self.chunking = Chunking(chunking);
@@ -216,12 +327,49 @@ impl Repo {
 
     pub fn set_nesting(&mut self, level: u8) -> super::Result<()> {
         if level > 31 {
-            return Err(super::Error::new(
-                io::ErrorKind::InvalidInput,
-                "nesting can't be greater than or equal to 32",
+            return Err(super::Error::InvalidInput(
+                "nesting can't be greater than or equal to 32".into(),
             ));
         }
         self.nesting = Nesting(level);
         Ok(())
     }
+
+    /// Store every chunk in a separate file (default)
+    pub fn use_file_layout(&mut self) {
+        self.layout = Layout(config::Layout::Files);
+    }
+
+    /// Store chunks in pack files of around `pack_size` bytes
+    pub fn use_pack_layout(
+        &mut self,
+        pack_size: Option<u64>,
+    ) -> super::Result<()> {
+        let pack_size = pack_size.unwrap_or(config::DEFAULT_PACK_SIZE);
+        if pack_size == 0 {
+            return Err(super::Error::InvalidInput(
+                "pack size can't be zero".into(),
+            ));
+        }
+        self.layout = Layout(config::Layout::Packs { pack_size });
+        Ok(())
+    }
+
+    /// Don't record data lengths in the index
+    ///
+    /// Repositories created this way can be used by older versions
+    /// of `rdedup`, but seeking in stored data is slow.
+    pub fn use_legacy_index(&mut self) {
+        self.index_format = IndexFormat(config::IndexFormat::V1);
+    }
+
+    /// Don't prefix chunks with a header describing their encoding
+    ///
+    /// Repositories created this way can be used by older versions
+    /// of `rdedup`, but all their chunks have to be compressed and
+    /// encrypted the same way. They can be upgraded to chunk headers later
+    /// (`Repo::upgrade_chunk_format`).
+    pub fn use_legacy_chunk_format(&mut self) {
+        self.chunk_format = ChunkFormat(config::ChunkFormat::V1);
+    }
 }
@@ -4,8 +4,11 @@ mod lib {
 
 use url::Url;
 
+use aio;
+use chrono;
 use hex;
 use iterators::StoredChunks;
+use lib::{Backend, BackendThread};
 use rand::{self, Rng};
 use settings;
 use sha2::{Digest, Sha256};
@@ -18,6 +21,8 @@ use std::fs::OpenOptions;
 use std::io::{Result, Write};
 use std::path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::{self, fs};
 
 const PASS: &'static str = "FOO";
@@ -32,6 +37,16 @@ fn rand_tmp_dir() -> path::PathBuf {
     )
 }
 
+fn rand_mem_url() -> Url {
+    Url::parse(&format!(
+        "mem:{}",
+        rand::thread_rng()
+            .gen_ascii_chars()
+            .take(20)
+            .collect::<String>()
+    )).unwrap()
+}
+
 fn list_stored_chunks(repo: &lib::Repo) -> Result<HashSet<Vec<u8>>> {
     let mut digests = HashSet::new();
     let data_chunks = StoredChunks::new(
@@ -48,11 +63,14 @@ fn list_stored_chunks(repo: &lib::Repo) -> Result<HashSet<Vec<u8>>> {
 }
 
 fn test_repo(pass: &str) -> lib::Repo {
+    test_repo_at(&rand_mem_url(), pass)
+}
+
+fn test_repo_at(url: &Url, pass: &str) -> lib::Repo {
     let mut settings = settings::Repo::new();
     // Make it fasts to use
     settings.set_pwhash(settings::PWHash::Weak);
-    let url = Url::from_file_path(rand_tmp_dir()).unwrap();
-    lib::Repo::init(&url, &|| Ok(pass.into()), settings, None).unwrap()
+    lib::Repo::init(url, &|| Ok(pass.into()), settings, None).unwrap()
 }
 
 fn test_repo_dir(pass: &str) -> (lib::Repo, PathBuf) {
@@ -301,6 +319,980 @@ fn change_passphrase() {
     wipe(&repo);
 }
 
+#[test]
+fn key_slots() {
+    let url = rand_mem_url();
+    let data_before = rand_data(1024);
+
+    {
+        let repo = test_repo_at(&url, "default-pass");
+        let enc_handle =
+            repo.unlock_encrypt(&|| Ok("default-pass".into())).unwrap();
+        repo.write("data", &mut io::Cursor::new(&data_before), &enc_handle)
+            .unwrap();
+    }
+
+    let mut repo = lib::Repo::open(&url, None).unwrap();
+    repo.add_key_slot("ops", &|| Ok("default-pass".into()), &|| {
+        Ok("ops-pass".into())
+    }).unwrap();
+    // unlocking with any slot works
+    repo.add_key_slot("audit", &|| Ok("ops-pass".into()), &|| {
+        Ok("audit-pass".into())
+    }).unwrap();
+    assert!(
+        repo.add_key_slot("ops", &|| Ok("ops-pass".into()), &|| {
+            Ok("x".into())
+        }).is_err()
+    );
+    assert!(
+        repo.add_key_slot("x", &|| Ok("wrong".into()), &|| Ok("x".into()))
+            .is_err()
+    );
+    assert_eq!(repo.list_key_slots(), vec!["default", "ops", "audit"]);
+
+    // change passphrase of the slot that was used
+    repo.change_passphrase(&|| Ok("audit-pass".into()), &|| {
+        Ok("audit-pass2".into())
+    }).unwrap();
+
+    let mut repo = lib::Repo::open(&url, None).unwrap();
+    for pass in &["default-pass", "ops-pass", "audit-pass2"] {
+        let dec_handle = repo.unlock_decrypt(&|| Ok((*pass).into())).unwrap();
+        let mut data_after = vec![];
+        repo.read("data", &mut data_after, &dec_handle).unwrap();
+        assert_eq!(data_before, data_after);
+    }
+    assert!(repo.unlock_decrypt(&|| Ok("audit-pass".into())).is_err());
+
+    assert!(repo.revoke_key_slot("nope").is_err());
+    repo.revoke_key_slot("ops").unwrap();
+
+    let mut repo = lib::Repo::open(&url, None).unwrap();
+    assert_eq!(repo.list_key_slots(), vec!["default", "audit"]);
+    assert!(repo.unlock_decrypt(&|| Ok("ops-pass".into())).is_err());
+    assert!(repo.unlock_decrypt(&|| Ok("audit-pass2".into())).is_ok());
+
+    // the default slot can be revoked too, but not the last passphrase slot
+    repo.revoke_key_slot("default").unwrap();
+    assert!(repo.revoke_key_slot("default").is_err());
+    assert!(repo.revoke_key_slot("audit").is_err());
+
+    let mut repo = lib::Repo::open(&url, None).unwrap();
+    assert_eq!(repo.list_key_slots(), vec!["audit"]);
+    assert!(repo.unlock_decrypt(&|| Ok("default-pass".into())).is_err());
+    let dec_handle =
+        repo.unlock_decrypt(&|| Ok("audit-pass2".into())).unwrap();
+    let mut data_after = vec![];
+    repo.read("data", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data_before, data_after);
+    repo.change_passphrase(&|| Ok("audit-pass2".into()), &|| {
+        Ok("audit-pass3".into())
+    }).unwrap();
+    assert!(repo.unlock_decrypt(&|| Ok("audit-pass3".into())).is_ok());
+}
+
+#[test]
+fn keyfile_slots() {
+    let mut repo = test_repo("foo");
+    let data_before = rand_data(1024);
+    let enc_handle = repo.unlock_encrypt(&|| Ok("foo".into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(&data_before), &enc_handle)
+        .unwrap();
+
+    assert!(repo.add_keyfile_slot("ci", &|| Ok("bar".into())).is_err());
+    let mut keyfile_data = vec![];
+    repo.add_keyfile_slot("ci", &|| Ok("foo".into()))
+        .unwrap()
+        .write(&mut keyfile_data)
+        .unwrap();
+    assert!(repo.add_keyfile_slot("ci", &|| Ok("foo".into())).is_err());
+    assert_eq!(repo.list_key_slots(), vec!["default", "ci"]);
+
+    let keyfile =
+        lib::Keyfile::read(&mut io::Cursor::new(&keyfile_data)).unwrap();
+    let dec_handle = repo.unlock_decrypt_with_keyfile(&keyfile).unwrap();
+    let mut data_after = vec![];
+    repo.read("data", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data_before, data_after);
+
+    // keyfile slots don't count as the last passphrase slot
+    assert!(repo.revoke_key_slot("default").is_err());
+
+    repo.revoke_key_slot("ci").unwrap();
+    assert!(repo.unlock_decrypt_with_keyfile(&keyfile).is_err());
+}
+
+#[test]
+fn argon2id_pwhash() {
+    // smallest allowed limits, to make it fast
+    let argon2id = || settings::PWHash::argon2id(Some(1), Some(8192)).unwrap();
+    assert!(settings::PWHash::argon2id(Some(0), None).is_err());
+    assert!(settings::PWHash::argon2id(None, Some(1024)).is_err());
+
+    let data_before = rand_data(1024);
+    let url = rand_mem_url();
+    {
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(argon2id());
+        let repo =
+            lib::Repo::init(&url, &|| Ok("foo".into()), settings, None)
+                .unwrap();
+        let enc_handle = repo.unlock_encrypt(&|| Ok("foo".into())).unwrap();
+        repo.write("data", &mut io::Cursor::new(&data_before), &enc_handle)
+            .unwrap();
+    }
+    let repo = lib::Repo::open(&url, None).unwrap();
+    assert!(repo.unlock_decrypt(&|| Ok("bar".into())).is_err());
+    let dec_handle = repo.unlock_decrypt(&|| Ok("foo".into())).unwrap();
+    let mut data_after = vec![];
+    repo.read("data", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data_before, data_after);
+
+    // migrate an existing (scrypt) repository, including a key slot
+    let url = rand_mem_url();
+    {
+        let repo = test_repo_at(&url, "foo");
+        let enc_handle = repo.unlock_encrypt(&|| Ok("foo".into())).unwrap();
+        repo.write("data", &mut io::Cursor::new(&data_before), &enc_handle)
+            .unwrap();
+    }
+    let mut repo = lib::Repo::open(&url, None).unwrap();
+    repo.add_key_slot("other", &|| Ok("foo".into()), &|| Ok("baz".into()))
+        .unwrap();
+    repo.change_passphrase_with_pwhash(
+        &|| Ok("foo".into()),
+        &|| Ok("bar".into()),
+        argon2id(),
+    ).unwrap();
+    repo.change_passphrase_with_pwhash(
+        &|| Ok("baz".into()),
+        &|| Ok("qux".into()),
+        argon2id(),
+    ).unwrap();
+
+    let repo = lib::Repo::open(&url, None).unwrap();
+    assert!(repo.unlock_decrypt(&|| Ok("foo".into())).is_err());
+    assert!(repo.unlock_decrypt(&|| Ok("baz".into())).is_err());
+    for pass in &["bar", "qux"] {
+        let dec_handle = repo.unlock_decrypt(&|| Ok((*pass).into())).unwrap();
+        let mut data_after = vec![];
+        repo.read("data", &mut data_after, &dec_handle).unwrap();
+        assert_eq!(data_before, data_after);
+    }
+}
+
+#[test]
+fn keyfile_unlock() {
+    let repo = test_repo("foo");
+    let data_before = rand_data(1024);
+    let enc_handle = repo.unlock_encrypt(&|| Ok("foo".into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(&data_before), &enc_handle)
+        .unwrap();
+
+    assert!(repo.export_keyfile(&|| Ok("bar".into())).is_err());
+    let mut keyfile_data = vec![];
+    repo.export_keyfile(&|| Ok("foo".into()))
+        .unwrap()
+        .write(&mut keyfile_data)
+        .unwrap();
+
+    let keyfile =
+        lib::Keyfile::read(&mut io::Cursor::new(&keyfile_data)).unwrap();
+    let dec_handle = repo.unlock_decrypt_with_keyfile(&keyfile).unwrap();
+    let mut data_after = vec![];
+    repo.read("data", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data_before, data_after);
+
+    // keyfile of a different repository
+    let other_repo = test_repo("foo");
+    assert!(other_repo.unlock_decrypt_with_keyfile(&keyfile).is_err());
+
+    // secret key of a different repository, with the right public key
+    let mut other_keyfile_data = vec![];
+    other_repo
+        .export_keyfile(&|| Ok("foo".into()))
+        .unwrap()
+        .write(&mut other_keyfile_data)
+        .unwrap();
+    let pub_key_line = |data: &[u8]| {
+        String::from_utf8(data.to_vec())
+            .unwrap()
+            .lines()
+            .find(|line| line.trim_start().starts_with("pub_key:"))
+            .unwrap()
+            .to_owned()
+    };
+    let mixed_keyfile_data = String::from_utf8(other_keyfile_data.clone())
+        .unwrap()
+        .replace(
+            &pub_key_line(&other_keyfile_data),
+            &pub_key_line(&keyfile_data),
+        );
+    assert_eq!(
+        lib::Keyfile::read(&mut io::Cursor::new(&mixed_keyfile_data))
+            .and_then(|keyfile| {
+                repo.unlock_decrypt_with_keyfile(&keyfile)
+                    .map_err(|e| e.into())
+            })
+            .err()
+            .unwrap()
+            .kind(),
+        io::ErrorKind::InvalidData
+    );
+}
+
+fn check_name_reader(repo: &lib::Repo, name: &str, data: &[u8]) {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut reader = repo.name_reader(name, &dec_handle).unwrap();
+    reader.set_chunk_cache(Arc::new(lib::ChunkCache::new(16 * 1024)));
+
+    let mut read_data = vec![];
+    reader.read_to_end(&mut read_data).unwrap();
+    assert_eq!(read_data, data);
+
+    assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+    assert!(reader.seek(SeekFrom::End(-(data.len() as i64) - 1)).is_err());
+
+    for _ in 0..100 {
+        let pos = rand::thread_rng().gen_range(0, data.len() + 2);
+        let len = rand::thread_rng().gen_range(0, 3000);
+        assert_eq!(
+            reader.seek(SeekFrom::Start(pos as u64)).unwrap(),
+            pos as u64
+        );
+
+        let mut buf = vec![0u8; len];
+        let mut read = 0;
+        loop {
+            let n = reader.read(&mut buf[read..]).unwrap();
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+
+        let start = cmp::min(pos, data.len());
+        let end = cmp::min(pos + len, data.len());
+        assert_eq!(&buf[..read], &data[start..end]);
+    }
+}
+
+#[test]
+fn name_reader_seek() {
+    for &legacy in &[false, true] {
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(settings::PWHash::Weak);
+        // small chunks, for multiple index levels
+        settings.use_fastcdc_chunking(Some(10)).unwrap();
+        if legacy {
+            settings.use_legacy_index();
+        }
+        let repo = lib::Repo::init(
+            &rand_mem_url(),
+            &|| Ok(PASS.into()),
+            settings,
+            None,
+        ).unwrap();
+        let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+        for &len in &[0, 1, 100, 5000, 512 * 1024] {
+            let data = rand_data(len);
+            let name = format!("data{}", len);
+            repo.write(&name, &mut io::Cursor::new(&data), &enc_handle)
+                .unwrap();
+
+            check_name_reader(&repo, &name, &data);
+        }
+    }
+}
+
+#[test]
+fn chunk_cache_lru() {
+    let cache = lib::ChunkCache::new(10);
+    cache.insert(b"a", Arc::new(vec![0; 4]));
+    cache.insert(b"b", Arc::new(vec![0; 4]));
+    // too big to be cached at all
+    cache.insert(b"c", Arc::new(vec![0; 11]));
+    assert!(cache.get(b"c").is_none());
+
+    // `b` is now the least recently used
+    assert!(cache.get(b"a").is_some());
+    cache.insert(b"d", Arc::new(vec![0; 4]));
+    assert!(cache.get(b"b").is_none());
+    assert!(cache.get(b"a").is_some());
+    assert!(cache.get(b"d").is_some());
+}
+
+#[test]
+fn mem_reopen() {
+    let url = rand_mem_url();
+    let data = rand_data(1024);
+    {
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(settings::PWHash::Weak);
+        let repo =
+            lib::Repo::init(&url, &|| Ok(PASS.into()), settings, None).unwrap();
+        let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+        repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+    }
+
+    let repo = lib::Repo::open(&url, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut data_after = vec![];
+    repo.read("data", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data, data_after);
+
+    assert!(
+        lib::Repo::open(&rand_mem_url(), None).is_err(),
+        "fresh mem repo should not be initialized"
+    );
+
+    drop(repo);
+    assert!(lib::Repo::remove_mem(&url).unwrap());
+    assert!(lib::Repo::open(&url, None).is_err());
+    assert!(
+        lib::Repo::remove_mem(&Url::from_file_path("/tmp/x").unwrap()).is_err()
+    );
+}
+
+/// A custom backend, counting writes and chunk lookups (metadata reads
+/// of data chunks) of the backend it wraps
+struct CountingBackend {
+    inner: aio::Mem,
+    writes: Arc<AtomicUsize>,
+    chunk_lookups: Arc<AtomicUsize>,
+}
+
+struct CountingBackendThread {
+    inner: Box<dyn BackendThread>,
+    writes: Arc<AtomicUsize>,
+    chunk_lookups: Arc<AtomicUsize>,
+}
+
+impl Backend for CountingBackend {
+    fn lock_exclusive(&self) -> Result<Box<dyn lib::Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> Result<Box<dyn lib::Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn new_thread(&self) -> Result<Box<dyn BackendThread>> {
+        Ok(Box::new(CountingBackendThread {
+            inner: self.inner.new_thread()?,
+            writes: self.writes.clone(),
+            chunk_lookups: self.chunk_lookups.clone(),
+        }))
+    }
+}
+
+impl BackendThread for CountingBackendThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> Result<()> {
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> Result<()> {
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(
+        &mut self,
+        path: PathBuf,
+        sg: lib::SGData,
+        idempotent: bool,
+    ) -> Result<()> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.write(path, sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> Result<lib::SGData> {
+        self.inner.read(path)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> Result<lib::Metadata> {
+        if path
+            .components()
+            .any(|c| c.as_os_str() == lib::config::DATA_SUBDIR)
+        {
+            self.chunk_lookups.fetch_add(1, Ordering::SeqCst);
+        }
+        self.inner.read_metadata(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> Result<Vec<PathBuf>> {
+        self.inner.list(path)
+    }
+
+    fn list_recursively(
+        &mut self,
+        path: PathBuf,
+        tx: mpsc::Sender<Result<Vec<PathBuf>>>,
+    ) {
+        self.inner.list_recursively(path, tx)
+    }
+}
+
+#[test]
+fn custom_backend() {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let backend = Arc::new(CountingBackend {
+        inner: aio::Mem::new(rand_mem_url().path()),
+        writes: writes.clone(),
+        chunk_lookups: Arc::new(AtomicUsize::new(0)),
+    });
+    let data = rand_data(1024);
+    {
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(settings::PWHash::Weak);
+        let repo = lib::Repo::init_with_backend(
+            backend.clone(),
+            &|| Ok(PASS.into()),
+            settings,
+            None,
+        ).unwrap();
+        let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+        repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+    }
+    assert!(writes.load(Ordering::SeqCst) > 0);
+
+    let repo = lib::Repo::open_with_backend(backend, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let mut data_after = vec![];
+    repo.read("data", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data, data_after);
+}
+
+#[test]
+fn chunk_cache() {
+    let chunk_lookups = Arc::new(AtomicUsize::new(0));
+    let backend = Arc::new(CountingBackend {
+        inner: aio::Mem::new(rand_mem_url().path()),
+        writes: Arc::new(AtomicUsize::new(0)),
+        chunk_lookups: chunk_lookups.clone(),
+    });
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    let mut repo = lib::Repo::init_with_backend(
+        backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    ).unwrap();
+    let cache_dir = rand_tmp_dir();
+    repo.set_chunk_cache_dir(Some(cache_dir.clone()));
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(512 * 1024);
+    // store `data` as `name`; returns chunk lookups and new chunks
+    let store = |repo: &lib::Repo, name: &str| {
+        let before = chunk_lookups.load(Ordering::SeqCst);
+        let stats = repo
+            .write(name, &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+        (
+            chunk_lookups.load(Ordering::SeqCst) - before,
+            stats.new_chunks,
+        )
+    };
+
+    let (lookups, new_chunks) = store(&repo, "a");
+    assert!(lookups > 0);
+    assert!(new_chunks > 0);
+
+    // every chunk is a hit now
+    assert_eq!(store(&repo, "b"), (0, 0));
+
+    // misses fall back to the backend
+    repo.set_chunk_cache_dir(Some(rand_tmp_dir()));
+    let (lookups, new_chunks) = store(&repo, "c");
+    assert!(lookups > 0);
+    assert_eq!(new_chunks, 0);
+    repo.set_chunk_cache_dir(Some(cache_dir.clone()));
+
+    // `gc` removes the generation, and its chunk cache with it
+    let old_gen = repo.read_generations().unwrap()[0];
+    let cache_file = cache_dir.join(format!("{}.chunks", old_gen));
+    assert!(cache_file.exists());
+    repo.gc(0).unwrap();
+    let generations = repo.read_generations().unwrap();
+    assert_eq!(generations.len(), 1);
+    assert!(generations[0] != old_gen);
+    assert!(!cache_file.exists());
+
+    // chunks moved by `gc` are found in the backend
+    let (lookups, new_chunks) = store(&repo, "d");
+    assert!(lookups > 0);
+    assert_eq!(new_chunks, 0);
+    assert_eq!(store(&repo, "e"), (0, 0));
+
+    for name in &["a", "b", "c", "d", "e"] {
+        let mut read = vec![];
+        repo.read(name, &mut read, &dec_handle).unwrap();
+        assert_eq!(read, data);
+    }
+}
+
+#[test]
+fn pack_layout() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_pack_layout(Some(64 * 1024)).unwrap();
+    let repo =
+        lib::Repo::init(&rand_mem_url(), &|| Ok(PASS.into()), settings, None)
+            .unwrap();
+
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data_a = rand_data(1024 * 1024);
+    let data_b = rand_data(256 * 1024);
+    repo.write("a", &mut io::Cursor::new(&data_a), &enc_handle)
+        .unwrap();
+    repo.write("b", &mut io::Cursor::new(&data_b), &enc_handle)
+        .unwrap();
+
+    let check = |name: &str, data: &[u8]| {
+        let mut read = vec![];
+        repo.read(name, &mut read, &dec_handle).unwrap();
+        assert_eq!(read.as_slice(), data);
+        assert!(repo.verify(name, &dec_handle).unwrap().errors.is_empty());
+    };
+    check("a", &data_a);
+    check("b", &data_b);
+
+    // moves all the chunks to a new generation
+    repo.gc(0).unwrap();
+    assert_eq!(repo.read_generations().unwrap().len(), 1);
+    check("a", &data_a);
+    check("b", &data_b);
+
+    repo.rm("a").unwrap();
+    repo.gc(0).unwrap();
+    check("b", &data_b);
+
+    wipe(&repo);
+
+    // needs ranged reads, which `CountingBackend` doesn't do
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.use_pack_layout(None).unwrap();
+    let backend = Arc::new(CountingBackend {
+        inner: aio::Mem::new(rand_mem_url().path()),
+        writes: Arc::new(AtomicUsize::new(0)),
+        chunk_lookups: Arc::new(AtomicUsize::new(0)),
+    });
+    match lib::Repo::init_with_backend(
+        backend,
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    ) {
+        Err(lib::Error::InvalidInput(_)) => {}
+        res => panic!("unexpected: {:?}", res.err()),
+    }
+}
+
+#[test]
+fn chunk_format() {
+    use config;
+    use DigestRef;
+
+    fn chunks_with_header(repo: &lib::Repo) -> usize {
+        let gen_str = repo.read_generations().unwrap()[0].to_string();
+        let store = repo.chunk_store(&repo.aio);
+        list_stored_chunks(repo)
+            .unwrap()
+            .iter()
+            .filter(|digest| {
+                let chunk = store.read(DigestRef(digest), &gen_str).unwrap();
+                chunk.to_linear_vec().starts_with(b"rdch\x01")
+            })
+            .count()
+    }
+
+    for &legacy in &[false, true] {
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(settings::PWHash::Weak);
+        if legacy {
+            settings.use_legacy_chunk_format();
+        }
+        let url = rand_mem_url();
+        let mut repo =
+            lib::Repo::init(&url, &|| Ok(PASS.into()), settings, None).unwrap();
+        let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+        let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+        let data = rand_data(1024 * 1024);
+        repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+
+        assert_eq!(chunks_with_header(&repo) > 0, !legacy);
+
+        if legacy {
+            // chunks with and without a header coexist after the upgrade
+            repo.upgrade_chunk_format().unwrap();
+            repo = lib::Repo::open(&url, None).unwrap();
+            assert_eq!(repo.config.chunk_format, config::ChunkFormat::V2);
+            assert!(repo.config.version >= config::REPO_VERSION_CHUNK_HEADER);
+        } else {
+            // chunks compressed differently are decoded per chunk
+            repo.config.compression = config::Compression::None;
+            repo.compression = repo.config.compression.to_engine();
+        }
+        let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+        let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+        let with_header = chunks_with_header(&repo);
+        let more_data = rand_data(1024 * 1024);
+        repo.write("more", &mut io::Cursor::new(&more_data), &enc_handle)
+            .unwrap();
+        assert!(chunks_with_header(&repo) > with_header);
+
+        let mut read = vec![];
+        repo.read("more", &mut read, &dec_handle).unwrap();
+        assert!(read == more_data);
+        assert!(repo.verify("more", &dec_handle).unwrap().errors.is_empty());
+
+        let mut read = vec![];
+        repo.read("data", &mut read, &dec_handle).unwrap();
+        assert!(read == data);
+        assert!(repo.verify("data", &dec_handle).unwrap().errors.is_empty());
+
+        wipe(&repo);
+    }
+}
+
+#[test]
+fn adaptive_compression() {
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_adaptive_compression(Some(10)).unwrap();
+    let repo = lib::Repo::init(
+        &rand_mem_url(),
+        &|| Ok(PASS.into()),
+        settings.clone(),
+        None,
+    ).unwrap();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let random = rand_data(1024 * 1024);
+    let stats = repo
+        .write("random", &mut io::Cursor::new(&random), &enc_handle)
+        .unwrap();
+    assert!(stats.uncompressed_chunks > 0);
+
+    let text: Vec<u8> = b"all work and no play makes jack a dull boy\n"
+        .iter()
+        .cycle()
+        .take(1024 * 1024)
+        .cloned()
+        .collect();
+    let stats = repo
+        .write("text", &mut io::Cursor::new(&text), &enc_handle)
+        .unwrap();
+    assert_eq!(stats.uncompressed_chunks, 0);
+    assert!(stats.new_bytes < text.len() as u64 / 10);
+
+    for &(name, data) in &[("random", &random), ("text", &text)] {
+        let mut read = vec![];
+        repo.read(name, &mut read, &dec_handle).unwrap();
+        assert!(&read == data);
+    }
+    wipe(&repo);
+
+    // needs chunk headers
+    settings.use_legacy_chunk_format();
+    match lib::Repo::init(&rand_mem_url(), &|| Ok(PASS.into()), settings, None)
+    {
+        Err(lib::Error::InvalidInput(_)) => {}
+        res => panic!("unexpected: {:?}", res.err()),
+    }
+}
+
+#[cfg(any(feature = "with-lz4", feature = "with-brotli"))]
+#[test]
+fn lz4_brotli_compression() {
+    let mut compressions = vec![];
+    #[cfg(feature = "with-lz4")]
+    compressions.push(settings::Compression::Lz4);
+    #[cfg(feature = "with-brotli")]
+    compressions.push(settings::Compression::Brotli);
+
+    for compression in compressions {
+        for &level in &[0, 1, 11] {
+            let mut settings = settings::Repo::new();
+            settings.set_pwhash(settings::PWHash::Weak);
+            settings.set_compression(compression.clone()).unwrap();
+            settings.set_compression_level(level);
+            let repo = lib::Repo::init(
+                &rand_mem_url(),
+                &|| Ok(PASS.into()),
+                settings,
+                None,
+            ).unwrap();
+            let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+            let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+            let data: Vec<u8> = b"lz4 is fast, brotli is small\n"
+                .iter()
+                .cycle()
+                .take(1024 * 1024)
+                .cloned()
+                .collect();
+            let stats = repo
+                .write("data", &mut io::Cursor::new(&data), &enc_handle)
+                .unwrap();
+            assert!(stats.new_bytes < data.len() as u64 / 10);
+
+            let mut read = vec![];
+            repo.read("data", &mut read, &dec_handle).unwrap();
+            assert!(read == data);
+            wipe(&repo);
+        }
+    }
+}
+
+#[cfg(feature = "with-zstd")]
+#[test]
+fn zstd_dictionary() {
+    use DigestRef;
+
+    // small, similar chunks
+    let json_lines = |first: u64| {
+        let mut data = vec![];
+        let mut i = first;
+        while data.len() < 1024 * 1024 {
+            let status = ["ok", "error", "timeout"][(i % 3) as usize];
+            data.extend_from_slice(
+                format!(
+                    "{{\"id\": {}, \"user\": \"user-{}\", \"status\": \"{}\", \
+                     \"latency_ms\": {}}}\n",
+                    i,
+                    i % 97,
+                    status,
+                    i * 7 % 1000
+                ).as_bytes(),
+            );
+            i += 1;
+        }
+        data
+    };
+    // ids of dictionaries recorded in data chunk headers
+    let dictionaries_used = |repo: &lib::Repo| -> Vec<u8> {
+        let gen_str = repo.read_generations().unwrap()[0].to_string();
+        let store = repo.chunk_store(&repo.aio);
+        let mut ids: Vec<u8> = list_stored_chunks(repo)
+            .unwrap()
+            .iter()
+            .filter_map(|digest| {
+                let chunk = store.read(DigestRef(digest), &gen_str).unwrap();
+                let chunk = chunk.to_linear_vec();
+                if chunk.starts_with(b"rdch\x01") {
+                    Some(chunk[7])
+                } else {
+                    None
+                }
+            })
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    };
+
+    let url = rand_mem_url();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_compression(settings::Compression::Zstd).unwrap();
+    settings.use_fastcdc_chunking(Some(12)).unwrap();
+    let mut repo =
+        lib::Repo::init(&url, &|| Ok(PASS.into()), settings, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data: Vec<_> = (0..3).map(|i| json_lines(i * 100_000)).collect();
+    let names = ["first", "second", "third"];
+    let no_pass = || -> io::Result<String> {
+        Err(io::Error::new(io::ErrorKind::Other, "no passphrase"))
+    };
+    for i in 0..3 {
+        let dry_run_before =
+            repo.write_dry_run(&mut io::Cursor::new(&data[i])).unwrap();
+        // the first dictionary is explicitly unencrypted
+        let unencrypted = i == 1;
+        if i > 0 {
+            let id = repo
+                .train_dictionary(
+                    &dec_handle,
+                    16 * 1024,
+                    1024 * 1024,
+                    unencrypted,
+                )
+                .unwrap();
+            assert_eq!(id, i as u8);
+
+            // the sampled data is readable only in the unencrypted one
+            let path =
+                PathBuf::from(lib::config::DICT_SUBDIR).join(id.to_string());
+            let dict = repo.aio.read(path).wait().unwrap().to_linear_vec();
+            let plaintext = b"\"latency_ms\": ";
+            let readable = dict
+                .windows(plaintext.len())
+                .any(|window| window == &plaintext[..]);
+            assert_eq!(readable, unencrypted);
+        }
+        if i == 1 {
+            // dry run uses the unencrypted dictionary
+            let dry_run =
+                repo.write_dry_run(&mut io::Cursor::new(&data[i])).unwrap();
+            assert!(dry_run.new_bytes < dry_run_before.new_bytes);
+        }
+        let enc_handle = if i == 2 {
+            assert!(repo.unlock_encrypt(&no_pass).is_err());
+            repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap()
+        } else {
+            repo.unlock_encrypt(&no_pass).unwrap()
+        };
+        repo.write(names[i], &mut io::Cursor::new(&data[i]), &enc_handle)
+            .unwrap();
+        let used: Vec<u8> = (0..=i as u8).collect();
+        assert_eq!(dictionaries_used(&repo), used);
+    }
+
+    // dictionaries are read from the repository
+    let repo = lib::Repo::open(&url, None).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    for i in 0..3 {
+        let mut read = vec![];
+        repo.read(names[i], &mut read, &dec_handle).unwrap();
+        assert!(read == data[i]);
+    }
+    assert!(repo.fsck(&dec_handle).unwrap().is_ok());
+    wipe(&repo);
+
+    // needs zstd compression
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_compression(settings::Compression::None).unwrap();
+    let mut repo = lib::Repo::init(
+        &rand_mem_url(),
+        &|| Ok(PASS.into()),
+        settings,
+        None,
+    ).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    match repo.train_dictionary(&dec_handle, 16 * 1024, 1024 * 1024, false) {
+        Err(lib::Error::InvalidInput(_)) => {}
+        res => panic!("unexpected: {:?}", res),
+    }
+}
+
+#[cfg(feature = "with-zstd")]
+#[test]
+fn compression_levels() {
+    use serde_yaml;
+
+    // out of range levels are rejected
+    for &level in &[-(1 << 17) - 1, 23] {
+        let mut settings = settings::Repo::new();
+        settings.set_pwhash(settings::PWHash::Weak);
+        settings.set_compression(settings::Compression::Zstd).unwrap();
+        settings.set_compression_level(level);
+        match lib::Repo::init(
+            &rand_mem_url(),
+            &|| Ok(PASS.into()),
+            settings,
+            None,
+        ) {
+            Err(lib::Error::InvalidInput(_)) => {}
+            res => panic!("level {} not rejected: {:?}", level, res.err()),
+        }
+    }
+
+    let url = rand_mem_url();
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    settings.set_compression(settings::Compression::Zstd).unwrap();
+    settings.set_compression_level(19);
+    let mut repo =
+        lib::Repo::init(&url, &|| Ok(PASS.into()), settings, None).unwrap();
+    assert_eq!(repo.compression_level(), Some(19));
+
+    let data: Vec<u8> = b"level 19 and level 1 "
+        .iter()
+        .cycle()
+        .take(1024 * 1024)
+        .cloned()
+        .collect();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("first", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    // changed levels are persisted
+    match repo.set_compression_level(Some(30)) {
+        Err(lib::Error::InvalidInput(_)) => {}
+        res => panic!("unexpected: {:?}", res),
+    }
+    // negative levels are zstd's fast ones
+    repo.set_compression_level(Some(-5)).unwrap();
+    assert_eq!(repo.compression_level(), Some(-5));
+    repo.set_compression_level(Some(1)).unwrap();
+    let mut repo = lib::Repo::open(&url, None).unwrap();
+    assert_eq!(repo.compression_level(), Some(1));
+    repo.set_compression_level(None).unwrap();
+    assert_eq!(repo.compression_level(), Some(3));
+
+    let mut reversed = data.clone();
+    reversed.reverse();
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("second", &mut io::Cursor::new(&reversed), &enc_handle)
+        .unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    for &(name, data) in &[("first", &data), ("second", &reversed)] {
+        let mut read = vec![];
+        repo.read(name, &mut read, &dec_handle).unwrap();
+        assert!(&read == data);
+    }
+    assert!(repo.fsck(&dec_handle).unwrap().is_ok());
+    wipe(&repo);
+
+    // `level` of configs written by older versions is relative
+    let legacy = |yaml: &str| {
+        serde_yaml::from_str::<lib::config::Compression>(yaml)
+            .unwrap()
+            .level()
+    };
+    assert_eq!(legacy("type: zstd\nlevel: 0"), Some(3));
+    assert_eq!(legacy("type: zstd\nlevel: 7"), Some(7));
+    assert_eq!(legacy("type: zstd\nlevel: -1"), Some(-1));
+    #[cfg(feature = "with-deflate")]
+    {
+        assert_eq!(legacy("type: deflate\nlevel: -1"), Some(1));
+        assert_eq!(legacy("type: deflate\nlevel: 0"), Some(6));
+        assert_eq!(legacy("type: deflate\nlevel: 1"), Some(9));
+    }
+    #[cfg(feature = "with-xz2")]
+    assert_eq!(legacy("type: xz2\nlevel: 2"), Some(8));
+    // newer algorithms store only native levels
+    #[cfg(feature = "with-lz4")]
+    {
+        assert_eq!(legacy("type: lz4"), Some(0));
+        assert_eq!(legacy("type: lz4\nnative_level: 9"), Some(9));
+    }
+    #[cfg(feature = "with-brotli")]
+    assert_eq!(legacy("type: brotli"), Some(9));
+}
+
 #[test]
 fn verify_name() {
     let (repo, dir) = test_repo_dir(PASS);
@@ -342,10 +1334,502 @@ fn verify_name() {
 
     result = repo.verify("data", &dec_handle).unwrap();
     assert_eq!(result.errors.len(), 1);
+    match result.errors[0].1 {
+        lib::Error::ChunkCorrupted { ref digest, .. } => {
+            assert_eq!(digest, &result.errors[0].0)
+        }
+        ref e => panic!("unexpected error: {:?}", e),
+    }
+
+    wipe(&repo);
+}
+
+#[test]
+fn name_metadata() {
+    use lib::{NameMetadata, WriteOptions};
+    use misc::DataAddress;
+    use name::Name;
+
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    let mut options = WriteOptions::new();
+    options.set_hostname(Some("backup-host".into()));
+    options.set_tag("source", "db");
+    options.set_tag("env", "prod");
+    let before = chrono::Utc::now();
+    repo.write_with(
+        "data",
+        &mut io::Cursor::new(&data),
+        &enc_handle,
+        &options,
+    ).unwrap();
+
+    let metadata = repo.name_metadata("data").unwrap();
+    let created = metadata.created.unwrap();
+    assert!(before <= created && created <= chrono::Utc::now());
+    assert_eq!(metadata.size, Some(data.len() as u64));
+    assert!(metadata.chunks.unwrap() > 1);
+    assert_eq!(metadata.hostname, Some("backup-host".into()));
+    assert_eq!(metadata.tags.len(), 2);
+    assert_eq!(metadata.tags["source"], "db");
+
+    // name stored by an older version, without any metadata
+    let generations = repo.read_generations().unwrap();
+    let name = Name::load_from_any("data", &generations, &repo.aio).unwrap();
+    let data_address: DataAddress = name.into();
+    let old_name: Name = data_address.into();
+    old_name.write_as("old", generations[0], &repo.aio).unwrap();
+
+    assert_eq!(repo.name_metadata("old").unwrap(), NameMetadata::default());
+    assert_eq!(
+        repo.names_metadata(&["data", "old"]).unwrap(),
+        vec![metadata, NameMetadata::default()]
+    );
+    assert!(repo.names_metadata(&["data", "nope"]).is_err());
+    let mut data_after = vec![];
+    repo.read("old", &mut data_after, &dec_handle).unwrap();
+    assert_eq!(data, data_after);
+
+    wipe(&repo);
+}
+
+#[test]
+fn prune_names() {
+    use lib::{PruneOptions, WriteOptions};
+
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024);
+    for name in &["db-1", "db-2", "db-3", "web-1"] {
+        let mut options = WriteOptions::new();
+        options.set_tag("env", "prod");
+        repo.write_with(
+            name,
+            &mut io::Cursor::new(&data),
+            &enc_handle,
+            &options,
+        ).unwrap();
+    }
+
+    let mut options = PruneOptions::default();
+    assert!(repo.prune(&options).is_err());
+
+    options.prefix = Some("db-".into());
+    options.keep_last = 1;
+    options.dry_run = true;
+    let decisions = repo.prune(&options).unwrap();
+    assert_eq!(decisions.len(), 3);
+    assert_eq!(decisions[0].name, "db-3");
+    assert!(decisions[0].keep());
+    assert!(decisions[1..].iter().all(|d| !d.keep()));
+    assert_eq!(repo.list_names().unwrap().len(), 4);
+
+    options.dry_run = false;
+    repo.prune(&options).unwrap();
+    let mut names = repo.list_names().unwrap();
+    names.sort();
+    assert_eq!(names, ["db-3", "web-1"]);
+
+    wipe(&repo);
+}
+
+#[test]
+fn write_dry_run() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(4 * 1024 * 1024);
+    let results = repo.write_dry_run(&mut io::Cursor::new(&data)).unwrap();
+    assert_eq!(results.bytes, data.len() as u64);
+    assert!(results.new_chunks > results.chunks);
+    assert!(repo.read_generations().unwrap().is_empty());
+
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    let stored = list_stored_chunks(&repo).unwrap();
+
+    let results = repo.write_dry_run(&mut io::Cursor::new(&data)).unwrap();
+    assert_eq!(results.new_chunks, 0);
+    assert_eq!(results.new_bytes, 0);
+
+    // a third of the data is new, and repeated
+    let mut more_data = data.clone();
+    let new_data = rand_data(1024 * 1024);
+    more_data.extend_from_slice(&new_data);
+    more_data.extend_from_slice(&new_data);
+    let results =
+        repo.write_dry_run(&mut io::Cursor::new(&more_data)).unwrap();
+    assert!(results.new_chunks > 0);
+    assert!(results.new_bytes < more_data.len() as u64 / 2);
+    assert!(results.dedup_ratio() > 2.0);
+
+    assert_eq!(list_stored_chunks(&repo).unwrap(), stored);
+    assert_eq!(repo.list_names().unwrap(), ["data"]);
+
+    wipe(&repo);
+}
+
+/// Reader failing after `fail_at` bytes, like an interrupted input
+struct FailingReader<'a> {
+    data: &'a [u8],
+    fail_at: usize,
+}
+
+impl<'a> io::Read for FailingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.fail_at == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "interrupted"));
+        }
+        let len = cmp::min(cmp::min(buf.len(), self.fail_at), self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        self.fail_at -= len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn resume_write() {
+    use checkpoint;
+    use lib::WriteOptions;
+
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(4 * 1024 * 1024);
+    let mut options = WriteOptions::new();
+    options.set_checkpoint_interval(Some(256 * 1024));
+
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let reader = FailingReader {
+            data: &data,
+            fail_at: 2 * 1024 * 1024,
+        };
+        repo.write_with("data", reader, &enc_handle, &options)
+    }));
+    assert!(res.is_err());
+    assert!(repo.list_names().unwrap().is_empty());
+
+    let gen = *repo.read_generations().unwrap().last().unwrap();
+    let (stored, next_seq) = checkpoint::load("data", gen, &repo.aio).unwrap();
+    assert!(!stored.is_empty());
+    assert!(next_seq > 0);
+    // checkpoints refer only to chunks really stored
+    let all_stored = list_stored_chunks(&repo).unwrap();
+    assert!(stored.iter().all(|digest| all_stored.contains(digest)));
+
+    options.set_resume(true);
+    let stats = repo
+        .write_with("data", &mut io::Cursor::new(&data), &enc_handle, &options)
+        .unwrap();
+
+    // resuming stores only what the checkpoint doesn't cover
+    let fresh_repo = test_repo(PASS);
+    let fresh_enc = fresh_repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let fresh_stats = fresh_repo
+        .write("data", &mut io::Cursor::new(&data), &fresh_enc)
+        .unwrap();
+    assert_eq!(stats.new_chunks, fresh_stats.new_chunks - stored.len());
+    assert!(stats.new_bytes > 0);
+    assert!(stats.new_bytes < fresh_stats.new_bytes);
+    wipe(&fresh_repo);
+
+    let mut read = vec![];
+    repo.read("data", &mut read, &dec_handle).unwrap();
+    assert_eq!(read, data);
+    assert!(repo.verify("data", &dec_handle).unwrap().errors.is_empty());
+
+    let (stored, next_seq) = checkpoint::load("data", gen, &repo.aio).unwrap();
+    assert!(stored.is_empty());
+    assert_eq!(next_seq, 0);
+
+    wipe(&repo);
+}
+
+#[test]
+fn hierarchical_names() {
+    // local backend, to have real directories
+    let (repo, _dir) = test_repo_dir(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024);
+    let names = [
+        "team-a/db/2026-10-16",
+        "team-a/db/2026-10-17",
+        "team-a/web",
+        "team-b/db/2026-10-17",
+        "top",
+    ];
+    for name in &names {
+        repo.write(name, &mut io::Cursor::new(&data), &enc_handle)
+            .unwrap();
+    }
+    for name in &["", "/abs", "a//b", "a/../b", "dir.yml/a"] {
+        match repo.write(name, &mut io::Cursor::new(&data), &enc_handle) {
+            Err(lib::Error::InvalidInput(_)) => {}
+            res => panic!("name `{}` not rejected: {:?}", name, res.err()),
+        }
+    }
+
+    let sorted = |mut names: Vec<String>| {
+        names.sort();
+        names
+    };
+    assert_eq!(sorted(repo.list_names().unwrap()), names);
+    assert_eq!(
+        sorted(repo.list_names_with_prefix("team-a/").unwrap()),
+        ["team-a/db/2026-10-16", "team-a/db/2026-10-17", "team-a/web"]
+    );
+    assert_eq!(
+        sorted(repo.list_names_with_prefix("team-a/db/2026-10-1").unwrap()),
+        ["team-a/db/2026-10-16", "team-a/db/2026-10-17"]
+    );
+    assert_eq!(
+        sorted(repo.list_names_matching("*/db/2026-10-17").unwrap()),
+        ["team-a/db/2026-10-17", "team-b/db/2026-10-17"]
+    );
+    assert_eq!(
+        sorted(repo.list_names_matching("team-a/*").unwrap()),
+        ["team-a/web"]
+    );
+
+    // moves the names to a new generation
+    repo.gc(0).unwrap();
+    assert_eq!(sorted(repo.list_names().unwrap()), names);
+    let mut read = vec![];
+    repo.read("team-b/db/2026-10-17", &mut read, &dec_handle)
+        .unwrap();
+    assert_eq!(read, data);
+
+    assert_eq!(
+        sorted(repo.rm_prefix("team-a/").unwrap()),
+        ["team-a/db/2026-10-16", "team-a/db/2026-10-17", "team-a/web"]
+    );
+    assert_eq!(
+        sorted(repo.list_names().unwrap()),
+        ["team-b/db/2026-10-17", "top"]
+    );
+
+    wipe(&repo);
+}
+
+/// Observer summing up everything reported to it
+#[derive(Default)]
+struct CountingProgress {
+    total_bytes: AtomicUsize,
+    total_names: AtomicUsize,
+    bytes: AtomicUsize,
+    chunks: AtomicUsize,
+    new_bytes: AtomicUsize,
+    names: Mutex<Vec<String>>,
+}
+
+impl lib::ProgressObserver for CountingProgress {
+    fn total_bytes(&self, bytes: u64) {
+        self.total_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+    }
+
+    fn total_names(&self, names: u64) {
+        self.total_names.fetch_add(names as usize, Ordering::SeqCst);
+    }
+
+    fn bytes_consumed(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+    }
+
+    fn chunk_processed(&self) {
+        self.chunks.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn new_bytes(&self, bytes: u64) {
+        self.new_bytes.fetch_add(bytes as usize, Ordering::SeqCst);
+    }
+
+    fn name_handled(&self, name: &str) {
+        self.names.lock().unwrap().push(name.to_owned());
+    }
+}
+
+#[test]
+fn progress_observer() {
+    let mut repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    let data = rand_data(1024 * 1024);
+
+    let progress = Arc::new(CountingProgress::default());
+    repo.set_progress_observer(progress.clone());
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert_eq!(progress.bytes.load(Ordering::SeqCst), data.len());
+    let chunks = progress.chunks.load(Ordering::SeqCst);
+    assert!(chunks > 1);
+    assert!(progress.new_bytes.load(Ordering::SeqCst) > 0);
+    assert_eq!(*progress.names.lock().unwrap(), ["data"]);
+
+    // nothing new the second time
+    let progress = Arc::new(CountingProgress::default());
+    repo.set_progress_observer(progress.clone());
+    repo.write("again", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    assert_eq!(progress.chunks.load(Ordering::SeqCst), chunks);
+    assert_eq!(progress.new_bytes.load(Ordering::SeqCst), 0);
+
+    for verify in &[false, true] {
+        let progress = Arc::new(CountingProgress::default());
+        repo.set_progress_observer(progress.clone());
+        if *verify {
+            repo.verify("data", &dec_handle).unwrap();
+        } else {
+            repo.read("data", &mut vec![], &dec_handle).unwrap();
+        }
+        assert_eq!(progress.total_bytes.load(Ordering::SeqCst), data.len());
+        assert_eq!(progress.bytes.load(Ordering::SeqCst), data.len());
+        assert_eq!(progress.chunks.load(Ordering::SeqCst), chunks);
+        assert_eq!(*progress.names.lock().unwrap(), ["data"]);
+    }
+
+    let progress = Arc::new(CountingProgress::default());
+    repo.set_progress_observer(progress.clone());
+    repo.gc(0).unwrap();
+    assert_eq!(progress.total_names.load(Ordering::SeqCst), 2);
+    assert_eq!(progress.chunks.load(Ordering::SeqCst), 2 * chunks);
+    let mut names = progress.names.lock().unwrap().clone();
+    names.sort();
+    assert_eq!(names, ["again", "data"]);
 
     wipe(&repo);
 }
 
+#[test]
+fn typed_errors() {
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    match repo.unlock_decrypt(&|| Ok("wrong".into())) {
+        Err(lib::Error::WrongPassphrase) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unlocked with a wrong passphrase"),
+    }
+
+    let data = rand_data(1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+    match repo.write("data", &mut io::Cursor::new(&data), &enc_handle) {
+        Err(lib::Error::NameExists(ref name)) => assert_eq!(name, "data"),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("name overwritten"),
+    }
+
+    match repo.read("nope", &mut io::sink(), &dec_handle) {
+        Err(lib::Error::NameNotFound(ref name)) => assert_eq!(name, "nope"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match repo.rm("nope") {
+        Err(lib::Error::NameNotFound(ref name)) => assert_eq!(name, "nope"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // remove every stored chunk
+    let gen = repo.read_generations().unwrap()[0].to_string();
+    for digest in repo.chunk_store(&repo.aio).list(&gen).unwrap() {
+        let path = repo.config.nesting.get_path(
+            path::Path::new(lib::config::DATA_SUBDIR),
+            &digest.unwrap(),
+            &gen,
+        );
+        repo.aio.remove(path).wait().unwrap();
+    }
+    match repo.read("data", &mut io::sink(), &dec_handle) {
+        Err(lib::Error::ChunkMissing(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    wipe(&repo);
+}
+
+#[test]
+fn fsck_finds_problems() {
+    use lib::{FsckProblemKind, FsckResults, SGData};
+
+    let repo = test_repo(PASS);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let results = repo.fsck(&dec_handle).unwrap();
+    assert!(results.is_ok());
+    assert_eq!(results.names, 1);
+    assert_eq!(results.stored_chunks, results.reachable_chunks);
+
+    let gen = repo.read_generations().unwrap()[0];
+    let chunk_path = |digest: &[u8]| {
+        repo.config.nesting.get_path(
+            path::Path::new(lib::config::DATA_SUBDIR),
+            digest,
+            &gen.to_string(),
+        )
+    };
+    let found =
+        |results: &FsckResults, kinds: &[FsckProblemKind], digest: &[u8]| {
+            results.problems.iter().any(|p| {
+                kinds.contains(&p.kind)
+                    && p.chunk == Some(hex::encode(digest))
+            })
+        };
+
+    let stored: Vec<_> =
+        list_stored_chunks(&repo).unwrap().into_iter().collect();
+    let orphan = vec![0u8; DIGEST_SIZE];
+    repo.aio.remove(chunk_path(&stored[0])).wait().unwrap();
+    repo.aio
+        .write(chunk_path(&stored[1]), SGData::from_single(vec![1, 2, 3]))
+        .wait()
+        .unwrap();
+    repo.aio
+        .write(chunk_path(&orphan), SGData::from_single(vec![1, 2, 3]))
+        .wait()
+        .unwrap();
+    repo.aio
+        .write(
+            PathBuf::from(gen.gen_next().to_string()).join("name/x.yml"),
+            SGData::empty(),
+        ).wait()
+        .unwrap();
+
+    let results = repo.fsck(&dec_handle).unwrap();
+    assert!(found(&results, &[FsckProblemKind::Missing], &stored[0]));
+    assert!(found(
+        &results,
+        &[
+            FsckProblemKind::Undecodable,
+            FsckProblemKind::DigestMismatch
+        ],
+        &stored[1]
+    ));
+    assert!(found(&results, &[FsckProblemKind::Unreferenced], &orphan));
+    assert!(
+        results
+            .problems
+            .iter()
+            .any(|p| p.kind == FsckProblemKind::MissingGenerationConfig)
+    );
+
+    let mut json = vec![];
+    results.write_json(&mut json).unwrap();
+    assert!(String::from_utf8(json).unwrap().contains("\"missing\""));
+}
+
 #[test]
 fn test_stored_chunks_iter() {
     let repo = test_repo(PASS);
@@ -486,6 +1970,77 @@ fn test_custom_nesting() {
     }
 }
 
+/// Store and load data using a real S3-compatible service
+///
+/// Run with `cargo test -- --ignored`, with `RDEDUP_TEST_S3_URL` (eg.
+/// `s3://rdedup-test`) set, and `RDEDUP_S3_ENDPOINT` and the `AWS_*`
+/// credentials pointing at eg. a local MinIO instance. The backend is
+/// otherwise tested against an in-process stand-in (see `aio::s3`).
+#[test]
+#[ignore]
+fn s3_store_load() {
+    let base = std::env::var("RDEDUP_TEST_S3_URL")
+        .expect("RDEDUP_TEST_S3_URL not set");
+    let url = Url::parse(&format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        rand::thread_rng()
+            .gen_ascii_chars()
+            .take(20)
+            .collect::<String>()
+    )).unwrap();
+
+    let mut settings = settings::Repo::new();
+    settings.set_pwhash(settings::PWHash::Weak);
+    let repo =
+        lib::Repo::init(&url, &|| Ok(PASS.into()), settings, None).unwrap();
+
+    let data = rand_data(1024 * 1024);
+    let enc_handle = repo.unlock_encrypt(&|| Ok(PASS.into())).unwrap();
+    let dec_handle = repo.unlock_decrypt(&|| Ok(PASS.into())).unwrap();
+    repo.write("data", &mut io::Cursor::new(&data), &enc_handle)
+        .unwrap();
+
+    let repo = lib::Repo::open(&url, None).unwrap();
+    let mut load_data = vec![];
+    repo.read("data", &mut load_data, &dec_handle).unwrap();
+    assert_eq!(load_data, data);
+
+    wipe(&repo);
+}
+
+#[test]
+fn lock_timeout() {
+    let (mut repo, _dir) = test_repo_dir(PASS);
+
+    // uncontended locks are taken right away
+    repo.set_lock_timeout(Some(0));
+    repo.gc(0).unwrap();
+    repo.list_names().unwrap();
+
+    let lock = repo.aio.try_lock_exclusive().unwrap();
+    match repo.list_names() {
+        Err(lib::Error::Locked(_)) => {}
+        res => panic!("unexpected: {:?}", res),
+    }
+    match repo.gc(0) {
+        Err(lib::Error::Locked(_)) => {}
+        res => panic!("unexpected: {:?}", res),
+    }
+    drop(lock);
+
+    let lock = repo.aio.try_lock_shared().unwrap();
+    repo.list_names().unwrap();
+    match repo.gc(0) {
+        Err(lib::Error::Locked(_)) => {}
+        res => panic!("unexpected: {:?}", res),
+    }
+    drop(lock);
+    repo.gc(0).unwrap();
+
+    wipe(&repo);
+}
+
 #[test]
 fn test_readerveciter() {
     let input = vec![0, 1, 2, 3, 4];
@@ -1,3 +1,5 @@
+#[cfg(unix)]
+use libc;
 use std::io;
 
 mod serde;
@@ -48,6 +50,27 @@ where
     }
 }
 
+/// Hostname of this machine, if it can be determined
+#[cfg(unix)]
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = vec![0u8; 256];
+    let res = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len())
+    };
+    if res != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    String::from_utf8(buf).ok()
+}
+
+/// Hostname of this machine, if it can be determined
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
 /// Like `enumerate` from stdlib,
 /// only guaranteed to be `u64`
 ///
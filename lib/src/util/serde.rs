@@ -47,6 +47,18 @@ impl MyTryFromBytes for pwhash::Salt {
     }
 }
 
+impl MyTryFromBytes for pwhash::argon2id13::Salt {
+    type Err = io::Error;
+    fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
+        pwhash::argon2id13::Salt::from_slice(slice).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can't derive Salt from invalid binary data",
+            )
+        })
+    }
+}
+
 impl MyTryFromBytes for Vec<u8> {
     type Err = io::Error;
     fn try_from(slice: &[u8]) -> Result<Self, Self::Err> {
@@ -112,11 +112,13 @@ impl SGData {
     pub fn to_linear_vec(mut self) -> Vec<u8> {
         match self.0.len() {
             0 => vec![],
-            1 => {
+            // the only part might be just a slice of the underlying data
+            1 if self.0[0].len() == self.0[0].as_owner().len() => {
                 let e = self.0.pop().unwrap();
                 Arc::try_unwrap(e.into_inner())
                     .unwrap_or_else(|a| a.as_ref().clone())
             }
+            1 => self.0[0].to_vec(),
             _ => {
                 let mut v = Vec::with_capacity(self.len());
                 for sg_part in &self.0 {
@@ -19,7 +19,9 @@
 //!  * variety of supported algorithms:
 //!    * chunking: fastcdc, gear, bup
 //!    * hashing: blake2b, sha256
-//!    * compression: zstd, deflate, xz2, bzip2, none
+//!    * compression: zstd, deflate, xz2, bzip2, lz4, brotli (`with-lz4` and
+//!      `with-brotli` features), none; optionally adaptive, skipping chunks
+//!      that don't compress well (`init --adaptive-compression`)
 //!    * encryption: curve25519, none
 //!    * very easy to add new ones
 //!    * check `rdedup init --help` output for up-to-date list
@@ -79,17 +81,85 @@
 //! Rdedup always operates on a *repo*, that you provide as an argument
 //! (eg. `--dir <DIR>`), or via environment variable (eg. `RDEDUP_DIR`).
 //!
+//! Repository URIs (`--repo <URI>` or `RDEDUP_URI`) can use following schemes:
+//!
+//! * `file:///path/to/repo` - a local directory (same as `--dir`)
+//! * `s3://bucket/prefix` - S3-compatible object storage; credentials are taken
+//!   from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` (and optionally
+//!   `AWS_SESSION_TOKEN`), region from `AWS_REGION`; set `RDEDUP_S3_ENDPOINT`
+//!   to use a service other than AWS (eg. `http://127.0.0.1:9000` for MinIO);
+//!   repository locks need the service to support conditional writes
+//! * `sftp://user@host:port/path` - a directory on a remote host, accessed over
+//!   SSH; authentication uses `RDEDUP_SFTP_KEY` (private key file, with optional
+//!   `RDEDUP_SFTP_KEY_PASSPHRASE`), `RDEDUP_SFTP_PASSWORD` or ssh-agent; host key
+//!   is checked against `~/.ssh/known_hosts` (or `RDEDUP_SFTP_KNOWN_HOSTS`)
+//! * `mem:<name>` - an in-memory repository that lives only as long as the process
+//!
 //! Supported commands:
 //!
 //! * `rdedup init` - create a new *repo*.
 //!   * `rdedup init --help` for repository configuration options.
 //! * `rdedup store <name>` - store data from standard input under a given
-//!   *name*.
+//!   *name*; `--tag key=value` records a tag with it. Checkpoints are written
+//!   while storing (`--checkpoint-interval`), so an interrupted store can be
+//!   continued with `--resume`, giving it the same data again. With
+//!   `--dry-run`, only reports how much of the data would be new.
 //! * `rdedup load <name>` - load data stored under given *name* and write it
 //!   to standard output.
-//! * `rdedup rm <name>` - remove the given *name*.
-//! * `rdedup ls` - list all stored names.
+//! * `rdedup rm <name>` - remove the given *name*; `--prefix <prefix>`
+//!   removes all the names starting with it.
+//! * `rdedup ls [prefix]` - list all stored names (or only ones starting with
+//!   the prefix); `--long` adds metadata: creation time, size, chunk count,
+//!   hostname and tags.
+//! * `rdedup prune` - remove names according to a retention policy, eg.
+//!   `--prefix db- --keep-daily 7 --keep-weekly 4 --keep-monthly 12`, based
+//!   on their creation time; `--dry-run` prints what would be removed. Each
+//!   name is listed with the rules keeping it (`keep-daily 2026-10-17`) or
+//!   why none did (`keep-daily: newer name in 2026-10-17`).
 //! * `rdedup gc` - remove any no longer reachable data.
+//! * `rdedup fsck` - check consistency of the whole *repo*: unreferenced,
+//!   missing and corrupted chunks, etc.; prints results as JSON and exits
+//!   with non-zero status if any problems were found.
+//! * `rdedup export-key <path>` - export the encryption key to a keyfile, that
+//!   can be used to unlock the *repo* without a passphrase (`--keyfile <path>`
+//!   or `RDEDUP_KEYFILE`); keep it as safe as the passphrase.
+//! * `rdedup key add|add-keyfile|list|revoke` - manage key slots:
+//!   additional, named passphrases (or keyfiles) unlocking the same encryption
+//!   key (eg. one per team). Any slot can be revoked, including `default`, as
+//!   long as one passphrase slot remains.
+//! * `rdedup rebuild-cache` - rebuild the local cache of stored chunks.
+//! * `rdedup config-edit --compression-level <N|default>` - change the level
+//!   new data is compressed with; levels are as defined by the compression
+//!   scheme (eg. 1-22 for zstd, or negative for its fast levels), and can
+//!   be also set by `init`. Up to `rdedup` 3.1, `init --compression-level`
+//!   was relative to the default level (eg. `-1` for faster, `1` for
+//!   smaller); repositories created that way keep their levels.
+//! * `rdedup config-edit --upgrade-chunk-format` - prefix new chunks with a
+//!   header describing their encoding, in *repos* created by older versions
+//!   of `rdedup` or with `init --legacy-chunk-format`; chunks already stored
+//!   stay readable, but older versions of `rdedup` can't use the *repo*
+//!   afterwards.
+//! * `rdedup train-dictionary` - train a zstd dictionary on stored data
+//!   (`--sample-size`) and compress new data with it; helps with many small,
+//!   similar chunks. Training again creates a new dictionary, without
+//!   affecting data compressed with the old ones. The dictionary contains
+//!   fragments of the stored data, so it's encrypted, and `store` in an
+//!   encrypted *repo* needs the passphrase then. `--unencrypted-dictionary`
+//!   stores it as it is, so `store` doesn't need the passphrase, but anyone
+//!   with access to the *repo* can read these fragments.
+//! * `rdedup mount <mountpoint>` - mount all the names as read-only files,
+//!   with data read on demand (requires `with-fuse` feature).
+//! * `rdedup nbd-serve <name> --socket <path>` - export a *name* as
+//!   a read-only network block device, on a Unix socket.
+//!
+//! Names can be hierarchical, like paths: `team-a/db/2026-10-17` is stored
+//! in a subdirectory. `verify`, `du` and `rm` accept glob patterns instead
+//! of names: `?` and `*` match within one component, `**` across
+//! components (eg. `rdedup verify 'team-a/**'`).
+//!
+//! `store`, `load`, `verify` and `gc` take `--progress`, to show a progress
+//! bar on standard error: data processed, throughput and, when the total is
+//! known (eg. `store` from a file, or names stored with their size), ETA.
 //!
 //!
 //! In combination with [rdup][rdup] this can be used to store and restore your
@@ -120,13 +190,19 @@
 //! [ddar-issue]: https://github.com/basak/ddar/issues/10
 
 extern crate clap;
+#[cfg(feature = "with-fuse")]
+extern crate fuse;
 extern crate hex;
+#[cfg(feature = "with-fuse")]
+extern crate libc;
 extern crate rdedup_lib as lib;
 extern crate rpassword;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
+#[cfg(feature = "with-fuse")]
+extern crate time;
 extern crate url;
 
 use clap::{Arg, SubCommand};
@@ -134,7 +210,9 @@ use lib::settings;
 use lib::Repo;
 use slog::Drain;
 use std::error::Error;
-use std::{env, io, process};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{env, fs, io, process};
 use url::Url;
 
 use std::str::FromStr;
@@ -154,17 +232,43 @@ struct Options {
     url: Url,
     debug_level: u32,
     settings: settings::Repo,
+    chunk_cache_dir: Option<PathBuf>,
+    lock_timeout: Option<u64>,
+    keyfile: Option<PathBuf>,
 }
 
 impl Options {
     fn new(url: Url) -> Options {
         Options {
+            chunk_cache_dir: util::default_cache_dir(&url),
+            lock_timeout: None,
+            keyfile: None,
             url,
             debug_level: 0,
             settings: settings::Repo::new(),
         }
     }
 
+    fn open_repo(&self, log: slog::Logger) -> io::Result<Repo> {
+        let mut repo = Repo::open(&self.url, log)?;
+        repo.set_chunk_cache_dir(self.chunk_cache_dir.clone());
+        repo.set_lock_timeout(self.lock_timeout);
+        Ok(repo)
+    }
+
+    /// Unlock using the keyfile, if given, or ask for passphrase
+    fn unlock_decrypt(
+        &self,
+        repo: &Repo,
+    ) -> lib::Result<lib::DecryptHandle> {
+        if let Some(ref path) = self.keyfile {
+            let keyfile = lib::Keyfile::read(&mut fs::File::open(path)?)?;
+            repo.unlock_decrypt_with_keyfile(&keyfile)
+        } else {
+            repo.unlock_decrypt(&|| read_passphrase())
+        }
+    }
+
     fn set_encryption(&mut self, s: &str) {
         let encryption = match s {
             "curve25519" => lib::settings::Encryption::Curve25519,
@@ -190,6 +294,10 @@ impl Options {
             "zstd" => lib::settings::Compression::Zstd,
             #[cfg(feature = "with-bzip2")]
             "bzip2" => lib::settings::Compression::Bzip2,
+            #[cfg(feature = "with-lz4")]
+            "lz4" => lib::settings::Compression::Lz4,
+            #[cfg(feature = "with-brotli")]
+            "brotli" => lib::settings::Compression::Brotli,
             "none" => lib::settings::Compression::None,
             _ => {
                 eprintln!("unsupported compression: {}", s);
@@ -243,8 +351,28 @@ impl Options {
     fn set_nesting(&mut self, level: u8) {
         self.settings.set_nesting(level).expect("invalid nesting");
     }
+
+    fn set_layout(&mut self, s: &str, pack_size: Option<u64>) {
+        match s {
+            "files" => self.settings.use_file_layout(),
+            "packs" => self
+                .settings
+                .use_pack_layout(pack_size)
+                .expect("wrong layout settings"),
+            _ => {
+                eprintln!("unsupported layout: {}", s);
+                process::exit(-1);
+            }
+        };
+    }
 }
 
+#[cfg(feature = "with-fuse")]
+mod mount;
+#[cfg(unix)]
+mod nbd;
+mod progress;
+use progress::ProgressBar;
 mod util;
 use util::{read_new_passphrase, read_passphrase};
 
@@ -268,6 +396,115 @@ fn validate_nesting(s: String) -> Result<(), String> {
     Ok(())
 }
 
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_ops_limit(s: String) -> Result<(), String> {
+    u64::from_str(s.as_str())
+        .map(|_| ())
+        .map_err(|_| "ops limit must be a non-negative integer".into())
+}
+
+fn parse_tag(tag: &str) -> io::Result<(&str, &str)> {
+    util::parse_tag(tag).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid tag `{}`, expected KEY=VALUE", tag),
+        )
+    })
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_lock_timeout(s: String) -> Result<(), String> {
+    u64::from_str(s.as_str())
+        .map(|_| ())
+        .map_err(|_| "lock timeout must be a non-negative integer".into())
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_tag(s: String) -> Result<(), String> {
+    parse_tag(&s).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Compression level given on the command line; `None` for `default`
+fn parse_compression_level(s: &str) -> Option<i32> {
+    if s == "default" {
+        None
+    } else {
+        Some(i32::from_str(s).expect("invalid compression level"))
+    }
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+fn validate_compression_level(s: String) -> Result<(), String> {
+    if s == "default" || i32::from_str(s.as_str()).is_ok() {
+        Ok(())
+    } else {
+        Err("compression level must be an integer or `default`".into())
+    }
+}
+
+fn pwhash_from_matches(
+    matches: &clap::ArgMatches,
+) -> io::Result<settings::PWHash> {
+    match matches.value_of("PWHASH").unwrap() {
+        "argon2id" => Ok(settings::PWHash::argon2id(
+            matches.value_of("PWHASH_OPS_LIMIT").map(|s| {
+                u64::from_str(s).expect("invalid pwhash ops limit")
+            }),
+            matches.value_of("PWHASH_MEM_LIMIT").map(|s| {
+                util::parse_size(s).expect("invalid pwhash memory limit")
+            }),
+        )?),
+        // otherwise the limits would be silently ignored
+        _ if matches.is_present("PWHASH_OPS_LIMIT")
+            || matches.is_present("PWHASH_MEM_LIMIT") =>
+        {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--pwhash-ops-limit and --pwhash-mem-limit require \
+                 --pwhash argon2id",
+            ))
+        }
+        s => Ok(settings::PWHash::from(s)),
+    }
+}
+
+/// Report progress of `repo` operations on a bar, if `--progress` was given
+fn progress_bar(
+    repo: &mut Repo,
+    matches: &clap::ArgMatches,
+) -> Option<Arc<ProgressBar>> {
+    if !matches.is_present("PROGRESS") {
+        return None;
+    }
+    let progress = Arc::new(ProgressBar::new());
+    repo.set_progress_observer(progress.clone());
+    Some(progress)
+}
+
+/// Replace glob patterns (eg. `team-a/*`) with the names they match
+fn expand_names<'a, I>(repo: &Repo, names: I) -> io::Result<Vec<String>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut res = vec![];
+    for name in names {
+        if !name.contains(|c| c == '*' || c == '?') {
+            res.push(name.to_owned());
+            continue;
+        }
+        let mut matching = repo.list_names_matching(name)?;
+        if matching.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no names matching: {}", name),
+            ));
+        }
+        matching.sort();
+        res.append(&mut matching);
+    }
+    Ok(res)
+}
+
 fn create_logger(verbosity: u32, timing_verbosity: u32) -> slog::Logger {
     match (verbosity, timing_verbosity) {
         (0, 0) => slog::Logger::root(slog::Discard, o!()),
@@ -330,10 +567,22 @@ fn run() -> io::Result<()> {
              .help("Rdedup repository URI. Overrides the `RDEDUP_URI` environment variable"))
         .arg(Arg::with_name("VERBOSE").short("v").multiple(true).help("Increase debugging level for general messages"))
         .arg(Arg::with_name("VERBOSE_TIMINGS").short("t").multiple(true).help("Increase debugging level for timings"))
+        .arg(Arg::with_name("KEYFILE").long("keyfile").takes_value(true).value_name("PATH")
+             .help("Unlock using a keyfile (see `export-key`) instead of a passphrase. Overrides the `RDEDUP_KEYFILE` environment variable"))
+        .arg(Arg::with_name("CACHE_DIR").long("cache-dir").takes_value(true).value_name("PATH")
+             .help("Directory of the local cache of stored chunks. Defaults to `~/.cache/rdedup/<URI>`"))
+        .arg(Arg::with_name("NO_CACHE").long("no-cache").conflicts_with("CACHE_DIR")
+             .help("Don't use the local cache of stored chunks"))
+        .arg(Arg::with_name("LOCK_TIMEOUT").long("lock-timeout").takes_value(true).value_name("SECS").validator(validate_lock_timeout)
+             .help("Give up waiting for a repository locked by another process after SECS seconds, instead of waiting as long as it takes"))
         .subcommand(SubCommand::with_name("init").display_order(0)
                     .about("Create a new repository")
-                    .arg(Arg::with_name("PWHASH").long("pwhash").takes_value(true).value_name("STRENGTH").possible_values(&["strong", "interactive", "weak"])
-                         .default_value("strong").help("Set pwhash strength"))
+                    .arg(Arg::with_name("PWHASH").long("pwhash").takes_value(true).value_name("STRENGTH").possible_values(&["strong", "interactive", "weak", "argon2id"])
+                         .default_value("strong").help("Set pwhash strength (scrypt), or use Argon2id"))
+                    .arg(Arg::with_name("PWHASH_OPS_LIMIT").long("pwhash-ops-limit").takes_value(true).value_name("N").validator(validate_ops_limit)
+                         .help("Set Argon2id ops limit (requires `--pwhash argon2id`)"))
+                    .arg(Arg::with_name("PWHASH_MEM_LIMIT").long("pwhash-mem-limit").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Set Argon2id memory limit (requires `--pwhash argon2id`)"))
                     .arg(Arg::with_name("CHUNKING").long("chunking").takes_value(true).value_name("SCHEME").possible_values(&["bup", "gear", "fastcdc"])
                          .default_value("fastcdc").help("Set chunking scheme"))
                     .arg(Arg::with_name("CHUNK_SIZE").long("chunk-size").takes_value(true).value_name("N").validator(validate_chunk_size)
@@ -341,31 +590,119 @@ fn run() -> io::Result<()> {
                     .arg(Arg::with_name("ENCRYPTION").long("encryption").takes_value(true).value_name("SCHEME").possible_values(&["curve25519", "none"])
                          .default_value("curve25519").help("Set encryption scheme"))
                     .arg(Arg::with_name("COMPRESSION").long("compression").takes_value(true).value_name("SCHEME")
-                         .possible_values(&["deflate", "xz2", "zstd", "bzip2", "none"])
+                         .possible_values(&["deflate", "xz2", "zstd", "bzip2", "lz4", "brotli", "none"])
                          .default_value("zstd").help("Set compression scheme"))
                     .arg(Arg::with_name("COMPRESSION_LEVEL").long("compression-level").takes_value(true).value_name("N")
-                         .default_value("0").help("Set compression level where negative numbers mean \"faster\" and positive ones \
-                                                   \"smaller\""))
+                         .allow_hyphen_values(true).validator(validate_compression_level)
+                         .help("Set compression level as defined by the compression scheme (eg. 1-22 for zstd, or negative \
+                                for its fast levels); defaults to its default level. Unlike in rdedup 3.1 and earlier, \
+                                it's not relative to the default level"))
+                    .arg(Arg::with_name("ADAPTIVE_COMPRESSION").long("adaptive-compression").takes_value(true).value_name("PERCENT")
+                         .help("Store chunks uncompressed if compressing them saves less than PERCENT of their size (eg. 10)"))
                     .arg(Arg::with_name("NESTING").long("nesting").takes_value(true).value_name("N").validator(validate_nesting)
                          .default_value("2").help("Set level of folder nesting"))
+                    .arg(Arg::with_name("LAYOUT").long("layout").takes_value(true).value_name("LAYOUT").possible_values(&["files", "packs"])
+                         .default_value("files").help("Set chunk storage layout: a file per chunk, or pack files"))
+                    .arg(Arg::with_name("PACK_SIZE").long("pack-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .help("Set approximate pack file size (for `packs` layout)"))
                     .arg(Arg::with_name("HASHING").long("hashing").takes_value(true).value_name("SCHEME").possible_values(&["sha256", "blake2b"])
-                         .default_value("blake2b").help("Set hashing scheme")))
+                         .default_value("blake2b").help("Set hashing scheme"))
+                    .arg(Arg::with_name("LEGACY_INDEX").long("legacy-index")
+                         .help("Don't record data lengths in the index, so older versions of rdedup can use the repository; seeking in stored data is slow then"))
+                    .arg(Arg::with_name("LEGACY_CHUNK_FORMAT").long("legacy-chunk-format")
+                         .help("Don't prefix chunks with a header describing their encoding, so older versions of rdedup can use the repository")))
         .subcommand(SubCommand::with_name("store").about("Store data to repository").display_order(1)
-                    .arg(Arg::with_name("NAME").required(true).help("Name to store to")))
+                    .arg(Arg::with_name("NAME").required(true).help("Name to store to"))
+                    .arg(Arg::with_name("TAG").long("tag").takes_value(true).multiple(true).number_of_values(1).value_name("KEY=VALUE").validator(validate_tag)
+                         .help("Record a tag with the name (can be given multiple times)"))
+                    .arg(Arg::with_name("CHECKPOINT_INTERVAL").long("checkpoint-interval").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .default_value("1G").help("Write a checkpoint every N bytes of data, to allow resuming an interrupted store (0 to disable)"))
+                    .arg(Arg::with_name("RESUME").long("resume")
+                         .help("Resume an interrupted store of the name; the same data has to be given again, from the start"))
+                    .arg(Arg::with_name("DRY_RUN").long("dry-run").short("n").conflicts_with("RESUME")
+                         .help("Only report how many chunks and bytes would be new; nothing is written and no passphrase is needed"))
+                    .arg(Arg::with_name("PROGRESS").long("progress")
+                         .help("Show a progress bar with throughput and ETA on stderr")))
         .subcommand(SubCommand::with_name("load").about("Load data from repository").display_order(2)
-                    .arg(Arg::with_name("NAME").required(true).help("Name to load from")))
-        .subcommand(SubCommand::with_name("list").visible_alias("ls").about("List names stored in the repository").display_order(3))
+                    .arg(Arg::with_name("NAME").required(true).help("Name to load from"))
+                    .arg(Arg::with_name("PROGRESS").long("progress")
+                         .help("Show a progress bar with throughput and ETA on stderr")))
+        .subcommand(SubCommand::with_name("list").visible_alias("ls").about("List names stored in the repository").display_order(3)
+                    .arg(Arg::with_name("LONG").short("l").long("long")
+                         .help("Print metadata: creation time, size, chunk count, hostname and tags (tab separated)"))
+                    .arg(Arg::with_name("PREFIX").help("Only names starting with the prefix (eg. `team-a/`)")))
         .subcommand(SubCommand::with_name("remove").visible_alias("rm").about("Remove name(s) stored in the repository").display_order(4)
-                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to remove")))
+                    .arg(Arg::with_name("NAME").required_unless("PREFIX").multiple(true).help("Names (or glob patterns, eg. `team-a/*`) to remove"))
+                    .arg(Arg::with_name("PREFIX").long("prefix").takes_value(true).help("Remove all names starting with the prefix")))
         .subcommand(SubCommand::with_name("change_passphrase").visible_alias("chpasswd")
-                    .about("Change the passphrase protecting the encryption key (if any)"))
+                    .about("Change the passphrase protecting the encryption key (if any)")
+                    .arg(Arg::with_name("PWHASH").long("pwhash").takes_value(true).value_name("STRENGTH").possible_values(&["strong", "interactive", "weak", "argon2id"])
+                         .help("Switch to a different pwhash strength, or to Argon2id"))
+                    .arg(Arg::with_name("PWHASH_OPS_LIMIT").long("pwhash-ops-limit").takes_value(true).value_name("N").validator(validate_ops_limit).requires("PWHASH")
+                         .help("Set Argon2id ops limit (requires `--pwhash argon2id`)"))
+                    .arg(Arg::with_name("PWHASH_MEM_LIMIT").long("pwhash-mem-limit").takes_value(true).value_name("N").validator(validate_chunk_size).requires("PWHASH")
+                         .help("Set Argon2id memory limit (requires `--pwhash argon2id`)")))
+        .subcommand(SubCommand::with_name("key").about("Manage key slots: additional passphrases unlocking the encryption key")
+                    .subcommand(SubCommand::with_name("add").about("Add a key slot with a new passphrase")
+                                .arg(Arg::with_name("NAME").required(true).help("Name of the new key slot")))
+                    .subcommand(SubCommand::with_name("add-keyfile").about("Add a key slot unlocked with a new keyfile instead of a passphrase; the keyfile stops working once the slot is revoked")
+                                .arg(Arg::with_name("NAME").required(true).help("Name of the new key slot"))
+                                .arg(Arg::with_name("PATH").required(true).help("Keyfile to create")))
+                    .subcommand(SubCommand::with_name("list").visible_alias("ls").about("List key slots"))
+                    .subcommand(SubCommand::with_name("revoke").about("Remove a key slot")
+                                .arg(Arg::with_name("NAME").required(true).help("Name of the key slot to remove"))))
+        .subcommand(SubCommand::with_name("export-key").about("Export the unlocked encryption key to a keyfile")
+                    .arg(Arg::with_name("PATH").required(true).help("Keyfile to create")))
+        .subcommand(SubCommand::with_name("prune").about("Remove names according to a retention policy (run `gc` afterwards to reclaim space)")
+                    .arg(Arg::with_name("PREFIX").long("prefix").takes_value(true).help("Only names starting with the prefix"))
+                    .arg(Arg::with_name("TAG").long("tag").takes_value(true).value_name("KEY=VALUE").validator(validate_tag).help("Only names with the tag"))
+                    .arg(Arg::with_name("KEEP_LAST").long("keep-last").takes_value(true).value_name("N").help("Keep N newest names"))
+                    .arg(Arg::with_name("KEEP_HOURLY").long("keep-hourly").takes_value(true).value_name("N").help("Keep the newest name of each of N last hours"))
+                    .arg(Arg::with_name("KEEP_DAILY").long("keep-daily").takes_value(true).value_name("N").help("Keep the newest name of each of N last days"))
+                    .arg(Arg::with_name("KEEP_WEEKLY").long("keep-weekly").takes_value(true).value_name("N").help("Keep the newest name of each of N last weeks"))
+                    .arg(Arg::with_name("KEEP_MONTHLY").long("keep-monthly").takes_value(true).value_name("N").help("Keep the newest name of each of N last months"))
+                    .arg(Arg::with_name("KEEP_YEARLY").long("keep-yearly").takes_value(true).value_name("N").help("Keep the newest name of each of N last years"))
+                    .arg(Arg::with_name("DRY_RUN").long("dry-run").short("n").help("Only print what would be removed")))
         .subcommand(SubCommand::with_name("gc").about("Garbage collect unreferenced chunks")
                     .arg(Arg::with_name("GRACE_TIME").long("grace").takes_value(true).value_name("SECONDS").default_value("86400")
-                         .help("Set grace time in seconds")))
+                         .help("Set grace time in seconds"))
+                    .arg(Arg::with_name("PROGRESS").long("progress")
+                         .help("Show a progress bar with throughput and ETA on stderr")))
         .subcommand(SubCommand::with_name("verify").about("Verify integrity of data stored in the repository")
-                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to verify")))
+                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names (or glob patterns) to verify"))
+                    .arg(Arg::with_name("PROGRESS").long("progress")
+                         .help("Show a progress bar with throughput and ETA on stderr")))
+        .subcommand(SubCommand::with_name("fsck").about("Check consistency of the whole repository; prints results as JSON"))
         .subcommand(SubCommand::with_name("du").about("Calculate disk usage due to the data stored for a set of names")
-                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names to check")))
+                    .arg(Arg::with_name("NAME").required(true).multiple(true).help("Names (or glob patterns) to check")))
+        .subcommand(SubCommand::with_name("rebuild-cache").about("Rebuild the local cache of stored chunks"))
+        .subcommand(SubCommand::with_name("config-edit").about("Change settings of an existing repository")
+                    .setting(clap::AppSettings::ArgRequiredElseHelp)
+                    .arg(Arg::with_name("COMPRESSION_LEVEL").long("compression-level").takes_value(true).value_name("N")
+                         .allow_hyphen_values(true).validator(validate_compression_level)
+                         .help("Set level new data is compressed with, as defined by the compression scheme, or `default`; \
+                                not relative to the default level, unlike `init` of rdedup 3.1 and earlier"))
+                    .arg(Arg::with_name("UPGRADE_CHUNK_FORMAT").long("upgrade-chunk-format")
+                         .help("Prefix new chunks with a header describing their encoding; older versions of rdedup can't use the repository afterwards")))
+        .subcommand(SubCommand::with_name("train-dictionary").about("Train a zstd dictionary on stored data and compress new data with it")
+                    .arg(Arg::with_name("MAX_SIZE").long("max-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .default_value("110K").help("Set maximum size of the dictionary"))
+                    .arg(Arg::with_name("SAMPLE_SIZE").long("sample-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .default_value("10M").help("Set amount of stored data to train the dictionary on"))
+                    .arg(Arg::with_name("UNENCRYPTED_DICTIONARY").long("unencrypted-dictionary")
+                         .help("Store the dictionary unencrypted, so `store` doesn't need the passphrase. WARNING: the \
+                                dictionary contains fragments of the stored data, readable to anyone with access to the \
+                                repository")))
+        .subcommand(SubCommand::with_name("mount").about("Mount names stored in the repository as read-only files (requires `with-fuse` feature)")
+                    .arg(Arg::with_name("MOUNTPOINT").required(true).help("Directory to mount at"))
+                    .arg(Arg::with_name("CACHE_SIZE").long("cache-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .default_value("64M").help("Set size of the in-memory cache of chunks")))
+        .subcommand(SubCommand::with_name("nbd-serve").about("Export a name as a read-only network block device (NBD) on a Unix socket")
+                    .arg(Arg::with_name("NAME").required(true).help("Name to export"))
+                    .arg(Arg::with_name("SOCKET").long("socket").takes_value(true).value_name("PATH").required(true)
+                         .help("Unix socket to listen on"))
+                    .arg(Arg::with_name("CACHE_SIZE").long("cache-size").takes_value(true).value_name("N").validator(validate_chunk_size)
+                         .default_value("64M").help("Set size of the in-memory cache of chunks")))
         .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
 
@@ -412,6 +749,18 @@ fn run() -> io::Result<()> {
     };
 
     let mut options = Options::new(url);
+    options.keyfile = matches
+        .value_of_os("KEYFILE")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("RDEDUP_KEYFILE").map(PathBuf::from));
+    if matches.is_present("NO_CACHE") {
+        options.chunk_cache_dir = None;
+    } else if let Some(dir) = matches.value_of_os("CACHE_DIR") {
+        options.chunk_cache_dir = Some(PathBuf::from(dir));
+    }
+    options.lock_timeout = matches
+        .value_of("LOCK_TIMEOUT")
+        .map(|s| u64::from_str(s).expect("invalid lock timeout"));
 
     let log = create_logger(
         matches.occurrences_of("VERBOSE") as u32,
@@ -430,18 +779,34 @@ fn run() -> io::Result<()> {
                     .map(|u| u.trailing_zeros()),
             );
             options.set_encryption(matches.value_of("ENCRYPTION").unwrap());
-            options.settings.set_pwhash(settings::PWHash::from(
-                matches.value_of("PWHASH").unwrap(),
-            ));
+            options.settings.set_pwhash(pwhash_from_matches(matches)?);
             options.set_compression(matches.value_of("COMPRESSION").unwrap());
-            options.settings.set_compression_level(
-                i32::from_str(matches.value_of("COMPRESSION_LEVEL").unwrap())
-                    .expect("invalid compression level"),
-            );
+            if let Some(level) = matches.value_of("COMPRESSION_LEVEL") {
+                if let Some(level) = parse_compression_level(level) {
+                    options.settings.set_compression_level(level);
+                }
+            }
+            if let Some(percent) = matches.value_of("ADAPTIVE_COMPRESSION") {
+                let percent = u8::from_str(percent)
+                    .expect("invalid adaptive compression percentage");
+                options.settings.set_adaptive_compression(Some(percent))?;
+            }
             options.set_nesting(
                 u8::from_str(matches.value_of("NESTING").unwrap()).unwrap(),
             );
             options.set_hashing(matches.value_of("HASHING").unwrap());
+            options.set_layout(
+                matches.value_of("LAYOUT").unwrap(),
+                matches.value_of("PACK_SIZE").map(|s| {
+                    util::parse_size(s).expect("Invalid pack size option")
+                }),
+            );
+            if matches.is_present("LEGACY_INDEX") {
+                options.settings.use_legacy_index();
+            }
+            if matches.is_present("LEGACY_CHUNK_FORMAT") {
+                options.settings.use_legacy_chunk_format();
+            }
             let _ = Repo::init(
                 &options.url,
                 &|| util::read_new_passphrase(),
@@ -451,60 +816,230 @@ fn run() -> io::Result<()> {
         }
         ("store", Some(matches)) => {
             let name = matches.value_of("NAME").expect("name agument missing");
-            let repo = Repo::open(&options.url, log)?;
+            let mut repo = options.open_repo(log)?;
+            let progress = progress_bar(&mut repo, matches);
+            if let (Some(progress), Some(size)) =
+                (progress.as_ref(), util::stdin_size())
+            {
+                progress.set_total_bytes(size);
+            }
+            if matches.is_present("DRY_RUN") {
+                let results = repo.write_dry_run(&mut io::stdin())?;
+                if let Some(ref progress) = progress {
+                    progress.finish();
+                }
+                println!("{} chunks", results.chunks);
+                println!("{} bytes", results.bytes);
+                println!("{} new chunks", results.new_chunks);
+                println!("{} new bytes", results.new_bytes);
+                println!(
+                    "{} new chunks stored uncompressed",
+                    results.uncompressed_chunks
+                );
+                println!("{:.2} deduplication ratio", results.dedup_ratio());
+                return Ok(());
+            }
+            let mut write_options = lib::WriteOptions::new();
+            for tag in matches.values_of("TAG").into_iter().flat_map(|v| v) {
+                let (key, value) = parse_tag(tag)?;
+                write_options.set_tag(key, value);
+            }
+            let interval = util::parse_size(
+                matches.value_of("CHECKPOINT_INTERVAL").unwrap(),
+            ).expect("invalid checkpoint interval");
+            if interval > 0 {
+                write_options.set_checkpoint_interval(Some(interval));
+            }
+            write_options.set_resume(matches.is_present("RESUME"));
             let enc = repo.unlock_encrypt(&|| util::read_passphrase())?;
-            let stats = repo.write(name, &mut io::stdin(), &enc)?;
+            let stats =
+                repo.write_with(name, &mut io::stdin(), &enc, &write_options)?;
+            if let Some(ref progress) = progress {
+                progress.finish();
+            }
             println!("{} new chunks", stats.new_chunks);
             println!("{} new bytes", stats.new_bytes);
+            println!(
+                "{} new chunks stored uncompressed",
+                stats.uncompressed_chunks
+            );
         }
         ("load", Some(matches)) => {
             let name = matches.value_of("NAME").expect("name agument missing");
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| util::read_passphrase())?;
+            let mut repo = options.open_repo(log)?;
+            let _progress = progress_bar(&mut repo, matches);
+            let dec = options.unlock_decrypt(&repo)?;
             repo.read(name, &mut io::stdout(), &dec)?;
         }
-        ("change_passphrase", Some(_matches)) => {
-            let mut repo = Repo::open(&options.url, log)?;
-            repo.change_passphrase(&|| read_passphrase(), &|| {
-                read_new_passphrase()
-            })?;
+        ("change_passphrase", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            if matches.is_present("PWHASH") {
+                repo.change_passphrase_with_pwhash(
+                    &|| read_passphrase(),
+                    &|| read_new_passphrase(),
+                    pwhash_from_matches(matches)?,
+                )?;
+            } else {
+                repo.change_passphrase(&|| read_passphrase(), &|| {
+                    read_new_passphrase()
+                })?;
+            }
+        }
+        ("key", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            match matches.subcommand() {
+                ("add", Some(matches)) => {
+                    let name =
+                        matches.value_of("NAME").expect("name agument missing");
+                    repo.add_key_slot(name, &|| read_passphrase(), &|| {
+                        read_new_passphrase()
+                    })?;
+                }
+                ("add-keyfile", Some(matches)) => {
+                    let name =
+                        matches.value_of("NAME").expect("name agument missing");
+                    let path =
+                        matches.value_of_os("PATH").expect("path missing");
+                    // fail before touching the repository
+                    let mut file = util::create_secret_file(path)?;
+                    let keyfile = repo
+                        .add_keyfile_slot(name, &|| read_passphrase())
+                        .map_err(|e| {
+                            let _ = fs::remove_file(path);
+                            e
+                        })?;
+                    keyfile.write(&mut file)?;
+                }
+                ("list", Some(_matches)) => {
+                    for name in repo.list_key_slots() {
+                        println!("{}", name);
+                    }
+                }
+                ("revoke", Some(matches)) => {
+                    let name =
+                        matches.value_of("NAME").expect("name agument missing");
+                    repo.revoke_key_slot(name)?;
+                }
+                _ => {
+                    eprintln!("{}", matches.usage());
+                    process::exit(-1);
+                }
+            }
         }
         ("remove", Some(matches)) => {
-            let repo = Repo::open(&options.url, log)?;
-            for name in matches.values_of("NAME").expect("names missing") {
-                repo.rm(name)?;
+            let repo = options.open_repo(log)?;
+            if let Some(prefix) = matches.value_of("PREFIX") {
+                for name in repo.rm_prefix(prefix)? {
+                    println!("removed {}", name);
+                }
+            }
+            let names = expand_names(
+                &repo,
+                matches.values_of("NAME").into_iter().flat_map(|v| v),
+            )?;
+            for name in names {
+                repo.rm(&name)?;
             }
         }
         ("du", Some(matches)) => {
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
+            let repo = options.open_repo(log)?;
+            let dec = options.unlock_decrypt(&repo)?;
 
-            for name in matches.values_of("NAME").expect("names missing") {
-                let result = repo.du(name, &dec)?;
+            let names = expand_names(
+                &repo,
+                matches.values_of("NAME").expect("names missing"),
+            )?;
+            for name in names {
+                let result = repo.du(&name, &dec)?;
                 println!("{} chunks", result.chunks);
                 println!("{} bytes", result.bytes);
             }
         }
+        ("prune", Some(matches)) => {
+            let keep = |arg: &str| {
+                matches.value_of(arg).map_or(0, |s| {
+                    usize::from_str(s).expect("invalid number of names to keep")
+                })
+            };
+            let prune_options = lib::PruneOptions {
+                prefix: matches.value_of("PREFIX").map(|s| s.to_owned()),
+                tag: match matches.value_of("TAG") {
+                    Some(tag) => {
+                        let (key, value) = parse_tag(tag)?;
+                        Some((key.to_owned(), value.to_owned()))
+                    }
+                    None => None,
+                },
+                keep_last: keep("KEEP_LAST"),
+                keep_hourly: keep("KEEP_HOURLY"),
+                keep_daily: keep("KEEP_DAILY"),
+                keep_weekly: keep("KEEP_WEEKLY"),
+                keep_monthly: keep("KEEP_MONTHLY"),
+                keep_yearly: keep("KEEP_YEARLY"),
+                dry_run: matches.is_present("DRY_RUN"),
+            };
+            let repo = options.open_repo(log)?;
+
+            for decision in repo.prune(&prune_options)? {
+                if decision.keep() {
+                    println!(
+                        "keep\t{}\t{}",
+                        decision.name,
+                        decision.kept_by.join(", ")
+                    );
+                } else {
+                    println!(
+                        "{}\t{}\t{}",
+                        if prune_options.dry_run {
+                            "would remove"
+                        } else {
+                            "remove"
+                        },
+                        decision.name,
+                        decision.skipped_by.join(", ")
+                    );
+                }
+            }
+        }
         ("gc", Some(matches)) => {
             let grace_secs = u64::from_str(
                 matches.value_of("GRACE_TIME").unwrap(),
             ).expect("invalid grace time");
-            let repo = Repo::open(&options.url, log)?;
+            let mut repo = options.open_repo(log)?;
+            let _progress = progress_bar(&mut repo, matches);
 
             repo.gc(grace_secs)?;
         }
-        ("list", Some(_matches)) => {
-            let repo = Repo::open(&options.url, log)?;
+        ("list", Some(matches)) => {
+            let repo = options.open_repo(log)?;
 
-            for name in repo.list_names()? {
-                println!("{}", name);
+            let mut names = match matches.value_of("PREFIX") {
+                Some(prefix) => repo.list_names_with_prefix(prefix)?,
+                None => repo.list_names()?,
+            };
+            names.sort();
+            if matches.is_present("LONG") {
+                let metadata = repo.names_metadata(&names)?;
+                for (name, metadata) in names.iter().zip(metadata) {
+                    println!("{}", util::format_name_long(name, &metadata));
+                }
+            } else {
+                for name in names {
+                    println!("{}", name);
+                }
             }
         }
         ("verify", Some(matches)) => {
-            let repo = Repo::open(&options.url, log)?;
-            let dec = repo.unlock_decrypt(&|| read_passphrase())?;
-            for name in matches.values_of("NAME").expect("values") {
-                let results = repo.verify(name, &dec)?;
+            let mut repo = options.open_repo(log)?;
+            let progress = progress_bar(&mut repo, matches);
+            let dec = options.unlock_decrypt(&repo)?;
+            let names =
+                expand_names(&repo, matches.values_of("NAME").expect("values"))?;
+            for name in names {
+                let results = repo.verify(&name, &dec)?;
+                if let Some(ref progress) = progress {
+                    progress.clear();
+                }
                 println!("scanned {} chunk(s)", results.scanned);
                 println!("found {} corrupted chunk(s)", results.errors.len());
                 for err in results.errors {
@@ -512,6 +1047,110 @@ fn run() -> io::Result<()> {
                 }
             }
         }
+        ("fsck", Some(_matches)) => {
+            let repo = options.open_repo(log)?;
+            let dec = options.unlock_decrypt(&repo)?;
+            let results = repo.fsck(&dec)?;
+            results.write_json(&mut io::stdout())?;
+            if !results.is_ok() {
+                process::exit(1);
+            }
+        }
+        ("export-key", Some(matches)) => {
+            let path = matches.value_of_os("PATH").expect("path missing");
+            let repo = options.open_repo(log)?;
+            let keyfile = repo.export_keyfile(&|| read_passphrase())?;
+            keyfile.write(&mut util::create_secret_file(path)?)?;
+        }
+        ("rebuild-cache", Some(_matches)) => {
+            let repo = options.open_repo(log)?;
+            repo.rebuild_chunk_cache()?;
+        }
+        ("config-edit", Some(matches)) => {
+            let mut repo = options.open_repo(log)?;
+            if let Some(level) = matches.value_of("COMPRESSION_LEVEL") {
+                repo.set_compression_level(parse_compression_level(level))?;
+            }
+            if matches.is_present("UPGRADE_CHUNK_FORMAT") {
+                repo.upgrade_chunk_format()?;
+            }
+        }
+        #[cfg(feature = "with-zstd")]
+        ("train-dictionary", Some(matches)) => {
+            let max_size =
+                util::parse_size(matches.value_of("MAX_SIZE").unwrap())
+                    .expect("invalid dictionary size");
+            let sample_size =
+                util::parse_size(matches.value_of("SAMPLE_SIZE").unwrap())
+                    .expect("invalid sample size");
+            let unencrypted = matches.is_present("UNENCRYPTED_DICTIONARY");
+            if unencrypted {
+                eprintln!(
+                    "WARNING: the dictionary is stored unencrypted; fragments \
+                     of the stored data it contains are readable to anyone \
+                     with access to the repository"
+                );
+            }
+            let mut repo = options.open_repo(log)?;
+            let dec = options.unlock_decrypt(&repo)?;
+            let id = repo.train_dictionary(
+                &dec,
+                max_size as usize,
+                sample_size,
+                unencrypted,
+            )?;
+            println!("stored dictionary {}", id);
+        }
+        #[cfg(not(feature = "with-zstd"))]
+        ("train-dictionary", Some(_matches)) => {
+            eprintln!("rdedup was built without `with-zstd` feature");
+            process::exit(-1);
+        }
+        #[cfg(feature = "with-fuse")]
+        ("mount", Some(matches)) => {
+            let mountpoint =
+                matches.value_of_os("MOUNTPOINT").expect("mountpoint missing");
+            let cache_size = util::parse_size(
+                matches.value_of("CACHE_SIZE").unwrap(),
+            ).expect("invalid cache size");
+            let repo = options.open_repo(log.clone())?;
+            let dec = options.unlock_decrypt(&repo)?;
+            mount::mount(
+                &repo,
+                &dec,
+                mountpoint.as_ref(),
+                cache_size as usize,
+                log,
+            )?;
+        }
+        #[cfg(not(feature = "with-fuse"))]
+        ("mount", Some(_matches)) => {
+            eprintln!("rdedup was built without `with-fuse` feature");
+            process::exit(-1);
+        }
+        #[cfg(unix)]
+        ("nbd-serve", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("name agument missing");
+            let socket = matches.value_of_os("SOCKET").expect("socket missing");
+            let cache_size = util::parse_size(
+                matches.value_of("CACHE_SIZE").unwrap(),
+            ).expect("invalid cache size");
+            let repo = options.open_repo(log.clone())?;
+            let dec = options.unlock_decrypt(&repo)?;
+            nbd::serve(
+                &repo,
+                &dec,
+                name,
+                socket.as_ref(),
+                cache_size as usize,
+                log,
+            )?;
+        }
+        #[cfg(not(unix))]
+        ("nbd-serve", Some(_matches)) => {
+            eprintln!("nbd-serve is supported only on Unix");
+            process::exit(-1);
+        }
         _ => panic!("Unrecognized subcommand"),
     }
 
@@ -0,0 +1,448 @@
+//! `rdedup mount`: FUSE filesystem exposing names as read-only files
+//!
+//! Every name stored in the repository is a file; components of
+//! hierarchical names (`team-a/db/2026-10-17`) are directories.
+//! Data is read on demand, with recently used chunks kept in a cache
+//! shared by all open files.
+use fuse;
+use fuse::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, Request,
+};
+use lib::{ChunkCache, DecryptHandle, NameReader, Repo};
+use libc;
+use libc::{EINVAL, EIO, ENOENT, EROFS};
+#[cfg(test)]
+use slog;
+use slog::Logger;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use time::{self, Timespec};
+
+const ROOT_INO: u64 = 1;
+/// Attributes don't change while mounted (except when names are added
+/// or removed, which is not reflected anyway)
+const TTL: Timespec = Timespec { sec: 60, nsec: 0 };
+
+enum EntryKind {
+    /// Inode numbers of entries in the directory
+    Dir(Vec<u64>),
+    /// Full name
+    File(String),
+}
+
+struct Entry {
+    parent: u64,
+    /// Last component of the name
+    name: String,
+    kind: EntryKind,
+}
+
+/// Directory tree of `names`, with inode number of an entry `index + 1`
+///
+/// A name that is also a directory of other names (`a` and `a/b`) can't be
+/// both; the directory is skipped.
+fn build_tree(names: &[String], log: &Logger) -> Vec<Entry> {
+    let mut entries = vec![Entry {
+        parent: ROOT_INO,
+        name: String::new(),
+        kind: EntryKind::Dir(vec![]),
+    }];
+
+    'names: for name in names {
+        let mut parent = ROOT_INO;
+        let mut components = name.split('/').peekable();
+        while let Some(component) = components.next() {
+            let last = components.peek().is_none();
+            let existing = match entries[parent as usize - 1].kind {
+                EntryKind::Dir(ref children) => children
+                    .iter()
+                    .find(|&&ino| entries[ino as usize - 1].name == component)
+                    .cloned(),
+                EntryKind::File(_) => unreachable!(),
+            };
+
+            let ino = match existing {
+                Some(ino) => match entries[ino as usize - 1].kind {
+                    EntryKind::Dir(_) if !last => ino,
+                    _ => {
+                        warn!(log, "name conflicts with another, skipping";
+                              "name" => name.as_str());
+                        continue 'names;
+                    }
+                },
+                None => {
+                    let kind = if last {
+                        EntryKind::File(name.clone())
+                    } else {
+                        EntryKind::Dir(vec![])
+                    };
+                    entries.push(Entry {
+                        parent,
+                        name: component.to_owned(),
+                        kind,
+                    });
+                    let ino = entries.len() as u64;
+                    if let EntryKind::Dir(ref mut children) =
+                        entries[parent as usize - 1].kind
+                    {
+                        children.push(ino);
+                    }
+                    ino
+                }
+            };
+            parent = ino;
+        }
+    }
+    entries
+}
+
+struct RepoFs<'a> {
+    repo: &'a Repo,
+    dec: &'a DecryptHandle,
+    entries: Vec<Entry>,
+    /// Data lengths of names, read when first needed
+    sizes: HashMap<u64, u64>,
+    readers: HashMap<u64, NameReader<'a>>,
+    next_fh: u64,
+    cache: Arc<ChunkCache>,
+    mount_time: Timespec,
+    uid: u32,
+    gid: u32,
+    log: Logger,
+}
+
+impl<'a> RepoFs<'a> {
+    fn entry(&self, ino: u64) -> Option<&Entry> {
+        if ino < 1 {
+            return None;
+        }
+        self.entries.get((ino - 1) as usize)
+    }
+
+    /// Full name of a file
+    fn name(&self, ino: u64) -> Option<&str> {
+        match self.entry(ino).map(|entry| &entry.kind) {
+            Some(EntryKind::File(name)) => Some(name.as_str()),
+            _ => None,
+        }
+    }
+
+    fn children(&self, ino: u64) -> Option<&[u64]> {
+        match self.entry(ino).map(|entry| &entry.kind) {
+            Some(EntryKind::Dir(children)) => Some(children.as_slice()),
+            _ => None,
+        }
+    }
+
+    fn open_reader(&self, name: &str) -> io::Result<NameReader<'a>> {
+        let mut reader = self.repo.name_reader(name, self.dec)?;
+        reader.set_chunk_cache(Arc::clone(&self.cache));
+        Ok(reader)
+    }
+
+    fn size(&mut self, ino: u64) -> io::Result<u64> {
+        if let Some(&size) = self.sizes.get(&ino) {
+            return Ok(size);
+        }
+
+        let name = self.name(ino).expect("valid inode").to_owned();
+        // recorded with the name, unless stored by an older version
+        let size = match self.repo.name_metadata(&name)?.size {
+            Some(size) => size,
+            None => self.open_reader(&name)?.data_len()?,
+        };
+        self.sizes.insert(ino, size);
+        Ok(size)
+    }
+
+    fn attr(&self, ino: u64, kind: FileType, size: u64) -> FileAttr {
+        let (perm, nlink) = match kind {
+            FileType::Directory => (0o555, 2),
+            _ => (0o444, 1),
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: self.mount_time,
+            mtime: self.mount_time,
+            ctime: self.mount_time,
+            crtime: self.mount_time,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn entry_attr(&mut self, ino: u64) -> io::Result<FileAttr> {
+        if self.children(ino).is_some() {
+            return Ok(self.attr(ino, FileType::Directory, 0));
+        }
+        let size = self.size(ino)?;
+        Ok(self.attr(ino, FileType::RegularFile, size))
+    }
+}
+
+impl<'a> Filesystem for RepoFs<'a> {
+    fn lookup(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let found = match (name.to_str(), self.children(parent)) {
+            (Some(name), Some(children)) => children
+                .iter()
+                .find(|&&ino| self.entries[ino as usize - 1].name == name)
+                .cloned(),
+            _ => None,
+        };
+        let ino = match found {
+            Some(ino) => ino,
+            None => return reply.error(ENOENT),
+        };
+
+        match self.entry_attr(ino) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => {
+                warn!(self.log, "couldn't read name size";
+                      "name" => %name.to_string_lossy(), "err" => %e);
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        if self.entry(ino).is_none() {
+            return reply.error(ENOENT);
+        }
+
+        match self.entry_attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => {
+                warn!(self.log, "couldn't read name size";
+                      "ino" => ino, "err" => %e);
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn open(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        flags: u32,
+        reply: ReplyOpen,
+    ) {
+        let name = match self.name(ino) {
+            Some(name) => name.to_owned(),
+            None => return reply.error(ENOENT),
+        };
+        if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+            return reply.error(EROFS);
+        }
+
+        match self.open_reader(&name) {
+            Ok(reader) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.readers.insert(fh, reader);
+                reply.opened(fh, 0)
+            }
+            Err(e) => {
+                warn!(self.log, "couldn't open name";
+                      "name" => name, "err" => %e);
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        let reader = match self.readers.get_mut(&fh) {
+            Some(reader) => reader,
+            None => return reply.error(EINVAL),
+        };
+        if offset < 0 {
+            return reply.error(EINVAL);
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let res = reader.seek(SeekFrom::Start(offset as u64)).and_then(|_| {
+            let mut read = 0;
+            while read < buf.len() {
+                match reader.read(&mut buf[read..])? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            Ok(read)
+        });
+
+        match res {
+            Ok(read) => reply.data(&buf[..read]),
+            Err(e) => {
+                warn!(self.log, "read failed";
+                      "fh" => fh, "offset" => offset, "err" => %e);
+                reply.error(EIO)
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.readers.remove(&fh);
+        reply.ok()
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let children = match self.children(ino) {
+            Some(children) => children,
+            None => return reply.error(ENOENT),
+        };
+
+        let parent = self.entries[ino as usize - 1].parent;
+        let entries = [(ino, "."), (parent, "..")];
+        let entries = entries
+            .iter()
+            .map(|&(ino, name)| (ino, FileType::Directory, name))
+            .chain(children.iter().map(|&ino| {
+                let entry = &self.entries[ino as usize - 1];
+                let kind = match entry.kind {
+                    EntryKind::Dir(_) => FileType::Directory,
+                    EntryKind::File(_) => FileType::RegularFile,
+                };
+                (ino, kind, entry.name.as_str())
+            }));
+
+        // `offset` is the one of the last entry returned previously
+        for (i, (ino, kind, name)) in entries.enumerate().skip(offset as usize)
+        {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+}
+
+/// Mount the names stored in `repo` at `mountpoint`
+///
+/// Blocks until unmounted (eg. with `fusermount -u`).
+pub fn mount(
+    repo: &Repo,
+    dec: &DecryptHandle,
+    mountpoint: &Path,
+    cache_size: usize,
+    log: Logger,
+) -> io::Result<()> {
+    let mut names = repo.list_names()?;
+    names.sort();
+
+    let fs = RepoFs {
+        repo,
+        dec,
+        entries: build_tree(&names, &log),
+        sizes: HashMap::new(),
+        readers: HashMap::new(),
+        next_fh: 0,
+        cache: Arc::new(ChunkCache::new(cache_size)),
+        mount_time: time::get_time(),
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        log,
+    };
+
+    let options = ["-o", "ro", "-o", "fsname=rdedup"]
+        .iter()
+        .map(OsStr::new)
+        .collect::<Vec<_>>();
+    fuse::mount(fs, &mountpoint, &options)
+}
+
+/// Full names of files in the tree, checking it's consistent
+#[cfg(test)]
+fn tree_files(entries: &[Entry]) -> Vec<String> {
+    let mut files = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        let ino = i as u64 + 1;
+        if ino != ROOT_INO {
+            match entries[entry.parent as usize - 1].kind {
+                EntryKind::Dir(ref children) => {
+                    assert!(children.contains(&ino))
+                }
+                EntryKind::File(_) => panic!("parent is a file"),
+            }
+        }
+        if let EntryKind::File(ref name) = entry.kind {
+            assert!(name.ends_with(&entry.name));
+            files.push(name.clone());
+        }
+    }
+    files
+}
+
+#[test]
+fn build_tree_nested() {
+    let log = Logger::root(slog::Discard, o!());
+    let names: Vec<String> = ["a", "b/c", "b/d/e", "b/d/f"]
+        .iter()
+        .map(|&s| s.into())
+        .collect();
+    let entries = build_tree(&names, &log);
+
+    assert_eq!(tree_files(&entries), names);
+    // root, a, b, b/c, b/d, b/d/e, b/d/f
+    assert_eq!(entries.len(), 7);
+    match entries[ROOT_INO as usize - 1].kind {
+        EntryKind::Dir(ref children) => assert_eq!(children.len(), 2),
+        EntryKind::File(_) => panic!("root is a file"),
+    }
+}
+
+#[test]
+fn build_tree_conflicts() {
+    fn tree(names: &[&str]) -> Vec<String> {
+        let log = Logger::root(slog::Discard, o!());
+        let names: Vec<String> = names.iter().map(|&s| s.into()).collect();
+        tree_files(&build_tree(&names, &log))
+    }
+
+    // sorted names: the file comes first, so names under it are skipped
+    assert_eq!(tree(&["a", "a/b", "a/c/d", "e"]), vec!["a", "e"]);
+    // a name that is a directory already is skipped
+    assert_eq!(tree(&["a/b", "a"]), vec!["a/b"]);
+    // duplicates
+    assert_eq!(tree(&["a", "a"]), vec!["a"]);
+}
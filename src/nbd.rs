@@ -0,0 +1,413 @@
+//! `rdedup nbd-serve`: read-only Network Block Device export of a name
+//!
+//! Implements the server side of the "fixed newstyle" NBD protocol
+//! (https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md)
+//! over a Unix socket, eg.:
+//!
+//! ```norust
+//! rdedup nbd-serve vm-image --socket /tmp/vm.sock
+//! nbd-client -unix /tmp/vm.sock /dev/nbd0 -readonly -name vm-image
+//! ```
+//!
+//! Clients are served one at a time.
+use lib::{ChunkCache, DecryptHandle, Repo};
+use slog::Logger;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::Arc;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const FLAG_HAS_FLAGS: u16 = 1 << 0;
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const TRANSMISSION_FLAGS: u16 = FLAG_HAS_FLAGS | FLAG_READ_ONLY;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+
+const INFO_EXPORT: u16 = 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+/// Limits protecting from misbehaving clients
+const MAX_OPTION_LEN: u32 = 4096;
+const MAX_READ_LEN: u32 = 32 * 1024 * 1024;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_option_reply<W: Write>(
+    w: &mut W,
+    option: u32,
+    reply_type: u32,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&reply_type.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    w.write_all(&buf)?;
+    w.flush()
+}
+
+fn write_simple_reply<W: Write>(
+    w: &mut W,
+    error: u32,
+    handle: u64,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&error.to_be_bytes());
+    buf.extend_from_slice(&handle.to_be_bytes());
+    buf.extend_from_slice(data);
+    w.write_all(&buf)?;
+    w.flush()
+}
+
+/// Empty export name means the default export: the only one we have
+fn export_matches(requested: &[u8], name: &str) -> bool {
+    requested.is_empty() || requested == name.as_bytes()
+}
+
+/// Handshake and option haggling
+///
+/// Returns `false` if the client went away without starting
+/// the transmission.
+fn negotiate<S: Read + Write>(
+    stream: &mut S,
+    name: &str,
+    size: u64,
+) -> io::Result<bool> {
+    let mut hello = Vec::with_capacity(18);
+    hello.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+    hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    hello.extend_from_slice(
+        &(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes(),
+    );
+    stream.write_all(&hello)?;
+    stream.flush()?;
+
+    let client_flags = read_u32(stream)?;
+    let no_zeroes = client_flags & FLAG_C_NO_ZEROES != 0;
+
+    loop {
+        if read_u64(stream)? != IHAVEOPT {
+            return Err(invalid_data("invalid option magic"));
+        }
+        let option = read_u32(stream)?;
+        let len = read_u32(stream)?;
+        if len > MAX_OPTION_LEN {
+            return Err(invalid_data("option too long"));
+        }
+        let mut data = vec![0u8; len as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            OPT_EXPORT_NAME => {
+                // no way to report an error other than disconnecting
+                if !export_matches(&data, name) {
+                    return Ok(false);
+                }
+                let mut buf = size.to_be_bytes().to_vec();
+                buf.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+                if !no_zeroes {
+                    buf.extend_from_slice(&[0u8; 124]);
+                }
+                stream.write_all(&buf)?;
+                stream.flush()?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                write_option_reply(stream, option, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                let mut server = (name.len() as u32).to_be_bytes().to_vec();
+                server.extend_from_slice(name.as_bytes());
+                write_option_reply(stream, option, REP_SERVER, &server)?;
+                write_option_reply(stream, option, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                // name length, name, information requests (ignored, as we
+                // only have the basic information anyway)
+                let requested = if data.len() >= 4 {
+                    let mut name_len = [0u8; 4];
+                    name_len.copy_from_slice(&data[..4]);
+                    let name_len = u32::from_be_bytes(name_len) as usize;
+                    data.get(4..4 + name_len)
+                } else {
+                    None
+                };
+
+                match requested {
+                    None => write_option_reply(
+                        stream,
+                        option,
+                        REP_ERR_INVALID,
+                        &[],
+                    )?,
+                    Some(requested) if !export_matches(requested, name) => {
+                        write_option_reply(
+                            stream,
+                            option,
+                            REP_ERR_UNKNOWN,
+                            &[],
+                        )?
+                    }
+                    Some(_) => {
+                        let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                        info.extend_from_slice(&size.to_be_bytes());
+                        info.extend_from_slice(
+                            &TRANSMISSION_FLAGS.to_be_bytes(),
+                        );
+                        write_option_reply(stream, option, REP_INFO, &info)?;
+                        write_option_reply(stream, option, REP_ACK, &[])?;
+                        if option == OPT_GO {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+            _ => write_option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn read_at<E: Read + Seek>(
+    export: &mut E,
+    offset: u64,
+    len: u32,
+) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; len as usize];
+    export.seek(SeekFrom::Start(offset))?;
+    export.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Serve requests until the client disconnects
+fn transmit<S: Read + Write, E: Read + Seek>(
+    stream: &mut S,
+    export: &mut E,
+    size: u64,
+    log: &Logger,
+) -> io::Result<()> {
+    loop {
+        if read_u32(stream)? != REQUEST_MAGIC {
+            return Err(invalid_data("invalid request magic"));
+        }
+        let _flags = read_u16(stream)?;
+        let cmd = read_u16(stream)?;
+        let handle = read_u64(stream)?;
+        let offset = read_u64(stream)?;
+        let len = read_u32(stream)?;
+
+        match cmd {
+            CMD_READ => {
+                let in_bounds = offset
+                    .checked_add(u64::from(len))
+                    .map_or(false, |end| end <= size);
+                if !in_bounds || len > MAX_READ_LEN {
+                    write_simple_reply(stream, EINVAL, handle, &[])?;
+                    continue;
+                }
+
+                match read_at(export, offset, len) {
+                    Ok(data) => write_simple_reply(stream, 0, handle, &data)?,
+                    Err(e) => {
+                        warn!(log, "read failed";
+                              "offset" => offset, "len" => len, "err" => %e);
+                        write_simple_reply(stream, EIO, handle, &[])?
+                    }
+                }
+            }
+            CMD_WRITE => {
+                // skip the data
+                let skipped = io::copy(
+                    &mut (&mut *stream).take(u64::from(len)),
+                    &mut io::sink(),
+                )?;
+                if skipped != u64::from(len) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "incomplete write request",
+                    ));
+                }
+                write_simple_reply(stream, EPERM, handle, &[])?;
+            }
+            CMD_DISC => return Ok(()),
+            _ => write_simple_reply(stream, EINVAL, handle, &[])?,
+        }
+    }
+}
+
+/// Serve `export` of `size` bytes to a single client
+fn serve_client<S: Read + Write, E: Read + Seek>(
+    stream: &mut S,
+    name: &str,
+    export: &mut E,
+    size: u64,
+    log: &Logger,
+) -> io::Result<()> {
+    if negotiate(stream, name, size)? {
+        transmit(stream, export, size, log)
+    } else {
+        Ok(())
+    }
+}
+
+/// Export data stored under `name` as a read-only block device
+///
+/// Listens on a Unix socket at `socket_path` (that must not exist),
+/// serving clients until killed.
+pub fn serve(
+    repo: &Repo,
+    dec: &DecryptHandle,
+    name: &str,
+    socket_path: &Path,
+    cache_size: usize,
+    log: Logger,
+) -> io::Result<()> {
+    let cache = Arc::new(ChunkCache::new(cache_size));
+    let listener = UnixListener::bind(socket_path)?;
+    info!(log, "serving";
+          "name" => name, "socket" => %socket_path.display());
+
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let mut reader = repo.name_reader(name, dec)?;
+        reader.set_chunk_cache(Arc::clone(&cache));
+        let size = reader.data_len()?;
+
+        debug!(log, "client connected");
+        let res = serve_client(&mut stream, name, &mut reader, size, &log);
+        if let Err(e) = res {
+            warn!(log, "client error"; "err" => %e);
+        }
+        debug!(log, "client disconnected");
+    }
+    Ok(())
+}
+
+#[test]
+fn nbd_go_and_read() {
+    use slog;
+
+    /// Client data to read, and server responses
+    struct Stream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request(input: &mut Vec<u8>, cmd: u16, handle: u64, offset: u64) {
+        input.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        input.extend_from_slice(&0u16.to_be_bytes());
+        input.extend_from_slice(&cmd.to_be_bytes());
+        input.extend_from_slice(&handle.to_be_bytes());
+        input.extend_from_slice(&offset.to_be_bytes());
+        input.extend_from_slice(&4u32.to_be_bytes());
+    }
+
+    let export_data: Vec<u8> = (0..100).collect();
+
+    let mut input = vec![];
+    input.extend_from_slice(&(FLAG_FIXED_NEWSTYLE as u32).to_be_bytes());
+    // NBD_OPT_GO of the default export, without information requests
+    input.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    input.extend_from_slice(&OPT_GO.to_be_bytes());
+    input.extend_from_slice(&6u32.to_be_bytes());
+    input.extend_from_slice(&[0; 6]);
+    request(&mut input, CMD_READ, 7, 10);
+    request(&mut input, CMD_READ, 8, 98);
+    request(&mut input, CMD_DISC, 9, 0);
+
+    let mut stream = Stream {
+        input: io::Cursor::new(input),
+        output: vec![],
+    };
+    serve_client(
+        &mut stream,
+        "name",
+        &mut io::Cursor::new(&export_data),
+        export_data.len() as u64,
+        &Logger::root(slog::Discard, o!()),
+    ).unwrap();
+
+    let mut expected = vec![];
+    expected.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+    expected.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    let handshake_flags = FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES;
+    expected.extend_from_slice(&handshake_flags.to_be_bytes());
+    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+    info.extend_from_slice(&100u64.to_be_bytes());
+    info.extend_from_slice(&TRANSMISSION_FLAGS.to_be_bytes());
+    write_option_reply(&mut expected, OPT_GO, REP_INFO, &info).unwrap();
+    write_option_reply(&mut expected, OPT_GO, REP_ACK, &[]).unwrap();
+    write_simple_reply(&mut expected, 0, 7, &export_data[10..14]).unwrap();
+    // beyond the end
+    write_simple_reply(&mut expected, EINVAL, 8, &[]).unwrap();
+
+    assert_eq!(stream.output, expected);
+}
@@ -0,0 +1,204 @@
+//! `--progress` bar, drawn on stderr
+use lib::ProgressObserver;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use util::{format_duration, format_size};
+
+/// Minimum time between redraws
+const REDRAW_INTERVAL_MS: u64 = 200;
+
+#[derive(Default)]
+struct State {
+    total_bytes: Option<u64>,
+    total_names: Option<u64>,
+    bytes: u64,
+    chunks: u64,
+    /// `None` until anything new is stored
+    new_bytes: Option<u64>,
+    names: u64,
+    last_draw: Option<Instant>,
+    finished: bool,
+}
+
+impl State {
+    /// Progress line after `elapsed` time
+    fn line(&self, elapsed: Duration) -> String {
+        let secs = elapsed.as_secs_f64();
+        let mut line = if let Some(total_names) = self.total_names {
+            // `gc`: moving names, no data is read
+            let mut line = format!(
+                "{}/{} names, {} chunks",
+                self.names, total_names, self.chunks
+            );
+            if let Some(eta) = eta(self.names, total_names, secs) {
+                line.push_str(&format!(", ETA {}", eta));
+            }
+            line
+        } else {
+            let rate = if secs > 0.0 {
+                (self.bytes as f64 / secs) as u64
+            } else {
+                0
+            };
+            let mut line = format_size(self.bytes);
+            if let Some(total_bytes) = self.total_bytes {
+                if total_bytes > 0 {
+                    let percent =
+                        self.bytes.min(total_bytes) * 100 / total_bytes;
+                    line.push_str(&format!(" ({}%)", percent));
+                }
+            }
+            line.push_str(&format!(
+                " at {}/s, {} chunks",
+                format_size(rate),
+                self.chunks
+            ));
+            if let Some(new_bytes) = self.new_bytes {
+                line.push_str(&format!(", {} new", format_size(new_bytes)));
+            }
+            if let Some(total_bytes) = self.total_bytes {
+                if let Some(eta) = eta(self.bytes, total_bytes, secs) {
+                    line.push_str(&format!(", ETA {}", eta));
+                }
+            }
+            line
+        };
+        let elapsed = format_duration(elapsed.as_secs());
+        line.push_str(&format!(", {} elapsed", elapsed));
+        line
+    }
+}
+
+/// Time left if the rate so far doesn't change
+fn eta(done: u64, total: u64, secs: f64) -> Option<String> {
+    if done == 0 || done > total {
+        return None;
+    }
+    let left = (total - done) as f64 * secs / done as f64;
+    Some(format_duration(left.round() as u64))
+}
+
+/// Progress bar showing throughput and ETA
+///
+/// The ETA is known only after `total_bytes` or `total_names` is reported,
+/// or `set_total_bytes` called. The line is ended by `finish`, or when the
+/// bar is dropped (eg. on an error).
+pub struct ProgressBar {
+    start: Instant,
+    state: Mutex<State>,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        ProgressBar {
+            start: Instant::now(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Set the amount of data to process (eg. the size of the input)
+    pub fn set_total_bytes(&self, bytes: u64) {
+        self.state.lock().unwrap().total_bytes = Some(bytes);
+    }
+
+    /// Update the state and redraw, unless it was done very recently
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut State),
+    {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+
+        let now = Instant::now();
+        let redraw = state.last_draw.map_or(true, |last| {
+            now - last >= Duration::from_millis(REDRAW_INTERVAL_MS)
+        });
+        if redraw && !state.finished {
+            state.last_draw = Some(now);
+            eprint!("\r{}\x1b[K", state.line(now - self.start));
+        }
+    }
+
+    /// Erase the bar, to print something else; it's drawn again on the
+    /// next update
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.last_draw.take().is_some() && !state.finished {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    /// Draw the final state and end the line
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.finished {
+            state.finished = true;
+            eprintln!("\r{}\x1b[K", state.line(self.start.elapsed()));
+        }
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl ProgressObserver for ProgressBar {
+    // Totals are reported for every name of a multi-name command, so they
+    // are counted from the progress so far
+
+    fn total_bytes(&self, bytes: u64) {
+        self.update(|state| state.total_bytes = Some(state.bytes + bytes));
+    }
+
+    fn total_names(&self, names: u64) {
+        self.update(|state| state.total_names = Some(state.names + names));
+    }
+
+    fn bytes_consumed(&self, bytes: u64) {
+        self.update(|state| state.bytes += bytes);
+    }
+
+    fn chunk_processed(&self) {
+        self.update(|state| state.chunks += 1);
+    }
+
+    fn new_bytes(&self, bytes: u64) {
+        self.update(|state| {
+            state.new_bytes = Some(state.new_bytes.unwrap_or(0) + bytes)
+        });
+    }
+
+    fn name_handled(&self, _name: &str) {
+        self.update(|state| state.names += 1);
+    }
+}
+
+#[test]
+fn test_progress_line() {
+    let mut state = State::default();
+    state.bytes = 10 * 1024 * 1024;
+    state.chunks = 20;
+    assert_eq!(
+        state.line(Duration::from_secs(10)),
+        "10.0 MiB at 1.0 MiB/s, 20 chunks, 00:10 elapsed"
+    );
+
+    state.total_bytes = Some(40 * 1024 * 1024);
+    state.new_bytes = Some(1024);
+    assert_eq!(
+        state.line(Duration::from_secs(10)),
+        "10.0 MiB (25%) at 1.0 MiB/s, 20 chunks, 1.0 KiB new, ETA 00:30, \
+         00:10 elapsed"
+    );
+
+    let mut state = State::default();
+    state.total_names = Some(4);
+    state.names = 1;
+    state.chunks = 5;
+    assert_eq!(
+        state.line(Duration::from_secs(60)),
+        "1/4 names, 5 chunks, ETA 03:00, 01:00 elapsed"
+    );
+}
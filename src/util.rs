@@ -1,6 +1,11 @@
+use lib::NameMetadata;
 use rpassword;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::{env, io};
+use url::Url;
 
 /// Parse human-readable size string
 ///
@@ -59,6 +64,175 @@ fn test_parse_size() {
     }
 }
 
+/// Parse a `KEY=VALUE` tag
+pub fn parse_tag(input: &str) -> Option<(&str, &str)> {
+    let mut parts = input.splitn(2, '=');
+    let key = parts.next()?;
+    let value = parts.next()?;
+    if key.is_empty() {
+        return None;
+    }
+    Some((key, value))
+}
+
+#[test]
+fn test_parse_tag() {
+    assert_eq!(parse_tag("env=prod"), Some(("env", "prod")));
+    assert_eq!(parse_tag("cmd=a=b"), Some(("cmd", "a=b")));
+    assert_eq!(parse_tag("empty="), Some(("empty", "")));
+    assert_eq!(parse_tag("=prod"), None);
+    assert_eq!(parse_tag("env"), None);
+}
+
+/// Line of `rdedup ls --long` output
+///
+/// Tab separated: name, creation time, size, chunk count, hostname and tags,
+/// with `-` for anything not recorded.
+pub fn format_name_long(name: &str, metadata: &NameMetadata) -> String {
+    fn or_dash<T: ToString>(value: Option<T>) -> String {
+        value.map_or_else(|| "-".into(), |v| v.to_string())
+    }
+
+    let tags = metadata
+        .tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        name,
+        or_dash(metadata.created.map(|created| created.to_rfc3339())),
+        or_dash(metadata.size),
+        or_dash(metadata.chunks),
+        or_dash(metadata.hostname.as_ref()),
+        if tags.is_empty() { "-".into() } else { tags },
+    )
+}
+
+#[test]
+fn test_format_name_long() {
+    let mut metadata = NameMetadata::default();
+    assert_eq!(format_name_long("old", &metadata), "old\t-\t-\t-\t-\t-");
+
+    metadata.size = Some(1024);
+    metadata.chunks = Some(2);
+    metadata.hostname = Some("host".into());
+    metadata.tags.insert("env".into(), "prod".into());
+    metadata.tags.insert("app".into(), "db".into());
+    assert_eq!(
+        format_name_long("new", &metadata),
+        "new\t-\t1024\t2\thost\tapp=db,env=prod"
+    );
+}
+
+/// Format a size in bytes in human-readable form, like "1.5 MiB"
+pub fn format_size(size: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+#[test]
+fn test_format_size() {
+    assert_eq!(format_size(0), "0 B");
+    assert_eq!(format_size(1023), "1023 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(10 * 1024 * 1024), "10.0 MiB");
+    assert_eq!(format_size(1024u64.pow(4)), "1.0 TiB");
+}
+
+/// Format a duration in seconds like "1:02:03" or "02:03"
+pub fn format_duration(secs: u64) -> String {
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, mins, secs)
+    } else {
+        format!("{:02}:{:02}", mins, secs)
+    }
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!(format_duration(0), "00:00");
+    assert_eq!(format_duration(123), "02:03");
+    assert_eq!(format_duration(3723), "1:02:03");
+}
+
+/// Size of the data on stdin, if it's a regular file
+#[cfg(unix)]
+pub fn stdin_size() -> Option<u64> {
+    fs::metadata("/dev/stdin")
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
+
+#[cfg(not(unix))]
+pub fn stdin_size() -> Option<u64> {
+    None
+}
+
+/// Escape repository URI so it can be used as a directory name
+///
+/// Alphanumerics, `-` and `.` are kept, any other byte is written as `_xx`,
+/// so different URIs always map to different names.
+fn escape_url(url: &Url) -> String {
+    let mut s = String::new();
+    for &b in url.as_str().as_bytes() {
+        if (b as char).is_ascii_alphanumeric() || b == b'-' || b == b'.' {
+            s.push(b as char);
+        } else {
+            s.push_str(&format!("_{:02x}", b));
+        }
+    }
+    s
+}
+
+/// Default directory of the local chunk cache of a repository
+///
+/// `$XDG_CACHE_HOME/rdedup/<uri>`, or `~/.cache/rdedup/<uri>`.
+pub fn default_cache_dir(url: &Url) -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache"))
+        })?;
+
+    Some(base.join("rdedup").join(escape_url(url)))
+}
+
+#[test]
+fn test_escape_url() {
+    let url = Url::parse("sftp://user@host:22/some_dir/repo-1.x").unwrap();
+    assert_eq!(
+        escape_url(&url),
+        "sftp_3a_2f_2fuser_40host_3a22_2fsome_5fdir_2frepo-1.x"
+    );
+}
+
+/// Create a new file readable only by the owner
+pub fn create_secret_file(path: &OsStr) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
 pub fn read_passphrase() -> io::Result<String> {
     if let Ok(pass) = env::var("RDEDUP_PASSPHRASE") {
         eprint!("Using passphrase set in RDEDUP_PASSPHRASE\n");